@@ -410,6 +410,9 @@ async fn delete_a_snapshot(
                     RegionState::Failed => {
                         bail!("Deleted snapshot went to Failed");
                     }
+                    RegionState::Resizing => {
+                        bail!("Deleted snapshot went to Resizing");
+                    }
                 }
             }
 
@@ -34,4 +34,10 @@ if [ "$val" != '""' ]; then
         args+=( "$val" )
 fi
 
+val="$(svcprop -c -p config/repair_server_name "${SMF_FMRI}")"
+if [ "$val" != '""' ]; then
+        args+=( '--repair-server-name' )
+        args+=( "$val" )
+fi
+
 exec /opt/oxide/crucible/bin/crucible-downstairs run "${args[@]}"
@@ -2,17 +2,22 @@
 
 use super::model::*;
 use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
 use crucible_common::write_json;
 use serde::{Deserialize, Serialize};
-use slog::{crit, error, info, Logger};
-use std::collections::BTreeMap;
+use slog::{crit, error, info, o, warn, Logger};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Command;
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use tokio::sync::watch;
+use uuid::Uuid;
 
+use crate::dataset::Dataset;
+use crate::retention;
 use crate::snapshot_interface::SnapshotInterface;
-use crate::ZFSDataset;
 
 pub struct DataFile {
     log: Logger,
@@ -24,16 +29,83 @@ pub struct DataFile {
     bell: Condvar,
     inner: Mutex<Inner>,
     snapshot_interface: Arc<dyn SnapshotInterface>,
+    dataset: Arc<dyn Dataset>,
+    changes: Mutex<ChangeLog>,
+    generation: watch::Sender<u64>,
+    downstairs_program: PathBuf,
+    repair_server_name: Option<String>,
+    verify_jobs: Arc<Mutex<BTreeMap<Uuid, VerifyJob>>>,
+    last_prunes: Mutex<BTreeMap<RegionId, PruneResult>>,
 }
 
+/// How many finished verify jobs to remember
+const MAX_VERIFY_JOBS: usize = 64;
+
+/// How many state changes to remember for `DataFile::changes_after`
+const MAX_CHANGES: usize = 1024;
+
+/// The most recent state changes, oldest first. This is not persisted:
+/// generation numbers start again from zero when the agent restarts, with a
+/// new `epoch` so that callers can tell.
+struct ChangeLog {
+    epoch: Uuid,
+    generation: u64,
+    changes: VecDeque<StateChange>,
+}
+
+/// A region could not be created because its reservation does not fit in the
+/// dataset's remaining capacity.
+#[derive(Debug)]
+pub struct InsufficientCapacity {
+    pub needed: u64,
+    pub available: u64,
+    pub total: u64,
+}
+
+impl std::fmt::Display for InsufficientCapacity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "region needs {} bytes but only {} of {} bytes are unreserved",
+            self.needed, self.available, self.total
+        )
+    }
+}
+
+impl std::error::Error for InsufficientCapacity {}
+
+/// A region could not be created or resized because its size does not fit
+/// in 64 bits.
+#[derive(Debug)]
+pub struct RegionTooLarge {
+    pub block_size: u64,
+    pub extent_size: u64,
+    pub extent_count: u32,
+}
+
+impl std::fmt::Display for RegionTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "region of {} extents of {} blocks of {} bytes is too large",
+            self.extent_count, self.extent_size, self.block_size
+        )
+    }
+}
+
+impl std::error::Error for RegionTooLarge {}
+
 #[derive(Serialize, Deserialize, Default)]
 struct Inner {
     regions: BTreeMap<RegionId, Region>,
     // indexed by region id and snapshot name
     running_snapshots: BTreeMap<RegionId, BTreeMap<String, RunningSnapshot>>,
+    #[serde(default)]
+    retention: BTreeMap<RegionId, RetentionPolicy>,
 }
 
 impl DataFile {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         log: Logger,
         base_path: &Path,
@@ -41,6 +113,9 @@ impl DataFile {
         port_min: u16,
         port_max: u16,
         snapshot_interface: Arc<dyn SnapshotInterface>,
+        dataset: Arc<dyn Dataset>,
+        downstairs_program: PathBuf,
+        repair_server_name: Option<String>,
     ) -> Result<DataFile> {
         let mut conf_path = base_path.to_path_buf();
         conf_path.push("crucible.json");
@@ -67,6 +142,17 @@ impl DataFile {
             bell: Condvar::new(),
             inner: Mutex::new(inner),
             snapshot_interface,
+            dataset,
+            changes: Mutex::new(ChangeLog {
+                epoch: Uuid::new_v4(),
+                generation: 0,
+                changes: VecDeque::new(),
+            }),
+            generation: watch::channel(0).0,
+            downstairs_program,
+            repair_server_name,
+            verify_jobs: Arc::new(Mutex::new(BTreeMap::new())),
+            last_prunes: Mutex::new(BTreeMap::new()),
         })
     }
 
@@ -74,6 +160,11 @@ impl DataFile {
         self.listen
     }
 
+    /// The name that downstairs' repair server certificates are issued for
+    pub fn repair_server_name(&self) -> Option<&str> {
+        self.repair_server_name.as_deref()
+    }
+
     pub fn regions(&self) -> Vec<Region> {
         self.inner
             .lock()
@@ -94,6 +185,79 @@ impl DataFile {
         self.inner.lock().unwrap().regions.get(id).cloned()
     }
 
+    /**
+     * Record that a region, or one of its running snapshots, moved to a new
+     * state, and wake anyone waiting for changes.
+     */
+    fn record_change(
+        &self,
+        id: &RegionId,
+        snapshot_name: Option<&str>,
+        state: &State,
+        reason: Option<String>,
+    ) {
+        let mut changes = self.changes.lock().unwrap();
+
+        changes.generation += 1;
+        let change = StateChange {
+            generation: changes.generation,
+            time: Utc::now(),
+            id: id.clone(),
+            snapshot_name: snapshot_name.map(String::from),
+            state: state.clone(),
+            reason,
+        };
+
+        changes.changes.push_back(change);
+        if changes.changes.len() > MAX_CHANGES {
+            changes.changes.pop_front();
+        }
+
+        self.generation.send_replace(changes.generation);
+    }
+
+    /**
+     * Return the state changes after `generation` of `epoch` (if the caller
+     * has seen one yet).
+     */
+    pub fn changes_after(
+        &self,
+        epoch: Option<Uuid>,
+        generation: u64,
+    ) -> StateChanges {
+        let changes = self.changes.lock().unwrap();
+
+        // If the agent restarted, or changes have been discarded since the
+        // caller last looked, the caller could have missed some.
+        let oldest = changes
+            .changes
+            .front()
+            .map(|c| c.generation)
+            .unwrap_or(changes.generation + 1);
+        let truncated = epoch.is_some_and(|e| e != changes.epoch)
+            || generation > changes.generation
+            || generation + 1 < oldest;
+
+        StateChanges {
+            epoch: changes.epoch,
+            generation: changes.generation,
+            truncated,
+            changes: changes
+                .changes
+                .iter()
+                .filter(|c| truncated || c.generation > generation)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /**
+     * Watch the latest change generation.
+     */
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+
     /**
      * Store the database into the JSON file.
      */
@@ -201,6 +365,16 @@ impl DataFile {
             return Ok(r.clone());
         }
 
+        let Some(reservation) = create.reservation() else {
+            return Err(RegionTooLarge {
+                block_size: create.block_size,
+                extent_size: create.extent_size,
+                extent_count: create.extent_count,
+            }
+            .into());
+        };
+        self.check_capacity(&inner, &create.id, reservation)?;
+
         /*
          * Allocate a port number that is not yet in use.
          */
@@ -223,11 +397,13 @@ impl DataFile {
             root_pem: create.root_pem,
             source: create.source,
             read_only,
+            failure: None,
         };
 
         info!(self.log, "region {} state: {:?}", r.id.0, r.state);
         let old = inner.regions.insert(create.id, r.clone());
         assert!(old.is_none());
+        self.record_change(&r.id, None, &r.state, None);
 
         /*
          * Wake the worker thread to look at the region we've created.
@@ -239,6 +415,144 @@ impl DataFile {
         Ok(r)
     }
 
+    /**
+     * Make sure `needed` more bytes can be reserved for a region.
+     * Reservations are not enforced on every platform, so whichever of the
+     * reserved and used space is larger is what's not available.
+     */
+    fn check_capacity(
+        &self,
+        inner: &MutexGuard<Inner>,
+        id: &RegionId,
+        needed: u64,
+    ) -> Result<()> {
+        let space = self.dataset.space()?;
+        let total = space.used + space.available;
+        let reserved: u64 = inner
+            .regions
+            .values()
+            .filter(|r| r.holds_space())
+            .map(|r| r.reservation())
+            .sum();
+        let available = total.saturating_sub(reserved.max(space.used));
+
+        if needed > available {
+            error!(
+                self.log,
+                "region {} needs {} bytes, but only {} of {} are available",
+                id.0,
+                needed,
+                available,
+                total,
+            );
+            return Err(InsufficientCapacity {
+                needed,
+                available,
+                total,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Nexus has requested that we grow a region to `extent_count` extents.
+     * Like region creation this is idempotent: asking for the size the region
+     * already has (or is being resized to) returns its current state.
+     *
+     * The worker thread stops the region's downstairs, extends the region,
+     * and then starts the downstairs again.
+     */
+    pub fn resize_region_request(
+        &self,
+        id: &RegionId,
+        extent_count: u32,
+    ) -> Result<Region> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let r = inner
+            .regions
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("region {} does not exist", id.0))?;
+
+        match r.state {
+            State::Created => (),
+            State::Resizing if r.extent_count == extent_count => {
+                return Ok(r);
+            }
+            State::Resizing => {
+                bail!(
+                    "region {} is already being resized to {} extents",
+                    id.0,
+                    r.extent_count
+                );
+            }
+            _ => {
+                bail!(
+                    "region {} is in state {:?}, cannot resize",
+                    id.0,
+                    r.state
+                );
+            }
+        }
+
+        if extent_count < r.extent_count {
+            bail!(
+                "region {} cannot shrink from {} to {} extents",
+                id.0,
+                r.extent_count,
+                extent_count
+            );
+        }
+
+        if extent_count == r.extent_count {
+            return Ok(r);
+        }
+
+        if r.read_only {
+            bail!("region {} is read only, cannot resize", id.0);
+        }
+
+        let mut resized = r.clone();
+        resized.extent_count = extent_count;
+        resized.state = State::Resizing;
+
+        let Some(reservation) = resized.checked_reservation() else {
+            return Err(RegionTooLarge {
+                block_size: resized.block_size,
+                extent_size: resized.extent_size,
+                extent_count: resized.extent_count,
+            }
+            .into());
+        };
+        self.check_capacity(&inner, id, reservation - r.reservation())?;
+
+        info!(
+            self.log,
+            "region {} extent count: {} -> {}",
+            id.0,
+            r.extent_count,
+            resized.extent_count,
+        );
+        info!(
+            self.log,
+            "region {} state: {:?} -> {:?}", id.0, r.state, resized.state,
+        );
+        inner.regions.insert(id.clone(), resized.clone());
+        self.record_change(id, None, &resized.state, None);
+
+        /*
+         * Wake the worker thread to resize the region.
+         */
+        self.bell.notify_all();
+
+        self.store(inner);
+
+        Ok(resized)
+    }
+
     pub fn create_running_snapshot_request(
         &self,
         request: CreateRunningSnapshotRequest,
@@ -300,6 +614,7 @@ impl DataFile {
             name: request.name.clone(),
             port_number,
             state: State::Requested,
+            failure: None,
         };
 
         info!(
@@ -315,6 +630,7 @@ impl DataFile {
             .get_mut(&request.id)
             .unwrap()
             .insert(request.name, s.clone());
+        self.record_change(&s.id, Some(&s.name), &s.state, None);
 
         /*
          * Wake the worker thread to look at the snapshot we've created.
@@ -393,6 +709,12 @@ impl DataFile {
                         );
 
                         existing.state = State::Tombstoned;
+                        self.record_change(
+                            &existing.id,
+                            Some(&existing.name),
+                            &existing.state,
+                            None,
+                        );
 
                         /*
                          * Wake the worker thread to remove the snapshot we've
@@ -437,7 +759,10 @@ impl DataFile {
             if let Some(running_snapshot) = running_snapshots.get(&request.name)
             {
                 match running_snapshot.state {
-                    State::Requested | State::Created | State::Tombstoned => {
+                    State::Requested
+                    | State::Created
+                    | State::Tombstoned
+                    | State::Resizing => {
                         bail!(
                             "read-only downstairs running for region {} snapshot {}",
                             request.id.0,
@@ -466,9 +791,7 @@ impl DataFile {
         path.push("regions");
         path.push(request.id.0.clone());
 
-        let dataset = match ZFSDataset::new(
-            path.into_os_string().into_string().unwrap(),
-        ) {
+        let dataset = match self.snapshot_interface.dataset_for_region(&path) {
             Ok(dataset) => dataset,
             Err(e) => {
                 // This branch can only be entered if `zfs list` for that
@@ -484,7 +807,7 @@ impl DataFile {
                             return Ok(());
                         }
 
-                        State::Requested | State::Created => {
+                        State::Requested | State::Created | State::Resizing => {
                             // This is a bug: according to the agent's datafile,
                             // the region exists, but according to zfs list, it
                             // does not
@@ -507,7 +830,7 @@ impl DataFile {
             }
         };
 
-        let snapshot_name = format!("{}@{}", dataset.dataset(), request.name);
+        let snapshot_name = format!("{}@{}", dataset, request.name);
 
         self.snapshot_interface.delete_snapshot(snapshot_name)?;
 
@@ -515,9 +838,9 @@ impl DataFile {
     }
 
     /**
-     * Mark a particular region as failed to provision.
+     * Mark a particular region as failed, and record why.
      */
-    pub fn fail(&self, id: &RegionId) {
+    pub fn fail(&self, id: &RegionId, phase: FailurePhase, message: String) {
         let mut inner = self.inner.lock().unwrap();
 
         let r = inner.regions.get_mut(id).unwrap();
@@ -531,14 +854,22 @@ impl DataFile {
             "region {} state: {:?} -> {:?}", r.id.0, r.state, nstate,
         );
         r.state = nstate;
+        r.failure = Some(Failure::new(phase, message.clone()));
+        self.record_change(&r.id, None, &r.state, Some(message));
 
         self.store(inner);
     }
 
     /**
-     * Mark a particular running snapshot as failed to provision.
+     * Mark a particular running snapshot as failed, and record why.
      */
-    pub fn fail_rs(&self, region_id: &RegionId, snapshot_name: &str) {
+    pub fn fail_rs(
+        &self,
+        region_id: &RegionId,
+        snapshot_name: &str,
+        phase: FailurePhase,
+        message: String,
+    ) {
         let mut inner = self.inner.lock().unwrap();
 
         let rs = inner
@@ -562,8 +893,164 @@ impl DataFile {
             nstate,
         );
         rs.state = nstate;
+        rs.failure = Some(Failure::new(phase, message.clone()));
+        self.record_change(&rs.id, Some(&rs.name), &rs.state, Some(message));
+
+        self.store(inner);
+    }
+
+    /**
+     * Retry whatever a failed region was doing, if that is safe:
+     *
+     * - creation starts again from an empty dataset,
+     * - extending a region is idempotent: the downstairs creates and syncs
+     *   the new extent files before recording the new size, replacing
+     *   anything left behind by an interrupted attempt,
+     * - destruction can always be attempted again.
+     *
+     * Retrying a region that is not failed returns its current state.
+     */
+    pub fn retry(&self, id: &RegionId) -> Result<Region> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let r = inner
+            .regions
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("region {} does not exist", id.0))?;
+
+        let nstate = match (&r.state, &r.failure) {
+            (State::Requested | State::Created | State::Resizing, _) => {
+                return Ok(r);
+            }
+            (State::Failed, Some(failure)) => match failure.phase {
+                FailurePhase::Create => State::Requested,
+                FailurePhase::Resize => State::Resizing,
+                FailurePhase::Destroy => State::Tombstoned,
+            },
+            (State::Failed, None) => {
+                bail!("region {} failed for an unknown reason", id.0);
+            }
+            (State::Tombstoned | State::Destroyed, _) => {
+                bail!(
+                    "region {} is in state {:?}, cannot retry",
+                    id.0,
+                    r.state
+                );
+            }
+        };
+
+        /*
+         * A failed region does not hold a reservation, so make sure it still
+         * fits before trying to create or resize it again.
+         */
+        if nstate != State::Tombstoned {
+            self.check_capacity(&inner, id, r.reservation())?;
+        }
+
+        let r = inner.regions.get_mut(id).unwrap();
+        info!(
+            self.log,
+            "retrying region {} state: {:?} -> {:?} after {:?}",
+            r.id.0,
+            r.state,
+            nstate,
+            r.failure,
+        );
+        r.state = nstate;
+        r.failure = None;
+        self.record_change(&r.id, None, &r.state, None);
+        let r = r.clone();
+
+        /*
+         * Wake the worker thread to try again.
+         */
+        self.bell.notify_all();
+
+        self.store(inner);
+
+        Ok(r)
+    }
+
+    /**
+     * Retry whatever a failed running snapshot was doing: starting or
+     * stopping its service can always be attempted again.
+     *
+     * Retrying a running snapshot that is not failed returns its current
+     * state.
+     */
+    pub fn retry_rs(
+        &self,
+        region_id: &RegionId,
+        snapshot_name: &str,
+    ) -> Result<RunningSnapshot> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let rs = inner
+            .running_snapshots
+            .get_mut(region_id)
+            .and_then(|s| s.get_mut(snapshot_name))
+            .ok_or_else(|| {
+                anyhow!(
+                    "region {} running snapshot {} does not exist",
+                    region_id.0,
+                    snapshot_name
+                )
+            })?;
+
+        let nstate = match (&rs.state, &rs.failure) {
+            (State::Requested | State::Created, _) => {
+                return Ok(rs.clone());
+            }
+            (State::Failed, Some(failure)) => match failure.phase {
+                FailurePhase::Create => State::Requested,
+                FailurePhase::Destroy => State::Tombstoned,
+                FailurePhase::Resize => {
+                    bail!("running snapshots are never resized");
+                }
+            },
+            (State::Failed, None) => {
+                bail!(
+                    "region {} running snapshot {} failed for an unknown \
+                    reason",
+                    region_id.0,
+                    snapshot_name
+                );
+            }
+            (state, _) => {
+                bail!(
+                    "region {} running snapshot {} is in state {:?}, cannot \
+                    retry",
+                    region_id.0,
+                    snapshot_name,
+                    state
+                );
+            }
+        };
+
+        info!(
+            self.log,
+            "retrying region {} running snapshot {} state: {:?} -> {:?} \
+            after {:?}",
+            rs.id.0,
+            rs.name,
+            rs.state,
+            nstate,
+            rs.failure,
+        );
+        rs.state = nstate;
+        rs.failure = None;
+        self.record_change(&rs.id, Some(&rs.name), &rs.state, None);
+        let rs = rs.clone();
+
+        /*
+         * Wake the worker thread to try again.
+         */
+        self.bell.notify_all();
 
         self.store(inner);
+
+        Ok(rs)
     }
 
     /**
@@ -591,6 +1078,38 @@ impl DataFile {
             "region {} state: {:?} -> {:?}", r.id.0, r.state, nstate,
         );
         r.state = nstate;
+        self.record_change(&r.id, None, &r.state, None);
+
+        self.store(inner);
+        Ok(())
+    }
+
+    /**
+     * Mark a particular region as resized.
+     */
+    pub fn resized(&self, id: &RegionId) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let r = inner.regions.get_mut(id).unwrap();
+        let nstate = State::Created;
+        match &r.state {
+            State::Resizing => (),
+            State::Tombstoned => {
+                /*
+                 * Nexus requested that we destroy this region before we
+                 * finished resizing it.
+                 */
+                return Ok(());
+            }
+            x => bail!("resized region in weird state {:?}", x),
+        }
+
+        info!(
+            self.log,
+            "region {} state: {:?} -> {:?}", r.id.0, r.state, nstate,
+        );
+        r.state = nstate;
+        self.record_change(&r.id, None, &r.state, None);
 
         self.store(inner);
         Ok(())
@@ -661,6 +1180,7 @@ impl DataFile {
             nstate,
         );
         rs.state = nstate;
+        self.record_change(&rs.id, Some(&rs.name), &rs.state, None);
 
         self.store(inner);
         Ok(())
@@ -687,6 +1207,11 @@ impl DataFile {
             "region {} state: {:?} -> {:?}", r.id.0, r.state, nstate,
         );
         r.state = nstate;
+        self.record_change(&r.id, None, &r.state, None);
+
+        // A destroyed region has no snapshots left to prune
+        inner.retention.remove(id);
+        self.last_prunes.lock().unwrap().remove(id);
 
         self.store(inner);
         Ok(())
@@ -722,6 +1247,7 @@ impl DataFile {
             nstate,
         );
         rs.state = nstate;
+        self.record_change(&rs.id, Some(&rs.name), &rs.state, None);
 
         self.store(inner);
         Ok(())
@@ -746,7 +1272,10 @@ impl DataFile {
                  * - Already destroyed; no more work to do.
                  */
             }
-            State::Requested | State::Created | State::Failed => {
+            State::Requested
+            | State::Created
+            | State::Resizing
+            | State::Failed => {
                 /*
                  * Schedule the destruction of this region.
                  */
@@ -758,6 +1287,7 @@ impl DataFile {
                     State::Tombstoned
                 );
                 r.state = State::Tombstoned;
+                self.record_change(&r.id, None, &r.state, None);
                 self.bell.notify_all();
                 self.store(inner);
             }
@@ -810,6 +1340,412 @@ impl DataFile {
         }
     }
 
+    /**
+     * Report space reserved and used by regions
+     */
+    pub fn capacity(&self) -> Result<Capacity> {
+        let space = self.dataset.space()?;
+        let regions_dataset = self.dataset.from_child_dataset("regions").ok();
+        let running_snapshots = self.running_snapshots();
+
+        let mut reserved = 0;
+        let mut regions = Vec::new();
+        for r in self.regions().into_iter().filter(|r| r.holds_space()) {
+            // The region's dataset may not have been created yet
+            let region_space = regions_dataset
+                .as_ref()
+                .and_then(|d| d.from_child_dataset(&r.id.0).ok())
+                .map(|d| d.space())
+                .transpose()?;
+
+            let running = running_snapshots
+                .get(&r.id)
+                .map(|s| {
+                    s.values()
+                        .filter(|s| {
+                            matches!(s.state, State::Requested | State::Created)
+                        })
+                        .count()
+                })
+                .unwrap_or(0);
+
+            reserved += r.reservation();
+            regions.push(RegionCapacity {
+                id: r.id.clone(),
+                state: r.state.clone(),
+                reserved: r.reservation(),
+                used: region_space.map(|s| s.used).unwrap_or(0),
+                snapshots: region_space.map(|s| s.snapshots).unwrap_or(0),
+                running_snapshots: running,
+            });
+        }
+
+        Ok(Capacity {
+            total: space.used + space.available,
+            reserved,
+            used: space.used,
+            regions,
+        })
+    }
+
+    /**
+     * Take a snapshot of a region
+     */
+    pub fn create_snapshot(
+        &self,
+        region_id: &RegionId,
+        snapshot_name: &str,
+    ) -> Result<Snapshot> {
+        let Some(region) = self.get(region_id) else {
+            bail!("region {:?} does not exist", region_id);
+        };
+
+        if region.state != State::Created {
+            bail!(
+                "region {:?} is in state {:?}, cannot snapshot",
+                region_id,
+                region.state
+            );
+        }
+
+        let mut path = self.base_path.to_path_buf();
+        path.push("regions");
+        path.push(region_id.0.clone());
+
+        let dataset = self.snapshot_interface.dataset_for_region(&path)?;
+
+        info!(
+            self.log,
+            "taking snapshot {} of dataset {}", snapshot_name, dataset
+        );
+
+        self.snapshot_interface
+            .take_snapshot(dataset.clone(), snapshot_name.to_string())?;
+
+        self.snapshot_interface
+            .get_snapshots_for_dataset(dataset)?
+            .into_iter()
+            .find(|s| s.name == snapshot_name)
+            .ok_or_else(|| anyhow!("snapshot {} not found", snapshot_name))
+    }
+
+    /**
+     * Start checking the data of a region, or one of its snapshots, against
+     * the hashes stored with each block. If such a check is already running,
+     * return that instead of starting another.
+     */
+    pub fn verify(
+        &self,
+        region_id: &RegionId,
+        snapshot_name: Option<String>,
+    ) -> Result<VerifyJob> {
+        let Some(region) = self.get(region_id) else {
+            bail!("region {:?} does not exist", region_id);
+        };
+
+        if region.state != State::Created {
+            bail!(
+                "region {:?} is in state {:?}, cannot verify",
+                region_id,
+                region.state
+            );
+        }
+
+        let mut path = self.base_path.to_path_buf();
+        path.push("regions");
+        path.push(region_id.0.clone());
+
+        if let Some(snapshot_name) = &snapshot_name {
+            path.push(".zfs");
+            path.push("snapshot");
+            path.push(snapshot_name);
+
+            if !path.is_dir() {
+                bail!("snapshot {} not found at {:?}", snapshot_name, path);
+            }
+        }
+
+        // The downstairs may be writing to a live region while it is read,
+        // which would look like corruption. Verify a temporary snapshot of it
+        // instead.
+        let live = if snapshot_name.is_none() {
+            let dataset = self.snapshot_interface.dataset_for_region(&path)?;
+            Some((Arc::clone(&self.snapshot_interface), dataset))
+        } else {
+            None
+        };
+
+        let mut jobs = self.verify_jobs.lock().unwrap();
+
+        if let Some(job) = jobs.values().find(|j| {
+            j.state == VerifyState::Running
+                && &j.region_id == region_id
+                && j.snapshot_name == snapshot_name
+        }) {
+            return Ok(job.clone());
+        }
+
+        // Forget the oldest finished jobs
+        while jobs.len() >= MAX_VERIFY_JOBS {
+            let Some(oldest) = jobs
+                .values()
+                .filter(|j| j.state != VerifyState::Running)
+                .min_by_key(|j| j.finished)
+                .map(|j| j.job_id)
+            else {
+                break;
+            };
+            jobs.remove(&oldest);
+        }
+
+        let job = VerifyJob {
+            job_id: Uuid::new_v4(),
+            region_id: region_id.clone(),
+            snapshot_name,
+            state: VerifyState::Running,
+            started: Utc::now(),
+            finished: None,
+            extents: vec![],
+            corrupt_blocks: 0,
+            error: None,
+        };
+        jobs.insert(job.job_id, job.clone());
+
+        info!(
+            self.log,
+            "verify job {} for region {} snapshot {:?} at {:?}",
+            job.job_id,
+            region_id.0,
+            job.snapshot_name,
+            path,
+        );
+
+        let log = self.log.new(o!("verify" => job.job_id.to_string()));
+        let program = self.downstairs_program.clone();
+        let output = self.base_path.join(format!("verify-{}.json", job.job_id));
+        let jobs = Arc::clone(&self.verify_jobs);
+        let job_id = job.job_id;
+
+        std::thread::spawn(move || {
+            let result = match &live {
+                Some((snapshot_interface, dataset)) => verify_live_region(
+                    &log,
+                    snapshot_interface.as_ref(),
+                    dataset,
+                    &format!("verify-{}", job_id),
+                    &program,
+                    &path,
+                    &output,
+                ),
+                None => verify_region(&log, &program, &path, &output),
+            };
+
+            let mut jobs = jobs.lock().unwrap();
+            let job = jobs.get_mut(&job_id).unwrap();
+            job.finished = Some(Utc::now());
+
+            match result {
+                Ok(extents) => {
+                    job.corrupt_blocks = extents
+                        .iter()
+                        .map(|e| e.corrupt_blocks.len() as u64)
+                        .sum();
+                    job.extents = extents;
+                    job.state = VerifyState::Done;
+
+                    if job.corrupt_blocks > 0 {
+                        warn!(log, "{} corrupt blocks", job.corrupt_blocks);
+                    } else {
+                        info!(log, "no corrupt blocks");
+                    }
+                }
+                Err(e) => {
+                    error!(log, "verify failed: {:?}", e);
+                    job.error = Some(format!("{:#}", e));
+                    job.state = VerifyState::Failed;
+                }
+            }
+        });
+
+        Ok(job)
+    }
+
+    pub fn verify_job(&self, job_id: &Uuid) -> Option<VerifyJob> {
+        self.verify_jobs.lock().unwrap().get(job_id).cloned()
+    }
+
+    /**
+     * Set the policy that decides which of a region's snapshots the
+     * background pruner keeps.
+     */
+    pub fn set_retention(
+        &self,
+        id: &RegionId,
+        policy: RetentionPolicy,
+    ) -> Result<RetentionPolicy> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let Some(region) = inner.regions.get(id) else {
+            bail!("region {:?} does not exist", id);
+        };
+
+        match region.state {
+            State::Requested
+            | State::Created
+            | State::Resizing
+            | State::Failed => (),
+
+            State::Tombstoned | State::Destroyed => {
+                bail!("region {:?} is in state {:?}", id, region.state);
+            }
+        }
+
+        info!(self.log, "region {} retention policy {:?}", id.0, policy);
+        inner.retention.insert(id.clone(), policy.clone());
+
+        self.store(inner);
+        Ok(policy)
+    }
+
+    /**
+     * Stop pruning a region's snapshots. This call is idempotent.
+     */
+    pub fn clear_retention(&self, id: &RegionId) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.retention.remove(id).is_some() {
+            info!(self.log, "region {} retention policy removed", id.0);
+            self.last_prunes.lock().unwrap().remove(id);
+            self.store(inner);
+        }
+    }
+
+    /**
+     * Work out what `policy` would do with a region's snapshots right now,
+     * without deleting anything.
+     */
+    pub fn retention_plan(
+        &self,
+        id: &RegionId,
+        policy: &RetentionPolicy,
+    ) -> Result<Vec<SnapshotRetention>> {
+        let snapshots = self.get_snapshots_for_region(id)?;
+
+        let protected: HashSet<String> = self
+            .inner
+            .lock()
+            .unwrap()
+            .running_snapshots
+            .get(id)
+            .map(|rs| {
+                rs.values()
+                    .filter(|rs| rs.state != State::Destroyed)
+                    .map(|rs| rs.name.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(retention::plan(policy, &snapshots, &protected))
+    }
+
+    /**
+     * Return a region's retention policy, the outcome of the last prune, and
+     * what the policy would do now. Returns None if there is no policy.
+     */
+    pub fn retention_status(
+        &self,
+        id: &RegionId,
+    ) -> Result<Option<RetentionStatus>> {
+        let policy = {
+            let inner = self.inner.lock().unwrap();
+            if !inner.regions.contains_key(id) {
+                bail!("region {:?} does not exist", id);
+            }
+            inner.retention.get(id).cloned()
+        };
+
+        let Some(policy) = policy else {
+            return Ok(None);
+        };
+
+        Ok(Some(RetentionStatus {
+            region_id: id.clone(),
+            snapshots: self.retention_plan(id, &policy)?,
+            last_prune: self.last_prunes.lock().unwrap().get(id).cloned(),
+            policy,
+        }))
+    }
+
+    /**
+     * Delete the snapshots that each region's retention policy doesn't keep.
+     * Only regions in state Created are pruned.
+     */
+    pub fn prune_snapshots(&self) {
+        let policies: Vec<(RegionId, RetentionPolicy)> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .retention
+                .iter()
+                .filter(|(id, _)| {
+                    inner
+                        .regions
+                        .get(id)
+                        .map(|r| r.state == State::Created)
+                        .unwrap_or(false)
+                })
+                .map(|(id, p)| (id.clone(), p.clone()))
+                .collect()
+        };
+
+        for (id, policy) in policies {
+            let plan = match self.retention_plan(&id, &policy) {
+                Ok(plan) => plan,
+                Err(e) => {
+                    error!(self.log, "region {} prune failed: {:?}", id.0, e);
+                    continue;
+                }
+            };
+
+            let mut result = PruneResult {
+                time: Utc::now(),
+                deleted: vec![],
+                errors: vec![],
+            };
+
+            for s in plan {
+                if s.action != RetentionAction::Delete {
+                    continue;
+                }
+
+                info!(
+                    self.log,
+                    "region {} pruning snapshot {}: {}", id.0, s.name, s.reason
+                );
+
+                let request = DeleteSnapshotRequest {
+                    id: id.clone(),
+                    name: s.name.clone(),
+                };
+
+                match self.delete_snapshot(request) {
+                    Ok(()) => result.deleted.push(s.name),
+                    Err(e) => {
+                        warn!(
+                            self.log,
+                            "region {} prune of snapshot {} failed: {:?}",
+                            id.0,
+                            s.name,
+                            e
+                        );
+                        result.errors.push(format!("{}: {}", s.name, e));
+                    }
+                }
+            }
+
+            self.last_prunes.lock().unwrap().insert(id, result);
+        }
+    }
+
     /**
      * Get snapshots for a region
      */
@@ -836,7 +1772,7 @@ impl DataFile {
                 return Ok(vec![]);
             }
 
-            State::Created => {
+            State::Created | State::Resizing => {
                 // proceed to next section
             }
         }
@@ -847,19 +1783,87 @@ impl DataFile {
 
         info!(self.log, "path is {:?}", &path);
 
-        let dataset =
-            ZFSDataset::new(path.into_os_string().into_string().unwrap())?;
+        let dataset = self.snapshot_interface.dataset_for_region(&path)?;
 
-        info!(self.log, "dataset is {}", dataset.dataset());
+        info!(self.log, "dataset is {}", dataset);
 
-        let results = self
-            .snapshot_interface
-            .get_snapshots_for_dataset(dataset.dataset())?;
+        let results =
+            self.snapshot_interface.get_snapshots_for_dataset(dataset)?;
 
         Ok(results)
     }
 }
 
+/**
+ * Verify a temporary snapshot of the region in `dir`, which may be in use,
+ * and remove the snapshot afterwards.
+ */
+fn verify_live_region(
+    log: &Logger,
+    snapshot_interface: &dyn SnapshotInterface,
+    dataset: &str,
+    snapshot_name: &str,
+    prog: &Path,
+    dir: &Path,
+    output: &Path,
+) -> Result<Vec<ExtentVerification>> {
+    info!(
+        log,
+        "taking snapshot {} of dataset {}", snapshot_name, dataset
+    );
+    snapshot_interface
+        .take_snapshot(dataset.to_string(), snapshot_name.to_string())?;
+
+    let mut path = dir.to_path_buf();
+    path.push(".zfs");
+    path.push("snapshot");
+    path.push(snapshot_name);
+
+    let result = verify_region(log, prog, &path, output);
+
+    if let Err(e) = snapshot_interface
+        .delete_snapshot(format!("{}@{}", dataset, snapshot_name))
+    {
+        error!(log, "deleting snapshot {} failed: {:?}", snapshot_name, e);
+    }
+
+    result
+}
+
+/**
+ * Run the downstairs program in the mode where it checks every block of the
+ * region in `dir` against its hashes, and return its per-extent report.
+ */
+fn verify_region(
+    log: &Logger,
+    prog: &Path,
+    dir: &Path,
+    output: &Path,
+) -> Result<Vec<ExtentVerification>> {
+    let mut cmd = Command::new(prog);
+    cmd.env_clear()
+        .arg("verify")
+        .arg("--data")
+        .arg(dir)
+        .arg("--output")
+        .arg(output);
+
+    info!(log, "downstairs verify with: {:?}", cmd);
+    let res = cmd.output()?;
+
+    if !res.status.success() {
+        let err = String::from_utf8_lossy(&res.stderr);
+        let out = String::from_utf8_lossy(&res.stdout);
+        error!(log, "downstairs verify failed: out {:?} err {:?}", out, err);
+        bail!("region verify failure: {}", err.trim_end());
+    }
+
+    let report = std::fs::read(output);
+    let _ = std::fs::remove_file(output);
+
+    Ok(serde_json::from_slice(&report?)?)
+}
+
 #[cfg(test)]
 mod test {
     use anyhow::{bail, Result};
@@ -0,0 +1,465 @@
+// Copyright 2024 Oxide Computer Company
+
+use anyhow::{bail, Result};
+use slog::{error, info, Logger};
+use std::path::{Path, PathBuf};
+
+/// Space accounting for a dataset, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatasetSpace {
+    /// Used by the dataset and everything under it
+    pub used: u64,
+    /// Available to the dataset
+    pub available: u64,
+    /// Used only by the dataset's snapshots
+    pub snapshots: u64,
+}
+
+/// A interface for an implementation to manage the storage that regions live
+/// in.
+///
+/// On illumos each region is a ZFS dataset; elsewhere, it is a plain
+/// directory.
+pub trait Dataset: std::fmt::Debug + Send + Sync {
+    /// Look up "self/child", failing if it does not exist.
+    fn from_child_dataset(&self, child: &str) -> Result<Box<dyn Dataset>>;
+
+    /// Given "self", ensure that "self/child" exists, and return it.
+    ///
+    /// `reservation` and `quota` are in bytes, and may not be enforced by
+    /// every implementation.
+    fn ensure_child_dataset(
+        &self,
+        child: &str,
+        reservation: Option<u64>,
+        quota: Option<u64>,
+        log: &Logger,
+    ) -> Result<Box<dyn Dataset>>;
+
+    /// Change the reservation and quota of an existing dataset, in bytes.
+    /// `None` leaves that setting alone.
+    fn set_space_limits(
+        &self,
+        reservation: Option<u64>,
+        quota: Option<u64>,
+        log: &Logger,
+    ) -> Result<()>;
+
+    /// Returns the path where this dataset's contents can be found.
+    fn path(&self) -> Result<PathBuf>;
+
+    /// Remove this dataset and all of its contents.
+    fn destroy(self: Box<Self>, log: &Logger) -> Result<()>;
+
+    /// Returns the name of this dataset.
+    fn dataset(&self) -> String;
+
+    /// Returns how much space this dataset uses, and has available.
+    fn space(&self) -> Result<DatasetSpace>;
+}
+
+/// Run a command and return its stdout, failing if it did not succeed.
+fn command_output(cmd: &mut std::process::Command) -> Result<String> {
+    let res = cmd.output()?;
+    let out = String::from_utf8(res.stdout)?;
+
+    if !res.status.success() {
+        let err = String::from_utf8_lossy(&res.stderr);
+        bail!("{:?} failed! out:{} err:{}", cmd, out, err);
+    }
+
+    Ok(out)
+}
+
+#[derive(Debug)]
+pub struct ZFSDataset {
+    dataset: String,
+}
+
+impl ZFSDataset {
+    /// From either dataset name or path, create the ZFSDataset object.
+    ///
+    /// Fails if the dataset name does not exist, or the path does not exist (or
+    /// belong to a dataset).
+    pub fn new(dataset: String) -> Result<ZFSDataset> {
+        // Validate the argument is a dataset
+        let cmd = std::process::Command::new("zfs")
+            .arg("list")
+            .arg("-pH")
+            .arg("-o")
+            .arg("name")
+            .arg(&dataset)
+            .output()?;
+
+        if !cmd.status.success() {
+            let stderr =
+                String::from_utf8_lossy(&cmd.stderr).trim_end().to_string();
+            bail!("zfs list failed! {stderr}");
+        }
+
+        Ok(ZFSDataset {
+            dataset: String::from_utf8(cmd.stdout)?.trim_end().to_string(),
+        })
+    }
+}
+
+impl Dataset for ZFSDataset {
+    fn from_child_dataset(&self, child: &str) -> Result<Box<dyn Dataset>> {
+        let dataset = format!("{}/{}", self.dataset, child);
+
+        // Does it exist already?
+        let cmd = std::process::Command::new("zfs")
+            .arg("list")
+            .arg(&dataset)
+            .output()?;
+
+        if cmd.status.success() {
+            return Ok(Box::new(ZFSDataset { dataset }));
+        }
+
+        bail!("Dataset does not exist!");
+    }
+
+    fn ensure_child_dataset(
+        &self,
+        child: &str,
+        reservation: Option<u64>,
+        quota: Option<u64>,
+        log: &Logger,
+    ) -> Result<Box<dyn Dataset>> {
+        let dataset = format!("{}/{}", self.dataset, child);
+
+        // Does it exist already?
+        let cmd = std::process::Command::new("zfs")
+            .arg("list")
+            .arg(&dataset)
+            .output()?;
+
+        if cmd.status.success() {
+            return Ok(Box::new(ZFSDataset { dataset }));
+        }
+
+        // If not, create it
+        let mut cmd = std::process::Command::new("zfs");
+        cmd.arg("create");
+
+        if let Some(reservation) = reservation {
+            info!(log, "zfs set reservation of {reservation} for {dataset}");
+            cmd.arg("-o").arg(format!("reservation={}", reservation));
+        }
+
+        if let Some(quota) = quota {
+            info!(log, "zfs set quota of {quota} for {dataset}");
+            cmd.arg("-o").arg(format!("quota={}", quota));
+        }
+
+        let res = cmd.arg(&dataset).output()?;
+
+        if !res.status.success() {
+            let out = String::from_utf8_lossy(&res.stdout);
+            let err = String::from_utf8_lossy(&res.stderr);
+            bail!("zfs create failed! out:{} err:{}", out, err);
+        }
+
+        Ok(Box::new(ZFSDataset { dataset }))
+    }
+
+    fn set_space_limits(
+        &self,
+        reservation: Option<u64>,
+        quota: Option<u64>,
+        log: &Logger,
+    ) -> Result<()> {
+        if reservation.is_none() && quota.is_none() {
+            return Ok(());
+        }
+
+        let mut cmd = std::process::Command::new("zfs");
+        cmd.arg("set");
+
+        if let Some(reservation) = reservation {
+            info!(
+                log,
+                "zfs set reservation of {reservation} for {}", self.dataset
+            );
+            cmd.arg(format!("reservation={}", reservation));
+        }
+
+        if let Some(quota) = quota {
+            info!(log, "zfs set quota of {quota} for {}", self.dataset);
+            cmd.arg(format!("quota={}", quota));
+        }
+
+        command_output(cmd.arg(&self.dataset))?;
+
+        Ok(())
+    }
+
+    fn path(&self) -> Result<PathBuf> {
+        let cmd = std::process::Command::new("zfs")
+            .arg("list")
+            .arg("-pH")
+            .arg("-o")
+            .arg("mountpoint")
+            .arg(&self.dataset)
+            .output()?;
+
+        let out = String::from_utf8(cmd.stdout)?;
+
+        if !cmd.status.success() {
+            let err = String::from_utf8_lossy(&cmd.stderr);
+            bail!("zfs list mountpoint failed! out:{} err:{}", out, err);
+        }
+
+        Ok(Path::new(&out.trim_end()).to_path_buf())
+    }
+
+    fn destroy(self: Box<Self>, log: &Logger) -> Result<()> {
+        // Retry a few times: apply_smf will remove the corresponding downstairs
+        // instance but this may take a few seconds to propagate.
+        for i in 0..5 {
+            let cmd = std::process::Command::new("zfs")
+                .arg("destroy")
+                .arg(&self.dataset)
+                .output()?;
+
+            if !cmd.status.success() {
+                let out = String::from_utf8_lossy(&cmd.stdout);
+                let err = String::from_utf8_lossy(&cmd.stderr);
+
+                error!(
+                    log,
+                    "zfs dataset {} delete attempt {} failed: out:{} err:{}",
+                    self.dataset,
+                    i,
+                    out,
+                    err,
+                );
+
+                if i == 4 {
+                    bail!(
+                        "zfs destroy dataset failed! out:{} err:{}",
+                        out,
+                        err
+                    );
+                }
+
+                std::thread::sleep(std::time::Duration::from_secs(2));
+            } else {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dataset(&self) -> String {
+        self.dataset.clone()
+    }
+
+    fn space(&self) -> Result<DatasetSpace> {
+        let out = command_output(
+            std::process::Command::new("zfs")
+                .arg("list")
+                .arg("-pH")
+                .arg("-o")
+                .arg("used,available,usedbysnapshots")
+                .arg(&self.dataset),
+        )?;
+
+        let values = out
+            .split_whitespace()
+            .map(|v| v.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let [used, available, snapshots] = values[..] else {
+            bail!("unexpected zfs list output {:?}", out);
+        };
+
+        Ok(DatasetSpace {
+            used,
+            available,
+            snapshots,
+        })
+    }
+}
+
+/// A dataset which is a plain directory, for platforms without ZFS.
+///
+/// Reservations and quotas cannot be expressed for a directory, so they are
+/// only logged; the operator is responsible for making sure the backing
+/// filesystem has room for the regions the agent is asked to create.
+#[derive(Debug)]
+pub struct DirectoryDataset {
+    path: PathBuf,
+}
+
+impl DirectoryDataset {
+    /// Fails if `path` is not an existing directory.
+    pub fn new(path: PathBuf) -> Result<DirectoryDataset> {
+        if !path.is_dir() {
+            bail!("{:?} is not a directory!", path);
+        }
+
+        Ok(DirectoryDataset { path })
+    }
+}
+
+impl Dataset for DirectoryDataset {
+    fn from_child_dataset(&self, child: &str) -> Result<Box<dyn Dataset>> {
+        let path = self.path.join(child);
+
+        if path.is_dir() {
+            return Ok(Box::new(DirectoryDataset { path }));
+        }
+
+        bail!("Dataset does not exist!");
+    }
+
+    fn ensure_child_dataset(
+        &self,
+        child: &str,
+        reservation: Option<u64>,
+        quota: Option<u64>,
+        log: &Logger,
+    ) -> Result<Box<dyn Dataset>> {
+        let path = self.path.join(child);
+
+        if !path.is_dir() {
+            info!(
+                log,
+                "creating directory {:?} (reservation {:?} quota {:?} are not \
+                 enforced)",
+                path,
+                reservation,
+                quota,
+            );
+            std::fs::create_dir(&path)?;
+        }
+
+        Ok(Box::new(DirectoryDataset { path }))
+    }
+
+    fn set_space_limits(
+        &self,
+        reservation: Option<u64>,
+        quota: Option<u64>,
+        log: &Logger,
+    ) -> Result<()> {
+        info!(
+            log,
+            "not enforcing reservation {:?} quota {:?} for directory {:?}",
+            reservation,
+            quota,
+            self.path,
+        );
+        Ok(())
+    }
+
+    fn path(&self) -> Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn destroy(self: Box<Self>, log: &Logger) -> Result<()> {
+        info!(log, "removing directory {:?}", self.path);
+        std::fs::remove_dir_all(&self.path)?;
+        Ok(())
+    }
+
+    fn dataset(&self) -> String {
+        self.path.to_string_lossy().to_string()
+    }
+
+    fn space(&self) -> Result<DatasetSpace> {
+        // Disk usage of everything under a path, in bytes
+        fn du(path: &Path) -> Result<u64> {
+            let out = command_output(
+                std::process::Command::new("du")
+                    .arg("-s")
+                    .arg("-B1")
+                    .arg(path),
+            )?;
+
+            match out.split_whitespace().next() {
+                Some(used) => Ok(used.parse()?),
+                None => bail!("unexpected du output {:?}", out),
+            }
+        }
+
+        let out = command_output(
+            std::process::Command::new("df")
+                .arg("-B1")
+                .arg("--output=avail")
+                .arg(&self.path),
+        )?;
+
+        let available = match out.lines().nth(1) {
+            Some(available) => available.trim().parse()?,
+            None => bail!("unexpected df output {:?}", out),
+        };
+
+        // Snapshots live under the region, see `ReflinkSnapshotInterface`
+        let snapshot_dir = self.path.join(".zfs");
+        let snapshots = if snapshot_dir.is_dir() {
+            du(&snapshot_dir)?
+        } else {
+            0
+        };
+
+        Ok(DatasetSpace {
+            used: du(&self.path)?,
+            available,
+            snapshots,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::{o, Drain};
+    use tempfile::tempdir;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    #[test]
+    fn test_directory_dataset() -> Result<()> {
+        let log = csl();
+        let dir = tempdir()?;
+
+        assert!(DirectoryDataset::new(dir.path().join("missing")).is_err());
+
+        let dataset = DirectoryDataset::new(dir.path().to_path_buf())?;
+        assert!(dataset.from_child_dataset("regions").is_err());
+
+        let regions =
+            dataset.ensure_child_dataset("regions", None, None, &log)?;
+        assert_eq!(regions.path()?, dir.path().join("regions"));
+
+        // Ensuring again is a no-op
+        std::fs::write(regions.path()?.join("file"), "data")?;
+        let regions =
+            dataset.ensure_child_dataset("regions", Some(1), Some(2), &log)?;
+        assert!(regions.path()?.join("file").exists());
+
+        let region = regions.ensure_child_dataset("r1", None, None, &log)?;
+        let region_path = region.path()?;
+        let region = regions.from_child_dataset("r1")?;
+        assert_eq!(region.dataset(), region_path.to_string_lossy());
+
+        std::fs::write(region_path.join("data"), vec![1u8; 65536])?;
+        let space = region.space()?;
+        assert!(space.used >= 65536);
+        assert!(space.available > 0);
+        assert_eq!(space.snapshots, 0);
+
+        region.destroy(&log)?;
+        assert!(!region_path.exists());
+        assert!(regions.from_child_dataset("r1").is_err());
+
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright 2021 Oxide Computer Company
 
-use anyhow::{anyhow, bail, Result};
-use clap::Parser;
+use anyhow::{bail, Result};
+use clap::{Parser, ValueEnum};
 use dropshot::{ConfigLogging, ConfigLoggingIfExists, ConfigLoggingLevel};
 use slog::{debug, error, info, o, Logger};
 use std::collections::HashSet;
@@ -39,14 +39,20 @@ const RESERVATION_FACTOR: f64 = 1.25;
 const QUOTA_FACTOR: u64 = 3;
 
 mod datafile;
+mod dataset;
 mod model;
+mod retention;
 mod server;
 mod smf_interface;
 mod snapshot_interface;
+mod supervisor;
 
+use dataset::{Dataset, DirectoryDataset, ZFSDataset};
+use model::FailurePhase;
 use model::Resource;
 use model::State;
 use smf_interface::*;
+use snapshot_interface::{ReflinkSnapshotInterface, SnapshotInterface};
 
 #[derive(Debug, Parser)]
 #[clap(name = PROG, about = "Crucible zone management agent")]
@@ -56,10 +62,14 @@ enum Args {
         output: PathBuf,
     },
     Run {
-        // zfs dataset to be used by the crucible agent
+        // zfs dataset to be used by the crucible agent (or, for the linux
+        // backend, a directory)
         #[clap(long, action)]
         dataset: PathBuf,
 
+        #[clap(long, value_enum, default_value_t = Backend::default())]
+        backend: Backend,
+
         #[clap(short = 'l', action)]
         listen: SocketAddr,
 
@@ -74,161 +84,41 @@ enum Args {
 
         #[clap(short = 's', action)]
         snapshot_prefix: String,
-    },
-}
-
-#[derive(Debug)]
-pub struct ZFSDataset {
-    dataset: String,
-}
-
-impl ZFSDataset {
-    /// From either dataset name or path, create the ZFSDataset object.
-    ///
-    /// Fails if the dataset name does not exist, or the path does not exist (or
-    /// belong to a dataset).
-    pub fn new(dataset: String) -> Result<ZFSDataset> {
-        // Validate the argument is a dataset
-        let cmd = std::process::Command::new("zfs")
-            .arg("list")
-            .arg("-pH")
-            .arg("-o")
-            .arg("name")
-            .arg(&dataset)
-            .output()?;
-
-        if !cmd.status.success() {
-            let stderr =
-                String::from_utf8_lossy(&cmd.stderr).trim_end().to_string();
-            bail!("zfs list failed! {stderr}");
-        }
-
-        Ok(ZFSDataset {
-            dataset: String::from_utf8(cmd.stdout)?.trim_end().to_string(),
-        })
-    }
-
-    pub fn from_child_dataset(&self, child: &str) -> Result<ZFSDataset> {
-        let dataset = format!("{}/{}", self.dataset, child);
-
-        // Does it exist already?
-        let cmd = std::process::Command::new("zfs")
-            .arg("list")
-            .arg(&dataset)
-            .output()?;
-
-        if cmd.status.success() {
-            return Ok(ZFSDataset { dataset });
-        }
-
-        bail!("Dataset does not exist!");
-    }
-
-    // Given "dataset", ensure that "dataset/child" exists, and return it.
-    pub fn ensure_child_dataset(
-        &self,
-        child: &str,
-        reservation: Option<u64>,
-        quota: Option<u64>,
-        log: &Logger,
-    ) -> Result<ZFSDataset> {
-        let dataset = format!("{}/{}", self.dataset, child);
-
-        // Does it exist already?
-        let cmd = std::process::Command::new("zfs")
-            .arg("list")
-            .arg(&dataset)
-            .output()?;
-
-        if cmd.status.success() {
-            return Ok(ZFSDataset { dataset });
-        }
-
-        // If not, create it
-        let mut cmd = std::process::Command::new("zfs");
-        cmd.arg("create");
-
-        if let Some(reservation) = reservation {
-            info!(log, "zfs set reservation of {reservation} for {dataset}");
-            cmd.arg("-o").arg(format!("reservation={}", reservation));
-        }
-
-        if let Some(quota) = quota {
-            info!(log, "zfs set quota of {quota} for {dataset}");
-            cmd.arg("-o").arg(format!("quota={}", quota));
-        }
-
-        let res = cmd.arg(&dataset).output()?;
-
-        if !res.status.success() {
-            let out = String::from_utf8_lossy(&res.stdout);
-            let err = String::from_utf8_lossy(&res.stderr);
-            bail!("zfs create failed! out:{} err:{}", out, err);
-        }
-
-        Ok(ZFSDataset { dataset })
-    }
-
-    pub fn path(&self) -> Result<PathBuf> {
-        let cmd = std::process::Command::new("zfs")
-            .arg("list")
-            .arg("-pH")
-            .arg("-o")
-            .arg("mountpoint")
-            .arg(&self.dataset)
-            .output()?;
-
-        let out = String::from_utf8(cmd.stdout)?;
-
-        if !cmd.status.success() {
-            let err = String::from_utf8_lossy(&cmd.stderr);
-            bail!("zfs list mountpoint failed! out:{} err:{}", out, err);
-        }
-
-        Ok(Path::new(&out.trim_end()).to_path_buf())
-    }
 
-    pub fn destroy(self, log: &Logger) -> Result<()> {
-        // Retry a few times: apply_smf will remove the corresponding downstairs
-        // instance but this may take a few seconds to propagate.
-        for i in 0..5 {
-            let cmd = std::process::Command::new("zfs")
-                .arg("destroy")
-                .arg(&self.dataset)
-                .output()?;
+        // Don't set a quota on each region's dataset
+        #[clap(long, action)]
+        no_region_quota: bool,
 
-            if !cmd.status.success() {
-                let out = String::from_utf8_lossy(&cmd.stdout);
-                let err = String::from_utf8_lossy(&cmd.stderr);
+        // How often to prune snapshots according to each region's retention
+        // policy
+        #[clap(long, default_value_t = 300, action)]
+        prune_interval_secs: u64,
 
-                error!(
-                    log,
-                    "zfs dataset {} delete attempt {} failed: out:{} err:{}",
-                    self.dataset,
-                    i,
-                    out,
-                    err,
-                );
+        // The name that downstairs' repair server certificates are issued
+        // for, which regions with certificates check when repairing from
+        // each other.  Empty means any certificate from the region's root.
+        #[clap(long, action)]
+        repair_server_name: Option<String>,
+    },
+}
 
-                if i == 4 {
-                    bail!(
-                        "zfs destroy dataset failed! out:{} err:{}",
-                        out,
-                        err
-                    );
-                }
+/// Which platform facilities the agent uses to store regions, take
+/// snapshots, and run downstairs.
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum Backend {
+    /// ZFS datasets and snapshots, with downstairs run by SMF
+    Illumos,
+    /// Plain directories and reflink copies, with downstairs run by the agent
+    Linux,
+}
 
-                std::thread::sleep(std::time::Duration::from_secs(2));
-            } else {
-                break;
-            }
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(target_os = "illumos") {
+            Backend::Illumos
+        } else {
+            Backend::Linux
         }
-
-        Ok(())
-    }
-
-    pub fn dataset(&self) -> String {
-        self.dataset.clone()
     }
 }
 
@@ -247,11 +137,15 @@ async fn main() -> Result<()> {
         }
         Args::Run {
             dataset,
+            backend,
             listen,
             downstairs_program,
             lowport,
             downstairs_prefix,
             snapshot_prefix,
+            no_region_quota,
+            prune_interval_secs,
+            repair_server_name,
         } => {
             let log = ConfigLogging::File {
                 level: ConfigLoggingLevel::Info,
@@ -261,17 +155,65 @@ async fn main() -> Result<()> {
             .to_logger(PROG)?;
 
             info!(log, "dataset: {:?}", dataset);
+            info!(log, "backend: {:?}", backend);
             info!(log, "listen IP: {:?}", listen);
             info!(
                 log,
                 "SMF instance name downstairs_prefix: {:?}", downstairs_prefix
             );
 
-            // Look up data directory from dataset mountpoint
-            let dataset = ZFSDataset::new(
-                dataset.into_os_string().into_string().unwrap(),
-            )
-            .unwrap();
+            let (dataset, snapshot_interface, services): (
+                Arc<dyn Dataset>,
+                Arc<dyn SnapshotInterface>,
+                Arc<dyn ServiceManager>,
+            ) = match backend {
+                Backend::Illumos => {
+                    // Look up data directory from dataset mountpoint
+                    let dataset = ZFSDataset::new(
+                        dataset.into_os_string().into_string().unwrap(),
+                    )
+                    .unwrap();
+
+                    /*
+                     * Ensure that the SMF service we will use exists already.
+                     * If not, something is seriously wrong with this
+                     * machine.
+                     */
+                    let services = RealServiceManager::new(SERVICE)?;
+
+                    (
+                        Arc::new(dataset),
+                        Arc::new(
+                            snapshot_interface::ZfsSnapshotInterface::new(
+                                log.new(
+                                    o!("component" => "ZfsSnapshotInterface"),
+                                ),
+                            ),
+                        ),
+                        Arc::new(services),
+                    )
+                }
+                Backend::Linux => {
+                    let services = Arc::new(supervisor::ProcessSmf::new(
+                        log.new(o!("component" => "supervisor")),
+                        SERVICE.to_string(),
+                        downstairs_program.clone(),
+                    ));
+                    services.supervise();
+
+                    let snapshots = ReflinkSnapshotInterface::with_supervisor(
+                        log.new(o!("component" => "ReflinkSnapshots")),
+                        services.clone(),
+                        &downstairs_prefix,
+                    );
+
+                    (
+                        Arc::new(DirectoryDataset::new(dataset)?),
+                        Arc::new(snapshots),
+                        services,
+                    )
+                }
+            };
 
             let df = Arc::new(datafile::DataFile::new(
                 log.new(o!("component" => "datafile")),
@@ -279,37 +221,22 @@ async fn main() -> Result<()> {
                 listen,
                 lowport,
                 lowport + 999, // TODO high port as an argument?
-                Arc::new(snapshot_interface::ZfsSnapshotInterface::new(
-                    log.new(
-                        o!("component" => String::from("ZfsSnapshotInterface")),
-                    ),
-                )),
+                snapshot_interface,
+                dataset.clone(),
+                downstairs_program.clone(),
+                repair_server_name.filter(|name| !name.is_empty()),
             )?);
 
             let regions_dataset = dataset
                 .ensure_child_dataset("regions", None, None, &log)
                 .unwrap();
 
-            /*
-             * Ensure that the SMF service we will use exists already.  If
-             * not, something is seriously wrong with this
-             * machine.
-             */
-            {
-                let scf = crucible_smf::Scf::new()?;
-                let scope = scf.scope_local()?;
-
-                let svc = scope.get_service(SERVICE)?;
-                if svc.is_none() {
-                    bail!("SMF service {} does not exist", SERVICE);
-                }
-            }
-
             // Apply any outstanding actions.
             //
             // Note: ? here means that the failure of apply_smf will cause the
             // binary to terminate.
             apply_smf(
+                &*services,
                 &log,
                 &df,
                 regions_dataset.path().unwrap(),
@@ -327,13 +254,29 @@ async fn main() -> Result<()> {
                 worker(
                     log0,
                     df0,
+                    services,
                     regions_dataset,
                     downstairs_program,
                     downstairs_prefix,
                     snapshot_prefix,
+                    !no_region_quota,
                 )
             });
 
+            /*
+             * Create the thread that deletes snapshots that regions'
+             * retention policies no longer keep.
+             */
+            let log0 = log.new(o!("component" => "pruner"));
+            let df0 = Arc::clone(&df);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(std::time::Duration::from_secs(
+                    prune_interval_secs,
+                ));
+                debug!(log0, "pruning snapshots");
+                df0.prune_snapshots();
+            });
+
             server::run_server(&log, listen, df).await
         }
     }
@@ -346,28 +289,23 @@ fn write_openapi<W: Write>(f: &mut W) -> Result<()> {
 }
 
 fn apply_smf(
+    services: &dyn ServiceManager,
     log: &Logger,
     df: &Arc<datafile::DataFile>,
     datapath: PathBuf,
     downstairs_prefix: &str,
     snapshot_prefix: &str,
 ) -> Result<()> {
-    let scf = crucible_smf::Scf::new()?;
-    let scope = scf.scope_local()?;
-    let svc = scope
-        .get_service(SERVICE)?
-        .ok_or_else(|| anyhow!("service missing"))?;
-
-    let smf_interface = RealSmf::new(&svc)?;
-
-    apply_smf_actual(
-        &smf_interface,
-        log,
-        df,
-        datapath,
-        downstairs_prefix,
-        snapshot_prefix,
-    )
+    services.with_interface(&mut |smf_interface: &dyn SmfInterface| {
+        apply_smf_actual(
+            smf_interface,
+            log,
+            df,
+            datapath.clone(),
+            downstairs_prefix,
+            snapshot_prefix,
+        )
+    })
 }
 
 /**
@@ -389,7 +327,7 @@ fn apply_smf_actual<T>(
     snapshot_prefix: &str,
 ) -> Result<()>
 where
-    T: SmfInterface,
+    T: SmfInterface + ?Sized,
 {
     let regions = df.regions();
     let mut running_snapshots = df.running_snapshots();
@@ -513,6 +451,18 @@ where
                 val: df.get_listen_addr().ip().to_string(),
             });
 
+            // Downstairs with certificates check other downstairs' repair
+            // servers against the name their certificates are issued for.
+            if let Some(name) = df.repair_server_name() {
+                if r.cert_pem.is_some() {
+                    properties.push(crate::model::SmfProperty {
+                        name: "repair_server_name",
+                        typ: crucible_smf::scf_type_t::SCF_TYPE_ASTRING,
+                        val: name.to_string(),
+                    });
+                }
+            }
+
             // If the region has a source, then it was created as a clone and
             // must be started read only.
             if r.source.is_some() {
@@ -844,6 +794,7 @@ mod test {
                     name: "first".into(),
                     port_number: 1,
                     state: State::Created,
+                    failure: None,
                 },
             );
         running_snapshots
@@ -856,6 +807,7 @@ mod test {
                     name: "second".into(),
                     port_number: 1,
                     state: State::Created,
+                    failure: None,
                 },
             );
 
@@ -870,6 +822,7 @@ mod test {
                     name: "third".into(),
                     port_number: 1,
                     state: State::Created,
+                    failure: None,
                 },
             );
 
@@ -913,6 +866,12 @@ mod test {
 
     impl TestSmfHarness {
         pub fn new() -> Result<TestSmfHarness> {
+            Self::with_repair_server_name(None)
+        }
+
+        pub fn with_repair_server_name(
+            repair_server_name: Option<&str>,
+        ) -> Result<TestSmfHarness> {
             let log = csl();
             let dir = tempdir()?;
             let snapshot_interface =
@@ -929,6 +888,9 @@ mod test {
                 1000,
                 2000,
                 snapshot_interface.clone(),
+                Arc::new(DirectoryDataset::new(dir.path().to_path_buf())?),
+                PathBuf::from("/nonexistent/downstairs"),
+                repair_server_name.map(String::from),
             )?);
 
             Ok(TestSmfHarness {
@@ -1039,6 +1001,142 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_region_capacity() -> Result<()> {
+        let harness = TestSmfHarness::new()?;
+
+        let request = |id: &RegionId, extent_count: u32| CreateRegion {
+            id: id.clone(),
+
+            block_size: 512,
+            extent_size: 10,
+            extent_count,
+            encrypted: true,
+
+            cert_pem: None,
+            key_pem: None,
+            root_pem: None,
+            source: None,
+        };
+
+        let region_id = RegionId(Uuid::new_v4().to_string());
+        let region =
+            harness.df.create_region_request(request(&region_id, 10))?;
+
+        let capacity = harness.df.capacity()?;
+        assert_eq!(capacity.reserved, region.reservation());
+        assert_eq!(capacity.reserved, 64000);
+        assert!(capacity.total >= capacity.used);
+        assert_eq!(capacity.regions.len(), 1);
+        assert_eq!(capacity.regions[0].id, region_id);
+        assert_eq!(capacity.regions[0].reserved, 64000);
+
+        // A region that can't possibly fit is rejected, and not recorded
+        let big_id = RegionId(Uuid::new_v4().to_string());
+        let e = harness
+            .df
+            .create_region_request(request(&big_id, u32::MAX))
+            .unwrap_err();
+        let e = e.downcast_ref::<datafile::InsufficientCapacity>().unwrap();
+        assert_eq!(e.total, capacity.total);
+        assert!(harness.df.get(&big_id).is_none());
+
+        // So is one whose size doesn't even fit in 64 bits
+        let huge = CreateRegion {
+            extent_size: u64::MAX / 512,
+            ..request(&big_id, 2)
+        };
+        assert!(huge.reservation().is_none());
+        let e = harness.df.create_region_request(huge).unwrap_err();
+        assert!(e.downcast_ref::<datafile::RegionTooLarge>().is_some());
+        assert!(harness.df.get(&big_id).is_none());
+
+        // Destroyed regions no longer hold a reservation
+        harness.df.created(&region_id)?;
+        harness.df.destroy(&region_id)?;
+        harness.df.destroyed(&region_id)?;
+        let capacity = harness.df.capacity()?;
+        assert_eq!(capacity.reserved, 0);
+        assert!(capacity.regions.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_changes() -> Result<()> {
+        let harness = TestSmfHarness::new()?;
+
+        let request = |id: &RegionId| CreateRegion {
+            id: id.clone(),
+
+            block_size: 512,
+            extent_size: 10,
+            extent_count: 10,
+            encrypted: true,
+
+            cert_pem: None,
+            key_pem: None,
+            root_pem: None,
+            source: None,
+        };
+
+        let changes = harness.df.changes_after(None, 0);
+        assert_eq!(changes.generation, 0);
+        assert!(!changes.truncated);
+        assert!(changes.changes.is_empty());
+
+        let good_id = RegionId(Uuid::new_v4().to_string());
+        let bad_id = RegionId(Uuid::new_v4().to_string());
+        harness.df.create_region_request(request(&good_id))?;
+        harness.df.create_region_request(request(&bad_id))?;
+
+        // Asking for a region that already exists is not a change
+        harness.df.create_region_request(request(&good_id))?;
+
+        let mut generation = harness.df.subscribe();
+        harness.df.created(&good_id)?;
+        harness.df.fail(
+            &bad_id,
+            FailurePhase::Create,
+            "creation failed".to_string(),
+        );
+        assert_eq!(*generation.borrow_and_update(), 4);
+
+        let epoch = changes.epoch;
+        let changes = harness.df.changes_after(Some(epoch), 2);
+        assert_eq!(changes.epoch, epoch);
+        assert_eq!(changes.generation, 4);
+        assert!(!changes.truncated);
+        assert_eq!(changes.changes.len(), 2);
+
+        assert_eq!(changes.changes[0].generation, 3);
+        assert_eq!(changes.changes[0].id, good_id);
+        assert_eq!(changes.changes[0].state, State::Created);
+        assert_eq!(changes.changes[0].reason, None);
+
+        assert_eq!(changes.changes[1].id, bad_id);
+        assert_eq!(changes.changes[1].state, State::Failed);
+        assert_eq!(
+            changes.changes[1].reason.as_deref(),
+            Some("creation failed")
+        );
+
+        // Nothing new after the latest generation
+        assert!(harness.df.changes_after(Some(epoch), 4).changes.is_empty());
+
+        // A generation from before an agent restart means the caller may
+        // have missed changes, even if it's one we've reached again since
+        let changes = harness.df.changes_after(None, 10);
+        assert!(changes.truncated);
+        assert_eq!(changes.changes.len(), 4);
+
+        let changes = harness.df.changes_after(Some(Uuid::new_v4()), 3);
+        assert!(changes.truncated);
+        assert_eq!(changes.changes.len(), 4);
+
+        Ok(())
+    }
+
     #[test]
     fn test_smf_region_good() -> Result<()> {
         let harness = TestSmfHarness::new()?;
@@ -1116,6 +1214,126 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_smf_region_repair_server_name() -> Result<()> {
+        let harness =
+            TestSmfHarness::with_repair_server_name(Some("crucible-repair"))?;
+
+        let tls_region_id = RegionId(Uuid::new_v4().to_string());
+        let plain_region_id = RegionId(Uuid::new_v4().to_string());
+
+        for (region_id, pem) in [
+            (&tls_region_id, Some("pem".to_string())),
+            (&plain_region_id, None),
+        ] {
+            harness.df.create_region_request(CreateRegion {
+                id: region_id.clone(),
+
+                block_size: 512,
+                extent_size: 10,
+                extent_count: 10,
+                encrypted: true,
+
+                cert_pem: pem.clone(),
+                key_pem: pem.clone(),
+                root_pem: pem,
+                source: None,
+            })?;
+            harness.df.created(region_id)?;
+        }
+
+        harness.apply_smf()?;
+
+        // Only the downstairs with certificates is told the name its peers'
+        // repair servers have certificates for
+        let repair_server_name = |region_id: &RegionId| -> Result<_> {
+            let instance = harness
+                .smf_interface
+                .get_instance(&format!("downstairs-{}", region_id.0))?
+                .unwrap();
+            let pg = instance.get_pg("config")?.unwrap();
+            Ok(match pg.get_property("repair_server_name")? {
+                Some(p) => Some(p.value()?.unwrap().as_string()?),
+                None => None,
+            })
+        };
+        assert_eq!(
+            repair_server_name(&tls_region_id)?,
+            Some("crucible-repair".to_string())
+        );
+        assert_eq!(repair_server_name(&plain_region_id)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_smf_region_resize() -> Result<()> {
+        let harness = TestSmfHarness::new()?;
+
+        let region_id = RegionId(Uuid::new_v4().to_string());
+
+        harness.df.create_region_request(CreateRegion {
+            id: region_id.clone(),
+
+            block_size: 512,
+            extent_size: 10,
+            extent_count: 10,
+            encrypted: true,
+
+            cert_pem: None,
+            key_pem: None,
+            root_pem: None,
+            source: None,
+        })?;
+
+        // Can't resize a region that hasn't been created yet
+        assert!(harness.df.resize_region_request(&region_id, 20).is_err());
+
+        harness.df.created(&region_id)?;
+        harness.apply_smf()?;
+
+        let downstairs_enabled = || -> Result<bool> {
+            Ok(harness
+                .smf_interface
+                .get_instance(&format!("downstairs-{}", region_id.0))?
+                .unwrap()
+                .enabled())
+        };
+        assert!(downstairs_enabled()?);
+
+        // Shrinking is rejected, and asking for the current size is a no-op
+        let e = harness.df.resize_region_request(&region_id, 5).unwrap_err();
+        assert!(e.to_string().contains("cannot shrink"));
+
+        let region = harness.df.resize_region_request(&region_id, 10)?;
+        assert_eq!(region.state, State::Created);
+
+        // Growing moves the region to Resizing, and reserves the new size
+        let region = harness.df.resize_region_request(&region_id, 20)?;
+        assert_eq!(region.state, State::Resizing);
+        assert_eq!(region.extent_count, 20);
+        assert_eq!(harness.df.capacity()?.reserved, region.reservation());
+
+        // Asking again is idempotent, but a different size is not
+        assert_eq!(harness.df.resize_region_request(&region_id, 20)?, region);
+        assert!(harness.df.resize_region_request(&region_id, 30).is_err());
+
+        // The downstairs is stopped while the region is resized
+        harness.apply_smf()?;
+        assert!(!downstairs_enabled()?);
+
+        // And started again after
+        harness.df.resized(&region_id)?;
+        harness.apply_smf()?;
+        assert!(downstairs_enabled()?);
+
+        let region = harness.df.get(&region_id).unwrap();
+        assert_eq!(region.state, State::Created);
+        assert_eq!(region.extent_count, 20);
+
+        Ok(())
+    }
+
     #[test]
     fn test_smf_region_failed() -> Result<()> {
         let harness = TestSmfHarness::new()?;
@@ -1138,7 +1356,11 @@ mod test {
         })?;
 
         // Pretend creating the region failed
-        harness.df.fail(&region_id);
+        harness.df.fail(
+            &region_id,
+            FailurePhase::Create,
+            "creation failed".to_string(),
+        );
 
         // Now call apply_smf
         harness.apply_smf()?;
@@ -1149,6 +1371,244 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_retry_failed() -> Result<()> {
+        let harness = TestSmfHarness::new()?;
+
+        let region_id = RegionId(Uuid::new_v4().to_string());
+        let snapshot_name = Uuid::new_v4().to_string();
+
+        harness.df.create_region_request(CreateRegion {
+            id: region_id.clone(),
+
+            block_size: 512,
+            extent_size: 10,
+            extent_count: 10,
+            encrypted: true,
+
+            cert_pem: None,
+            key_pem: None,
+            root_pem: None,
+            source: None,
+        })?;
+
+        // Retrying a region that hasn't failed does nothing
+        assert_eq!(harness.df.retry(&region_id)?.state, State::Requested);
+
+        // The failure is recorded, and a retry creates the region again
+        harness.df.fail(
+            &region_id,
+            FailurePhase::Create,
+            "creation failed".to_string(),
+        );
+        let failure = harness.df.get(&region_id).unwrap().failure.unwrap();
+        assert_eq!(failure.phase, FailurePhase::Create);
+        assert_eq!(failure.message, "creation failed");
+
+        let region = harness.df.retry(&region_id)?;
+        assert_eq!(region.state, State::Requested);
+        assert_eq!(region.failure, None);
+
+        // A failed resize is retried as a resize
+        harness.df.created(&region_id)?;
+        harness.df.resize_region_request(&region_id, 20)?;
+        harness.df.fail(
+            &region_id,
+            FailurePhase::Resize,
+            "resize failed".to_string(),
+        );
+        assert_eq!(harness.df.retry(&region_id)?.state, State::Resizing);
+        harness.df.resized(&region_id)?;
+
+        // A running snapshot that failed to start is started again
+        harness.create_snapshot(region_id.0.to_string(), snapshot_name.clone());
+        harness.df.create_running_snapshot_request(
+            CreateRunningSnapshotRequest {
+                id: region_id.clone(),
+                name: snapshot_name.clone(),
+
+                cert_pem: None,
+                key_pem: None,
+                root_pem: None,
+            },
+        )?;
+        harness.df.fail_rs(
+            &region_id,
+            &snapshot_name,
+            FailurePhase::Create,
+            "start failed".to_string(),
+        );
+        let running_snapshots = harness.df.running_snapshots();
+        let running_snapshot = &running_snapshots[&region_id][&snapshot_name];
+        assert_eq!(running_snapshot.state, State::Failed);
+        assert!(running_snapshot.failure.is_some());
+
+        let running_snapshot =
+            harness.df.retry_rs(&region_id, &snapshot_name)?;
+        assert_eq!(running_snapshot.state, State::Requested);
+        assert_eq!(running_snapshot.failure, None);
+        assert!(harness.df.retry_rs(&region_id, "missing").is_err());
+
+        // Destroyed regions can't be retried
+        harness.df.destroy(&region_id)?;
+        harness.df.destroyed(&region_id)?;
+        assert!(harness.df.retry(&region_id).is_err());
+
+        harness.apply_smf()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_job() -> Result<()> {
+        let harness = TestSmfHarness::new()?;
+
+        let region_id = RegionId(Uuid::new_v4().to_string());
+
+        harness.df.create_region_request(CreateRegion {
+            id: region_id.clone(),
+
+            block_size: 512,
+            extent_size: 10,
+            extent_count: 10,
+            encrypted: true,
+
+            cert_pem: None,
+            key_pem: None,
+            root_pem: None,
+            source: None,
+        })?;
+
+        // Only created regions can be verified
+        assert!(harness.df.verify(&region_id, None).is_err());
+
+        harness.df.created(&region_id)?;
+        harness.apply_smf()?;
+
+        // A snapshot that doesn't exist can't be verified
+        assert!(harness
+            .df
+            .verify(&region_id, Some("missing".to_string()))
+            .is_err());
+
+        // The harness's downstairs program doesn't exist, so the job fails
+        let job = harness.df.verify(&region_id, None)?;
+        assert_eq!(job.region_id, region_id);
+        assert_eq!(job.snapshot_name, None);
+
+        let mut job = job;
+        for _ in 0..50 {
+            job = harness.df.verify_job(&job.job_id).unwrap();
+            if job.state != VerifyState::Running {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        assert_eq!(job.state, VerifyState::Failed);
+        assert!(job.error.is_some());
+        assert!(job.finished.is_some());
+
+        // The temporary snapshot the live region was verified through is
+        // gone again
+        assert!(harness
+            .snapshot_interface
+            .get_snapshots_for_dataset(region_id.0.clone())?
+            .is_empty());
+
+        assert!(harness.df.verify_job(&Uuid::new_v4()).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention_prune() -> Result<()> {
+        let harness = TestSmfHarness::new()?;
+
+        let region_id = RegionId(Uuid::new_v4().to_string());
+
+        harness.df.create_region_request(CreateRegion {
+            id: region_id.clone(),
+
+            block_size: 512,
+            extent_size: 10,
+            extent_count: 10,
+            encrypted: true,
+
+            cert_pem: None,
+            key_pem: None,
+            root_pem: None,
+            source: None,
+        })?;
+        harness.df.created(&region_id)?;
+        harness.apply_smf()?;
+
+        for name in ["a", "b", "c"] {
+            harness.create_snapshot(region_id.0.to_string(), name.to_string());
+        }
+
+        // A read-only downstairs is running from "a", so it's kept
+        harness.df.create_running_snapshot_request(
+            CreateRunningSnapshotRequest {
+                id: region_id.clone(),
+                name: "a".to_string(),
+
+                cert_pem: None,
+                key_pem: None,
+                root_pem: None,
+            },
+        )?;
+        harness.apply_smf()?;
+
+        assert!(harness.df.retention_status(&region_id)?.is_none());
+
+        // The test snapshots are all created at the same time, so they're
+        // ordered by name: "c" is the newest.
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+
+        // A dry run deletes nothing
+        let plan = harness.df.retention_plan(&region_id, &policy)?;
+        let deleted: Vec<&str> = plan
+            .iter()
+            .filter(|s| s.action == RetentionAction::Delete)
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(deleted, vec!["b"]);
+        assert_eq!(harness.df.get_snapshots_for_region(&region_id)?.len(), 3);
+
+        // Regions without a policy aren't pruned
+        harness.df.prune_snapshots();
+        assert_eq!(harness.df.get_snapshots_for_region(&region_id)?.len(), 3);
+
+        harness.df.set_retention(&region_id, policy.clone())?;
+        harness.df.prune_snapshots();
+
+        let mut names: Vec<String> = harness
+            .df
+            .get_snapshots_for_region(&region_id)?
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a".to_string(), "c".to_string()]);
+
+        let status = harness.df.retention_status(&region_id)?.unwrap();
+        assert_eq!(status.policy, policy);
+        assert_eq!(status.last_prune.unwrap().deleted, vec!["b".to_string()]);
+        assert!(status
+            .snapshots
+            .iter()
+            .all(|s| s.action == RetentionAction::Keep));
+
+        harness.df.clear_retention(&region_id);
+        assert!(harness.df.retention_status(&region_id)?.is_none());
+
+        Ok(())
+    }
+
     #[test]
     fn test_smf_region_source_ro() {
         // Verify that a region created with a source endpoint will result
@@ -1585,18 +2045,51 @@ mod test {
     }
 }
 
+/**
+ * Wait for SMF to report that an instance has stopped, if it exists.
+ */
+fn wait_for_disabled(services: &dyn ServiceManager, name: &str) -> Result<()> {
+    for _ in 0..30 {
+        let mut disabled = false;
+
+        services.with_interface(&mut |smf_interface: &dyn SmfInterface| {
+            disabled = match smf_interface.get_instance(name)? {
+                Some(inst) => matches!(
+                    inst.states()?,
+                    (Some(crucible_smf::State::Disabled), None)
+                ),
+                None => true,
+            };
+            Ok(())
+        })?;
+
+        if disabled {
+            return Ok(());
+        }
+
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+
+    bail!("instance {} did not stop", name);
+}
+
 /**
  * For region with state Tombstoned, destroy the region.
  *
  * For region with state Requested, create the region.
+ *
+ * For region with state Resizing, stop its downstairs, grow the region, then
+ * start the downstairs again.
  */
 fn worker(
     log: Logger,
     df: Arc<datafile::DataFile>,
-    regions_dataset: ZFSDataset,
+    services: Arc<dyn ServiceManager>,
+    regions_dataset: Box<dyn Dataset>,
     downstairs_program: PathBuf,
     downstairs_prefix: String,
     snapshot_prefix: String,
+    region_quota: bool,
 ) {
     let regions_dataset_path = match regions_dataset.path() {
         Ok(regions_dataset_path) => regions_dataset_path,
@@ -1614,6 +2107,7 @@ fn worker(
          *
          * - create a region
          * - delete a region
+         * - resize a region
          * - create a running snapshot
          * - delete a running snapshot
          *
@@ -1621,7 +2115,11 @@ fn worker(
          * which wraps either a Region or RegionSnapshot that has changed.
          * Otherwise, first_in_states will wait on the condvar.
          */
-        let work = df.first_in_states(&[State::Tombstoned, State::Requested]);
+        let work = df.first_in_states(&[
+            State::Tombstoned,
+            State::Requested,
+            State::Resizing,
+        ]);
 
         match work {
             Resource::Region(r) => {
@@ -1638,17 +2136,17 @@ fn worker(
                          * Compute the actual size required for a full region,
                          * then add our metadata overhead to that.
                          */
-                        let region_size = r.block_size
-                            * r.extent_size
-                            * r.extent_count as u64;
-                        let reservation =
-                            (region_size as f64 * RESERVATION_FACTOR).round()
-                                as u64;
-                        let quota = region_size * QUOTA_FACTOR;
+                        let region_size = r.size();
+                        let reservation = r.reservation();
+                        let quota = if region_quota {
+                            Some(region_size * QUOTA_FACTOR)
+                        } else {
+                            None
+                        };
 
                         info!(
                             log,
-                            "Region size:{} reservation:{} quota:{}",
+                            "Region size:{} reservation:{} quota:{:?}",
                             region_size,
                             reservation,
                             quota,
@@ -1660,7 +2158,7 @@ fn worker(
                             .ensure_child_dataset(
                                 &r.id.0,
                                 Some(reservation),
-                                Some(quota),
+                                quota,
                                 &log,
                             ) {
                             Ok(region_dataset) => region_dataset,
@@ -1671,7 +2169,11 @@ fn worker(
                                     &r.id.0,
                                     e,
                                 );
-                                df.fail(&r.id);
+                                df.fail(
+                                    &r.id,
+                                    FailurePhase::Create,
+                                    format!("dataset creation failed: {}", e),
+                                );
                                 break 'requested;
                             }
                         };
@@ -1685,7 +2187,14 @@ fn worker(
                                     &r.id.0,
                                     e,
                                 );
-                                df.fail(&r.id);
+                                df.fail(
+                                    &r.id,
+                                    FailurePhase::Create,
+                                    format!(
+                                        "failed to find path for dataset: {}",
+                                        e
+                                    ),
+                                );
                                 break 'requested;
                             }
                         };
@@ -1712,12 +2221,17 @@ fn worker(
                                 log,
                                 "region {:?} create failed: {:?}", r.id.0, e
                             );
-                            df.fail(&r.id);
+                            df.fail(
+                                &r.id,
+                                FailurePhase::Create,
+                                format!("create failed: {:#}", e),
+                            );
                             break 'requested;
                         }
 
                         info!(log, "applying SMF actions post create...");
                         let result = apply_smf(
+                            &*services,
                             &log,
                             &df,
                             regions_dataset_path.clone(),
@@ -1735,6 +2249,7 @@ fn worker(
                     State::Tombstoned => 'tombstoned: {
                         info!(log, "applying SMF actions before removal...");
                         let result = apply_smf(
+                            &*services,
                             &log,
                             &df,
                             regions_dataset_path.clone(),
@@ -1773,9 +2288,124 @@ fn worker(
                                 log,
                                 "region {:?} destroy failed: {:?}", r.id.0, e
                             );
-                            df.fail(&r.id);
+                            df.fail(
+                                &r.id,
+                                FailurePhase::Destroy,
+                                format!("destroy failed: {:#}", e),
+                            );
                         }
                     }
+
+                    State::Resizing => 'resizing: {
+                        /*
+                         * The downstairs must not have the region open while
+                         * it grows. apply_smf only runs downstairs for
+                         * regions in state Created, so this stops it.
+                         */
+                        info!(log, "applying SMF actions before resize...");
+                        let name = format!("{}-{}", downstairs_prefix, r.id.0);
+                        let result = apply_smf(
+                            &*services,
+                            &log,
+                            &df,
+                            regions_dataset_path.clone(),
+                            &downstairs_prefix,
+                            &snapshot_prefix,
+                        )
+                        .and_then(|_| wait_for_disabled(&*services, &name));
+
+                        if let Err(e) = result {
+                            error!(
+                                log,
+                                "region {:?} downstairs did not stop: {:?}",
+                                r.id.0,
+                                e
+                            );
+                            df.fail(
+                                &r.id,
+                                FailurePhase::Resize,
+                                format!("downstairs did not stop: {:#}", e),
+                            );
+                            break 'resizing;
+                        }
+
+                        let region_dataset =
+                            match regions_dataset.from_child_dataset(&r.id.0) {
+                                Ok(region_dataset) => region_dataset,
+                                Err(e) => {
+                                    error!(
+                                        log,
+                                        "Cannot find region {:?} to resize: {}",
+                                        r.id.0,
+                                        e,
+                                    );
+                                    df.fail(
+                                        &r.id,
+                                        FailurePhase::Resize,
+                                        format!(
+                                            "cannot find region to resize: {}",
+                                            e
+                                        ),
+                                    );
+                                    break 'resizing;
+                                }
+                            };
+
+                        let quota = if region_quota {
+                            Some(r.size() * QUOTA_FACTOR)
+                        } else {
+                            None
+                        };
+
+                        // Grow the reservation first, so the new extents have
+                        // somewhere to go.
+                        let res = region_dataset
+                            .set_space_limits(
+                                Some(r.reservation()),
+                                quota,
+                                &log,
+                            )
+                            .and_then(|_| region_dataset.path())
+                            .and_then(|dataset_path| {
+                                worker_region_resize(
+                                    &log,
+                                    &downstairs_program,
+                                    &r,
+                                    &dataset_path,
+                                )
+                            })
+                            .and_then(|_| df.resized(&r.id));
+
+                        if let Err(e) = res {
+                            error!(
+                                log,
+                                "region {:?} resize failed: {:?}", r.id.0, e
+                            );
+                            df.fail(
+                                &r.id,
+                                FailurePhase::Resize,
+                                format!("resize failed: {:#}", e),
+                            );
+                            break 'resizing;
+                        }
+
+                        info!(log, "applying SMF actions post resize...");
+                        let result = apply_smf(
+                            &*services,
+                            &log,
+                            &df,
+                            regions_dataset_path.clone(),
+                            &downstairs_prefix,
+                            &snapshot_prefix,
+                        );
+
+                        if let Err(e) = result {
+                            error!(log, "SMF application failure: {:?}", e);
+                        } else {
+                            info!(log, "SMF ok!");
+                        }
+                    }
+
                     _ => {
                         error!(
                             log,
@@ -1808,6 +2438,7 @@ fn worker(
                 );
 
                 let result = apply_smf(
+                    &*services,
                     &log,
                     &df,
                     regions_dataset_path.clone(),
@@ -1852,7 +2483,18 @@ fn worker(
                             e
                         );
 
-                        df.fail_rs(&region_id, &snapshot_name);
+                        let phase = if rs.state == State::Tombstoned {
+                            FailurePhase::Destroy
+                        } else {
+                            FailurePhase::Create
+                        };
+
+                        df.fail_rs(
+                            &region_id,
+                            &snapshot_name,
+                            phase,
+                            format!("state change failed: {:#}", e),
+                        );
                     }
                 }
             }
@@ -1955,16 +2597,56 @@ fn worker_region_create(
     Ok(())
 }
 
+fn worker_region_resize(
+    log: &Logger,
+    prog: &Path,
+    region: &model::Region,
+    dir: &Path,
+) -> Result<()> {
+    let log = log.new(o!("region" => region.id.0.to_string()));
+
+    /*
+     * Run the downstairs program in the mode where it will add extent files
+     * to an existing region. The new extent files are created and synced
+     * before the region's new size is recorded, and leftovers from an earlier
+     * attempt are replaced, so a resize interrupted by a crash can simply be
+     * run again.
+     */
+    info!(log, "resizing region {:?} at {:?}", region, dir);
+
+    let mut cmd = Command::new(prog);
+    cmd.env_clear()
+        .arg("extend")
+        .arg("--data")
+        .arg(dir)
+        .arg("--extent-count")
+        .arg(region.extent_count.to_string());
+
+    info!(log, "downstairs extend with: {:?}", cmd);
+    let cmd = cmd.output()?;
+
+    if cmd.status.success() {
+        info!(log, "region extended ok");
+    } else {
+        let err = String::from_utf8_lossy(&cmd.stderr);
+        let out = String::from_utf8_lossy(&cmd.stdout);
+        error!(log, "downstairs extend failed: out {:?} err {:?}", out, err);
+        bail!("region extend failure");
+    }
+
+    Ok(())
+}
+
 fn worker_region_destroy(
     log: &Logger,
     region: &model::Region,
-    region_dataset: ZFSDataset,
+    region_dataset: Box<dyn Dataset>,
 ) -> Result<()> {
     let log = log.new(o!("region" => region.id.0.to_string()));
 
     let region_dataset_name = region_dataset.dataset();
 
-    info!(log, "deleting zfs dataset {:?}", region_dataset_name);
+    info!(log, "deleting dataset {:?}", region_dataset_name);
 
     // Note: zfs destroy will fail if snapshots exist, but previous steps should
     // prevent that scenario.
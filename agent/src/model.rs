@@ -7,6 +7,7 @@ use chrono::prelude::*;
 use crucible_smf::scf_type_t::{self, *};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -17,6 +18,36 @@ pub enum State {
     Tombstoned,
     Destroyed,
     Failed,
+    Resizing,
+}
+
+/// The part of a region's or running snapshot's lifecycle that failed
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePhase {
+    Create,
+    Resize,
+    Destroy,
+}
+
+/// Why a region or running snapshot moved to state `Failed`
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct Failure {
+    pub phase: FailurePhase,
+    pub message: String,
+    pub time: DateTime<Utc>,
+}
+
+impl Failure {
+    pub fn new(phase: FailurePhase, message: String) -> Failure {
+        Failure {
+            phase,
+            message,
+            time: Utc::now(),
+        }
+    }
 }
 
 // If not provided, select None as the default for source.
@@ -56,6 +87,10 @@ pub struct Region {
     // If this region is read only
     #[serde(default = "read_only_default")]
     pub read_only: bool,
+
+    // The most recent failure, kept until the region is retried
+    #[serde(default)]
+    pub failure: Option<Failure>,
 }
 
 pub struct SmfProperty<'a> {
@@ -64,7 +99,61 @@ pub struct SmfProperty<'a> {
     pub val: String,
 }
 
+/// Bytes needed for a region's extents, or `None` if that overflows
+fn extents_size(
+    block_size: u64,
+    extent_size: u64,
+    extent_count: u32,
+) -> Option<u64> {
+    block_size
+        .checked_mul(extent_size)?
+        .checked_mul(extent_count as u64)
+}
+
+/// Bytes to reserve for a region's extents (see `RESERVATION_FACTOR`), or
+/// `None` if that overflows
+fn reservation(
+    block_size: u64,
+    extent_size: u64,
+    extent_count: u32,
+) -> Option<u64> {
+    let size = extents_size(block_size, extent_size, extent_count)?;
+    let r = (size as f64 * crate::RESERVATION_FACTOR).round();
+    (r < u64::MAX as f64).then_some(r as u64)
+}
+
 impl Region {
+    /// Bytes needed for the region's extents
+    ///
+    /// Requests whose size overflows are refused (see
+    /// `CreateRegion::reservation` and `Region::checked_reservation`), so
+    /// this only saturates for regions recorded before that was checked.
+    pub fn size(&self) -> u64 {
+        extents_size(self.block_size, self.extent_size, self.extent_count)
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Bytes reserved for the region, including metadata and snapshots
+    pub fn reservation(&self) -> u64 {
+        self.checked_reservation().unwrap_or(u64::MAX)
+    }
+
+    /// Bytes reserved for the region, or `None` if that overflows
+    pub fn checked_reservation(&self) -> Option<u64> {
+        reservation(self.block_size, self.extent_size, self.extent_count)
+    }
+
+    /// Whether the region is (or will be) taking up space in the dataset
+    pub fn holds_space(&self) -> bool {
+        matches!(
+            self.state,
+            State::Requested
+                | State::Created
+                | State::Tombstoned
+                | State::Resizing
+        )
+    }
+
     /**
      * Given a root directory, return a list of SMF properties to ensure for
      * the corresponding running instance.
@@ -144,6 +233,12 @@ pub struct CreateRegion {
 }
 
 impl CreateRegion {
+    /// Bytes that will be reserved for the requested region, or `None` if
+    /// that overflows
+    pub fn reservation(&self) -> Option<u64> {
+        reservation(self.block_size, self.extent_size, self.extent_count)
+    }
+
     pub fn mismatch(&self, r: &Region) -> Option<String> {
         if self.block_size != r.block_size {
             Some(format!(
@@ -191,6 +286,13 @@ impl CreateRegion {
     }
 }
 
+/// Grow a region to a new number of extents
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct ResizeRegion {
+    pub extent_count: u32,
+}
+
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(
     Serialize,
@@ -210,6 +312,9 @@ pub struct RegionId(pub String);
 pub struct Snapshot {
     pub name: String,
     pub created: DateTime<Utc>,
+    /// Bytes that deleting the snapshot would free, if the backend knows
+    #[serde(default)]
+    pub size: Option<u64>,
 }
 
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -219,6 +324,10 @@ pub struct RunningSnapshot {
     pub name: String,
     pub port_number: u16,
     pub state: State,
+
+    // The most recent failure, kept until the running snapshot is retried
+    #[serde(default)]
+    pub failure: Option<Failure>,
 }
 
 impl RunningSnapshot {
@@ -316,6 +425,158 @@ pub struct DeleteSnapshotRequest {
     pub name: String,
 }
 
+/// Space accounting for the agent's dataset, in bytes
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct Capacity {
+    /// Space used plus space available
+    pub total: u64,
+    /// Reserved for regions; new regions must fit in what's left
+    pub reserved: u64,
+    /// Actually in use
+    pub used: u64,
+    pub regions: Vec<RegionCapacity>,
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct RegionCapacity {
+    pub id: RegionId,
+    pub state: State,
+    pub reserved: u64,
+    /// Actually in use by the region, including its snapshots
+    pub used: u64,
+    /// In use only by the region's snapshots
+    pub snapshots: u64,
+    pub running_snapshots: usize,
+}
+
+/// A region, or one of its running snapshots, moved to a new state
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct StateChange {
+    /// Increases by one with every change the agent makes
+    pub generation: u64,
+    pub time: DateTime<Utc>,
+    pub id: RegionId,
+    /// Set if the change is to one of the region's running snapshots
+    pub snapshot_name: Option<String>,
+    pub state: State,
+    /// Why the resource failed, if `state` is `Failed`
+    pub reason: Option<String>,
+}
+
+/// State changes after some generation
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct StateChanges {
+    /// Identifies this run of the agent, whose generations start again from
+    /// zero when it restarts: pass this as `epoch` along with `after`
+    pub epoch: Uuid,
+    /// The latest generation: pass this as `after` to wait for the next change
+    pub generation: u64,
+    /// Some changes after the requested generation were discarded (or the
+    /// agent restarted), so callers should re-read the state they care about
+    pub truncated: bool,
+    pub changes: Vec<StateChange>,
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// How many of an extent's blocks were checked, and which were corrupt
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct ExtentVerification {
+    pub extent: u32,
+    /// Blocks that have been written, and so have hashes to check against
+    pub blocks_checked: u64,
+    /// Offsets within the extent of blocks whose data does not match
+    pub corrupt_blocks: Vec<u64>,
+}
+
+/// A check of a region's (or snapshot's) data against its stored block hashes
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct VerifyJob {
+    pub job_id: Uuid,
+    pub region_id: RegionId,
+    pub snapshot_name: Option<String>,
+    pub state: VerifyState,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Per-extent results, once the job is done
+    pub extents: Vec<ExtentVerification>,
+    /// Corrupt blocks across all extents
+    pub corrupt_blocks: u64,
+    /// Why the job failed, if it did
+    pub error: Option<String>,
+}
+
+/// Which of a region's snapshots to keep. A snapshot is kept if any of the
+/// count limits keeps it; a policy with no count limits keeps every snapshot.
+/// Snapshots that a read-only downstairs is running from are never pruned.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone, Default,
+)]
+pub struct RetentionPolicy {
+    /// Keep this many of the newest snapshots
+    pub keep_last: Option<u32>,
+    /// Keep the newest snapshot in each of this many most recent hours
+    pub keep_hourly: Option<u32>,
+    /// Keep the newest snapshot in each of this many most recent days (UTC)
+    pub keep_daily: Option<u32>,
+    /// After the count limits, delete the oldest snapshots until the ones
+    /// left use at most this many bytes
+    pub max_total_size: Option<u64>,
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum RetentionAction {
+    Keep,
+    Delete,
+}
+
+/// What a retention policy does with one snapshot, and why
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct SnapshotRetention {
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub size: Option<u64>,
+    pub action: RetentionAction,
+    pub reason: String,
+}
+
+/// The outcome of the last time a region's snapshots were pruned
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct PruneResult {
+    pub time: DateTime<Utc>,
+    pub deleted: Vec<String>,
+    /// Snapshots that should have been deleted but could not be
+    pub errors: Vec<String>,
+}
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct RetentionStatus {
+    pub region_id: RegionId,
+    pub policy: RetentionPolicy,
+    pub last_prune: Option<PruneResult>,
+    /// What the policy would do with the region's snapshots right now
+    pub snapshots: Vec<SnapshotRetention>,
+}
+
 // The different types of resources the worker thread monitors for changes. This
 // wraps the object that has been added, or changed somehow.
 pub enum Resource {
@@ -342,6 +603,7 @@ mod test {
             root_pem: None,
             source: None,
             read_only: false,
+            failure: None,
         };
 
         let s = serde_json::to_string(&r).expect("serialise");
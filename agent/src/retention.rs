@@ -0,0 +1,213 @@
+// Copyright 2024 Oxide Computer Company
+
+use super::model::*;
+use std::collections::HashSet;
+
+/// Decide which of a region's snapshots `policy` keeps and which it deletes.
+///
+/// Snapshots named in `protected` have a read-only downstairs running from
+/// them and are always kept. The result is ordered newest first.
+pub fn plan(
+    policy: &RetentionPolicy,
+    snapshots: &[Snapshot],
+    protected: &HashSet<String>,
+) -> Vec<SnapshotRetention> {
+    let mut snapshots = snapshots.to_vec();
+    snapshots.sort_by(|a, b| {
+        b.created.cmp(&a.created).then_with(|| b.name.cmp(&a.name))
+    });
+
+    let has_counts = policy.keep_last.is_some()
+        || policy.keep_hourly.is_some()
+        || policy.keep_daily.is_some();
+
+    let mut reasons: Vec<Option<String>> = vec![None; snapshots.len()];
+
+    for (i, s) in snapshots.iter().enumerate() {
+        if protected.contains(&s.name) {
+            reasons[i] = Some("a read-only downstairs is running".into());
+        }
+    }
+
+    if let Some(n) = policy.keep_last {
+        for reason in reasons.iter_mut().take(n as usize) {
+            reason.get_or_insert_with(|| format!("one of the newest {}", n));
+        }
+    }
+
+    if let Some(n) = policy.keep_hourly {
+        keep_newest_per_period(&snapshots, &mut reasons, n, "%Y-%m-%dT%H");
+    }
+
+    if let Some(n) = policy.keep_daily {
+        keep_newest_per_period(&snapshots, &mut reasons, n, "%Y-%m-%d");
+    }
+
+    if !has_counts {
+        for reason in reasons.iter_mut() {
+            reason.get_or_insert_with(|| "no count limits".into());
+        }
+    }
+
+    let mut result: Vec<SnapshotRetention> = snapshots
+        .into_iter()
+        .zip(reasons)
+        .map(|(s, reason)| {
+            let (action, reason) = match reason {
+                Some(reason) => (RetentionAction::Keep, reason),
+                None => {
+                    (RetentionAction::Delete, "not kept by any limit".into())
+                }
+            };
+            SnapshotRetention {
+                name: s.name,
+                created: s.created,
+                size: s.size,
+                action,
+                reason,
+            }
+        })
+        .collect();
+
+    // Prefer newer snapshots when trimming to size. Protected snapshots
+    // count towards the total but can't be deleted. Snapshots of unknown
+    // size count as zero bytes.
+    if let Some(max) = policy.max_total_size {
+        let mut total: u64 = result
+            .iter()
+            .filter(|r| protected.contains(&r.name))
+            .map(|r| r.size.unwrap_or(0))
+            .sum();
+
+        for r in result.iter_mut() {
+            if r.action != RetentionAction::Keep || protected.contains(&r.name)
+            {
+                continue;
+            }
+
+            let size = r.size.unwrap_or(0);
+            if total + size <= max {
+                total += size;
+            } else {
+                r.action = RetentionAction::Delete;
+                r.reason = format!("over the size limit of {} bytes", max);
+            }
+        }
+    }
+
+    result
+}
+
+/// Keep the newest snapshot in each of the `n` most recent periods, where a
+/// period is the snapshots whose creation time formats the same way.
+fn keep_newest_per_period(
+    snapshots: &[Snapshot],
+    reasons: &mut [Option<String>],
+    n: u32,
+    format: &str,
+) {
+    let mut seen = HashSet::new();
+    for (s, reason) in snapshots.iter().zip(reasons.iter_mut()) {
+        let period = s.created.format(format).to_string();
+        if seen.contains(&period) {
+            continue;
+        }
+        if seen.len() >= n as usize {
+            break;
+        }
+        reason.get_or_insert_with(|| format!("newest in {}", period));
+        seen.insert(period);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn snapshot(name: &str, day: u32, hour: u32, size: u64) -> Snapshot {
+        Snapshot {
+            name: name.to_string(),
+            created: Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap(),
+            size: Some(size),
+        }
+    }
+
+    fn kept(plan: &[SnapshotRetention]) -> Vec<&str> {
+        plan.iter()
+            .filter(|r| r.action == RetentionAction::Keep)
+            .map(|r| r.name.as_str())
+            .collect()
+    }
+
+    fn snapshots() -> Vec<Snapshot> {
+        vec![
+            snapshot("a", 1, 10, 100),
+            snapshot("b", 1, 11, 100),
+            snapshot("c", 2, 10, 100),
+            snapshot("d", 2, 10, 100),
+            snapshot("e", 2, 12, 100),
+        ]
+    }
+
+    #[test]
+    fn empty_policy_keeps_everything() {
+        let decisions =
+            plan(&RetentionPolicy::default(), &snapshots(), &HashSet::new());
+        assert_eq!(kept(&decisions), vec!["e", "d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn keep_last() {
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, &snapshots(), &HashSet::new());
+        assert_eq!(kept(&decisions), vec!["e", "d"]);
+        assert_eq!(decisions[4].reason, "not kept by any limit");
+    }
+
+    #[test]
+    fn keep_hourly_and_daily() {
+        let policy = RetentionPolicy {
+            keep_hourly: Some(2),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, &snapshots(), &HashSet::new());
+        // "c" is in the same hour as the newer "d"
+        assert_eq!(kept(&decisions), vec!["e", "d"]);
+
+        let policy = RetentionPolicy {
+            keep_daily: Some(2),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, &snapshots(), &HashSet::new());
+        assert_eq!(kept(&decisions), vec!["e", "b"]);
+        assert_eq!(decisions[3].reason, "newest in 2024-03-01");
+    }
+
+    #[test]
+    fn protected_snapshots_are_kept() {
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            max_total_size: Some(100),
+            ..Default::default()
+        };
+        let protected = HashSet::from(["a".to_string()]);
+        let decisions = plan(&policy, &snapshots(), &protected);
+        // "a" is kept and counts towards the size limit, so "e" goes
+        assert_eq!(kept(&decisions), vec!["a"]);
+        assert_eq!(decisions[0].reason, "over the size limit of 100 bytes");
+    }
+
+    #[test]
+    fn max_total_size() {
+        let policy = RetentionPolicy {
+            max_total_size: Some(250),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, &snapshots(), &HashSet::new());
+        assert_eq!(kept(&decisions), vec!["e", "d"]);
+    }
+}
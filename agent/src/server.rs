@@ -1,10 +1,10 @@
 // Copyright 2024 Oxide Computer Company
-use super::datafile::DataFile;
+use super::datafile::{DataFile, InsufficientCapacity, RegionTooLarge};
 use super::model;
 use anyhow::{anyhow, Result};
 use dropshot::{
     endpoint, HandlerTaskMode, HttpError, HttpResponseDeleted, HttpResponseOk,
-    Path as TypedPath, RequestContext, TypedBody,
+    Path as TypedPath, Query as TypedQuery, RequestContext, TypedBody,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -13,6 +13,11 @@ use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::result::Result as SResult;
 use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The longest a request for state changes will wait for one
+const MAX_CHANGES_WAIT: Duration = Duration::from_secs(60);
 
 #[endpoint {
     method = GET,
@@ -36,10 +41,79 @@ async fn region_create(
 
     match rc.context().create_region_request(create) {
         Ok(r) => Ok(HttpResponseOk(r)),
-        Err(e) => Err(HttpError::for_internal_error(format!(
-            "region create failure: {:?}",
-            e
-        ))),
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<InsufficientCapacity>() {
+                return Err(HttpError::for_bad_request(
+                    Some("InsufficientCapacity".to_string()),
+                    e.to_string(),
+                ));
+            }
+            if let Some(e) = e.downcast_ref::<RegionTooLarge>() {
+                return Err(HttpError::for_bad_request(
+                    Some("RegionTooLarge".to_string()),
+                    e.to_string(),
+                ));
+            }
+
+            Err(HttpError::for_internal_error(format!(
+                "region create failure: {:?}",
+                e
+            )))
+        }
+    }
+}
+
+#[endpoint {
+    method = GET,
+    path = "/crucible/0/capacity",
+}]
+async fn capacity(
+    rc: RequestContext<Arc<DataFile>>,
+) -> SResult<HttpResponseOk<model::Capacity>, HttpError> {
+    match rc.context().capacity() {
+        Ok(c) => Ok(HttpResponseOk(c)),
+        Err(e) => Err(HttpError::for_internal_error(e.to_string())),
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ChangesQuery {
+    /// Only return changes after this generation
+    after: Option<u64>,
+    /// The `epoch` that `after` came from; if the agent has restarted since,
+    /// the response is `truncated`
+    epoch: Option<Uuid>,
+    /// If there are no such changes yet, wait up to this many seconds (at
+    /// most 60) for one
+    wait_secs: Option<u64>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/crucible/0/changes",
+}]
+async fn changes(
+    rc: RequestContext<Arc<DataFile>>,
+    query: TypedQuery<ChangesQuery>,
+) -> SResult<HttpResponseOk<model::StateChanges>, HttpError> {
+    let q = query.into_inner();
+    let after = q.after.unwrap_or(0);
+    let wait = Duration::from_secs(q.wait_secs.unwrap_or(0));
+    let deadline = tokio::time::Instant::now() + wait.min(MAX_CHANGES_WAIT);
+
+    // Subscribe before looking, so a change made in between isn't missed
+    let mut generation = rc.context().subscribe();
+
+    loop {
+        let changes = rc.context().changes_after(q.epoch, after);
+        if !changes.changes.is_empty() || changes.truncated {
+            return Ok(HttpResponseOk(changes));
+        }
+
+        match tokio::time::timeout_at(deadline, generation.changed()).await {
+            Ok(Ok(())) => continue,
+            Ok(Err(_)) | Err(_) => return Ok(HttpResponseOk(changes)),
+        }
     }
 }
 
@@ -100,6 +174,81 @@ async fn region_delete(
     }
 }
 
+#[endpoint {
+    method = PUT,
+    path = "/crucible/0/regions/{id}/resize",
+}]
+async fn region_resize(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RegionPath>,
+    body: TypedBody<model::ResizeRegion>,
+) -> SResult<HttpResponseOk<model::Region>, HttpError> {
+    let p = path.into_inner();
+    let resize = body.into_inner();
+
+    if rc.context().get(&p.id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", p.id),
+        ));
+    }
+
+    match rc
+        .context()
+        .resize_region_request(&p.id, resize.extent_count)
+    {
+        Ok(r) => Ok(HttpResponseOk(r)),
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<InsufficientCapacity>() {
+                return Err(HttpError::for_bad_request(
+                    Some("InsufficientCapacity".to_string()),
+                    e.to_string(),
+                ));
+            }
+            if let Some(e) = e.downcast_ref::<RegionTooLarge>() {
+                return Err(HttpError::for_bad_request(
+                    Some("RegionTooLarge".to_string()),
+                    e.to_string(),
+                ));
+            }
+
+            Err(HttpError::for_bad_request(None, e.to_string()))
+        }
+    }
+}
+
+#[endpoint {
+    method = POST,
+    path = "/crucible/0/regions/{id}/retry",
+}]
+async fn region_retry(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RegionPath>,
+) -> SResult<HttpResponseOk<model::Region>, HttpError> {
+    let p = path.into_inner();
+
+    if rc.context().get(&p.id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", p.id),
+        ));
+    }
+
+    match rc.context().retry(&p.id) {
+        Ok(r) => Ok(HttpResponseOk(r)),
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<InsufficientCapacity>() {
+                return Err(HttpError::for_bad_request(
+                    Some("InsufficientCapacity".to_string()),
+                    e.to_string(),
+                ));
+            }
+
+            Err(HttpError::for_bad_request(None, e.to_string()))
+        }
+    }
+}
+
 #[derive(Serialize, JsonSchema)]
 pub struct GetSnapshotResponse {
     snapshots: Vec<model::Snapshot>,
@@ -192,6 +341,43 @@ async fn region_get_snapshot(
     ))
 }
 
+#[derive(Deserialize, JsonSchema)]
+struct CreateSnapshotPath {
+    id: model::RegionId,
+    name: String,
+}
+
+// Snapshots are normally taken outside of the agent (with `zfs snapshot`); this
+// is for platforms where the agent is responsible for taking them.
+#[endpoint {
+    method = POST,
+    path = "/crucible/0/regions/{id}/snapshots/{name}",
+}]
+async fn region_create_snapshot(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<CreateSnapshotPath>,
+) -> Result<HttpResponseOk<model::Snapshot>, HttpError> {
+    let p = path.into_inner();
+
+    match rc.context().get(&p.id) {
+        Some(_) => (),
+        None => {
+            return Err(HttpError::for_not_found(
+                None,
+                format!("region {:?} not found", p.id),
+            ));
+        }
+    }
+
+    match rc.context().create_snapshot(&p.id, &p.name) {
+        Ok(snapshot) => Ok(HttpResponseOk(snapshot)),
+        Err(e) => Err(HttpError::for_internal_error(format!(
+            "snapshot create failure: {:?}",
+            e
+        ))),
+    }
+}
+
 #[derive(Deserialize, JsonSchema)]
 struct DeleteSnapshotPath {
     id: model::RegionId,
@@ -324,6 +510,216 @@ async fn region_delete_running_snapshot(
     }
 }
 
+#[endpoint {
+    method = POST,
+    path = "/crucible/0/regions/{id}/snapshots/{name}/run/retry",
+}]
+async fn region_retry_running_snapshot(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RunSnapshotPath>,
+) -> Result<HttpResponseOk<model::RunningSnapshot>, HttpError> {
+    let p = path.into_inner();
+
+    let exists = rc
+        .context()
+        .running_snapshots()
+        .get(&p.id)
+        .map(|s| s.contains_key(&p.name))
+        .unwrap_or(false);
+
+    if !exists {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("running snapshot {:?} {:?} not found", p.id, p.name),
+        ));
+    }
+
+    match rc.context().retry_rs(&p.id, &p.name) {
+        Ok(rs) => Ok(HttpResponseOk(rs)),
+        Err(e) => Err(HttpError::for_bad_request(None, e.to_string())),
+    }
+}
+
+#[endpoint {
+    method = POST,
+    path = "/crucible/0/regions/{id}/verify",
+}]
+async fn region_verify(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RegionPath>,
+) -> SResult<HttpResponseOk<model::VerifyJob>, HttpError> {
+    let id = path.into_inner().id;
+
+    if rc.context().get(&id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", id),
+        ));
+    }
+
+    match rc.context().verify(&id, None) {
+        Ok(job) => Ok(HttpResponseOk(job)),
+        Err(e) => Err(HttpError::for_bad_request(None, e.to_string())),
+    }
+}
+
+#[endpoint {
+    method = POST,
+    path = "/crucible/0/regions/{id}/snapshots/{name}/verify",
+}]
+async fn region_verify_snapshot(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<GetSnapshotPath>,
+) -> SResult<HttpResponseOk<model::VerifyJob>, HttpError> {
+    let p = path.into_inner();
+
+    if rc.context().get(&p.id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", p.id),
+        ));
+    }
+
+    let snapshots = match rc.context().get_snapshots_for_region(&p.id) {
+        Ok(results) => results,
+        Err(e) => {
+            return Err(HttpError::for_internal_error(e.to_string()));
+        }
+    };
+
+    if !snapshots.iter().any(|s| s.name == p.name) {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("snapshot {:?} not found", p.name),
+        ));
+    }
+
+    match rc.context().verify(&p.id, Some(p.name)) {
+        Ok(job) => Ok(HttpResponseOk(job)),
+        Err(e) => Err(HttpError::for_bad_request(None, e.to_string())),
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct VerifyJobPath {
+    job_id: Uuid,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/crucible/0/verify/{job_id}",
+}]
+async fn verify_job_get(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<VerifyJobPath>,
+) -> SResult<HttpResponseOk<model::VerifyJob>, HttpError> {
+    let job_id = path.into_inner().job_id;
+
+    match rc.context().verify_job(&job_id) {
+        Some(job) => Ok(HttpResponseOk(job)),
+        None => Err(HttpError::for_not_found(
+            None,
+            format!("verify job {} not found", job_id),
+        )),
+    }
+}
+
+#[endpoint {
+    method = GET,
+    path = "/crucible/0/regions/{id}/retention",
+}]
+async fn region_get_retention(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RegionPath>,
+) -> SResult<HttpResponseOk<model::RetentionStatus>, HttpError> {
+    let id = path.into_inner().id;
+
+    if rc.context().get(&id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", id),
+        ));
+    }
+
+    match rc.context().retention_status(&id) {
+        Ok(Some(status)) => Ok(HttpResponseOk(status)),
+        Ok(None) => Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} has no retention policy", id),
+        )),
+        Err(e) => Err(HttpError::for_internal_error(e.to_string())),
+    }
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/crucible/0/regions/{id}/retention",
+}]
+async fn region_set_retention(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RegionPath>,
+    body: TypedBody<model::RetentionPolicy>,
+) -> SResult<HttpResponseOk<model::RetentionPolicy>, HttpError> {
+    let id = path.into_inner().id;
+
+    if rc.context().get(&id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", id),
+        ));
+    }
+
+    match rc.context().set_retention(&id, body.into_inner()) {
+        Ok(policy) => Ok(HttpResponseOk(policy)),
+        Err(e) => Err(HttpError::for_bad_request(None, e.to_string())),
+    }
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/crucible/0/regions/{id}/retention",
+}]
+async fn region_delete_retention(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RegionPath>,
+) -> SResult<HttpResponseDeleted, HttpError> {
+    let id = path.into_inner().id;
+
+    if rc.context().get(&id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", id),
+        ));
+    }
+
+    rc.context().clear_retention(&id);
+    Ok(HttpResponseDeleted())
+}
+
+#[endpoint {
+    method = POST,
+    path = "/crucible/0/regions/{id}/retention/dry-run",
+}]
+async fn region_retention_dry_run(
+    rc: RequestContext<Arc<DataFile>>,
+    path: TypedPath<RegionPath>,
+    body: TypedBody<model::RetentionPolicy>,
+) -> SResult<HttpResponseOk<Vec<model::SnapshotRetention>>, HttpError> {
+    let id = path.into_inner().id;
+
+    if rc.context().get(&id).is_none() {
+        return Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", id),
+        ));
+    }
+
+    match rc.context().retention_plan(&id, &body.into_inner()) {
+        Ok(plan) => Ok(HttpResponseOk(plan)),
+        Err(e) => Err(HttpError::for_internal_error(e.to_string())),
+    }
+}
+
 pub fn make_api() -> Result<dropshot::ApiDescription<Arc<DataFile>>> {
     let mut api = dropshot::ApiDescription::new();
 
@@ -331,13 +727,28 @@ pub fn make_api() -> Result<dropshot::ApiDescription<Arc<DataFile>>> {
     api.register(region_create)?;
     api.register(region_get)?;
     api.register(region_delete)?;
+    api.register(region_resize)?;
+    api.register(region_retry)?;
+    api.register(region_verify)?;
+    api.register(region_get_retention)?;
+    api.register(region_set_retention)?;
+    api.register(region_delete_retention)?;
+    api.register(region_retention_dry_run)?;
+
+    api.register(capacity)?;
+    api.register(changes)?;
 
     api.register(region_get_snapshots)?;
     api.register(region_get_snapshot)?;
+    api.register(region_create_snapshot)?;
     api.register(region_delete_snapshot)?;
 
     api.register(region_run_snapshot)?;
     api.register(region_delete_running_snapshot)?;
+    api.register(region_retry_running_snapshot)?;
+    api.register(region_verify_snapshot)?;
+
+    api.register(verify_job_get)?;
 
     Ok(api)
 }
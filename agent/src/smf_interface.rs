@@ -1,10 +1,61 @@
 // Copyright 2023 Oxide Computer Company
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+/// A service manager that downstairs instances are created in.
+///
+/// `apply_smf` reconciles the agent's datafile against the `SmfInterface` this
+/// provides: on illumos that's SMF itself, elsewhere the agent supervises the
+/// downstairs processes (see `ProcessSmf`).
+pub trait ServiceManager: Send + Sync {
+    /// Call `f` with an interface to the downstairs service.
+    fn with_interface(
+        &self,
+        f: &mut dyn FnMut(&dyn SmfInterface) -> Result<()>,
+    ) -> Result<()>;
+}
+
+/// The illumos service manager
+pub struct RealServiceManager {
+    service: String,
+}
+
+impl RealServiceManager {
+    /// Fails if the SMF service `service` does not exist.
+    pub fn new(service: &str) -> Result<RealServiceManager> {
+        let scf = crucible_smf::Scf::new()?;
+        let scope = scf.scope_local()?;
+
+        if scope.get_service(service)?.is_none() {
+            bail!("SMF service {} does not exist", service);
+        }
+
+        Ok(RealServiceManager {
+            service: service.to_string(),
+        })
+    }
+}
+
+impl ServiceManager for RealServiceManager {
+    fn with_interface(
+        &self,
+        f: &mut dyn FnMut(&dyn SmfInterface) -> Result<()>,
+    ) -> Result<()> {
+        let scf = crucible_smf::Scf::new()?;
+        let scope = scf.scope_local()?;
+        let svc = scope
+            .get_service(&self.service)?
+            .ok_or_else(|| anyhow!("service missing"))?;
+
+        let smf_interface = RealSmf::new(&svc)?;
+
+        f(&smf_interface)
+    }
+}
+
 /// Interfaces for an implementation to interface with SMF objects.
 
 pub trait SmfInterface {
@@ -1,16 +1,16 @@
 // Copyright 2023 Oxide Computer Company
 
 use super::model::*;
+use crate::dataset::{Dataset, ZFSDataset};
+use crate::supervisor::ProcessSmf;
 use anyhow::{bail, Result};
 use slog::{error, info, Logger};
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use chrono::{TimeZone, Utc};
-
-#[cfg(test)]
-use std::path::PathBuf;
+use chrono::{DateTime, TimeZone, Utc};
 
 /// A interface for an implementation to interact with ZFS snapshots.
 pub trait SnapshotInterface: Sync + Send {
@@ -23,6 +23,17 @@ pub trait SnapshotInterface: Sync + Send {
     /// Delete a snapshot if it exists. This call should be idempotent.
     fn delete_snapshot(&self, snapshot_name: String) -> Result<()>;
 
+    /// Take a snapshot named `dataset@snapshot_name`.
+    fn take_snapshot(
+        &self,
+        dataset: String,
+        snapshot_name: String,
+    ) -> Result<()>;
+
+    /// Get the dataset that snapshots of the region at `region_dir` are taken
+    /// against. Fails if the region does not exist.
+    fn dataset_for_region(&self, region_dir: &Path) -> Result<String>;
+
     /// Create a snapshot. Only used for testing.
     #[cfg(test)]
     fn create_snapshot(
@@ -103,7 +114,7 @@ impl SnapshotInterface for ZfsSnapshotInterface {
                 .arg("-pH")
                 .arg("-o")
                 .arg("value")
-                .arg("creation")
+                .arg("creation,used")
                 .arg(snapshot)
                 .output()?;
 
@@ -130,9 +141,15 @@ impl SnapshotInterface for ZfsSnapshotInterface {
                 bail!("zfs get failed!");
             }
 
+            // One line per property, in the order they were asked for
+            let Some((creation, used)) = cmd_stdout.split_once('\n') else {
+                bail!("bad zfs get output {:?}", cmd_stdout);
+            };
+
             results.push(Snapshot {
                 name: snapshot_name.to_string(),
-                created: Utc.timestamp_opt(cmd_stdout.parse()?, 0).unwrap(),
+                created: Utc.timestamp_opt(creation.parse()?, 0).unwrap(),
+                size: Some(used.parse()?),
             });
         }
 
@@ -190,6 +207,46 @@ impl SnapshotInterface for ZfsSnapshotInterface {
         Ok(())
     }
 
+    fn take_snapshot(
+        &self,
+        dataset: String,
+        snapshot_name: String,
+    ) -> Result<()> {
+        let snapshot = format!("{}@{}", dataset, snapshot_name);
+        let cmd = Command::new("zfs")
+            .arg("snapshot")
+            .arg(&snapshot)
+            .output()?;
+
+        if !cmd.status.success() {
+            let err = String::from_utf8_lossy(&cmd.stderr);
+            let out = String::from_utf8_lossy(&cmd.stdout);
+
+            error!(
+                self.log,
+                "zfs snapshot {:?} create failed: out {:?} err {:?}",
+                snapshot,
+                out,
+                err,
+            );
+
+            bail!("zfs snapshot create failure");
+        }
+
+        Ok(())
+    }
+
+    fn dataset_for_region(&self, region_dir: &Path) -> Result<String> {
+        let dataset = ZFSDataset::new(
+            region_dir
+                .to_path_buf()
+                .into_os_string()
+                .into_string()
+                .unwrap(),
+        )?;
+        Ok(dataset.dataset())
+    }
+
     #[cfg(test)]
     fn create_snapshot(
         &self,
@@ -201,6 +258,240 @@ impl SnapshotInterface for ZfsSnapshotInterface {
     }
 }
 
+/// Snapshots for platforms without ZFS, taken by copying a region's files.
+///
+/// A region's "dataset" is its directory, and to keep the layout that the rest
+/// of the agent expects, snapshot `name` of that region lives in
+/// `<region>/.zfs/snapshot/<name>`.
+///
+/// Files are copied with `cp --reflink=auto`, so on filesystems that support
+/// it (e.g. XFS or btrfs) the snapshot shares blocks with the region instead of
+/// duplicating them. Hardlinks can't be used, because the downstairs modifies
+/// extent files in place. Unlike a ZFS snapshot the copy is not atomic, so the
+/// region's downstairs is paused (see `ProcessSmf::pause`) while it's made.
+pub struct ReflinkSnapshotInterface {
+    log: Logger,
+
+    /// The supervisor running each region's downstairs, and the prefix of
+    /// their instance names
+    downstairs: Option<(Arc<ProcessSmf>, String)>,
+}
+
+impl ReflinkSnapshotInterface {
+    #[cfg(test)]
+    pub fn new(log: Logger) -> ReflinkSnapshotInterface {
+        ReflinkSnapshotInterface {
+            log,
+            downstairs: None,
+        }
+    }
+
+    /// Pause regions' downstairs, run by `supervisor` with instance names
+    /// starting with `downstairs_prefix`, while they're being copied.
+    pub fn with_supervisor(
+        log: Logger,
+        supervisor: Arc<ProcessSmf>,
+        downstairs_prefix: &str,
+    ) -> ReflinkSnapshotInterface {
+        ReflinkSnapshotInterface {
+            log,
+            downstairs: Some((supervisor, downstairs_prefix.to_string())),
+        }
+    }
+
+    /// Snapshot names must stay inside the snapshot directory, and must not
+    /// look like a partial copy (which start with '.')
+    fn check_snapshot_name(name: &str) -> Result<()> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            bail!("bad snapshot name {}!", name);
+        }
+        Ok(())
+    }
+
+    fn snapshot_dir(dataset: &str) -> PathBuf {
+        let mut path = PathBuf::from(dataset);
+        path.push(".zfs");
+        path.push("snapshot");
+        path
+    }
+}
+
+impl SnapshotInterface for ReflinkSnapshotInterface {
+    fn get_snapshots_for_dataset(
+        &self,
+        dataset: String,
+    ) -> Result<Vec<Snapshot>> {
+        let snapshot_dir = Self::snapshot_dir(&dataset);
+        if !snapshot_dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut results = Vec::new();
+        for entry in std::fs::read_dir(&snapshot_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            // Skip partially copied snapshots
+            if name.starts_with('.') {
+                continue;
+            }
+
+            let created: DateTime<Utc> = entry.metadata()?.modified()?.into();
+
+            // This is the size of the copy: blocks it shares with the region
+            // through reflinks are counted even though deleting the snapshot
+            // would not free them.
+            let size = Some(dir_size(&entry.path())?);
+
+            results.push(Snapshot {
+                name,
+                created,
+                size,
+            });
+        }
+        results.sort_by(|a, b| a.name.cmp(&b.name));
+
+        info!(self.log, "dataset {} has snapshots {:?}", dataset, results);
+
+        Ok(results)
+    }
+
+    fn delete_snapshot(&self, snapshot_name: String) -> Result<()> {
+        let Some((dataset, name)) = snapshot_name.rsplit_once('@') else {
+            bail!("bad snapshot name {}!", snapshot_name);
+        };
+        Self::check_snapshot_name(name)?;
+
+        // If the snapshot doesn't exist, return Ok - this call should be
+        // idempotent
+        let path = Self::snapshot_dir(dataset).join(name);
+        if !path.exists() {
+            return Ok(());
+        }
+
+        info!(self.log, "deleting snapshot {:?}", path);
+        std::fs::remove_dir_all(&path)?;
+
+        Ok(())
+    }
+
+    fn take_snapshot(
+        &self,
+        dataset: String,
+        snapshot_name: String,
+    ) -> Result<()> {
+        Self::check_snapshot_name(&snapshot_name)?;
+
+        let snapshot_dir = Self::snapshot_dir(&dataset);
+        let path = snapshot_dir.join(&snapshot_name);
+        if path.exists() {
+            bail!("snapshot {}@{} already exists", dataset, snapshot_name);
+        }
+
+        // Copy into a temporary directory first, so that a crash part way
+        // through doesn't leave behind something that looks like a snapshot.
+        let tmp = snapshot_dir.join(format!(".{}.tmp", snapshot_name));
+        if tmp.exists() {
+            std::fs::remove_dir_all(&tmp)?;
+        }
+        std::fs::create_dir_all(&tmp)?;
+
+        let mut sources = Vec::new();
+        for entry in std::fs::read_dir(&dataset)? {
+            let entry = entry?;
+            if entry.file_name() != ".zfs" {
+                sources.push(entry.path());
+            }
+        }
+
+        if !sources.is_empty() {
+            // A region's dataset is its directory, named after the region
+            let _paused = match &self.downstairs {
+                Some((supervisor, prefix)) => {
+                    let region_id = Path::new(&dataset)
+                        .file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    supervisor.pause(&format!("{}-{}", prefix, region_id))?
+                }
+                None => None,
+            };
+
+            info!(
+                self.log,
+                "copying {} to snapshot {:?}", dataset, snapshot_name
+            );
+            let cmd = Command::new("cp")
+                .arg("-a")
+                .arg("--reflink=auto")
+                .args(&sources)
+                .arg(&tmp)
+                .output()?;
+
+            if !cmd.status.success() {
+                let err = String::from_utf8_lossy(&cmd.stderr);
+                let out = String::from_utf8_lossy(&cmd.stdout);
+
+                error!(
+                    self.log,
+                    "snapshot {:?} copy failed: out {:?} err {:?}",
+                    path,
+                    out,
+                    err,
+                );
+
+                let _ = std::fs::remove_dir_all(&tmp);
+                bail!("snapshot copy failure");
+            }
+        }
+
+        std::fs::rename(&tmp, &path)?;
+
+        Ok(())
+    }
+
+    fn dataset_for_region(&self, region_dir: &Path) -> Result<String> {
+        if !region_dir.is_dir() {
+            bail!("region directory {:?} does not exist", region_dir);
+        }
+
+        Ok(region_dir.to_string_lossy().to_string())
+    }
+
+    #[cfg(test)]
+    fn create_snapshot(
+        &self,
+        base_path: PathBuf,
+        region_id: String,
+        snapshot_name: String,
+    ) {
+        let mut region_dir = base_path;
+        region_dir.push("regions");
+        region_dir.push(region_id);
+
+        self.take_snapshot(
+            region_dir.into_os_string().into_string().unwrap(),
+            snapshot_name,
+        )
+        .unwrap();
+    }
+}
+
+/// The total size of the files under `path`.
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
 pub struct TestSnapshotInterface {
     log: Logger,
     snapshots: Mutex<HashSet<String>>,
@@ -225,10 +516,11 @@ impl SnapshotInterface for TestSnapshotInterface {
 
         let snapshots = snapshots
             .iter()
-            .filter(|x| x.starts_with(&format!("{}@", dataset)))
+            .filter_map(|x| x.strip_prefix(&format!("{}@", dataset)))
             .map(|x| Snapshot {
-                name: x.clone(),
+                name: x.to_string(),
                 created: Utc::now(),
+                size: None,
             })
             .collect();
 
@@ -247,6 +539,25 @@ impl SnapshotInterface for TestSnapshotInterface {
         Ok(())
     }
 
+    fn take_snapshot(
+        &self,
+        dataset: String,
+        snapshot_name: String,
+    ) -> Result<()> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        info!(self.log, "taking snapshot {}@{}", dataset, snapshot_name);
+        snapshots.insert(format!("{}@{}", dataset, snapshot_name));
+        Ok(())
+    }
+
+    fn dataset_for_region(&self, region_dir: &Path) -> Result<String> {
+        // Snapshots are recorded against the region id, see `create_snapshot`
+        match region_dir.file_name() {
+            Some(name) => Ok(name.to_string_lossy().to_string()),
+            None => bail!("bad region directory {:?}", region_dir),
+        }
+    }
+
     #[cfg(test)]
     fn create_snapshot(
         &self,
@@ -281,3 +592,96 @@ impl SnapshotInterface for TestSnapshotInterface {
         std::fs::create_dir_all(&snapshot_path).unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::{o, Drain};
+    use tempfile::tempdir;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_reflink_snapshots() -> Result<()> {
+        let dir = tempdir()?;
+        let snapshots = ReflinkSnapshotInterface::new(csl());
+
+        let region_dir = dir.path().join("regions").join("r1");
+        std::fs::create_dir_all(region_dir.join("00"))?;
+        std::fs::write(region_dir.join("region.json"), "one")?;
+        std::fs::write(region_dir.join("00").join("000"), "two")?;
+
+        let dataset = snapshots.dataset_for_region(&region_dir)?;
+        assert!(snapshots
+            .dataset_for_region(&dir.path().join("missing"))
+            .is_err());
+        assert!(snapshots
+            .get_snapshots_for_dataset(dataset.clone())?
+            .is_empty());
+
+        snapshots.take_snapshot(dataset.clone(), "first".to_string())?;
+        assert!(snapshots
+            .take_snapshot(dataset.clone(), "first".to_string())
+            .is_err());
+        assert!(snapshots
+            .take_snapshot(dataset.clone(), "../bad".to_string())
+            .is_err());
+
+        // Changing the region afterwards doesn't change the snapshot
+        std::fs::write(region_dir.join("00").join("000"), "changed")?;
+        snapshots.take_snapshot(dataset.clone(), "second".to_string())?;
+
+        let snapshot_dir = region_dir.join(".zfs").join("snapshot");
+        assert_eq!(
+            std::fs::read_to_string(snapshot_dir.join("first/00/000"))?,
+            "two"
+        );
+        assert_eq!(
+            std::fs::read_to_string(snapshot_dir.join("second/00/000"))?,
+            "changed"
+        );
+        assert!(!snapshot_dir.join("second/.zfs").exists());
+
+        let names: Vec<String> = snapshots
+            .get_snapshots_for_dataset(dataset.clone())?
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["first".to_string(), "second".to_string()]);
+
+        let sizes: Vec<Option<u64>> = snapshots
+            .get_snapshots_for_dataset(dataset.clone())?
+            .into_iter()
+            .map(|s| s.size)
+            .collect();
+        assert_eq!(sizes, vec![Some(6), Some(10)]);
+
+        // Names that would escape the snapshot directory are refused, and
+        // leave everything where it was
+        for bad in ["..", "../x", "../../00", "", ".first.tmp"] {
+            assert!(snapshots
+                .delete_snapshot(format!("{}@{}", dataset, bad))
+                .is_err());
+        }
+        assert_eq!(
+            std::fs::read_to_string(region_dir.join("00").join("000"))?,
+            "changed"
+        );
+        assert!(snapshot_dir.join("first").exists());
+
+        snapshots.delete_snapshot(format!("{}@first", dataset))?;
+        snapshots.delete_snapshot(format!("{}@first", dataset))?;
+        let names: Vec<String> = snapshots
+            .get_snapshots_for_dataset(dataset)?
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["second".to_string()]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,505 @@
+// Copyright 2024 Oxide Computer Company
+
+use crate::smf_interface::*;
+use anyhow::{bail, Result};
+use crucible_smf::ScfError;
+use slog::{error, info, o, warn, Logger};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the supervisor looks for downstairs that have exited
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A service manager for platforms without SMF, where the agent runs each
+/// enabled downstairs instance as a child process (and restarts it if it
+/// exits).
+///
+/// Instance configuration is kept in memory in the same property groups that
+/// SMF would store, and each process is started with the arguments that
+/// `downstairs_method_script.sh` builds from them. Nothing is persisted:
+/// `apply_smf` recreates every instance from the datafile when the agent
+/// starts, so any downstairs left running by a previous agent must be stopped
+/// first, otherwise the new ones will fail to bind their ports.
+pub struct ProcessSmf {
+    log: Logger,
+    scope: String,
+    program: PathBuf,
+    instances: Mutex<HashMap<String, ProcessSmfInstance>>,
+}
+
+impl ProcessSmf {
+    pub fn new(log: Logger, scope: String, program: PathBuf) -> ProcessSmf {
+        ProcessSmf {
+            log,
+            scope,
+            program,
+            instances: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spawn a thread which restarts any enabled downstairs that has exited.
+    pub fn supervise(self: &Arc<Self>) {
+        let this = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(SUPERVISE_INTERVAL);
+
+            let instances: Vec<ProcessSmfInstance> =
+                this.instances.lock().unwrap().values().cloned().collect();
+
+            for inst in instances {
+                if let Err(e) = inst.ensure_running() {
+                    error!(this.log, "{}: could not restart: {}", inst.name, e);
+                }
+            }
+        });
+    }
+
+    /// Suspend the named instance's downstairs, if it's running, until the
+    /// returned guard is dropped.
+    ///
+    /// A suspended downstairs can't change its region, so a copy made
+    /// meanwhile holds what a crash at that instant would have left on disk
+    /// (which is also what a ZFS snapshot of a running region holds). The
+    /// downstairs recovers from that on startup, and its upstairs replays
+    /// anything that wasn't flushed.
+    pub fn pause(&self, name: &str) -> Result<Option<Paused>> {
+        let instances = self.instances.lock().unwrap();
+        let Some(inst) = instances.get(name) else {
+            return Ok(None);
+        };
+
+        let mut process = inst.process.lock().unwrap();
+        let Some(p) = process.as_mut() else {
+            return Ok(None);
+        };
+        if !matches!(p.child.try_wait(), Ok(None)) {
+            return Ok(None);
+        }
+
+        let pid = p.child.id();
+        info!(inst.log, "pausing downstairs pid {}", pid);
+        signal(pid, "STOP")?;
+        Ok(Some(Paused {
+            log: inst.log.clone(),
+            pid,
+        }))
+    }
+}
+
+/// Send a signal to a process
+fn signal(pid: u32, signal: &str) -> Result<()> {
+    let cmd = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg(pid.to_string())
+        .output()?;
+
+    if !cmd.status.success() {
+        bail!(
+            "kill -{} {} failed: {}",
+            signal,
+            pid,
+            String::from_utf8_lossy(&cmd.stderr).trim_end()
+        );
+    }
+    Ok(())
+}
+
+/// A downstairs suspended by `ProcessSmf::pause`, which is resumed on drop
+pub struct Paused {
+    log: Logger,
+    pid: u32,
+}
+
+impl Drop for Paused {
+    fn drop(&mut self) {
+        info!(self.log, "resuming downstairs pid {}", self.pid);
+        if let Err(e) = signal(self.pid, "CONT") {
+            error!(self.log, "could not resume downstairs: {}", e);
+        }
+    }
+}
+
+impl SmfInterface for ProcessSmf {
+    fn instances(&self) -> Result<Vec<Box<dyn SmfInstance + '_>>, ScfError> {
+        let instances = self.instances.lock().unwrap();
+        let mut result: Vec<Box<dyn SmfInstance>> = vec![];
+
+        for value in instances.values() {
+            result.push(Box::new(value.clone()));
+        }
+
+        Ok(result)
+    }
+
+    fn get_instance(
+        &self,
+        name: &str,
+    ) -> Result<Option<Box<dyn SmfInstance + '_>>, ScfError> {
+        let instances = self.instances.lock().unwrap();
+        if let Some(value) = instances.get(name) {
+            Ok(Some(Box::new(value.clone())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn add_instance(
+        &self,
+        name: &str,
+    ) -> Result<Box<dyn SmfInstance + '_>, ScfError> {
+        let mut instances = self.instances.lock().unwrap();
+
+        let inst = instances.entry(name.to_string()).or_insert_with(|| {
+            ProcessSmfInstance {
+                log: self.log.new(o!("instance" => name.to_string())),
+                name: name.to_string(),
+                program: self.program.clone(),
+                config: MockSmfInstance::new(
+                    name.to_string(),
+                    format!("{}:{}", self.scope, name),
+                ),
+                process: Arc::new(Mutex::new(None)),
+            }
+        });
+
+        Ok(Box::new(inst.clone()))
+    }
+
+    /// Like `MockSmf::prune`: drop instances that have been disabled, so that
+    /// what's left can be compared against what `apply_smf` would create.
+    #[cfg(test)]
+    fn prune(&self) {
+        self.instances
+            .lock()
+            .unwrap()
+            .retain(|_, inst| inst.config.enabled());
+    }
+}
+
+impl ServiceManager for ProcessSmf {
+    fn with_interface(
+        &self,
+        f: &mut dyn FnMut(&dyn SmfInterface) -> Result<()>,
+    ) -> Result<()> {
+        f(self)
+    }
+}
+
+/// A running downstairs, and the arguments it was started with
+struct Supervised {
+    args: Vec<String>,
+    child: Child,
+}
+
+impl Supervised {
+    fn stop(mut self, log: &Logger) {
+        info!(log, "stopping downstairs pid {}", self.child.id());
+        if let Err(e) = self.child.kill() {
+            warn!(log, "kill failed: {}", e);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Clone)]
+pub struct ProcessSmfInstance {
+    log: Logger,
+    name: String,
+    program: PathBuf,
+    config: MockSmfInstance,
+    process: Arc<Mutex<Option<Supervised>>>,
+}
+
+impl ProcessSmfInstance {
+    /// Build the downstairs arguments from the "config" property group
+    fn args(&self) -> Result<Vec<String>, ScfError> {
+        let Some(pg) = self.config.get_pg("config")? else {
+            return Err(ScfError::NotFound);
+        };
+
+        let property = |name: &str| -> Result<Option<String>, ScfError> {
+            match pg.get_property(name)? {
+                Some(property) => match property.value()? {
+                    Some(value) => Ok(Some(value.as_string()?)),
+                    None => Ok(None),
+                },
+                None => Ok(None),
+            }
+        };
+
+        let mut args = vec!["run".to_string()];
+        for (arg, name) in [
+            ("--data", "directory"),
+            ("--address", "address"),
+            ("--port", "port"),
+        ] {
+            args.push(arg.to_string());
+            args.push(property(name)?.ok_or(ScfError::NotFound)?);
+        }
+
+        args.push("--mode".to_string());
+        args.push(property("mode")?.unwrap_or_else(|| "rw".to_string()));
+
+        for (arg, name) in [
+            ("--cert-pem", "cert_pem_path"),
+            ("--key-pem", "key_pem_path"),
+            ("--root-cert-pem", "root_pem_path"),
+            ("--repair-server-name", "repair_server_name"),
+        ] {
+            if let Some(val) = property(name)? {
+                args.push(arg.to_string());
+                args.push(val);
+            }
+        }
+
+        Ok(args)
+    }
+
+    /// If this instance is enabled, make sure its downstairs is running with
+    /// the current configuration.
+    fn ensure_running(&self) -> Result<(), ScfError> {
+        let mut process = self.process.lock().unwrap();
+
+        if !self.config.enabled() {
+            return Ok(());
+        }
+
+        let args = self.args()?;
+
+        if let Some(p) = process.as_mut() {
+            match p.child.try_wait() {
+                Ok(None) if p.args == args => return Ok(()),
+                Ok(None) => {
+                    info!(self.log, "configuration changed, restarting");
+                    process.take().unwrap().stop(&self.log);
+                }
+                Ok(Some(status)) => {
+                    warn!(
+                        self.log,
+                        "downstairs exited ({}), restarting", status
+                    );
+                }
+                Err(e) => {
+                    warn!(self.log, "could not check downstairs: {}", e);
+                    process.take().unwrap().stop(&self.log);
+                }
+            }
+        }
+
+        info!(self.log, "starting {:?} {:?}", self.program, args);
+        let child = Command::new(&self.program)
+            .args(&args)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| {
+                error!(self.log, "could not start {:?}: {}", self.program, e);
+                ScfError::Internal
+            })?;
+
+        *process = Some(Supervised { args, child });
+
+        Ok(())
+    }
+}
+
+impl SmfInstance for ProcessSmfInstance {
+    fn name(&self) -> Result<String, ScfError> {
+        self.config.name()
+    }
+
+    fn fmri(&self) -> Result<String, ScfError> {
+        self.config.fmri()
+    }
+
+    fn states(
+        &self,
+    ) -> Result<
+        (Option<crucible_smf::State>, Option<crucible_smf::State>),
+        ScfError,
+    > {
+        if !self.config.enabled() {
+            return Ok((Some(crucible_smf::State::Disabled), None));
+        }
+
+        let mut process = self.process.lock().unwrap();
+        let running = match process.as_mut() {
+            Some(p) => matches!(p.child.try_wait(), Ok(None)),
+            None => false,
+        };
+
+        if running {
+            Ok((Some(crucible_smf::State::Online), None))
+        } else {
+            Ok((Some(crucible_smf::State::Offline), None))
+        }
+    }
+
+    fn disable(&self, temporary: bool) -> Result<(), ScfError> {
+        let mut process = self.process.lock().unwrap();
+        self.config.disable(temporary)?;
+
+        if let Some(p) = process.take() {
+            p.stop(&self.log);
+        }
+
+        Ok(())
+    }
+
+    fn enable(&self, temporary: bool) -> Result<(), ScfError> {
+        self.config.enable(temporary)?;
+        self.ensure_running()
+    }
+
+    fn enabled(&self) -> bool {
+        self.config.enabled()
+    }
+
+    fn get_pg(
+        &self,
+        name: &str,
+    ) -> Result<Option<Box<dyn SmfPropertyGroup + '_>>, ScfError> {
+        self.config.get_pg(name)
+    }
+
+    fn add_pg(
+        &self,
+        name: &str,
+        pgtype: &str,
+    ) -> Result<Box<dyn SmfPropertyGroup + '_>, ScfError> {
+        self.config.add_pg(name, pgtype)
+    }
+
+    fn get_snapshot(
+        &self,
+        name: &str,
+    ) -> Result<Option<Box<dyn SmfSnapshot + '_>>, ScfError> {
+        self.config.get_snapshot(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::Drain;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    fn configure(inst: &dyn SmfInstance, port: &str) -> Result<()> {
+        let pg = inst.add_pg("config", "application")?;
+        let tx = pg.transaction()?;
+        tx.start()?;
+        for (name, val) in [
+            ("directory", "/data"),
+            ("address", "127.0.0.1"),
+            ("port", port),
+        ] {
+            tx.property_ensure(
+                name,
+                crucible_smf::scf_type_t::SCF_TYPE_ASTRING,
+                val,
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_supervision() -> Result<()> {
+        // Stand in for the downstairs with something that records its
+        // arguments and then waits to be killed.
+        let dir = tempdir()?;
+        let program = dir.path().join("downstairs");
+        let args_file = dir.path().join("args");
+        std::fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {:?}\nexec sleep 60\n",
+                args_file
+            ),
+        )?;
+        std::fs::set_permissions(
+            &program,
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+
+        let smf = ProcessSmf::new(csl(), "test".to_string(), program);
+        let inst = smf.add_instance("downstairs-r1")?;
+        assert_eq!(inst.fmri()?, "test:downstairs-r1");
+
+        // Can't start without configuration
+        assert!(inst.enable(false).is_err());
+
+        configure(&*inst, "1000")?;
+        inst.enable(false)?;
+        assert_eq!(inst.states()?.0, Some(crucible_smf::State::Online));
+
+        let args = |smf: &ProcessSmf| {
+            let instances = smf.instances.lock().unwrap();
+            let process = instances["downstairs-r1"].process.lock().unwrap();
+            process.as_ref().unwrap().args.join(" ")
+        };
+        assert_eq!(
+            args(&smf),
+            "run --data /data --address 127.0.0.1 --port 1000 --mode rw"
+        );
+
+        // Enabling again with the same config leaves the process alone, but a
+        // config change restarts it.
+        let pid = |smf: &ProcessSmf| {
+            let instances = smf.instances.lock().unwrap();
+            let process = instances["downstairs-r1"].process.lock().unwrap();
+            process.as_ref().unwrap().child.id()
+        };
+        let first = pid(&smf);
+        inst.enable(false)?;
+        assert_eq!(pid(&smf), first);
+
+        configure(&*inst, "1001")?;
+        inst.enable(false)?;
+        assert_ne!(pid(&smf), first);
+        assert!(args(&smf).contains("--port 1001"));
+
+        // Pausing stops the process until the guard is dropped
+        #[cfg(target_os = "linux")]
+        {
+            // Signals are delivered asynchronously, so give them a moment
+            let stopped = |pid: u32, want: bool| -> Result<bool> {
+                for _ in 0..100 {
+                    let stat =
+                        std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+                    let (_, rest) = stat.rsplit_once(") ").unwrap();
+                    if rest.starts_with('T') == want {
+                        return Ok(true);
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Ok(false)
+            };
+            let pid = pid(&smf);
+            let paused = smf.pause("downstairs-r1")?;
+            assert!(paused.is_some());
+            assert!(stopped(pid, true)?);
+            drop(paused);
+            assert!(stopped(pid, false)?);
+        }
+        assert!(smf.pause("downstairs-r2")?.is_none());
+
+        inst.disable(false)?;
+        assert_eq!(inst.states()?.0, Some(crucible_smf::State::Disabled));
+        assert!(smf.pause("downstairs-r1")?.is_none());
+        assert_eq!(smf.instances()?.len(), 1);
+
+        smf.prune();
+        assert!(smf.instances()?.is_empty());
+
+        Ok(())
+    }
+}
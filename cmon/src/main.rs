@@ -1,14 +1,16 @@
 // Copyright 2022 Oxide Computer Company
 use clap::{Parser, Subcommand, ValueEnum};
-use crucible_control_client::Client;
-use crucible_protocol::ClientId;
+use crucible_control_client::{types, Client};
+use crucible_protocol::{ClientId, JobId};
+use serde::Deserialize;
 use std::fmt;
 use std::io::{self, BufRead};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 use tokio::time::{sleep, Duration};
+use uuid::Uuid;
 
-use crucible::{Arg, DsState};
+use crucible::{Arg, ClientData, ClientIOStateCount, DsState, IOStateCount};
 
 /// Connect to crucible control server
 #[derive(Parser, Debug)]
@@ -25,6 +27,10 @@ struct Args {
     /// Seconds to wait between displaying data.
     #[clap(short, long, default_value = "5", action)]
     seconds: u64,
+
+    /// Bearer token for the control server's write endpoints
+    #[clap(short, long, env = "CRUCIBLE_CONTROL_TOKEN", action)]
+    token: Option<String>,
 }
 
 // The possible fields we will display when receiving DTrace output.
@@ -80,10 +86,47 @@ enum Action {
     },
     /// Decode what options will display what headers.
     DtraceDecode,
+    /// Show the same fields as `dtrace`, streamed from the control server
+    Live {
+        /// Fields to display
+        #[clap(short, long, default_value = "io-count")]
+        #[arg(value_enum)]
+        output: Vec<DtraceDisplay>,
+        /// Also print each job as it's acked
+        #[clap(short, long, action)]
+        jobs: bool,
+    },
     /// Show the current downstairs job queue
     Jobs,
     /// Show the status of various LiveRepair stats
     Repair,
+    /// Fault a downstairs, so it goes through live-repair when it reconnects
+    Fault {
+        /// Client ID (0, 1 or 2) of the downstairs to fault
+        client_id: u8,
+        /// Fault it even if that leaves too few active downstairs to
+        /// complete writes
+        #[clap(long, action)]
+        force: bool,
+    },
+    /// Replace a downstairs with a new one
+    Replace {
+        /// ID of the region set (the upstairs' volume ID)
+        #[clap(long, action)]
+        id: Uuid,
+        /// Address of the downstairs being replaced
+        #[clap(long, action)]
+        old: String,
+        /// Address of the new downstairs
+        #[clap(long, action)]
+        new: String,
+    },
+    /// Send a flush, and wait for it to finish
+    Flush,
+    /// Deactivate the upstairs
+    Deactivate,
+    /// Abort the live-repair in progress
+    AbortRepair,
 }
 
 // Translate a DsState into a three letter string for printing.
@@ -420,6 +463,215 @@ fn dtrace_loop(output: Vec<DtraceDisplay>) {
     }
 }
 
+// The fields of the control server's `UpstairsStats` that `live` shows
+#[derive(Deserialize)]
+struct LiveStats {
+    ds_state: Vec<DsState>,
+    up_jobs: u32,
+    ds_jobs: u32,
+    reconcile_done: usize,
+    reconcile_needed: usize,
+    reconcile_aborted: usize,
+    live_repair_completed: Vec<usize>,
+    live_repair_aborted: Vec<usize>,
+    ds_connected: Vec<usize>,
+    ds_replaced: Vec<usize>,
+    extents_repaired: Vec<usize>,
+    extents_confirmed: Vec<usize>,
+    extent_limit: Vec<Option<usize>>,
+    ds_delay_us: Vec<usize>,
+    ds_io_count: Vec<ClientIOStateCount>,
+    next_job_id: JobId,
+    backpressure_us: u64,
+    write_bytes_out: u64,
+}
+
+const LIVE_FIELDS: &str = "ds_state,up_jobs,ds_jobs,reconcile_done,\
+    reconcile_needed,reconcile_aborted,live_repair_completed,\
+    live_repair_aborted,ds_connected,ds_replaced,extents_repaired,\
+    extents_confirmed,extent_limit,ds_delay_us,ds_io_count,next_job_id,\
+    backpressure_us,write_bytes_out";
+
+// Put the stats into the shape of the DTrace `up-status` probe's argument, so
+// they can be shown by `print_dtrace_row`.
+impl From<LiveStats> for Arg {
+    fn from(s: LiveStats) -> Arg {
+        let io = |g: fn(&ClientIOStateCount) -> u32| {
+            ClientData::from_fn(s.ds_io_count.len(), |i| {
+                g(&s.ds_io_count[i.get() as usize])
+            })
+        };
+        Arg {
+            session_id: String::new(),
+            up_count: s.up_jobs,
+            up_counters: Default::default(),
+            next_job_id: s.next_job_id,
+            up_backpressure: s.backpressure_us,
+            ds_count: s.ds_jobs,
+            write_bytes_out: s.write_bytes_out,
+            ds_io_count: IOStateCount {
+                new: io(|c| c.new),
+                in_progress: io(|c| c.in_progress),
+                done: io(|c| c.done),
+                skipped: io(|c| c.skipped),
+                error: io(|c| c.error),
+            },
+            ds_state: s.ds_state,
+            ds_reconciled: s.reconcile_done,
+            ds_reconcile_needed: s.reconcile_needed,
+            ds_reconcile_aborted: s.reconcile_aborted,
+            ds_live_repair_completed: s.live_repair_completed,
+            ds_live_repair_aborted: s.live_repair_aborted,
+            ds_connected: s.ds_connected,
+            ds_replaced: s.ds_replaced,
+            ds_extents_repaired: s.extents_repaired,
+            ds_extents_confirmed: s.extents_confirmed,
+            ds_extent_limit: s
+                .extent_limit
+                .iter()
+                .flatten()
+                .copied()
+                .next()
+                .unwrap_or(0),
+            ds_delay_us: s.ds_delay_us,
+            ds_ro_lr_skipped: vec![],
+        }
+    }
+}
+
+// Split one server-sent event into its name and data
+fn parse_sse(event: &str) -> (&str, String) {
+    let mut name = "message";
+    let mut data = Vec::new();
+    for line in event.lines() {
+        if let Some(n) = line.strip_prefix("event:") {
+            name = n.trim();
+        } else if let Some(d) = line.strip_prefix("data:") {
+            data.push(d.strip_prefix(' ').unwrap_or(d));
+        }
+    }
+    (name, data.join("\n"))
+}
+
+// Show the fields requested in the output Vec from the control server's stats
+// stream, the same way `dtrace_loop` does, along with acked jobs if asked.
+async fn live_loop(args: Args, output: Vec<DtraceDisplay>, jobs: bool) {
+    let url = format!(
+        "{}/stats/stream?interval_ms={}&jobs={}&fields={}",
+        args.control,
+        args.seconds * 1000,
+        jobs,
+        LIVE_FIELDS,
+    );
+    let mut response =
+        match reqwest::get(&url).await.and_then(|r| r.error_for_status()) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Control returned error: {}", e);
+                return;
+            }
+        };
+
+    let mut buf = Vec::new();
+    let mut count = 0;
+    let mut last_job_id: u64 = 0;
+    loop {
+        match response.chunk().await {
+            Ok(Some(bytes)) => buf.extend_from_slice(&bytes),
+            Ok(None) => {
+                println!("Stream closed");
+                return;
+            }
+            Err(e) => {
+                println!("Stream error: {}", e);
+                return;
+            }
+        }
+
+        // Events are separated by a blank line
+        while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = buf.drain(..end + 2).collect();
+            let event = String::from_utf8_lossy(&event);
+            match parse_sse(&event) {
+                ("stats", data) => {
+                    let stats: LiveStats = match serde_json::from_str(&data) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            println!("Err {:?}", e);
+                            continue;
+                        }
+                    };
+                    if count == 0 {
+                        print_dtrace_header(&output);
+                    }
+                    count = (count + 1) % 20;
+                    print_dtrace_row(stats.into(), &output, &mut last_job_id);
+                }
+                ("job", data) => {
+                    match serde_json::from_str::<types::WorkSummary>(&data) {
+                        Ok(job) => {
+                            print!(
+                                "{:>7} {:>6} {:>4}",
+                                job.id, job.job_type, job.num_blocks
+                            );
+                            for state in job.state.iter() {
+                                print!(" {:>6}", state);
+                            }
+                            println!();
+                        }
+                        Err(e) => {
+                            println!("Err {:?}", e);
+                        }
+                    }
+                }
+                ("lagged", data) => {
+                    println!("(skipped {} jobs)", data);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+// Build a client which sends our token (if any) with every request
+fn write_client(args: &Args) -> Client {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(token) = &args.token {
+        let value = format!("Bearer {token}").parse().unwrap();
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    let client = reqwest::ClientBuilder::new()
+        .default_headers(headers)
+        .build()
+        .unwrap();
+    Client::new_with_client(&args.control, client)
+}
+
+// Print the downstairs state changes caused by a write endpoint
+fn print_op_result<E: fmt::Debug>(
+    r: Result<
+        crucible_control_client::ResponseValue<types::ControlOpResult>,
+        crucible_control_client::Error<E>,
+    >,
+) {
+    let r = match r {
+        Ok(r) => r.into_inner(),
+        Err(e) => {
+            println!("Control returned error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(replace) = r.replace {
+        println!("replace: {:?}", replace);
+    }
+    if r.transitions.is_empty() {
+        println!("no downstairs changed state");
+    }
+    for t in r.transitions {
+        println!("[{}] {:?} -> {:?}", t.client_id, t.old, t.new);
+    }
+}
+
 /*
  * Simple tool to connect to a crucible upstairs control http port
  * and report back the results from a upstairs_fill_info command.
@@ -438,11 +690,41 @@ async fn main() {
                 print_dtrace_header(&[dd]);
             }
         }
+        Action::Live { ref output, jobs } => {
+            let output = output.clone();
+            live_loop(args, output, jobs).await;
+        }
         Action::Jobs => {
             show_work_queue(args).await;
         }
         Action::Repair => {
             show_repair_stats(args).await;
         }
+        Action::Fault { client_id, force } => {
+            let ca = write_client(&args);
+            print_op_result(ca.fault_downstairs(client_id, Some(force)).await);
+        }
+        Action::Replace {
+            id,
+            ref old,
+            ref new,
+        } => {
+            let ca = write_client(&args);
+            let body = types::ReplaceRequest {
+                id,
+                old: old.clone(),
+                new: new.clone(),
+            };
+            print_op_result(ca.replace_downstairs(&body).await);
+        }
+        Action::Flush => {
+            print_op_result(write_client(&args).flush().await);
+        }
+        Action::Deactivate => {
+            print_op_result(write_client(&args).deactivate().await);
+        }
+        Action::AbortRepair => {
+            print_op_result(write_client(&args).abort_live_repair().await);
+        }
     }
 }
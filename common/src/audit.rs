@@ -0,0 +1,582 @@
+// Copyright 2024 Oxide Computer Company
+//! A structured log of volume lifecycle events
+//!
+//! Activation, takeover, faults, replacements, reconciliation and live repair
+//! are all in the regular log, but mixed in with everything else and in free
+//! form.  An [`AuditLog`] records them as [`AuditRecord`]s instead: one JSON
+//! object per line in a file of their own, and the most recent ones in memory
+//! for the `/audit` endpoints of the upstairs control server and the
+//! downstairs repair server.
+//!
+//! The schema is versioned by [`AUDIT_SCHEMA_VERSION`].  Fields may be added
+//! to records without changing it, but not removed or renamed.
+//!
+//! Once the file reaches `AUDIT_MAX_FILE_LEN`, it is renamed with an `.old`
+//! suffix (replacing any earlier one) and a new file is started.
+
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Version of the [`AuditRecord`] schema, written into every record
+pub const AUDIT_SCHEMA_VERSION: u32 = 1;
+
+/// Number of records kept in memory for the `/audit` endpoints
+const AUDIT_RECENT_RECORDS: usize = 1000;
+
+/// Records which may be waiting for the writer thread before writing stops
+const AUDIT_QUEUE_LEN: usize = 1024;
+
+/// Size at which an audit log file is rotated
+const AUDIT_MAX_FILE_LEN: u64 = 64 << 20;
+
+/// Amount of the end of an audit log file first read for the last record
+const AUDIT_TAIL_LEN: u64 = 4096;
+
+/// Which side of the connection wrote a record
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditSource {
+    Upstairs,
+    Downstairs,
+}
+
+/// The upstairs session that an event concerns
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema,
+)]
+pub struct AuditSession {
+    pub upstairs_id: Uuid,
+    pub session_id: Uuid,
+    pub generation: u64,
+}
+
+/// A lifecycle event
+///
+/// States are given by name, in the same form as they are serialized
+/// elsewhere (e.g. `live_repair_ready`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// The upstairs changed state
+    UpstairsState {
+        old: String,
+        new: String,
+        cause: String,
+    },
+    /// The upstairs changed its state for one downstairs
+    DsState {
+        target: Option<SocketAddr>,
+        old: String,
+        new: String,
+        cause: String,
+    },
+    /// Another upstairs took over from this session
+    TakenOver {
+        new_upstairs_id: Uuid,
+        new_session_id: Uuid,
+        new_generation: u64,
+    },
+    /// The downstairs made this session active
+    Promoted,
+    /// The downstairs dropped this session's connection
+    Disconnected { reason: String },
+    /// A downstairs was replaced by another
+    Replaced { old: SocketAddr, new: SocketAddr },
+    /// Reconciliation started, with `extents` extents to repair
+    ReconciliationStarted { id: Uuid, extents: u64 },
+    /// Reconciliation finished, or was abandoned
+    ReconciliationFinished { id: Uuid, aborted: bool },
+    /// Live repair started, from client `source` to the `repairing` clients
+    LiveRepairStarted {
+        id: Uuid,
+        source: u8,
+        repairing: Vec<u8>,
+        extents: u64,
+    },
+    /// Live repair finished, or was abandoned
+    LiveRepairFinished { id: Uuid, aborted: bool },
+}
+
+/// One line of an audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AuditRecord {
+    /// Schema version, [`AUDIT_SCHEMA_VERSION`] when written
+    pub version: u32,
+    /// Position in the log, starting at 1 and carried on across restarts
+    pub seq: u64,
+    pub time: DateTime<Utc>,
+    pub source: AuditSource,
+    #[serde(flatten)]
+    pub session: AuditSession,
+    /// Upstairs client id of the downstairs concerned, if it's just one
+    pub client_id: Option<u8>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Audit records after some sequence number
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AuditRecords {
+    /// The latest sequence number: pass this as `after` to get the next ones
+    pub seq: u64,
+    /// Some records after the requested sequence number are no longer in
+    /// memory, so callers should read the rest from the file
+    pub truncated: bool,
+    pub records: Vec<AuditRecord>,
+}
+
+#[derive(Debug)]
+struct AuditLogInner {
+    /// Lines to be written by the writer thread, if there's a file
+    tx: Option<SyncSender<Vec<u8>>>,
+    seq: u64,
+    recent: VecDeque<AuditRecord>,
+}
+
+/// Where audit records are written
+///
+/// Records go to a dedicated thread, which appends them to the file and syncs
+/// it, so recording never blocks an async task.  If writing to the file fails,
+/// or the thread falls more than `AUDIT_QUEUE_LEN` records behind, records are
+/// only kept in memory from then on: losing the audit log is no reason to stop
+/// serving IO.
+///
+/// Dropping the `AuditLog` waits for queued records to be written.
+#[derive(Debug)]
+pub struct AuditLog {
+    source: AuditSource,
+    inner: Mutex<AuditLogInner>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Returns an audit log that's only kept in memory
+    pub fn new(source: AuditSource) -> Self {
+        AuditLog {
+            source,
+            inner: Mutex::new(AuditLogInner {
+                tx: None,
+                seq: 0,
+                recent: VecDeque::new(),
+            }),
+            writer: None,
+        }
+    }
+
+    /// Opens (or creates) an audit log file, appending to what's already there
+    pub fn open(source: AuditSource, path: &Path) -> std::io::Result<Self> {
+        Self::open_with_max_len(source, path, AUDIT_MAX_FILE_LEN)
+    }
+
+    fn open_with_max_len(
+        source: AuditSource,
+        path: &Path,
+        max_len: u64,
+    ) -> std::io::Result<Self> {
+        let mut file = open_file(path)?;
+        let mut seq = last_seq(&mut file)?;
+
+        // If we crashed just after rotating, the numbering carries on from
+        // the old file.
+        let old = old_path(path);
+        if seq == 0 && old.exists() {
+            seq = last_seq(&mut open_file(&old)?)?;
+        }
+
+        let writer = AuditWriter {
+            len: file.metadata()?.len(),
+            file: BufWriter::new(file),
+            path: path.to_owned(),
+            max_len,
+        };
+        let (tx, rx) = sync_channel(AUDIT_QUEUE_LEN);
+        let writer = std::thread::Builder::new()
+            .name("audit".to_string())
+            .spawn(move || writer.run(rx))?;
+
+        Ok(AuditLog {
+            source,
+            inner: Mutex::new(AuditLogInner {
+                tx: Some(tx),
+                seq,
+                recent: VecDeque::new(),
+            }),
+            writer: Some(writer),
+        })
+    }
+
+    /// Records an event
+    pub fn record(
+        &self,
+        session: AuditSession,
+        client_id: Option<u8>,
+        event: AuditEvent,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.seq += 1;
+        let r = AuditRecord {
+            version: AUDIT_SCHEMA_VERSION,
+            seq: inner.seq,
+            time: Utc::now(),
+            source: self.source,
+            session,
+            client_id,
+            event,
+        };
+
+        if let Some(tx) = inner.tx.as_ref() {
+            let mut line = serde_json::to_vec(&r).unwrap();
+            line.push(b'\n');
+            if tx.try_send(line).is_err() {
+                inner.tx = None;
+            }
+        }
+
+        if inner.recent.len() == AUDIT_RECENT_RECORDS {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(r);
+    }
+
+    /// Returns the records in memory after sequence number `after`
+    pub fn after(&self, after: u64) -> AuditRecords {
+        let inner = self.inner.lock().unwrap();
+
+        // If we restarted, or records have been discarded since the caller
+        // last looked, the caller could have missed some.
+        let oldest =
+            inner.recent.front().map(|r| r.seq).unwrap_or(inner.seq + 1);
+        let truncated = after > inner.seq || after + 1 < oldest;
+
+        AuditRecords {
+            seq: inner.seq,
+            truncated,
+            records: inner
+                .recent
+                .iter()
+                .filter(|r| truncated || r.seq > after)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        // Hang up, then wait for the writer thread to drain the queue
+        let inner = self.inner.get_mut().unwrap_or_else(|e| e.into_inner());
+        inner.tx.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The writer thread's end of an [`AuditLog`]
+struct AuditWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    /// Current length of the file
+    len: u64,
+    max_len: u64,
+}
+
+impl AuditWriter {
+    /// Writes lines until every sender is gone or writing fails
+    ///
+    /// The file is synced whenever the queue is empty.
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        while let Ok(line) = rx.recv() {
+            for line in std::iter::once(line).chain(rx.try_iter()) {
+                if self.write(&line).is_err() {
+                    return;
+                }
+            }
+            if self.sync().is_err() {
+                return;
+            }
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_len {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    /// Moves the current file aside, and starts a new one
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.sync()?;
+        std::fs::rename(&self.path, old_path(&self.path))?;
+        self.file = BufWriter::new(open_file(&self.path)?);
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn open_file(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+}
+
+/// Returns the path to which an audit log file is rotated
+fn old_path(path: &Path) -> PathBuf {
+    let mut old = OsString::from(path);
+    old.push(".old");
+    old.into()
+}
+
+/// Returns the sequence number of the last record in an audit log file
+///
+/// Only the end of the file is read, going further back only if no whole
+/// record is found there.  A torn last line (from a crash mid-write) is
+/// skipped over, and finished off so that the next record starts on a line of
+/// its own.
+fn last_seq(file: &mut File) -> std::io::Result<u64> {
+    #[derive(Deserialize)]
+    struct Seq {
+        seq: u64,
+    }
+
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let mut last = [0u8];
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.read_exact(&mut last)?;
+    if last[0] != b'\n' {
+        file.write_all(b"\n")?;
+    }
+
+    let mut window = AUDIT_TAIL_LEN.min(len);
+    loop {
+        let start = len - window;
+        let mut tail = vec![0u8; window as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut tail)?;
+
+        // The first line is only known to be whole if it starts the file
+        let seq = tail
+            .split(|&b| b == b'\n')
+            .skip(usize::from(start > 0))
+            .filter_map(|line| serde_json::from_slice::<Seq>(line).ok())
+            .last();
+        match seq {
+            Some(s) => return Ok(s.seq),
+            None if start == 0 => return Ok(0),
+            None => window = (window * 2).min(len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session() -> AuditSession {
+        AuditSession {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            generation: 3,
+        }
+    }
+
+    fn promoted(log: &AuditLog, n: usize) {
+        let s = session();
+        for _ in 0..n {
+            log.record(s, None, AuditEvent::Promoted);
+        }
+    }
+
+    #[test]
+    fn after_returns_newer_records() {
+        let log = AuditLog::new(AuditSource::Downstairs);
+        promoted(&log, 3);
+
+        let r = log.after(1);
+        assert_eq!(r.seq, 3);
+        assert!(!r.truncated);
+        assert_eq!(
+            r.records.iter().map(|r| r.seq).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(log.after(3).records.is_empty());
+    }
+
+    #[test]
+    fn after_reports_truncation() {
+        let log = AuditLog::new(AuditSource::Downstairs);
+        promoted(&log, AUDIT_RECENT_RECORDS + 10);
+
+        let r = log.after(5);
+        assert!(r.truncated);
+        assert_eq!(r.records.len(), AUDIT_RECENT_RECORDS);
+        assert_eq!(r.records[0].seq, 11);
+
+        assert!(!log.after(10).truncated);
+
+        // A caller from before a restart
+        assert!(log.after(5000).truncated);
+    }
+
+    #[test]
+    fn seq_continues_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let log = AuditLog::open(AuditSource::Upstairs, &path).unwrap();
+        promoted(&log, 2);
+        drop(log);
+
+        let log = AuditLog::open(AuditSource::Upstairs, &path).unwrap();
+        promoted(&log, 1);
+        assert_eq!(log.after(0).seq, 3);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let seqs: Vec<u64> = contents
+            .lines()
+            .map(|l| serde_json::from_str::<AuditRecord>(l).unwrap().seq)
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reopen_after_torn_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let log = AuditLog::open(AuditSource::Downstairs, &path).unwrap();
+        promoted(&log, 1);
+        drop(log);
+
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(b"{\"version\":1,\"seq\":2,\"ti").unwrap();
+        drop(f);
+
+        let log = AuditLog::open(AuditSource::Downstairs, &path).unwrap();
+        promoted(&log, 1);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let last = contents.lines().last().unwrap();
+        let r: AuditRecord = serde_json::from_str(last).unwrap();
+        assert_eq!(r.seq, 2);
+    }
+
+    #[test]
+    fn last_seq_reads_from_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        // Enough records that the last is well past the first tail read
+        let log = AuditLog::open(AuditSource::Upstairs, &path).unwrap();
+        promoted(&log, 200);
+        drop(log);
+        assert!(std::fs::metadata(&path).unwrap().len() > AUDIT_TAIL_LEN * 4);
+        assert_eq!(last_seq(&mut open_file(&path).unwrap()).unwrap(), 200);
+
+        // A torn line longer than the tail read makes it look further back
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[b'x'; AUDIT_TAIL_LEN as usize * 3]).unwrap();
+        drop(f);
+        assert_eq!(last_seq(&mut open_file(&path).unwrap()).unwrap(), 200);
+    }
+
+    #[test]
+    fn rotates_at_max_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let max_len = 2000;
+
+        let log =
+            AuditLog::open_with_max_len(AuditSource::Upstairs, &path, max_len)
+                .unwrap();
+        promoted(&log, 20);
+        drop(log);
+
+        let seqs = |path: &Path| -> Vec<u64> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str::<AuditRecord>(l).unwrap().seq)
+                .collect()
+        };
+        let new = seqs(&path);
+        let old = seqs(&old_path(&path));
+        assert!(!new.is_empty());
+        assert!(std::fs::metadata(&path).unwrap().len() <= max_len);
+        assert!(std::fs::metadata(old_path(&path)).unwrap().len() <= max_len);
+
+        // The files hold the most recent records, in order
+        let all: Vec<u64> = old.into_iter().chain(new).collect();
+        assert_eq!(all.last(), Some(&20));
+        assert!(all.windows(2).all(|w| w[1] == w[0] + 1));
+
+        // If we crashed just after rotating, numbering still carries on
+        std::fs::write(&path, b"").unwrap();
+        let log =
+            AuditLog::open_with_max_len(AuditSource::Upstairs, &path, max_len)
+                .unwrap();
+        let last_old = *seqs(&old_path(&path)).last().unwrap();
+        assert_eq!(log.after(0).seq, last_old);
+    }
+
+    #[test]
+    fn record_schema() {
+        let log = AuditLog::new(AuditSource::Upstairs);
+        let s = session();
+        log.record(
+            s,
+            Some(1),
+            AuditEvent::DsState {
+                target: None,
+                old: "active".to_string(),
+                new: "faulted".to_string(),
+                cause: "io_error".to_string(),
+            },
+        );
+
+        let r = log.after(0).records.pop().unwrap();
+        let v = serde_json::to_value(&r).unwrap();
+        assert_eq!(v["version"], AUDIT_SCHEMA_VERSION);
+        assert_eq!(v["seq"], 1);
+        assert_eq!(v["source"], "upstairs");
+        assert_eq!(v["upstairs_id"], s.upstairs_id.to_string());
+        assert_eq!(v["session_id"], s.session_id.to_string());
+        assert_eq!(v["generation"], 3);
+        assert_eq!(v["client_id"], 1);
+        assert_eq!(v["event"], "ds_state");
+        assert_eq!(v["old"], "active");
+        assert_eq!(v["new"], "faulted");
+        assert_eq!(v["cause"], "io_error");
+
+        let back: AuditRecord = serde_json::from_value(v).unwrap();
+        assert_eq!(back, r);
+    }
+}
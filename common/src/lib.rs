@@ -21,7 +21,10 @@ pub use region::{
     MIN_BLOCK_SIZE, MIN_SHIFT,
 };
 
+pub mod audit;
 pub mod impacted_blocks;
+pub mod metrics;
+pub mod otel;
 pub mod x509;
 
 pub const REPAIR_PORT_OFFSET: u16 = 4000;
@@ -0,0 +1,472 @@
+// Copyright 2024 Oxide Computer Company
+//! Latency histograms, and Prometheus text-format output for our stats
+//!
+//! Stats normally go to oximeter, which needs an Oxide control plane to
+//! collect them.  The `/metrics` endpoints of the upstairs control server and
+//! the downstairs repair server render them with [`PrometheusText`] instead,
+//! for anything that can scrape Prometheus.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Each power of two of latency is split into `2^SUB_BUCKET_BITS` buckets
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// Latencies of `2^MAX_LATENCY_BITS` microseconds (about 34 seconds) or more
+/// all share the last bucket
+const MAX_LATENCY_BITS: u32 = 25;
+
+/// Number of buckets in a [`LatencyHistogram`]
+const LATENCY_BUCKETS: usize = ((MAX_LATENCY_BITS - SUB_BUCKET_BITS + 1) as u64
+    * SUB_BUCKETS) as usize
+    + 1;
+
+/// Histogram of job latencies
+///
+/// Buckets are log-linear, as in an HDR histogram: below `SUB_BUCKETS`
+/// microseconds each bucket is one microsecond wide, and above that each
+/// power of two is split into `SUB_BUCKETS` equal buckets, so that a bucket is
+/// never wider than an eighth of the latencies it holds.  The last bucket
+/// also counts anything slower than the rest.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct LatencyHistogram {
+    pub buckets: Vec<u64>,
+    /// Sum of all recorded latencies, in microseconds
+    pub total_us: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS],
+            total_us: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        self.buckets[Self::bucket(us)] += 1;
+        self.total_us += us;
+    }
+
+    /// Returns the index of the bucket for a latency in microseconds
+    fn bucket(us: u64) -> usize {
+        if us < SUB_BUCKETS {
+            return us as usize;
+        }
+        let log2 = u64::BITS - 1 - us.leading_zeros();
+        if log2 >= MAX_LATENCY_BITS {
+            return LATENCY_BUCKETS - 1;
+        }
+        // The top SUB_BUCKET_BITS + 1 bits of `us` pick the sub-bucket
+        let shift = log2 - SUB_BUCKET_BITS;
+        (shift as u64 * SUB_BUCKETS + (us >> shift)) as usize
+    }
+
+    /// Returns the inclusive lower bound of bucket `i`, in microseconds
+    fn bucket_start_us(i: usize) -> u64 {
+        let i = i as u64;
+        if i < SUB_BUCKETS {
+            return i;
+        }
+        let shift = i / SUB_BUCKETS - 1;
+        (SUB_BUCKETS + i % SUB_BUCKETS) << shift
+    }
+
+    /// Returns the number of latencies recorded
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns the exclusive upper bound of every bucket but the last, in
+    /// microseconds
+    pub fn bucket_bounds_us() -> Vec<u64> {
+        (1..LATENCY_BUCKETS).map(Self::bucket_start_us).collect()
+    }
+
+    /// Estimates the latency, in microseconds, within which the fraction `q`
+    /// of recorded jobs completed
+    ///
+    /// The estimate is interpolated within the bucket holding that job, so it
+    /// is off by at most the bucket's width: an eighth of the latency, or a
+    /// microsecond for the smallest.  Returns 0 if nothing has been recorded.
+    pub fn quantile_us(&self, q: f64) -> u64 {
+        let count = self.count();
+        if count == 0 {
+            return 0;
+        }
+        let rank = ((q * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            if seen + n >= rank {
+                let lo = Self::bucket_start_us(i);
+                if i + 1 == self.buckets.len() {
+                    return lo;
+                }
+                let hi = Self::bucket_start_us(i + 1);
+                let frac = (rank - seen) as f64 / n as f64;
+                return lo + ((hi - lo) as f64 * frac) as u64;
+            }
+            seen += n;
+        }
+        unreachable!("rank is at most the count")
+    }
+}
+
+/// Latency histograms for the stages that IOs pass through, by stage and then
+/// by operation type
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StageLatency {
+    stages: BTreeMap<String, BTreeMap<String, LatencyHistogram>>,
+}
+
+impl StageLatency {
+    pub fn record(&mut self, stage: &str, op: &str, latency: Duration) {
+        if !self.stages.contains_key(stage) {
+            self.stages.insert(stage.to_string(), BTreeMap::new());
+        }
+        let by_op = self.stages.get_mut(stage).unwrap();
+        if !by_op.contains_key(op) {
+            by_op.insert(op.to_string(), LatencyHistogram::default());
+        }
+        by_op.get_mut(op).unwrap().record(latency);
+    }
+
+    pub fn get(&self, stage: &str, op: &str) -> Option<&LatencyHistogram> {
+        self.stages.get(stage).and_then(|by_op| by_op.get(op))
+    }
+
+    /// Iterates over every histogram, with its stage and operation type
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (&str, &str, &LatencyHistogram)> + '_ {
+        self.stages.iter().flat_map(|(stage, by_op)| {
+            by_op
+                .iter()
+                .map(move |(op, h)| (stage.as_str(), op.as_str(), h))
+        })
+    }
+
+    /// Returns the estimated percentiles of every histogram
+    pub fn summaries(&self) -> Vec<StageLatencySummary> {
+        self.iter()
+            .map(|(stage, op, h)| StageLatencySummary {
+                stage: stage.to_string(),
+                op: op.to_string(),
+                count: h.count(),
+                p50_us: h.quantile_us(0.5),
+                p99_us: h.quantile_us(0.99),
+                p999_us: h.quantile_us(0.999),
+            })
+            .collect()
+    }
+}
+
+/// Estimated latency percentiles for one stage of one type of operation
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct StageLatencySummary {
+    pub stage: String,
+    pub op: String,
+    /// Number of operations which have passed through this stage
+    pub count: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+}
+
+/// The kinds of Prometheus metric that we produce
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Display for MetricType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricType::Counter => write!(f, "counter"),
+            MetricType::Gauge => write!(f, "gauge"),
+            MetricType::Histogram => write!(f, "histogram"),
+        }
+    }
+}
+
+/// A page of metrics in the Prometheus text exposition format
+///
+/// Each metric family is started with [`PrometheusText::family`], and all of
+/// its samples must follow before the next family starts.
+#[derive(Debug, Default)]
+pub struct PrometheusText {
+    out: String,
+}
+
+impl PrometheusText {
+    /// Value for the `Content-Type` header of a response holding the page
+    pub const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a metric family, giving its type and description
+    pub fn family(&mut self, name: &str, kind: MetricType, help: &str) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        writeln!(self.out, "# HELP {name} {help}").unwrap();
+        writeln!(self.out, "# TYPE {name} {kind}").unwrap();
+    }
+
+    /// Adds one sample to the current counter or gauge family
+    pub fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) {
+        self.out.push_str(name);
+        self.labels(labels, None);
+        writeln!(self.out, " {value}").unwrap();
+    }
+
+    /// Adds the samples for one histogram to the current histogram family
+    ///
+    /// Latencies are given in seconds, as Prometheus prefers.  The bound of
+    /// each bucket is exclusive, rather than Prometheus' inclusive `le`, which
+    /// is near enough at these scales.
+    pub fn histogram(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        h: &LatencyHistogram,
+    ) {
+        let mut cumulative = 0;
+        for (i, n) in h.buckets.iter().enumerate() {
+            cumulative += n;
+            let le = if i + 1 == h.buckets.len() {
+                "+Inf".to_string()
+            } else {
+                (LatencyHistogram::bucket_start_us(i + 1) as f64 / 1e6)
+                    .to_string()
+            };
+            write!(self.out, "{name}_bucket").unwrap();
+            self.labels(labels, Some(("le", &le)));
+            writeln!(self.out, " {cumulative}").unwrap();
+        }
+        write!(self.out, "{name}_sum").unwrap();
+        self.labels(labels, None);
+        writeln!(self.out, " {}", h.total_us as f64 / 1e6).unwrap();
+        write!(self.out, "{name}_count").unwrap();
+        self.labels(labels, None);
+        writeln!(self.out, " {cumulative}").unwrap();
+    }
+
+    /// Returns the finished page
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn labels(&mut self, labels: &[(&str, &str)], extra: Option<(&str, &str)>) {
+        if labels.is_empty() && extra.is_none() {
+            return;
+        }
+        self.out.push('{');
+        for (i, (k, v)) in labels.iter().chain(extra.iter()).enumerate() {
+            if i > 0 {
+                self.out.push(',');
+            }
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            write!(self.out, "{k}=\"{v}\"").unwrap();
+        }
+        self.out.push('}');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_buckets() {
+        let mut h = LatencyHistogram::default();
+        h.record(Duration::from_nanos(500));
+        h.record(Duration::from_micros(1));
+        h.record(Duration::from_micros(3));
+        h.record(Duration::from_secs(3600));
+        h.record(Duration::from_micros(1000));
+        assert_eq!(h.buckets[0], 1);
+        assert_eq!(h.buckets[1], 1);
+        assert_eq!(h.buckets[3], 1);
+        assert_eq!(h.buckets[LatencyHistogram::bucket(1000)], 1);
+        assert_eq!(h.buckets[LATENCY_BUCKETS - 1], 1);
+        assert_eq!(h.count(), 5);
+        assert_eq!(h.total_us, 3_600_001_004);
+
+        // 1000us is in [960, 1024), an eighth of [512, 1024)
+        let i = LatencyHistogram::bucket(1000);
+        assert_eq!(LatencyHistogram::bucket_start_us(i), 960);
+        assert_eq!(LatencyHistogram::bucket_start_us(i + 1), 1024);
+    }
+
+    #[test]
+    fn latency_bucket_bounds() {
+        let bounds = LatencyHistogram::bucket_bounds_us();
+        assert_eq!(bounds.len(), LATENCY_BUCKETS - 1);
+        assert_eq!(&bounds[..10], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(bounds[bounds.len() - 1], 1 << MAX_LATENCY_BITS);
+
+        for i in 0..LATENCY_BUCKETS {
+            let start = LatencyHistogram::bucket_start_us(i);
+            assert_eq!(LatencyHistogram::bucket(start), i);
+            if i + 1 < LATENCY_BUCKETS {
+                let end = LatencyHistogram::bucket_start_us(i + 1);
+                assert!(end > start);
+                assert_eq!(LatencyHistogram::bucket(end - 1), i);
+                // No bucket is wider than an eighth of its start
+                assert!(end - start <= (start / SUB_BUCKETS).max(1));
+            }
+        }
+    }
+
+    #[test]
+    fn latency_quantile_error_bound() {
+        // Latencies spread over several orders of magnitude
+        let mut latencies: Vec<u64> = (0..10_000u64)
+            .map(|i| (i * 7919 % 10_000) * (i * 7907 % 10_000) / 7 + i % 13)
+            .collect();
+
+        let mut h = LatencyHistogram::default();
+        for &us in &latencies {
+            h.record(Duration::from_micros(us));
+        }
+        latencies.sort_unstable();
+
+        for q in (1..=1000).map(|i| i as f64 / 1000.0) {
+            let rank = (q * latencies.len() as f64).ceil() as usize;
+            let exact = latencies[rank.clamp(1, latencies.len()) - 1];
+            let estimate = h.quantile_us(q);
+            let bound = (exact / SUB_BUCKETS).max(1);
+            assert!(
+                estimate.abs_diff(exact) <= bound,
+                "q {q}: estimated {estimate}, exact {exact}"
+            );
+        }
+    }
+
+    #[test]
+    fn latency_quantiles() {
+        let mut h = LatencyHistogram::default();
+        assert_eq!(h.quantile_us(0.5), 0);
+
+        // 100 jobs in the [3, 4) bucket, then one in [1408, 1536)
+        for _ in 0..100 {
+            h.record(Duration::from_micros(3));
+        }
+        h.record(Duration::from_micros(1500));
+        assert_eq!(h.quantile_us(0.5), 3);
+        assert_eq!(h.quantile_us(0.99), 3);
+        assert_eq!(h.quantile_us(0.999), 1536);
+        assert_eq!(h.quantile_us(1.0), 1536);
+
+        // Anything past the last bound is reported as that bound
+        h.record(Duration::from_secs(3600));
+        assert_eq!(h.quantile_us(1.0), 1 << MAX_LATENCY_BITS);
+    }
+
+    #[test]
+    fn stage_latency_summaries() {
+        let mut s = StageLatency::default();
+        s.record("send", "write", Duration::from_micros(3));
+        s.record("send", "read", Duration::from_micros(3));
+        s.record("ack", "read", Duration::from_micros(1));
+        s.record("ack", "read", Duration::from_micros(1));
+        assert_eq!(s.get("ack", "read").unwrap().count(), 2);
+        assert!(s.get("ack", "write").is_none());
+
+        let summaries = s.summaries();
+        let keys: Vec<_> = summaries
+            .iter()
+            .map(|s| (s.stage.as_str(), s.op.as_str(), s.count))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ("ack", "read", 2),
+                ("send", "read", 1),
+                ("send", "write", 1)
+            ]
+        );
+        assert_eq!(summaries[0].p50_us, 1);
+        assert_eq!(summaries[0].p999_us, 2);
+    }
+
+    #[test]
+    fn prometheus_samples() {
+        let mut p = PrometheusText::new();
+        p.family("crucible_reads_total", MetricType::Counter, "Reads\ndone");
+        p.sample("crucible_reads_total", &[], 3);
+        p.sample(
+            "crucible_reads_total",
+            &[("client_id", "1"), ("note", "a \"b\"\\")],
+            4,
+        );
+        assert_eq!(
+            p.finish(),
+            "# HELP crucible_reads_total Reads\\ndone\n\
+             # TYPE crucible_reads_total counter\n\
+             crucible_reads_total 3\n\
+             crucible_reads_total{client_id=\"1\",note=\"a \\\"b\\\"\\\\\"} 4\n"
+        );
+    }
+
+    #[test]
+    fn prometheus_histogram() {
+        let mut h = LatencyHistogram::default();
+        h.record(Duration::from_micros(1));
+        h.record(Duration::from_micros(3));
+        h.record(Duration::from_secs(3600));
+
+        let mut p = PrometheusText::new();
+        p.histogram("lat_seconds", &[("op", "read")], &h);
+        let page = p.finish();
+        let lines: Vec<&str> = page.lines().collect();
+        assert_eq!(lines.len(), LATENCY_BUCKETS + 2);
+        assert_eq!(
+            lines[0],
+            "lat_seconds_bucket{op=\"read\",le=\"0.000001\"} 0"
+        );
+        assert_eq!(
+            lines[1],
+            "lat_seconds_bucket{op=\"read\",le=\"0.000002\"} 1"
+        );
+        assert_eq!(
+            lines[2],
+            "lat_seconds_bucket{op=\"read\",le=\"0.000003\"} 1"
+        );
+        assert_eq!(
+            lines[3],
+            "lat_seconds_bucket{op=\"read\",le=\"0.000004\"} 2"
+        );
+        assert_eq!(
+            lines[LATENCY_BUCKETS - 1],
+            "lat_seconds_bucket{op=\"read\",le=\"+Inf\"} 3"
+        );
+        assert_eq!(
+            lines[LATENCY_BUCKETS],
+            "lat_seconds_sum{op=\"read\"} 3600.000004"
+        );
+        assert_eq!(
+            lines[LATENCY_BUCKETS + 1],
+            "lat_seconds_count{op=\"read\"} 3"
+        );
+    }
+}
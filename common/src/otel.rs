@@ -0,0 +1,136 @@
+// Copyright 2024 Oxide Computer Company
+//! OpenTelemetry tracing for guest IO
+//!
+//! When tracing is enabled, the upstairs creates a span for each guest IO and
+//! a child span for each downstairs job.  If a downstairs supports it, a job's
+//! trace context is sent along with the job (as a W3C `traceparent` value), so
+//! the downstairs can attach its own spans to the same trace.
+//!
+//! Without [`init_tracing`], spans are disabled and none of this costs more
+//! than a branch.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self as sdktrace, Sampler};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Key for the trace context in a W3C propagation carrier
+const TRACEPARENT: &str = "traceparent";
+
+/// Export spans to the OTLP (gRPC) collector at `endpoint`
+///
+/// `sample_ratio` is the fraction of new traces to record, from 0.0 to 1.0.
+/// Spans with a sampled parent (including a parent in another process) are
+/// always recorded, so a trace is either complete or absent.
+///
+/// This must be called from within a Tokio runtime, which is used to export
+/// batches of spans in the background.
+pub fn init_tracing(
+    service: &str,
+    endpoint: &str,
+    sample_ratio: f64,
+) -> Result<()> {
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        sample_ratio.clamp(0.0, 1.0),
+    )));
+    let resource =
+        Resource::new(vec![KeyValue::new("service.name", service.to_owned())]);
+
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            sdktrace::Config::default()
+                .with_sampler(sampler)
+                .with_resource(resource),
+        )
+        .install_batch(runtime::Tokio)?;
+    let tracer = provider.tracer("crucible");
+
+    // Registered globally so that shutdown_tracing() can flush it
+    opentelemetry::global::set_tracer_provider(provider);
+
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    Ok(())
+}
+
+/// Flush any spans which haven't been exported yet
+///
+/// Spans are exported in batches, so short-lived programs should call this
+/// before exiting.  It blocks until the export is done, so it must not be
+/// called from a current-thread Tokio runtime.
+pub fn shutdown_tracing() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Returns the `traceparent` for `span`, if it is being recorded
+pub fn traceparent(span: &tracing::Span) -> Option<String> {
+    if span.is_disabled() {
+        return None;
+    }
+    let cx = span.context();
+    if !cx.span().span_context().is_sampled() {
+        return None;
+    }
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&cx, &mut carrier);
+    carrier.remove(TRACEPARENT)
+}
+
+/// Make `span` a child of the span described by `traceparent`
+///
+/// This must be called before `span` is first entered.  An invalid
+/// `traceparent` is ignored, leaving `span` as the root of a new trace.
+pub fn set_parent(span: &tracing::Span, traceparent: &str) {
+    let carrier =
+        HashMap::from([(TRACEPARENT.to_owned(), traceparent.to_owned())]);
+    let cx = TraceContextPropagator::new().extract(&carrier);
+    span.set_parent(cx);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_traceparent_without_subscriber() {
+        let span = tracing::info_span!("job");
+        assert_eq!(traceparent(&span), None);
+        set_parent(&span, "not a traceparent");
+    }
+
+    #[test]
+    fn traceparent_round_trip() {
+        let provider = sdktrace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("test")),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let parent = tracing::info_span!("guest_io");
+            let tp = traceparent(&parent).unwrap();
+            assert!(tp.starts_with("00-"), "bad traceparent {tp}");
+
+            // A span in "another process" continues the same trace
+            let child = tracing::info_span!("do_work");
+            set_parent(&child, &tp);
+            let child_tp = traceparent(&child).unwrap();
+            assert_eq!(tp.split('-').nth(1), child_tp.split('-').nth(1));
+            assert_ne!(tp, child_tp);
+        });
+    }
+}
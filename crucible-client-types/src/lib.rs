@@ -31,6 +31,25 @@ pub enum VolumeConstructionRequest {
         opts: CrucibleOpts,
         gen: u64,
     },
+    /// A region set which stores each stripe of `data_fragments` blocks
+    /// across `data_fragments + parity_fragments` downstairs using
+    /// Reed-Solomon coding, rather than mirroring every block.
+    ///
+    /// `opts.target` lists the data fragments first, followed by the parity
+    /// fragments.  Each fragment is a region with the given extent geometry,
+    /// whose last block is reserved for the region set's own state.
+    ErasureCoded {
+        block_size: u64,
+        blocks_per_extent: u64,
+        extent_count: u32,
+        /// Number of data fragments per stripe
+        data_fragments: u8,
+        /// Number of parity fragments per stripe, i.e. how many downstairs
+        /// may be lost without losing data
+        parity_fragments: u8,
+        opts: CrucibleOpts,
+        gen: u64,
+    },
     File {
         id: Uuid,
         block_size: u64,
@@ -44,6 +63,8 @@ pub enum VolumeConstructionRequest {
 )]
 pub struct CrucibleOpts {
     pub id: Uuid,
+    /// Downstairs making up the region set; normally three, but anywhere
+    /// from a two-way mirror up to eight copies is supported
     pub target: Vec<SocketAddr>,
     pub lossy: bool,
     pub flush_timeout: Option<f32>,
@@ -52,7 +73,17 @@ pub struct CrucibleOpts {
     pub key_pem: Option<String>,
     pub root_cert_pem: Option<String>,
     pub control: Option<SocketAddr>,
+    /// Bearer token that must accompany requests to the control server's
+    /// write endpoints; without one, those endpoints are disabled
+    pub control_token: Option<ControlToken>,
     pub read_only: bool,
+    pub client_timeouts: Option<ClientTimeoutOpts>,
+    pub replica: Option<ReplicaOpts>,
+    pub capture: Option<CaptureOpts>,
+    /// File to which lifecycle events are appended, one JSON object per line
+    ///
+    /// The file is renamed with an `.old` suffix once it reaches 64 MiB.
+    pub audit_log: Option<String>,
 }
 
 impl CrucibleOpts {
@@ -74,6 +105,36 @@ impl CrucibleOpts {
     }
 }
 
+/// A control server bearer token, which is kept out of `Debug` output so
+/// that logging the options it's part of doesn't give it away
+#[derive(Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct ControlToken(String);
+
+impl ControlToken {
+    pub fn new(token: String) -> Self {
+        ControlToken(token)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ControlToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ControlToken(<redacted>)")
+    }
+}
+
+impl std::str::FromStr for ControlToken {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ControlToken(s.to_string()))
+    }
+}
+
 /// Display the contents of CrucibleOpts, Only print if keys are populated,
 /// not what the actual contents are.
 impl std::fmt::Display for CrucibleOpts {
@@ -91,11 +152,72 @@ impl std::fmt::Display for CrucibleOpts {
             self.root_cert_pem.is_some()
         )?;
         write!(f, " Control: {:?}, ", self.control)?;
-        write!(f, " read_only: {:?}", self.read_only)?;
+        write!(
+            f,
+            " control_token populated: {}, ",
+            self.control_token.is_some()
+        )?;
+        write!(f, " read_only: {:?},", self.read_only)?;
+        write!(f, " client_timeouts: {:?},", self.client_timeouts)?;
+        write!(f, " replica: {:?},", self.replica)?;
+        write!(f, " capture: {:?},", self.capture)?;
+        write!(f, " audit_log: {:?}", self.audit_log)?;
         Ok(())
     }
 }
 
+/// Configuration for an asynchronous disaster-recovery replica
+///
+/// The replica is a fourth downstairs which receives every write and flush
+/// after the three primary downstairs have completed it.  It is allowed to lag
+/// behind by a bounded amount; if it falls further behind (for example while
+/// reconnecting), it is dropped without affecting guest I/O.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct ReplicaOpts {
+    /// Address of the downstairs serving the replica region
+    pub target: SocketAddr,
+    /// Max number of jobs the replica may lag behind the primaries
+    pub max_lag_jobs: Option<usize>,
+    /// Max number of write bytes the replica may lag behind the primaries
+    pub max_lag_bytes: Option<u64>,
+    /// Name expected in the replica's TLS certificate (default `replica`)
+    pub tls_server_name: Option<String>,
+}
+
+/// Recording of the messages exchanged with each downstairs, for debugging
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct CaptureOpts {
+    /// Directory in which a capture file is created for each connection
+    pub dir: String,
+    /// Replace read and write data with zeros in the capture files
+    pub redact: bool,
+}
+
+/// Tunables for the upstairs' connection to each downstairs
+///
+/// Any value left as `None` falls back to the upstairs' built-in default.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq,
+)]
+pub struct ClientTimeoutOpts {
+    /// How long we wait before logging that we have not heard from a
+    /// downstairs
+    pub timeout_secs: Option<f32>,
+    /// How many consecutive timeouts we tolerate before disconnecting
+    pub timeout_limit: Option<usize>,
+    /// How often we send a `Ruok` ping to each downstairs
+    pub ping_interval_secs: Option<f32>,
+    /// Stretch the timeout based on each downstairs' observed round-trip time
+    pub adaptive: Option<bool>,
+    /// Max number of outstanding jobs before an offline downstairs is faulted
+    pub io_outstanding_max_jobs: Option<usize>,
+    /// Max number of outstanding bytes before an offline downstairs is faulted
+    pub io_outstanding_max_bytes: Option<u64>,
+}
+
 #[derive(Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ReplaceResult {
@@ -0,0 +1,509 @@
+// Copyright 2024 Oxide Computer Company
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde_json::json;
+
+use crucible_common::BlockIndex;
+use crucible_protocol::capture::{CaptureReader, CaptureRecord, Direction};
+use crucible_protocol::{JobId, Message, MessageDiscriminants};
+
+/// Decode a Crucible protocol capture file
+#[derive(Parser, Debug)]
+#[clap(name = "crudump", term_width = 80)]
+#[clap(about = "Crucible protocol capture decoder", long_about = None)]
+struct Args {
+    /// Capture file, from the upstairs or `crucible-downstairs run --capture`
+    #[clap(value_name = "FILE", action)]
+    capture: PathBuf,
+
+    /// Only show messages of this type (e.g. `Write`, `ReadResponse`).  May
+    /// be given more than once.
+    #[clap(short = 't', long = "type", value_name = "TYPE", action)]
+    types: Vec<String>,
+
+    /// Only show messages touching this extent.  Flushes touch every extent.
+    #[clap(short, long, value_name = "EXTENT", action)]
+    extent: Option<u32>,
+
+    /// Blocks per extent, used to find the extents of reads and writes.
+    /// Taken from the capture's `RegionInfo` if not given.
+    #[clap(long, value_name = "BLOCKS", action)]
+    extent_size: Option<u64>,
+
+    /// Show one line per job, from request to ack, instead of each message
+    #[clap(short, long, action)]
+    jobs: bool,
+
+    /// Print one JSON object per line
+    #[clap(long, action)]
+    json: bool,
+
+    /// Include read and write data, as hex, in JSON output
+    #[clap(long, requires = "json", action)]
+    payload: bool,
+}
+
+/// The extents that a message touches
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Extents {
+    /// Not tied to an extent, or we don't know the extent size
+    Unknown,
+    Range {
+        first: u32,
+        last: u32,
+    },
+    All,
+}
+
+impl Extents {
+    fn one(e: u32) -> Self {
+        Extents::Range { first: e, last: e }
+    }
+
+    fn contains(&self, e: u32) -> bool {
+        match self {
+            Extents::Unknown => false,
+            Extents::Range { first, last } => (*first..=*last).contains(&e),
+            Extents::All => true,
+        }
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        match self {
+            Extents::Unknown => serde_json::Value::Null,
+            Extents::Range { first, last } => json!([first, last]),
+            Extents::All => json!("all"),
+        }
+    }
+}
+
+impl fmt::Display for Extents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Extents::Unknown => write!(f, "-"),
+            Extents::Range { first, last } if first == last => {
+                write!(f, "{first}")
+            }
+            Extents::Range { first, last } => write!(f, "{first}-{last}"),
+            Extents::All => write!(f, "all"),
+        }
+    }
+}
+
+/// Returns the job ID of an IO or live repair message
+fn job_id(m: &Message) -> Option<JobId> {
+    match m {
+        Message::Write { header, .. }
+        | Message::WriteUnwritten { header, .. } => Some(header.job_id),
+        Message::ReadResponse { header, .. } => Some(header.job_id),
+        Message::ExtentLiveClose { job_id, .. }
+        | Message::ExtentLiveFlushClose { job_id, .. }
+        | Message::ExtentLiveRepair { job_id, .. }
+        | Message::ExtentLiveReopen { job_id, .. }
+        | Message::ExtentLiveNoOp { job_id, .. }
+        | Message::ExtentLiveCloseAck { job_id, .. }
+        | Message::ExtentLiveRepairAckId { job_id, .. }
+        | Message::ExtentLiveAckId { job_id, .. }
+        | Message::WriteAck { job_id, .. }
+        | Message::Flush { job_id, .. }
+        | Message::FlushAck { job_id, .. }
+        | Message::ReadRequest { job_id, .. }
+        | Message::WriteUnwrittenAck { job_id, .. }
+        | Message::ErrorReport { job_id, .. }
+        | Message::TraceContext { job_id, .. } => Some(*job_id),
+        _ => None,
+    }
+}
+
+/// Is this a job sent by the upstairs?
+fn is_request(m: &Message) -> bool {
+    matches!(
+        m,
+        Message::Write { .. }
+            | Message::WriteUnwritten { .. }
+            | Message::Flush { .. }
+            | Message::ReadRequest { .. }
+            | Message::ExtentLiveClose { .. }
+            | Message::ExtentLiveFlushClose { .. }
+            | Message::ExtentLiveRepair { .. }
+            | Message::ExtentLiveReopen { .. }
+            | Message::ExtentLiveNoOp { .. }
+    )
+}
+
+/// Is this the downstairs' answer to a job?
+fn is_ack(m: &Message) -> bool {
+    matches!(
+        m,
+        Message::WriteAck { .. }
+            | Message::WriteUnwrittenAck { .. }
+            | Message::FlushAck { .. }
+            | Message::ReadResponse { .. }
+            | Message::ExtentLiveCloseAck { .. }
+            | Message::ExtentLiveRepairAckId { .. }
+            | Message::ExtentLiveAckId { .. }
+    )
+}
+
+fn payload(m: &Message) -> Option<&[u8]> {
+    match m {
+        Message::Write { data, .. } | Message::WriteUnwritten { data, .. } => {
+            Some(&data[..])
+        }
+        Message::ReadResponse { data, .. } => Some(&data[..]),
+        _ => None,
+    }
+}
+
+fn nanos(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// One job, from the upstairs' request to the downstairs' ack
+struct Job {
+    kind: MessageDiscriminants,
+    extents: Extents,
+    request: SystemTime,
+    ack: Option<SystemTime>,
+    error: Option<String>,
+}
+
+/// Walks through a capture, working out what each message refers to
+#[derive(Default)]
+struct ExtentTracker {
+    extent_size: Option<u64>,
+
+    /// Extents of each job seen so far, so that acks can be matched up
+    job_extents: HashMap<JobId, Extents>,
+}
+
+impl ExtentTracker {
+    fn new(extent_size: Option<u64>) -> Self {
+        ExtentTracker {
+            extent_size,
+            ..Default::default()
+        }
+    }
+
+    fn blocks(&self, start: BlockIndex, count: u64) -> Extents {
+        match self.extent_size {
+            Some(size) if size > 0 && count > 0 => Extents::Range {
+                first: (start.0 / size) as u32,
+                last: ((start.0 + count - 1) / size) as u32,
+            },
+            _ => Extents::Unknown,
+        }
+    }
+
+    /// Returns the extents that `m` touches
+    fn extents(&mut self, m: &Message) -> Extents {
+        let e = match m {
+            Message::RegionInfo { region_def } => {
+                self.extent_size
+                    .get_or_insert(region_def.extent_size().value);
+                Extents::Unknown
+            }
+            Message::ExtentClose { extent_id, .. }
+            | Message::ExtentReopen { extent_id, .. }
+            | Message::ExtentFlush { extent_id, .. }
+            | Message::ExtentRepair { extent_id, .. }
+            | Message::ExtentError { extent_id, .. }
+            | Message::ExtentLiveClose { extent_id, .. }
+            | Message::ExtentLiveFlushClose { extent_id, .. }
+            | Message::ExtentLiveRepair { extent_id, .. }
+            | Message::ExtentLiveReopen { extent_id, .. } => {
+                Extents::one(extent_id.0)
+            }
+            Message::Write { header, .. }
+            | Message::WriteUnwritten { header, .. } => {
+                self.blocks(header.start, header.contexts.len() as u64)
+            }
+            Message::ReadRequest { start, count, .. } => {
+                self.blocks(*start, *count)
+            }
+            Message::Flush { .. } => Extents::All,
+            m => job_id(m)
+                .and_then(|j| self.job_extents.get(&j).copied())
+                .unwrap_or(Extents::Unknown),
+        };
+        if is_request(m) {
+            if let Some(j) = job_id(m) {
+                self.job_extents.insert(j, e);
+            }
+        }
+        e
+    }
+}
+
+/// Decides which messages (or jobs) to show
+struct Filter {
+    types: Vec<String>,
+    extent: Option<u32>,
+}
+
+impl Filter {
+    fn matches(&self, kind: MessageDiscriminants, extents: Extents) -> bool {
+        let name = format!("{kind:?}");
+        (self.types.is_empty()
+            || self.types.iter().any(|t| t.eq_ignore_ascii_case(&name)))
+            && self.extent.map_or(true, |e| extents.contains(e))
+    }
+}
+
+/// Collects each job's request and ack into a timeline
+fn timeline(
+    records: &[CaptureRecord],
+    extent_size: Option<u64>,
+) -> BTreeMap<JobId, Job> {
+    let mut tracker = ExtentTracker::new(extent_size);
+    let mut jobs: BTreeMap<JobId, Job> = BTreeMap::new();
+    for r in records {
+        let extents = tracker.extents(&r.message);
+        let Some(id) = job_id(&r.message) else {
+            continue;
+        };
+        if is_request(&r.message) {
+            jobs.entry(id).or_insert(Job {
+                kind: MessageDiscriminants::from(&r.message),
+                extents,
+                request: r.time,
+                ack: None,
+                error: None,
+            });
+        } else if is_ack(&r.message) {
+            if let Some(job) = jobs.get_mut(&id) {
+                if job.ack.is_none() {
+                    job.ack = Some(r.time);
+                    job.error = r.message.err().map(|e| e.to_string());
+                }
+            }
+        }
+    }
+    jobs
+}
+
+fn print_jobs(jobs: &BTreeMap<JobId, Job>, filter: &Filter, as_json: bool) {
+    for (id, job) in jobs {
+        if !filter.matches(job.kind, job.extents) {
+            continue;
+        }
+        let latency = job
+            .ack
+            .map(|a| a.duration_since(job.request).unwrap_or_default());
+        if as_json {
+            let v = json!({
+                "job_id": id.0,
+                "type": format!("{:?}", job.kind),
+                "extents": job.extents.to_json(),
+                "request_ns": nanos(job.request),
+                "ack_ns": job.ack.map(nanos),
+                "latency_us": latency.map(|d| d.as_micros() as u64),
+                "error": job.error,
+            });
+            println!("{v}");
+        } else {
+            let latency = match latency {
+                Some(d) => format!("{:.3}ms", d.as_secs_f64() * 1000.0),
+                None => "pending".to_string(),
+            };
+            println!(
+                "{:>8} {:<20} extents {:<8} {:>12} {}",
+                id.0,
+                format!("{:?}", job.kind),
+                job.extents.to_string(),
+                latency,
+                job.error.as_deref().unwrap_or(""),
+            );
+        }
+    }
+}
+
+fn print_messages(
+    records: &[CaptureRecord],
+    extent_size: Option<u64>,
+    filter: &Filter,
+    as_json: bool,
+    with_payload: bool,
+) {
+    let start = records.first().map(|r| r.time).unwrap_or(UNIX_EPOCH);
+    let mut tracker = ExtentTracker::new(extent_size);
+    for r in records {
+        let kind = MessageDiscriminants::from(&r.message);
+        let extents = tracker.extents(&r.message);
+        if !filter.matches(kind, extents) {
+            continue;
+        }
+        let data = payload(&r.message);
+        if as_json {
+            let mut v = json!({
+                "time_ns": nanos(r.time),
+                "direction": r.direction,
+                "type": format!("{kind:?}"),
+                "job_id": job_id(&r.message).map(|j| j.0),
+                "extents": extents.to_json(),
+                "data_len": data.map(|d| d.len()),
+                "message": r.message.to_string(),
+            });
+            if with_payload {
+                v["data"] = json!(data.map(hex::encode));
+            }
+            println!("{v}");
+        } else {
+            let offset: Duration =
+                r.time.duration_since(start).unwrap_or_default();
+            let arrow = match r.direction {
+                Direction::Sent => "->",
+                Direction::Received => "<-",
+            };
+            println!(
+                "+{:>12.6} {arrow} {:<8} {}",
+                offset.as_secs_f64(),
+                extents.to_string(),
+                r.message
+            );
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let file = File::open(&args.capture)
+        .with_context(|| format!("could not open {:?}", args.capture))?;
+    let mut records = vec![];
+    for r in CaptureReader::new(BufReader::new(file))? {
+        match r {
+            Ok(r) => records.push(r),
+            Err(e) => {
+                // The writer may have been stopped mid-record
+                eprintln!("stopping at bad record: {e:#}");
+                break;
+            }
+        }
+    }
+
+    let filter = Filter {
+        types: args.types,
+        extent: args.extent,
+    };
+    if args.jobs {
+        let jobs = timeline(&records, args.extent_size);
+        print_jobs(&jobs, &filter, args.json);
+    } else {
+        print_messages(
+            &records,
+            args.extent_size,
+            &filter,
+            args.json,
+            args.payload,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crucible_protocol::{ReadResponseHeader, WriteHeader};
+    use uuid::Uuid;
+
+    fn record(
+        ms: u64,
+        direction: Direction,
+        message: Message,
+    ) -> CaptureRecord {
+        CaptureRecord {
+            time: UNIX_EPOCH + Duration::from_millis(ms),
+            direction,
+            message,
+        }
+    }
+
+    #[test]
+    fn timeline_matches_requests_and_acks() {
+        let upstairs_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+        let records = vec![
+            record(
+                0,
+                Direction::Received,
+                Message::Write {
+                    header: WriteHeader {
+                        upstairs_id,
+                        session_id,
+                        job_id: JobId(1000),
+                        dependencies: vec![],
+                        start: BlockIndex(15),
+                        contexts: vec![],
+                    },
+                    data: vec![].into(),
+                },
+            ),
+            record(
+                1,
+                Direction::Received,
+                Message::ReadRequest {
+                    upstairs_id,
+                    session_id,
+                    job_id: JobId(1001),
+                    dependencies: vec![],
+                    start: BlockIndex(25),
+                    count: 10,
+                },
+            ),
+            record(
+                5,
+                Direction::Sent,
+                Message::ReadResponse {
+                    header: ReadResponseHeader {
+                        upstairs_id,
+                        session_id,
+                        job_id: JobId(1001),
+                        blocks: Ok(vec![]),
+                    },
+                    data: Default::default(),
+                },
+            ),
+            record(
+                7,
+                Direction::Sent,
+                Message::WriteAck {
+                    upstairs_id,
+                    session_id,
+                    job_id: JobId(1000),
+                    result: Ok(()),
+                },
+            ),
+        ];
+
+        let jobs = timeline(&records, Some(10));
+        assert_eq!(jobs.len(), 2);
+
+        // An empty write has no extents
+        let write = &jobs[&JobId(1000)];
+        assert_eq!(write.kind, MessageDiscriminants::Write);
+        assert_eq!(write.extents, Extents::Unknown);
+        assert_eq!(
+            write.ack.unwrap().duration_since(write.request).unwrap(),
+            Duration::from_millis(7)
+        );
+
+        let read = &jobs[&JobId(1001)];
+        assert_eq!(read.extents, Extents::Range { first: 2, last: 3 });
+        assert!(read.ack.is_some());
+
+        let filter = Filter {
+            types: vec!["readrequest".to_string()],
+            extent: Some(3),
+        };
+        assert!(filter.matches(read.kind, read.extents));
+        assert!(!filter.matches(write.kind, read.extents));
+    }
+}
@@ -160,6 +160,10 @@ enum Workload {
 #[clap(name = "client", term_width = 80)]
 #[clap(about = "A Crucible upstairs test client", long_about = None)]
 pub struct Opt {
+    /// File to append upstairs lifecycle events to, as JSON lines
+    #[clap(long, global = true, action)]
+    audit_log: Option<String>,
+
     // TLS options
     #[clap(long, action)]
     cert_pem: Option<String>,
@@ -172,6 +176,16 @@ pub struct Opt {
     #[clap(long, global = true, action)]
     control: Option<SocketAddr>,
 
+    /// Bearer token to require on the control server's write endpoints
+    #[clap(
+        long,
+        global = true,
+        env = "CRUCIBLE_CONTROL_TOKEN",
+        requires = "control",
+        action
+    )]
+    control_token: Option<ControlToken>,
+
     /// For tests that support it, pass this count value for the number
     /// of loops the test should do.
     #[clap(short, long, global = true, action)]
@@ -733,7 +747,12 @@ async fn main() -> Result<()> {
         key_pem: opt.key_pem,
         root_cert_pem: opt.root_cert_pem,
         control: opt.control,
+        control_token: opt.control_token,
         read_only: opt.read_only,
+        client_timeouts: None,
+        replica: None,
+        capture: None,
+        audit_log: opt.audit_log,
     };
 
     /*
@@ -27,6 +27,9 @@ pub struct RunDownstairsForRegionParams {
     cert_pem: Option<String>,
     key_pem: Option<String>,
     root_cert_pem: Option<String>,
+    repair_server_name: Option<String>,
+    #[serde(default)]
+    repair_tls_only: bool,
     read_only: bool,
 }
 
@@ -69,6 +72,8 @@ pub async fn run_downstairs_for_region(
             run_params.write_errors,
             run_params.flush_errors,
         )
+        .set_repair_server_name(run_params.repair_server_name)
+        .set_repair_tls_only(run_params.repair_tls_only)
         .build()
         .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
 
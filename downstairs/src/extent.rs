@@ -60,10 +60,8 @@ pub(crate) trait ExtentInner: Send + Sync + Debug {
 
     /// Reads zero or one context slots for each block in the given range
     ///
-    /// # Panics
-    /// If an extent implementation cannot get block contexts separately from a
-    /// read, this is allowed to panic.
-    #[cfg(test)]
+    /// Region verification uses this to check both the block hash and the
+    /// on-disk hash, so every extent implementation must support it.
     fn get_block_contexts(
         &mut self,
         block: u64,
@@ -278,6 +276,29 @@ pub fn remove_copy_cleanup_dir<P: AsRef<Path>>(
     Ok(())
 }
 
+/**
+ * Remove any files belonging to extent "eid" under "dir".  This is only
+ * used for extents which are past the end of the region, i.e. left over
+ * from a resize that was interrupted before it was recorded.
+ */
+pub fn remove_extent_files<P: AsRef<Path>>(
+    dir: P,
+    eid: ExtentId,
+) -> Result<()> {
+    for t in [
+        ExtentType::Data,
+        ExtentType::Db,
+        ExtentType::DbShm,
+        ExtentType::DbWal,
+    ] {
+        let path = extent_dir(&dir, eid).join(extent_file_name(eid, t));
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    remove_copy_cleanup_dir(dir, eid)
+}
+
 impl Extent {
     fn get_iov_max() -> Result<usize> {
         let i: i64 = sysconf(SysconfVar::IOV_MAX)?
@@ -630,11 +651,6 @@ impl Extent {
     }
 
     /// Gets zero or one block contexts for each block in the given range
-    ///
-    /// # Panics
-    /// If the inner extent implementation does not support getting block
-    /// contexts separately from a read operation
-    #[cfg(test)]
     pub fn get_block_contexts(
         &mut self,
         block: u64,
@@ -823,6 +839,27 @@ pub fn sync_path<P: AsRef<Path> + std::fmt::Debug>(
     Ok(())
 }
 
+/**
+ * Return the hex SHA-256 digest of a file's contents.  Used to check that
+ * extent files copied for repair arrived intact.
+ */
+pub fn file_sha256<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
 /// Verify that the requested block offset and size of the buffer
 /// will fit within the extent.
 pub(crate) fn check_input(
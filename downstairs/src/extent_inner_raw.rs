@@ -461,7 +461,6 @@ impl ExtentInner for RawInner {
         Ok(())
     }
 
-    #[cfg(test)]
     fn get_block_contexts(
         &mut self,
         block: u64,
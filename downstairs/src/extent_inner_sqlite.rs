@@ -69,7 +69,6 @@ impl ExtentInner for SqliteInner {
         )
     }
 
-    #[cfg(test)]
     fn get_block_contexts(
         &mut self,
         block: u64,
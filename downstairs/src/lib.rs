@@ -14,16 +14,18 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crucible_common::{
-    build_logger, integrity_hash, mkdir_for_file, verbose_timeout, Block,
+    audit::{AuditEvent, AuditLog, AuditSession, AuditSource},
+    build_logger, integrity_hash, mkdir_for_file, otel, verbose_timeout, Block,
     BlockIndex, BlockOffset, CrucibleError, ExtentId, RegionDefinition,
     MAX_BLOCK_SIZE,
 };
 use crucible_protocol::{
-    BlockContext, CrucibleDecoder, JobId, Message, MessageWriter,
-    ReadBlockContext, ReconciliationId, SnapshotDetails,
-    CRUCIBLE_MESSAGE_VERSION,
+    capture::CaptureConfig, negotiate_version, BlockContext, Capabilities,
+    Compression, ConnectionVersion, CrucibleDecoder, JobId, Message,
+    MessageWriter, ReadBlockContext, ReconciliationId, SnapshotDetails,
+    CRUCIBLE_CAPABILITIES, CRUCIBLE_MESSAGE_VERSION,
+    SUPPORTED_MESSAGE_VERSIONS,
 };
-use repair_client::Client;
 
 use anyhow::{bail, Context, Result};
 use bytes::BytesMut;
@@ -34,6 +36,7 @@ use tokio::net::TcpListener;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
 use tokio_util::codec::FramedRead;
+use tracing::Instrument;
 use uuid::Uuid;
 
 pub mod admin;
@@ -43,6 +46,7 @@ mod extent;
 pub mod region;
 pub mod repair;
 mod stats;
+mod verify;
 
 mod extent_inner_raw;
 pub(crate) mod extent_inner_raw_common;
@@ -50,11 +54,16 @@ mod extent_inner_sqlite;
 
 use extent::ExtentState;
 use region::Region;
+use repair::RepairClient;
 
 pub use admin::run_dropshot;
 pub use dump::dump_region;
 pub use dynamometer::*;
-pub use stats::{DsCountStat, DsStatOuter};
+pub use stats::{
+    DsCountStat, DsStatOuter, LatencyHistogram, RepairTransfer,
+    RepairTransferStats, UpstairsConnectionStats,
+};
+pub use verify::{verify_region, ExtentVerify};
 
 /// Single IO operation
 ///
@@ -429,6 +438,11 @@ impl RegionWrite {
     fn iter(&self) -> impl Iterator<Item = &RegionWriteReq> {
         self.0.iter()
     }
+
+    /// Returns the total number of data bytes carried by this write
+    fn data_len(&self) -> usize {
+        self.0.iter().map(|w| w.write.data.len()).sum()
+    }
 }
 
 impl IntoIterator for RegionWrite {
@@ -854,22 +868,48 @@ async fn proc_stream(
     handle: DownstairsHandle,
     id: ConnectionId,
     stream: WrappedStream,
+    capture: Option<&CaptureConfig>,
     log: &Logger,
 ) -> Result<()> {
+    let capture =
+        capture.and_then(|c| match c.create(&format!("conn{}", id.0)) {
+            Ok(c) => Some(Arc::new(c)),
+            Err(e) => {
+                warn!(log, "could not create capture file in {:?}: {e}", c.dir);
+                None
+            }
+        });
+
     match stream {
         WrappedStream::Http(sock) => {
             let (read, write) = sock.into_split();
 
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = MessageWriter::new(write);
+            let version = ConnectionVersion::new();
+            let mut fr = FramedRead::new(
+                read,
+                CrucibleDecoder::with_version(version.clone()),
+            );
+            let mut fw = MessageWriter::with_version(write, version);
+            if let Some(c) = capture {
+                fr.decoder_mut().set_capture(c.clone());
+                fw.set_capture(c);
+            }
 
             proc(handle, id, fr, fw, log).await
         }
         WrappedStream::Https(stream) => {
             let (read, write) = tokio::io::split(stream);
 
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = MessageWriter::new(write);
+            let version = ConnectionVersion::new();
+            let mut fr = FramedRead::new(
+                read,
+                CrucibleDecoder::with_version(version.clone()),
+            );
+            let mut fw = MessageWriter::with_version(write, version);
+            if let Some(c) = capture {
+                fr.decoder_mut().set_capture(c.clone());
+                fw.set_capture(c);
+            }
 
             proc(handle, id, fr, fw, log).await
         }
@@ -911,6 +951,11 @@ struct ConnectionData {
 
     /// IO channel to the reply task
     reply_channel_tx: mpsc::UnboundedSender<Message>,
+
+    /// Optional features supported by both us and the upstairs
+    ///
+    /// This is empty until `HereIAm` is received
+    capabilities: Capabilities,
 }
 
 impl ConnectionData {
@@ -923,6 +968,7 @@ impl ConnectionData {
             ),
             cancel: tokio_util::sync::CancellationToken::new().drop_guard(),
             reply_channel_tx: mpsc::unbounded_channel().0,
+            capabilities: Capabilities::empty(),
         }
     }
 }
@@ -1057,19 +1103,19 @@ impl ActiveConnection {
         &mut self,
         m: Message,
         flags: &DownstairsFlags,
-        reqwest_client: &reqwest::Client,
+        repair_client: &RepairClient,
         dss: &mut DsStatOuter,
         region: &mut Region,
     ) -> Result<()> {
         match self
-            .proc_frame(m, flags, reqwest_client, dss, region)
+            .proc_frame(m, flags, repair_client, dss, region)
             .await
             .context("proc_frame")?
         {
             WorkResult::Queued => Ok(()),
             WorkResult::Handled => {
                 // This work may have unblocked future work
-                self.do_ready_work(flags, reqwest_client, dss, region)
+                self.do_ready_work(flags, repair_client, dss, region)
                     .await
                     .context("do_work_for")
             }
@@ -1084,7 +1130,7 @@ impl ActiveConnection {
         &mut self,
         m: Message,
         flags: &DownstairsFlags,
-        reqwest_client: &reqwest::Client,
+        repair_client: &RepairClient,
         dss: &mut DsStatOuter,
         region: &mut Region,
     ) -> Result<WorkResult> {
@@ -1131,7 +1177,7 @@ impl ActiveConnection {
                     header.job_id,
                     new_write,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1160,7 +1206,7 @@ impl ActiveConnection {
                     job_id,
                     new_flush,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1183,7 +1229,7 @@ impl ActiveConnection {
                     header.job_id,
                     new_write,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1210,7 +1256,7 @@ impl ActiveConnection {
                     job_id,
                     new_read,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1233,7 +1279,7 @@ impl ActiveConnection {
                     job_id,
                     ext_close,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1259,7 +1305,7 @@ impl ActiveConnection {
                     job_id,
                     new_flush,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1285,7 +1331,7 @@ impl ActiveConnection {
                     job_id,
                     new_repair,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1306,7 +1352,7 @@ impl ActiveConnection {
                     job_id,
                     new_open,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1324,7 +1370,7 @@ impl ActiveConnection {
                     job_id,
                     new_noop,
                     flags,
-                    reqwest_client,
+                    repair_client,
                     dss,
                     region,
                 )
@@ -1406,10 +1452,11 @@ impl ActiveConnection {
                     );
                     match region
                         .repair_extent(
-                            reqwest_client.clone(),
+                            repair_client,
                             extent_id,
                             source_repair_address,
                             false,
+                            dss,
                         )
                         .await
                     {
@@ -1449,6 +1496,14 @@ impl ActiveConnection {
                 self.reply(Message::Imok)?;
                 WorkResult::Handled
             }
+            Message::TraceContext {
+                job_id,
+                traceparent,
+            } => {
+                // Held for the job that follows; there's no new work to do yet
+                self.work.next_trace_parent = Some((job_id, traceparent));
+                WorkResult::Queued
+            }
             x => bail!("unexpected frame {:?}", x),
         };
         Ok(r)
@@ -1458,7 +1513,7 @@ impl ActiveConnection {
     async fn do_ready_work(
         &mut self,
         flags: &DownstairsFlags,
-        reqwest_client: &reqwest::Client,
+        repair_client: &RepairClient,
         dss: &mut DsStatOuter,
         region: &mut Region,
     ) -> Result<()> {
@@ -1491,12 +1546,13 @@ impl ActiveConnection {
             };
 
             if let Some(failed) = self
-                .do_work(new_id, dw, flags, reqwest_client, dss, region)
+                .do_work(new_id, dw, flags, repair_client, dss, region)
                 .await?
             {
                 new_work.push_back(failed);
             }
         }
+        dss.set_queue_depth(&self.upstairs_connection, self.work.jobs());
         Ok(())
     }
 
@@ -1505,24 +1561,27 @@ impl ActiveConnection {
         ds_id: JobId,
         mut job: IOop,
         flags: &DownstairsFlags,
-        reqwest_client: &reqwest::Client,
+        repair_client: &RepairClient,
         dss: &mut DsStatOuter,
         region: &mut Region,
     ) -> Result<WorkResult> {
+        self.work.on_received(ds_id);
         if self.work.check_ready(ds_id, &mut job) {
             cdt::work__start!(|| ds_id.0);
             let mut prev = self
-                .do_work(ds_id, job, flags, reqwest_client, dss, region)
+                .do_work(ds_id, job, flags, repair_client, dss, region)
                 .await?;
             while let Some(ds_id) = prev.take() {
                 let job = self.work.in_progress(ds_id).unwrap();
                 prev = self
-                    .do_work(ds_id, job, flags, reqwest_client, dss, region)
+                    .do_work(ds_id, job, flags, repair_client, dss, region)
                     .await?;
             }
+            dss.set_queue_depth(&self.upstairs_connection, self.work.jobs());
             Ok(WorkResult::Handled)
         } else {
             self.work.add_work(ds_id, job);
+            dss.set_queue_depth(&self.upstairs_connection, self.work.jobs());
             Ok(WorkResult::Queued)
         }
     }
@@ -1544,16 +1603,23 @@ impl ActiveConnection {
         new_id: JobId,
         job: IOop,
         flags: &DownstairsFlags,
-        reqwest_client: &reqwest::Client,
+        repair_client: &RepairClient,
         dss: &mut DsStatOuter,
         region: &mut Region,
     ) -> Result<Option<JobId>> {
         let upstairs_connection = self.upstairs_connection;
 
         cdt::work__process!(|| new_id.0);
+        let span = tracing::info_span!("do_work", job_id = new_id.0);
+        if let Some(traceparent) = self.work.trace_parents.get(&new_id) {
+            otel::set_parent(&span, traceparent);
+        }
+        let start = Instant::now();
         let m = self
-            .do_work_inner(new_id, &job, flags, reqwest_client, region)
+            .do_work_inner(new_id, &job, flags, repair_client, dss, region)
+            .instrument(span)
             .await;
+        let latency = start.elapsed();
 
         if let Some(error) = m.err() {
             self.reply(Message::ErrorReport {
@@ -1583,7 +1649,24 @@ impl ActiveConnection {
             Ok(Some(new_id))
         } else {
             // The job completed successfully, so update our stats
-            dss.on_complete(&m);
+            let bytes_written = match &job {
+                IOop::Write { writes, .. }
+                | IOop::WriteUnwritten { writes, .. } => writes.data_len(),
+                _ => 0,
+            };
+            let queued = self
+                .work
+                .received
+                .remove(&new_id)
+                .map(|r| start.saturating_duration_since(r));
+            self.work.trace_parents.remove(&new_id);
+            dss.on_complete(
+                &upstairs_connection,
+                &m,
+                bytes_written,
+                queued,
+                latency,
+            );
 
             // Notify the upstairs before completing work, which
             // consumes the message (so we'll check whether it's
@@ -1617,7 +1700,8 @@ impl ActiveConnection {
         job_id: JobId,
         work: &IOop,
         flags: &DownstairsFlags,
-        reqwest_client: &reqwest::Client,
+        repair_client: &RepairClient,
+        dss: &mut DsStatOuter,
         region: &mut Region,
     ) -> Message {
         let upstairs_connection = self.upstairs_connection;
@@ -1811,10 +1895,11 @@ impl ActiveConnection {
                 );
                 let result = region
                     .repair_extent(
-                        reqwest_client.clone(),
+                        repair_client,
                         *extent,
                         *source_repair_address,
                         false,
+                        dss,
                     )
                     .await;
                 debug!(
@@ -2140,6 +2225,11 @@ pub struct DownstairsBuilder {
     write_errors: Option<bool>, // Test flag
     flush_errors: Option<bool>, // Test flag
     log: Option<Logger>,
+    capture: Option<CaptureConfig>,
+    compression: Option<Compression>,
+    audit_log: Option<PathBuf>,
+    repair_server_name: Option<String>,
+    repair_tls_only: bool,
 }
 
 impl DownstairsBuilder {
@@ -2151,6 +2241,11 @@ impl DownstairsBuilder {
             write_errors: Some(false),
             flush_errors: Some(false),
             log: None,
+            capture: None,
+            compression: None,
+            audit_log: None,
+            repair_server_name: None,
+            repair_tls_only: false,
         }
     }
 
@@ -2173,6 +2268,38 @@ impl DownstairsBuilder {
         self.log = Some(log);
         self
     }
+    /// Record each upstairs connection's messages to a capture file
+    pub fn set_capture(mut self, capture: Option<CaptureConfig>) -> Self {
+        self.capture = capture;
+        self
+    }
+    /// Offer to compress bulk data on upstairs connections
+    pub fn set_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+    /// Append lifecycle events to the given file, as well as keeping them in
+    /// memory
+    pub fn set_audit_log(mut self, audit_log: Option<PathBuf>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+    /// The name that other downstairs' repair servers have certificates
+    /// for, checked when repairing over TLS
+    pub fn set_repair_server_name(
+        mut self,
+        repair_server_name: Option<String>,
+    ) -> Self {
+        self.repair_server_name = repair_server_name;
+        self
+    }
+    /// Refuse repair connections that don't use TLS, in both directions,
+    /// rather than falling back to plain HTTP for downstairs whose repair
+    /// server predates TLS
+    pub fn set_repair_tls_only(mut self, repair_tls_only: bool) -> Self {
+        self.repair_tls_only = repair_tls_only;
+        self
+    }
 
     pub fn build(self) -> Result<Downstairs> {
         let lossy = self.lossy.unwrap_or(false);
@@ -2195,6 +2322,12 @@ impl DownstairsBuilder {
 
         let encrypted = region.encrypted();
 
+        let audit = match &self.audit_log {
+            Some(path) => AuditLog::open(AuditSource::Downstairs, path)
+                .with_context(|| format!("opening audit log {path:?}"))?,
+            None => AuditLog::new(AuditSource::Downstairs),
+        };
+
         let dss = DsStatOuter {
             ds_stat_wrap: Arc::new(std::sync::Mutex::new(DsCountStat::new(
                 region.def().uuid(),
@@ -2220,6 +2353,7 @@ impl DownstairsBuilder {
                 flush_errors,
                 read_only,
                 encrypted,
+                compression: self.compression,
             },
             active_upstairs: HashMap::new(),
             connection_state: HashMap::new(),
@@ -2229,11 +2363,11 @@ impl DownstairsBuilder {
             log,
             request_tx,
             request_rx,
-            reqwest_client: reqwest::ClientBuilder::new()
-                .connect_timeout(std::time::Duration::from_secs(15))
-                .timeout(std::time::Duration::from_secs(15))
-                .build()
-                .unwrap(),
+            repair_client: RepairClient::new(None, None, false)?,
+            repair_server_name: self.repair_server_name,
+            repair_tls_only: self.repair_tls_only,
+            capture: self.capture,
+            audit: Arc::new(audit),
         })
     }
 }
@@ -2247,6 +2381,23 @@ struct DownstairsFlags {
     flush_errors: bool, // Test flag
     read_only: bool,
     encrypted: bool,
+
+    /// Compression to offer each upstairs, if any
+    compression: Option<Compression>,
+}
+
+impl DownstairsFlags {
+    /// Returns the capabilities that we offer to an upstairs
+    ///
+    /// Compression is only offered if it was configured, so whether (and
+    /// how) a connection is compressed is up to the downstairs.
+    fn capabilities(&self) -> Capabilities {
+        let c = CRUCIBLE_CAPABILITIES.difference(Capabilities::COMPRESSION);
+        match self.compression {
+            Some(z) => c.union(z.capability()),
+            None => c,
+        }
+    }
 }
 
 /*
@@ -2275,8 +2426,23 @@ pub struct Downstairs {
     request_tx: mpsc::UnboundedSender<DownstairsRequest>,
     request_rx: mpsc::UnboundedReceiver<DownstairsRequest>,
 
-    // A reqwest client, to be reused when creating progenitor clients
-    pub reqwest_client: reqwest::Client,
+    // A client for other downstairs' repair servers, to be reused when
+    // creating progenitor clients
+    pub repair_client: RepairClient,
+
+    /// The name other downstairs' repair servers have certificates for
+    repair_server_name: Option<String>,
+
+    /// Whether repair connections must use TLS, when we have certificates
+    repair_tls_only: bool,
+
+    /// Where to record each connection's messages, if anywhere
+    capture: Option<CaptureConfig>,
+
+    /// Where lifecycle events are recorded
+    ///
+    /// Shared with the repair server, which serves the recent ones.
+    audit: Arc<AuditLog>,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -2292,6 +2458,11 @@ impl Downstairs {
             write_errors: Some(false),
             flush_errors: Some(false),
             log: None,
+            capture: None,
+            compression: None,
+            audit_log: None,
+            repair_server_name: None,
+            repair_tls_only: false,
         }
     }
 
@@ -2359,6 +2530,7 @@ impl Downstairs {
         let log = self.log.new(o!(
            "upstairs_id" => upstairs_connection.upstairs_id.to_string()
         ));
+        self.dss.add_upstairs(&upstairs_connection);
         self.connection_state.insert(
             conn_id,
             ConnectionState::Running(ActiveConnection {
@@ -2402,6 +2574,7 @@ impl Downstairs {
                 .insert(upstairs_connection.upstairs_id, conn_id);
             assert!(prev.is_none());
 
+            self.audit(upstairs_connection, AuditEvent::Promoted);
             Ok(())
         } else {
             // Only one active read-write session is allowed. Kick out the
@@ -2425,6 +2598,7 @@ impl Downstairs {
                         "{:?} is now active (read-write)", upstairs_connection,
                     );
 
+                    self.audit(upstairs_connection, AuditEvent::Promoted);
                     Ok(())
                 }
 
@@ -2520,6 +2694,7 @@ impl Downstairs {
                         "{:?} is now active (read-write)", upstairs_connection,
                     );
 
+                    self.audit(upstairs_connection, AuditEvent::Promoted);
                     Ok(())
                 }
 
@@ -2560,7 +2735,7 @@ impl Downstairs {
         state
             .do_ready_work(
                 &self.flags,
-                &self.reqwest_client,
+                &self.repair_client,
                 &mut self.dss,
                 &mut self.region,
             )
@@ -2589,11 +2764,10 @@ impl Downstairs {
             CRUCIBLE_MESSAGE_VERSION
         );
 
-        info!(log, "Connecting to {source} to obtain our extent files.");
+        let url = self.repair_client.url(source).await;
+        info!(log, "Connecting to {url} to obtain our extent files.");
 
-        let url = format!("http://{:?}", source);
-        let repair_server =
-            Client::new_with_client(&url, self.reqwest_client.clone());
+        let repair_server = self.repair_client.api(&url);
 
         let source_def = match repair_server.get_region_info().await {
             Ok(def) => def.into_inner(),
@@ -2632,7 +2806,13 @@ impl Downstairs {
 
             if let Err(e) = self
                 .region
-                .repair_extent(self.reqwest_client.clone(), eid, source, true)
+                .repair_extent(
+                    &self.repair_client,
+                    eid,
+                    source,
+                    true,
+                    &mut self.dss,
+                )
                 .await
             {
                 bail!("repair extent {eid} returned: {e}");
@@ -2683,7 +2863,13 @@ impl Downstairs {
                 DownstairsRequest::ConnectionClosed { id } => {
                     // Upstairs disconnected, so discard our local state
                     info!(self.log, "connection closed; disconnection");
-                    self.remove_connection(id);
+                    self.remove_connection(id, "connection_closed");
+                }
+                DownstairsRequest::Disconnect { session_id, done } => {
+                    let found = self.disconnect_session(session_id);
+                    if done.send(found).is_err() {
+                        warn!(log, "failed to reply to Disconnect");
+                    }
                 }
             }
         }
@@ -2716,6 +2902,7 @@ impl Downstairs {
                 read_only,
                 encrypted,
                 alternate_versions,
+                capabilities,
             } => {
                 let ConnectionState::Open(data) = state else {
                     bail!("Received connect out of order",);
@@ -2727,38 +2914,38 @@ impl Downstairs {
                     version
                 );
 
-                // Verify we can communicate with the upstairs.  First
-                // check our message version.  If that fails,  check
-                // to see if our version is one of the supported
-                // versions the upstairs has told us it can support.
-                if version != CRUCIBLE_MESSAGE_VERSION {
-                    if alternate_versions.contains(&CRUCIBLE_MESSAGE_VERSION) {
+                // Verify we can communicate with the upstairs, using the
+                // newest message version that both of us support.
+                let Some(negotiated_version) =
+                    negotiate_version(version, &alternate_versions)
+                else {
+                    let m = Message::VersionMismatch {
+                        version: CRUCIBLE_MESSAGE_VERSION,
+                    };
+                    if let Err(e) = state.reply(m) {
                         warn!(
                             self.log,
-                            "downstairs and upstairs using different \
-                             but compatible versions, Upstairs is {}, \
-                             but supports {:?}, downstairs is {}",
-                            version,
-                            alternate_versions,
-                            CRUCIBLE_MESSAGE_VERSION,
-                        );
-                    } else {
-                        let m = Message::VersionMismatch {
-                            version: CRUCIBLE_MESSAGE_VERSION,
-                        };
-                        if let Err(e) = state.reply(m) {
-                            warn!(
-                                self.log,
-                                "Failed to send VersionMismatch: {}", e
-                            );
-                        }
-                        bail!(
-                            "Required version {}, Or {:?} got {}",
-                            CRUCIBLE_MESSAGE_VERSION,
-                            alternate_versions,
-                            version,
+                            "Failed to send VersionMismatch: {}", e
                         );
                     }
+                    bail!(
+                        "Required one of versions {:?}, got {} or {:?}",
+                        SUPPORTED_MESSAGE_VERSIONS,
+                        version,
+                        alternate_versions,
+                    );
+                };
+                if negotiated_version != CRUCIBLE_MESSAGE_VERSION {
+                    warn!(
+                        self.log,
+                        "downstairs and upstairs using different \
+                         but compatible versions, Upstairs is {}, \
+                         but supports {:?}, downstairs is {}, using {}",
+                        version,
+                        alternate_versions,
+                        CRUCIBLE_MESSAGE_VERSION,
+                        negotiated_version,
+                    );
                 }
 
                 // Reject an Upstairs negotiation if there is a mismatch
@@ -2801,7 +2988,9 @@ impl Downstairs {
                 };
 
                 // Steal data from the connection state
-                let data = std::mem::replace(data, ConnectionData::dummy());
+                let mut data = std::mem::replace(data, ConnectionData::dummy());
+                data.capabilities =
+                    capabilities.intersection(self.flags.capabilities());
                 *state = ConnectionState::Negotiating {
                     negotiated: NegotiationState::ConnectedToUpstairs,
                     upstairs_connection,
@@ -2809,14 +2998,18 @@ impl Downstairs {
                 };
                 info!(
                     self.log,
-                    "upstairs {:?} connected, version {}",
+                    "upstairs {:?} connected, version {}, capabilities {}",
                     upstairs_connection,
-                    CRUCIBLE_MESSAGE_VERSION
+                    negotiated_version,
+                    state.data().capabilities,
                 );
 
+                // Our reply task switches to the negotiated version once
+                // this has been sent.
                 if let Err(e) = state.reply(Message::YesItsMe {
-                    version: CRUCIBLE_MESSAGE_VERSION,
+                    version: negotiated_version,
                     repair_addr: state.data().repair_addr,
+                    capabilities: state.data().capabilities,
                 }) {
                     bail!("Failed sending YesItsMe: {}", e);
                 }
@@ -2942,6 +3135,7 @@ impl Downstairs {
                     upstairs_connection,
                     &self.log,
                 ));
+                self.dss.add_upstairs(&upstairs_connection);
 
                 let work = self.work_mut(conn_id);
                 work.last_flush = last_flush_number;
@@ -2983,6 +3177,7 @@ impl Downstairs {
                     upstairs_connection,
                     &self.log,
                 ));
+                self.dss.add_upstairs(&upstairs_connection);
 
                 let meta_info = self.region.meta_info()?;
 
@@ -3049,6 +3244,7 @@ impl Downstairs {
                 repair_addr: self.repair_address.unwrap(),
                 cancel: cancel_guard,
                 reply_channel_tx,
+                capabilities: Capabilities::empty(),
             }),
         );
         assert!(prev.is_none());
@@ -3060,8 +3256,8 @@ impl Downstairs {
     ///
     /// The connection is removed from `self.connection_state` and
     /// `self.active_upstairs` (if active), and various log messages are
-    /// printed.
-    fn remove_connection(&mut self, id: ConnectionId) {
+    /// printed.  `reason` is recorded in the audit log.
+    fn remove_connection(&mut self, id: ConnectionId, reason: &str) {
         let Some(state) = self.connection_state.remove(&id) else {
             warn!(
                 self.log,
@@ -3078,12 +3274,19 @@ impl Downstairs {
                     upstairs_connection,
                     state.work.jobs(),
                 );
+                self.dss.remove_upstairs(&upstairs_connection);
             } else {
                 info!(
                     self.log,
                     "upstairs {:?} ({id:?}) removed", upstairs_connection,
                 );
             }
+            self.audit(
+                upstairs_connection,
+                AuditEvent::Disconnected {
+                    reason: reason.to_owned(),
+                },
+            );
 
             if self
                 .active_upstairs
@@ -3101,6 +3304,25 @@ impl Downstairs {
         }
     }
 
+    /// Forcibly disconnects the upstairs with the given session id
+    ///
+    /// Removing the connection state drops its cancellation guard, which stops
+    /// the connection's IO tasks.  Returns `false` if no connection matches.
+    fn disconnect_session(&mut self, session_id: Uuid) -> bool {
+        let Some(id) = self.connection_state.iter().find_map(|(id, state)| {
+            state
+                .upstairs_connection()
+                .filter(|c| c.session_id == session_id)
+                .map(|_| *id)
+        }) else {
+            warn!(self.log, "no connection with session id {session_id}");
+            return false;
+        };
+        info!(self.log, "disconnecting session {session_id} ({id:?})");
+        self.remove_connection(id, "disconnect_requested");
+        true
+    }
+
     /// Handles a single message, either negotiation or doing IO
     async fn on_message_for(&mut self, id: ConnectionId, m: Message) {
         let Some(state) = self.connection_state.get_mut(&id) else {
@@ -3112,7 +3334,7 @@ impl Downstairs {
                 .handle_frame(
                     m,
                     &self.flags,
-                    &self.reqwest_client,
+                    &self.repair_client,
                     &mut self.dss,
                     &mut self.region,
                 )
@@ -3122,14 +3344,14 @@ impl Downstairs {
                     self.log,
                     "handle_frame returns error {e:?}; disconnecting"
                 );
-                self.remove_connection(id);
+                self.remove_connection(id, &e.to_string());
             }
         } else if let Err(e) = self.on_negotiation_step(m, id) {
             warn!(
                 self.log,
                 "on_negotiation_step returns error {e:?}, disconnecting"
             );
-            self.remove_connection(id);
+            self.remove_connection(id, &e.to_string());
         }
     }
 
@@ -3187,7 +3409,29 @@ impl Downstairs {
             warn!(self.log, "Failed sending YouAreNoLongerActive: {e}");
         }
 
-        self.connection_state.remove(&id);
+        if let Some(ConnectionState::Running(state)) =
+            self.connection_state.remove(&id)
+        {
+            self.dss.remove_upstairs(&state.upstairs_connection);
+            self.audit(
+                state.upstairs_connection,
+                AuditEvent::TakenOver {
+                    new_upstairs_id: new_upstairs_connection.upstairs_id,
+                    new_session_id: new_upstairs_connection.session_id,
+                    new_generation: new_upstairs_connection.gen,
+                },
+            );
+        }
+    }
+
+    /// Records a lifecycle event for the given upstairs in the audit log
+    fn audit(&self, c: UpstairsConnection, event: AuditEvent) {
+        let session = AuditSession {
+            upstairs_id: c.upstairs_id,
+            session_id: c.session_id,
+            generation: c.gen,
+        };
+        self.audit.record(session, None, event);
     }
 }
 
@@ -3218,6 +3462,14 @@ enum DownstairsRequest {
 
     /// The given id's upstream connection has closed
     ConnectionClosed { id: ConnectionId },
+
+    /// Forcibly disconnects the upstairs with the given session id
+    ///
+    /// Replies with `false` if there is no such connection
+    Disconnect {
+        session_id: Uuid,
+        done: oneshot::Sender<bool>,
+    },
 }
 
 /// Handle allowing for async calls to the Downstairs task
@@ -3240,6 +3492,17 @@ impl DownstairsHandle {
             .context("could not send message on channel")
     }
 
+    /// Disconnects the upstairs with the given session id
+    ///
+    /// Returns `false` if no connected upstairs has that session id
+    pub async fn disconnect(&self, session_id: Uuid) -> Result<bool> {
+        let (done, rx) = oneshot::channel();
+        self.tx
+            .send(DownstairsRequest::Disconnect { session_id, done })
+            .context("could not send message on channel")?;
+        rx.await.context("could not receive result")
+    }
+
     async fn new_connection(
         &self,
         id: ConnectionId,
@@ -3279,6 +3542,19 @@ pub struct Work {
     last_flush: JobId,
     completed: Vec<JobId>,
 
+    /// When each outstanding job was received, for latency stats
+    received: HashMap<JobId, Instant>,
+
+    /// Trace context sent by the upstairs for outstanding jobs
+    ///
+    /// Entries are only added by `on_received`, so each belongs to a job which
+    /// was actually received; they're removed when the job completes.
+    trace_parents: HashMap<JobId, String>,
+
+    /// Trace context from the most recent `Message::TraceContext`, which
+    /// belongs to the next job received
+    next_trace_parent: Option<(JobId, String)>,
+
     log: Logger,
 }
 
@@ -3289,10 +3565,27 @@ impl Work {
             outstanding_deps: HashMap::new(),
             last_flush: JobId(0), // TODO(matt) make this an Option?
             completed: Vec::with_capacity(32),
+            received: HashMap::new(),
+            trace_parents: HashMap::new(),
+            next_trace_parent: None,
             log,
         }
     }
 
+    /// Notes that a job has been received from the upstairs
+    ///
+    /// The job picks up the trace context sent just before it, if any.  A
+    /// trace context for some other job (one which was rejected, say) is
+    /// dropped here rather than left behind.
+    fn on_received(&mut self, ds_id: JobId) {
+        self.received.insert(ds_id, Instant::now());
+        if let Some((id, traceparent)) = self.next_trace_parent.take() {
+            if id == ds_id {
+                self.trace_parents.insert(ds_id, traceparent);
+            }
+        }
+    }
+
     fn jobs(&self) -> usize {
         self.dep_wait.len()
     }
@@ -3470,12 +3763,29 @@ pub fn create_region_with_backend(
     Ok(region)
 }
 
+/// Grow an existing region to `extent_count` extents.
+///
+/// The region must not be open anywhere else (i.e. its downstairs must be
+/// stopped).  Shrinking a region is not supported.
+pub fn extend_region(
+    data: PathBuf,
+    extent_count: u32,
+    log: Logger,
+) -> Result<Region> {
+    let mut region = Region::open(data, true, false, &log)?;
+    region.extend(extent_count, Backend::default())?;
+
+    Ok(region)
+}
+
 /// Return `struct` for `start_downstairs`
 #[derive(Debug)]
 pub struct RunningDownstairs {
     pub join_handle: tokio::task::JoinHandle<Result<()>>,
     pub address: SocketAddr,
     pub repair_address: SocketAddr,
+    /// The certificates in use, if any, for the caller to reload
+    pub tls: Option<Arc<crucible_common::x509::TLSContext>>,
 }
 
 /// Returns Ok if everything spawned ok, Err otherwise
@@ -3535,37 +3845,68 @@ pub async fn start_downstairs(
         IpAddr::V6(ipv6) => SocketAddr::new(std::net::IpAddr::V6(ipv6), rport),
     };
 
+    // Optionally require TLS connections, both from the upstairs and to the
+    // repair server.
+    let tls_context = match (cert_pem, key_pem, root_cert_pem) {
+        (Some(cert_pem_path), Some(key_pem_path), Some(root_cert_pem_path)) => {
+            Some(Arc::new(crucible_common::x509::TLSContext::from_paths(
+                &cert_pem_path,
+                &key_pem_path,
+                &root_cert_pem_path,
+            )?))
+        }
+        (None, None, None) => None,
+        (cert_pem, key_pem, root_cert_pem) => {
+            bail!(
+                "TLS needs cert_pem, key_pem and root_cert_pem together, \
+                but got cert_pem:{} key_pem:{} root_cert_pem:{}",
+                cert_pem.is_some(),
+                key_pem.is_some(),
+                root_cert_pem.is_some(),
+            );
+        }
+    };
+
+    // Other downstairs' repair servers will expect our certificate too, and
+    // theirs must be issued for the repair server name.  The certificates
+    // can be rotated with the repair server's `/tls/reload`, or through the
+    // context returned in `RunningDownstairs`.
+    if let Some(tls_context) = &tls_context {
+        ds.repair_client = RepairClient::new(
+            Some(tls_context.clone()),
+            ds.repair_server_name.clone(),
+            ds.repair_tls_only,
+        )?;
+        ds.dss.set_tls(tls_context.clone());
+    }
+
     let repair_log = root_log.new(o!("task" => "repair".to_string()));
-    let repair_listener =
-        match repair::repair_main(&ds, repair_address, &repair_log) {
-            Err(e) => {
-                // TODO tear down other things if repair server can't be
-                // started?
-                bail!("got {:?} from repair main", e);
-            }
+    let repair_listener = match repair::repair_main(
+        &ds,
+        repair_address,
+        tls_context.clone(),
+        &repair_log,
+    ) {
+        Err(e) => {
+            // TODO tear down other things if repair server can't be
+            // started?
+            bail!("got {:?} from repair main", e);
+        }
 
-            Ok(socket_addr) => socket_addr,
-        };
+        Ok(socket_addr) => socket_addr,
+    };
 
     ds.repair_address = Some(repair_listener);
     info!(log, "Using repair address: {:?}", repair_listener);
 
-    // Optionally require SSL connections
-    let ssl_acceptor = if let Some(cert_pem_path) = cert_pem {
-        let key_pem_path = key_pem.unwrap();
-        let root_cert_pem_path = root_cert_pem.unwrap();
-
-        let context = crucible_common::x509::TLSContext::from_paths(
-            &cert_pem_path,
-            &key_pem_path,
-            &root_cert_pem_path,
-        )?;
-
-        let config = context.get_server_config()?;
+    // The acceptor is rebuilt for each connection, so that reloaded
+    // certificates are picked up; this checks that it can be built at all.
+    let ssl_context = if let Some(context) = &tls_context {
+        context.get_server_config()?;
 
         info!(log, "Configured SSL acceptor");
 
-        Some(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+        Some(context.clone())
     } else {
         // unencrypted
         info!(log, "No SSL acceptor configured");
@@ -3574,6 +3915,10 @@ pub async fn start_downstairs(
 
     let mut dss = ds.dss.clone(); // shared handle for stats
     let handle = ds.handle(); // handle for passing messages
+    let capture = ds.capture.clone();
+    if let Some(c) = &capture {
+        info!(log, "capturing connections to {:?}", c.dir);
+    }
 
     // This is where the actual work takes place; owning the Downstairs
     let mut ds_runner = Downstairs::spawn_runner(ds);
@@ -3600,10 +3945,16 @@ pub async fn start_downstairs(
              */
             sock.set_nodelay(true).expect("could not set TCP_NODELAY");
 
-            let stream: WrappedStream = if let Some(ssl_acceptor) =
-                &ssl_acceptor
-            {
-                let ssl_acceptor = ssl_acceptor.clone();
+            let stream: WrappedStream = if let Some(context) = &ssl_context {
+                let ssl_acceptor = match context.get_server_config() {
+                    Ok(config) => {
+                        tokio_rustls::TlsAcceptor::from(Arc::new(config))
+                    }
+                    Err(e) => {
+                        error!(log, "SSL acceptor config failed: {:?}", e);
+                        continue;
+                    }
+                };
                 WrappedStream::Https(match ssl_acceptor.accept(sock).await {
                     Ok(v) => v,
                     Err(e) => {
@@ -3625,7 +3976,10 @@ pub async fn start_downstairs(
             dss.add_connection();
             let task_log = root_log.new(o!("id" => id.0.to_string()));
             let handle = handle.clone();
-            if let Err(e) = proc_stream(handle, id, stream, &task_log).await {
+            if let Err(e) =
+                proc_stream(handle, id, stream, capture.as_ref(), &task_log)
+                    .await
+            {
                 error!(
                     task_log,
                     "connection ({raddr}) failed to spawn tasks: {e:?}",
@@ -3641,6 +3995,7 @@ pub async fn start_downstairs(
         join_handle,
         address: local_addr,
         repair_address: repair_listener,
+        tls: tls_context,
     })
 }
 
@@ -3771,6 +4126,23 @@ mod test {
         assert!(test_push_next_jobs(&mut work).is_empty());
     }
 
+    #[test]
+    fn trace_context_is_kept_for_its_job_only() {
+        let mut work = Work::new(csl());
+
+        // A trace context whose job never arrived doesn't stick around
+        work.next_trace_parent = Some((JobId(1000), "a".to_string()));
+        work.on_received(JobId(1001));
+        assert!(work.next_trace_parent.is_none());
+        assert!(work.trace_parents.is_empty());
+
+        work.next_trace_parent = Some((JobId(1002), "b".to_string()));
+        work.on_received(JobId(1002));
+        assert!(work.next_trace_parent.is_none());
+        assert_eq!(work.trace_parents.len(), 1);
+        assert_eq!(work.trace_parents[&JobId(1002)], "b");
+    }
+
     #[tokio::test]
     async fn test_simple_read() -> Result<()> {
         // Test region create and a read of one block.
@@ -3848,6 +4220,116 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_connection_stats_and_disconnect() -> Result<()> {
+        // Each upstairs connection gets its own stats, which go away when
+        // that upstairs is disconnected by session id.
+        let dir = tempdir()?;
+        let mut ds = create_test_downstairs(512, 4, 2, &dir)?;
+
+        let first = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 10,
+        };
+        let second = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 10,
+        };
+        let (_, mut rx) = ds.add_fake_connection(first, ConnectionId(0));
+        let _ = ds.add_fake_connection(second, ConnectionId(1));
+        ds.promote_to_active(first, ConnectionId(0))?;
+        assert_eq!(ds.dss.connections().len(), 2);
+
+        for (i, eid) in [0, 1].into_iter().enumerate() {
+            let rio = IOop::Read {
+                dependencies: Vec::new(),
+                requests: RegionReadRequest(vec![RegionReadReq {
+                    extent: ExtentId(eid),
+                    offset: BlockOffset(1),
+                    count: NonZeroUsize::new(1).unwrap(),
+                }]),
+            };
+            ds.active_mut(ConnectionId(0))
+                .add_work(JobId(1000 + i as u64), rio);
+        }
+        ds.do_work_for(ConnectionId(0)).await.unwrap();
+        while rx.try_recv().is_ok() {}
+
+        let stats = ds.dss.connections();
+        let s = stats.iter().find(|s| s.session_id == first.session_id);
+        let s = s.unwrap();
+        assert_eq!(s.reads, 2);
+        assert_eq!(s.writes, 0);
+        assert_eq!(s.bytes_read, 1024);
+        assert_eq!(s.queue_depth, 0);
+        assert_eq!(s.latency.buckets.iter().sum::<u64>(), 2);
+
+        let s = stats.iter().find(|s| s.session_id == second.session_id);
+        assert_eq!(s.unwrap().reads, 0);
+
+        assert!(ds.disconnect_session(second.session_id));
+        assert!(!ds.disconnect_session(second.session_id));
+        assert!(!ds.connection_state.contains_key(&ConnectionId(1)));
+
+        let stats = ds.dss.connections();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].session_id, first.session_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_promote_takeover_disconnect() -> Result<()> {
+        let dir = tempdir()?;
+        let mut ds = create_test_downstairs(512, 4, 2, &dir)?;
+
+        let first = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 10,
+        };
+        let second = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 11,
+        };
+        let _ = ds.add_fake_connection(first, ConnectionId(0));
+        let _ = ds.add_fake_connection(second, ConnectionId(1));
+        ds.promote_to_active(first, ConnectionId(0))?;
+        ds.promote_to_active(second, ConnectionId(1))?;
+        assert!(ds.disconnect_session(second.session_id));
+
+        let records = ds.audit.after(0).records;
+        let events: Vec<_> = records
+            .iter()
+            .map(|r| (r.session.session_id, &r.event))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (first.session_id, &AuditEvent::Promoted),
+                (
+                    first.session_id,
+                    &AuditEvent::TakenOver {
+                        new_upstairs_id: second.upstairs_id,
+                        new_session_id: second.session_id,
+                        new_generation: 11,
+                    }
+                ),
+                (second.session_id, &AuditEvent::Promoted),
+                (
+                    second.session_id,
+                    &AuditEvent::Disconnected {
+                        reason: "disconnect_requested".to_owned()
+                    }
+                ),
+            ]
+        );
+        assert!(records.iter().all(|r| r.source == AuditSource::Downstairs));
+        Ok(())
+    }
+
     // Test function to create a simple downstairs with the given block
     // and extent values.  Returns the Downstairs.
     fn create_test_downstairs(
@@ -3879,6 +4361,36 @@ mod test {
         Ok(ds)
     }
 
+    #[test]
+    fn extend_region_retry() -> Result<()> {
+        // The agent retries a failed resize by running `extend` again, so
+        // extending must cope with leftovers from an interrupted attempt and
+        // with being repeated after it succeeded.
+        let dir = tempdir()?;
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_uuid(Uuid::new_v4());
+        let mut region = Region::create(&dir, region_options, csl())?;
+        region.extend(2, Backend::default())?;
+        drop(region);
+
+        let torn = extent::extent_path(dir.path(), ExtentId(2));
+        std::fs::create_dir_all(torn.parent().unwrap())?;
+        std::fs::write(&torn, b"torn")?;
+
+        let region = extend_region(dir.path().to_path_buf(), 4, csl())?;
+        assert_eq!(region.def().extent_count(), 4);
+        drop(region);
+
+        let region = extend_region(dir.path().to_path_buf(), 4, csl())?;
+        assert_eq!(region.def().extent_count(), 4);
+        drop(region);
+
+        let region = Region::open(&dir, false, false, &csl())?;
+        assert_eq!(region.def().extent_count(), 4);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_extent_simple_close_flush_close() -> Result<()> {
         // Test creating these IOops:
@@ -5918,6 +6430,10 @@ mod test {
             read_only: false,
             encrypted: false,
             alternate_versions: Vec::new(),
+            // Include a capability we don't know about, which must not be
+            // echoed back.
+            capabilities: CRUCIBLE_CAPABILITIES
+                .union(Capabilities::from_bits(1 << 63)),
         };
         fw.send(m).await?;
 
@@ -5927,9 +6443,15 @@ mod test {
             Ok(Message::YesItsMe {
                 version,
                 repair_addr,
+                capabilities,
             }) => {
                 assert_eq!(version, CRUCIBLE_MESSAGE_VERSION);
                 assert_eq!(repair_addr, "127.0.0.1:5556".parse().unwrap());
+                // Compression isn't offered unless it was configured
+                assert_eq!(
+                    capabilities,
+                    CRUCIBLE_CAPABILITIES.difference(Capabilities::COMPRESSION)
+                );
             }
             x => {
                 panic!("Invalid answer from downstairs: {:?}", x);
@@ -5940,8 +6462,8 @@ mod test {
 
     #[tokio::test]
     async fn test_version_downrev() -> Result<()> {
-        // Test that a newer crucible version will result in a message
-        // indicating there is a version mismatch.
+        // Test that an upstairs one version behind is accepted, and that the
+        // downstairs answers with that older version.
         let tcp = start_ds_and_connect(5557, 5558).await.unwrap();
         let (read, write) = tcp.into_split();
         let mut fr = FramedRead::new(read, CrucibleDecoder::new());
@@ -5956,6 +6478,42 @@ mod test {
             read_only: false,
             encrypted: false,
             alternate_versions: vec![CRUCIBLE_MESSAGE_VERSION - 1],
+            capabilities: Capabilities::empty(),
+        };
+        fw.send(m).await?;
+
+        let f = fr.next().await.unwrap();
+
+        match f {
+            Ok(Message::YesItsMe { version, .. }) => {
+                assert_eq!(version, CRUCIBLE_MESSAGE_VERSION - 1);
+            }
+            x => {
+                panic!("Invalid answer from downstairs: {:?}", x);
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_downrev_unsupported() -> Result<()> {
+        // Test that a crucible version older than any we support will result
+        // in a message indicating there is a version mismatch.
+        let tcp = start_ds_and_connect(5565, 5566).await.unwrap();
+        let (read, write) = tcp.into_split();
+        let mut fr = FramedRead::new(read, CrucibleDecoder::new());
+        let mut fw = MessageWriter::new(write);
+
+        let oldest = *SUPPORTED_MESSAGE_VERSIONS.iter().min().unwrap();
+        let m = Message::HereIAm {
+            version: oldest - 1,
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 1,
+            read_only: false,
+            encrypted: false,
+            alternate_versions: vec![oldest - 1],
+            capabilities: Capabilities::empty(),
         };
         fw.send(m).await?;
 
@@ -5990,6 +6548,7 @@ mod test {
             read_only: false,
             encrypted: false,
             alternate_versions: vec![CRUCIBLE_MESSAGE_VERSION + 1],
+            capabilities: Capabilities::empty(),
         };
         fw.send(m).await?;
 
@@ -6027,6 +6586,7 @@ mod test {
                 CRUCIBLE_MESSAGE_VERSION,
                 CRUCIBLE_MESSAGE_VERSION + 1,
             ],
+            capabilities: Capabilities::empty(),
         };
         fw.send(m).await?;
 
@@ -6036,6 +6596,7 @@ mod test {
             Ok(Message::YesItsMe {
                 version,
                 repair_addr,
+                ..
             }) => {
                 assert_eq!(version, CRUCIBLE_MESSAGE_VERSION);
                 assert_eq!(repair_addr, "127.0.0.1:5562".parse().unwrap());
@@ -6069,6 +6630,7 @@ mod test {
                 CRUCIBLE_MESSAGE_VERSION,
                 CRUCIBLE_MESSAGE_VERSION + 1,
             ],
+            capabilities: Capabilities::empty(),
         };
         fw.send(m).await?;
 
@@ -6078,6 +6640,7 @@ mod test {
             Ok(Message::YesItsMe {
                 version,
                 repair_addr,
+                ..
             }) => {
                 assert_eq!(version, CRUCIBLE_MESSAGE_VERSION);
                 assert_eq!(repair_addr, "127.0.0.1:5564".parse().unwrap());
@@ -8,15 +8,14 @@ use std::time::Duration;
 
 use anyhow::{bail, Result};
 use clap::Parser;
-use slog::info;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
+use slog::{info, o};
 use uuid::Uuid;
 
-use crucible_common::{build_logger, ExtentId};
+use crucible_common::{build_logger, otel, ExtentId};
 use crucible_downstairs::admin::*;
 use crucible_downstairs::*;
-use crucible_protocol::{JobId, CRUCIBLE_MESSAGE_VERSION};
+use crucible_protocol::capture::CaptureConfig;
+use crucible_protocol::{Compression, JobId, CRUCIBLE_MESSAGE_VERSION};
 
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -54,8 +53,30 @@ enum Args {
         #[clap(short, long, action)]
         source: SocketAddr,
 
+        /// OTLP collector to export tracing spans to
         #[clap(short, long, action)]
         trace_endpoint: Option<String>,
+
+        /// Fraction of new traces to record, if exporting spans
+        #[clap(long, default_value_t = 1.0, action)]
+        trace_sample_rate: f64,
+
+        // TLS options, for a source whose repair server requires them
+        #[clap(long, action)]
+        cert_pem: Option<String>,
+        #[clap(long, action)]
+        key_pem: Option<String>,
+        #[clap(long, action)]
+        root_cert_pem: Option<String>,
+
+        /// Name the source's repair server certificate must be issued for
+        #[clap(long, requires = "cert_pem", action)]
+        repair_server_name: Option<String>,
+
+        /// Don't fall back to plain HTTP if the source's repair server
+        /// doesn't speak TLS
+        #[clap(long, requires = "cert_pem", action)]
+        repair_tls_only: bool,
     },
     Create {
         /// Block size.
@@ -155,6 +176,19 @@ enum Args {
         #[clap(short, long, default_value = "0", value_name = "SKIP", action)]
         skip: u64,
     },
+    /// Add extents to an existing region.
+    ///
+    /// The region's downstairs must not be running.  Regions can only
+    /// grow: an extent count smaller than the current one is an error.
+    Extend {
+        /// Directory where the region is located.
+        #[clap(short, long, value_name = "DIRECTORY", action)]
+        data: PathBuf,
+
+        /// New number of extent files.
+        #[clap(long, action)]
+        extent_count: u32,
+    },
     Run {
         /// Address the downstairs will listen for the upstairs on.
         #[clap(
@@ -199,9 +233,14 @@ enum Args {
         #[clap(long, action)]
         flush_errors: bool,
 
+        /// OTLP collector to export tracing spans to
         #[clap(short, long, action)]
         trace_endpoint: Option<String>,
 
+        /// Fraction of new traces to record, if exporting spans
+        #[clap(long, default_value_t = 1.0, action)]
+        trace_sample_rate: f64,
+
         // TLS options
         #[clap(long, action)]
         cert_pem: Option<String>,
@@ -210,18 +249,70 @@ enum Args {
         #[clap(long, action)]
         root_cert_pem: Option<String>,
 
+        /// Name that other downstairs' repair server certificates must be
+        /// issued for, when repairing from them over TLS.  Without one, any
+        /// certificate signed by the root is accepted.
+        #[clap(long, requires = "cert_pem", action)]
+        repair_server_name: Option<String>,
+
+        /// Refuse repair connections that don't use TLS, both to our repair
+        /// server and to others.  Otherwise plain HTTP is still allowed, so
+        /// that downstairs whose repair server predates TLS can repair from
+        /// and to this one during an upgrade.
+        #[clap(long, requires = "cert_pem", action)]
+        repair_tls_only: bool,
+
         #[clap(long, default_value = "rw", action)]
         mode: Mode,
+
+        /// Record the messages on each upstairs connection to a capture
+        /// file in this directory, for decoding with `crudump`.
+        #[clap(long, value_name = "DIRECTORY", action)]
+        capture: Option<PathBuf>,
+
+        /// Replace read and write data with zeros in capture files.
+        #[clap(long, requires = "capture", action)]
+        capture_redact: bool,
+
+        /// Offer to compress read and write data (lz4 or zstd), which the
+        /// upstairs will use if it supports it.  Encrypted data is never
+        /// compressed.
+        #[clap(long, value_name = "ALGORITHM", action)]
+        compression: Option<Compression>,
+
+        /// Append lifecycle events (activation, takeover, disconnection) to
+        /// this file, one JSON object per line.  The file is renamed to
+        /// FILE.old once it reaches 64 MiB.
+        #[clap(long, value_name = "FILE", action)]
+        audit_log: Option<PathBuf>,
     },
     RepairAPI,
     Serve {
+        /// OTLP collector to export tracing spans to
         #[clap(short, long, action)]
         trace_endpoint: Option<String>,
 
+        /// Fraction of new traces to record, if exporting spans
+        #[clap(long, default_value_t = 1.0, action)]
+        trace_sample_rate: f64,
+
         // Dropshot server details
         #[clap(long, default_value = "127.0.0.1:4567", action)]
         bind_addr: SocketAddr,
     },
+    /// Check every written block in a region against its stored hashes.
+    ///
+    /// The region is opened read-only.  Blocks that don't match are logged,
+    /// and a per-extent report can be written out as JSON.
+    Verify {
+        /// Directory where the region is located.
+        #[clap(short, long, value_name = "DIRECTORY", action)]
+        data: PathBuf,
+
+        /// Write the per-extent report to this file.
+        #[clap(short, long, value_name = "FILE", action)]
+        output: Option<PathBuf>,
+    },
     Version,
     /// Measure an isolated downstairs
     Dynamometer {
@@ -278,27 +369,43 @@ async fn main() -> Result<()> {
             data,
             source,
             trace_endpoint,
+            trace_sample_rate,
+            cert_pem,
+            key_pem,
+            root_cert_pem,
+            repair_server_name,
+            repair_tls_only,
         } => {
             // Instrumentation is shared.
             if let Some(endpoint) = trace_endpoint {
-                let tracer = opentelemetry_jaeger::new_agent_pipeline()
-                    .with_endpoint(endpoint) // usually port 6831
-                    .with_service_name("downstairs")
-                    .install_simple()
-                    .expect("Error initializing Jaeger exporter");
-
-                let telemetry =
-                    tracing_opentelemetry::layer().with_tracer(tracer);
-
-                tracing_subscriber::registry()
-                    .with(telemetry)
-                    .try_init()
-                    .expect("Error init tracing subscriber");
+                otel::init_tracing("downstairs", &endpoint, trace_sample_rate)?;
             }
 
             let mut ds = Downstairs::new_builder(&data, true)
                 .set_logger(log)
                 .build()?;
+
+            match (cert_pem, key_pem, root_cert_pem) {
+                (Some(cert_pem), Some(key_pem), Some(root_cert_pem)) => {
+                    let tls = crucible_common::x509::TLSContext::from_paths(
+                        &cert_pem,
+                        &key_pem,
+                        &root_cert_pem,
+                    )?;
+                    ds.repair_client = repair::RepairClient::new(
+                        Some(std::sync::Arc::new(tls)),
+                        repair_server_name,
+                        repair_tls_only,
+                    )?;
+                }
+                (None, None, None) => (),
+                _ => {
+                    bail!(
+                        "--cert-pem, --key-pem and --root-cert-pem must be \
+                        given together"
+                    );
+                }
+            }
             ds.clone_region(source).await?;
             Ok(())
         }
@@ -377,6 +484,17 @@ async fn main() -> Result<()> {
 
             downstairs_export(&mut region, export_path, skip, count)
         }
+        Args::Extend { data, extent_count } => {
+            let region = extend_region(data, extent_count, log.clone())?;
+
+            info!(
+                log,
+                "Blocks per extent:{} Total Extents: {}",
+                region.def().extent_size().value,
+                region.def().extent_count(),
+            );
+            Ok(())
+        }
         Args::Run {
             address,
             data,
@@ -387,34 +505,37 @@ async fn main() -> Result<()> {
             write_errors,
             flush_errors,
             trace_endpoint,
+            trace_sample_rate,
             cert_pem,
             key_pem,
             root_cert_pem,
+            repair_server_name,
+            repair_tls_only,
             mode,
+            capture,
+            capture_redact,
+            compression,
+            audit_log,
         } => {
             // Instrumentation is shared.
             if let Some(endpoint) = trace_endpoint {
-                let tracer = opentelemetry_jaeger::new_agent_pipeline()
-                    .with_endpoint(endpoint) // usually port 6831
-                    .with_service_name("downstairs")
-                    .install_simple()
-                    .expect("Error initializing Jaeger exporter");
-
-                let telemetry =
-                    tracing_opentelemetry::layer().with_tracer(tracer);
-
-                tracing_subscriber::registry()
-                    .with(telemetry)
-                    .try_init()
-                    .expect("Error init tracing subscriber");
+                otel::init_tracing("downstairs", &endpoint, trace_sample_rate)?;
             }
 
             let read_only = mode == Mode::Ro;
 
             let d = Downstairs::new_builder(&data, read_only)
                 .set_lossy(lossy)
-                .set_logger(log)
+                .set_logger(log.clone())
                 .set_test_errors(read_errors, write_errors, flush_errors)
+                .set_capture(capture.map(|dir| CaptureConfig {
+                    dir,
+                    redact: capture_redact,
+                }))
+                .set_compression(compression)
+                .set_audit_log(audit_log)
+                .set_repair_server_name(repair_server_name)
+                .set_repair_tls_only(repair_tls_only)
                 .build()?;
 
             let downstairs = start_downstairs(
@@ -430,32 +551,42 @@ async fn main() -> Result<()> {
             )
             .await?;
 
+            // Certificates can also be rotated with a SIGHUP
+            if let Some(tls) = &downstairs.tls {
+                tokio::spawn(crucible_common::x509::reload_on_sighup(
+                    tls.clone(),
+                    log.new(o!("task" => "tls".to_string())),
+                ));
+            }
+
             downstairs.join_handle.await?
         }
         Args::RepairAPI => repair::write_openapi(&mut std::io::stdout()),
         Args::Serve {
             trace_endpoint,
+            trace_sample_rate,
             bind_addr,
         } => {
             // Instrumentation is shared.
             if let Some(endpoint) = trace_endpoint {
-                let tracer = opentelemetry_jaeger::new_agent_pipeline()
-                    .with_endpoint(endpoint) // usually port 6831
-                    .with_service_name("downstairs")
-                    .install_simple()
-                    .expect("Error initializing Jaeger exporter");
-
-                let telemetry =
-                    tracing_opentelemetry::layer().with_tracer(tracer);
-
-                tracing_subscriber::registry()
-                    .with(telemetry)
-                    .try_init()
-                    .expect("Error init tracing subscriber");
+                otel::init_tracing("downstairs", &endpoint, trace_sample_rate)?;
             }
 
             run_dropshot(bind_addr, &log).await
         }
+        Args::Verify { data, output } => {
+            let results = verify_region(data, log.clone())?;
+
+            let checked: u64 = results.iter().map(|r| r.blocks_checked).sum();
+            let corrupt: usize =
+                results.iter().map(|r| r.corrupt_blocks.len()).sum();
+            info!(log, "Checked {} blocks, {} corrupt", checked, corrupt);
+
+            if let Some(output) = output {
+                crucible_common::write_json(&output, &results, true)?;
+            }
+            Ok(())
+        }
         Args::Version => {
             let info = crucible_common::BuildInfo::default();
             println!("Crucible Version: {}", info);
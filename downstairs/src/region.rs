@@ -1,18 +1,16 @@
 use std::collections::{BTreeSet, HashSet};
 use std::fmt::Debug;
 use std::fs::{rename, File, OpenOptions};
-use std::io::Write;
+use std::io::{Seek, SeekFrom, Write};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Result};
-use futures::TryStreamExt;
 
 use tracing::instrument;
 
 use crucible_common::*;
 use crucible_protocol::SnapshotDetails;
-use repair_client::Client;
 
 /// Number of worker threads in the Rayon thread pool
 ///
@@ -30,9 +28,12 @@ const MIN_BLOCKING_SIZE: usize = 64 * 1024; // 64 KiB
 
 use super::*;
 use crate::extent::{
-    copy_dir, extent_dir, extent_file_name, move_replacement_extent,
-    replace_dir, sync_path, Extent, ExtentMeta, ExtentState, ExtentType,
+    copy_dir, extent_dir, extent_file_name, file_sha256,
+    move_replacement_extent, remove_extent_files, replace_dir, sync_path,
+    Extent, ExtentMeta, ExtentState, ExtentType,
 };
+use crate::repair::RepairClient;
+use crate::verify::ExtentVerify;
 
 /// Validate files for a repair or clone operation
 ///
@@ -523,10 +524,11 @@ impl Region {
      */
     pub async fn repair_extent(
         &self,
-        client: reqwest::Client,
+        client: &RepairClient,
         eid: ExtentId,
         repair_addr: SocketAddr,
         clone: bool,
+        dss: &mut DsStatOuter,
     ) -> Result<(), CrucibleError> {
         // Make sure the extent:
         // is currently closed, matches our eid, is not read-only
@@ -537,7 +539,7 @@ impl Region {
             assert!(!self.read_only);
         }
 
-        self.get_extent_copy(client, eid, repair_addr, clone)
+        self.get_extent_copy(client, eid, repair_addr, clone, dss)
             .await?;
 
         // Returning from get_extent_copy means we have copied all our
@@ -551,16 +553,18 @@ impl Region {
     /**
      * Connect to the source and pull over all the extent files for the
      * given extent ID.
-     * The files are loaded into the copy_dir for the given extent.
+     * The files are loaded into the copy_dir for the given extent, and
+     * each is checked against the digest the source gave for it.
      * After all the files have been copied locally, we rename the
      * copy_dir to replace_dir.
      */
     pub async fn get_extent_copy(
         &self,
-        client: reqwest::Client,
+        client: &RepairClient,
         eid: ExtentId,
         repair_addr: SocketAddr,
         clone: bool,
+        dss: &mut DsStatOuter,
     ) -> Result<(), CrucibleError> {
         // An extent must be closed before we replace its files.
         assert!(matches!(self.extents[eid.0 as usize], ExtentState::Closed));
@@ -580,9 +584,8 @@ impl Region {
         let copy_dir = Self::create_copy_dir(&self.dir, eid)?;
         info!(self.log, "Created copy dir {:?}", copy_dir);
 
-        // XXX TLS someday?  Authentication?
-        let url = format!("http://{:?}", repair_addr);
-        let repair_server = Client::new_with_client(&url, client);
+        let url = client.url(repair_addr).await;
+        let repair_server = client.api(&url);
 
         let mut repair_files =
             match repair_server.get_files_for_extent(eid.0).await {
@@ -590,7 +593,8 @@ impl Region {
                 Err(e) => {
                     crucible_bail!(
                         RepairRequestError,
-                        "Failed to get repair files: {:?}",
+                        "Failed to get repair files from {}: {:?}",
+                        url,
                         e,
                     );
                 }
@@ -620,6 +624,32 @@ impl Region {
             );
         }
 
+        // A repair server from before digests were added doesn't have this
+        // endpoint, in which case the files are copied without checking them.
+        let digests =
+            match repair_server.get_file_digests_for_extent(eid.0).await {
+                Ok(d) => Some(d.into_inner()),
+                Err(repair_client::Error::ErrorResponse(e))
+                    if e.status() == reqwest::StatusCode::NOT_FOUND =>
+                {
+                    warn!(
+                        self.log,
+                        "eid:{} {} has no repair file digests, copying \
+                        without verifying",
+                        eid,
+                        url,
+                    );
+                    None
+                }
+                Err(e) => {
+                    crucible_bail!(
+                        RepairRequestError,
+                        "Failed to get repair file digests: {:?}",
+                        e,
+                    );
+                }
+            };
+
         // Replace local files with their remote copies.
         //
         // If we are replacing our region with one from an older version
@@ -637,24 +667,56 @@ impl Region {
             if !repair_files.contains(&filename) {
                 continue;
             }
+            let digest = match &digests {
+                Some(digests) => {
+                    match digests.iter().find(|d| d.name == filename) {
+                        Some(digest) => Some(digest),
+                        None => crucible_bail!(
+                            RepairFilesInvalid,
+                            "No digest for repair file {}",
+                            filename,
+                        ),
+                    }
+                }
+                None => None,
+            };
+
             let local_file =
                 Self::create_copy_file(copy_dir.clone(), eid, *opt_file)?;
-            let repair_stream = match repair_server
-                .get_extent_file(eid.0, opt_file.to_file_type())
-                .await
-            {
-                Ok(rs) => rs,
-                Err(e) => {
-                    crucible_bail!(
-                        RepairRequestError,
-                        "Failed to get extent {} {} file: {:?}",
-                        eid,
-                        opt_file,
-                        e,
-                    );
+            let file_url = format!(
+                "{}/newextent/{}/{}",
+                url,
+                eid.0,
+                opt_file.to_file_type()
+            );
+
+            let mut transfer = RepairTransfer::default();
+            let result = save_url_to_file(
+                &client.http(),
+                &file_url,
+                local_file,
+                &mut transfer,
+                &self.log,
+            )
+            .await
+            .and_then(|()| match digest {
+                Some(digest) => {
+                    check_file_digest(&copy_dir, eid, *opt_file, digest)
                 }
-            };
-            save_stream_to_file(local_file, repair_stream.into_inner()).await?;
+                None => Ok(()),
+            });
+            dss.on_repair_transfer(&transfer, result.is_ok());
+            result?;
+
+            info!(
+                self.log,
+                "eid:{} copied {} bytes of {} in {:?} with {} retries",
+                eid,
+                transfer.bytes,
+                filename,
+                transfer.elapsed,
+                transfer.retries,
+            );
             count += 1;
         }
 
@@ -690,7 +752,7 @@ impl Region {
         );
         rename(copy_dir.clone(), rd.clone())?;
 
-        // Files are synced in save_stream_to_file(). Now make sure
+        // Files are synced in save_url_to_file(). Now make sure
         // the parent directory containing the repair directory has
         // been synced so that change is persistent.
         let current_dir = extent_dir(&self.dir, eid);
@@ -718,13 +780,103 @@ impl Region {
         }
 
         if newsize > self.def.extent_count() {
+            /*
+             * Create and sync the new extent files before recording them in
+             * the region config, so that a crash part way through leaves a
+             * region which still opens with its old size.  Extent files past
+             * that size can only be left over from such a crash, so they are
+             * removed and created again.
+             */
+            let old_count = self.def.extent_count();
+            for eid in (old_count..newsize).map(ExtentId) {
+                remove_extent_files(&self.dir, eid)?;
+            }
+
             self.def.set_extent_count(newsize);
+            if let Err(e) = self.create_extents(backend) {
+                self.def.set_extent_count(old_count);
+                self.extents.truncate(old_count as usize);
+                return Err(e);
+            }
+
+            let dirs: BTreeSet<PathBuf> = (old_count..newsize)
+                .map(|eid| extent_dir(&self.dir, ExtentId(eid)))
+                .collect();
+            for d in &dirs {
+                sync_path(d, &self.log)?;
+                sync_path(d.parent().unwrap(), &self.log)?;
+            }
+            sync_path(&self.dir, &self.log)?;
+
             write_json(config_path(&self.dir), &self.def, true)?;
-            self.create_extents(backend)?;
+            sync_path(&self.dir, &self.log)?;
         }
         Ok(())
     }
 
+    /// Recompute the hash of every written block in an extent, and compare
+    /// it with the hashes stored in the block's context.
+    pub fn verify_extent(
+        &mut self,
+        eid: ExtentId,
+    ) -> Result<ExtentVerify, CrucibleError> {
+        // Bound how much of the extent is read at once
+        const VERIFY_CHUNK_BLOCKS: u64 = 128;
+
+        let block_size = self.def.block_size();
+        let extent_size = self.def.extent_size().value;
+        let extent = self.get_opened_extent_mut(eid);
+
+        let mut result = ExtentVerify {
+            extent: eid.0,
+            blocks_checked: 0,
+            corrupt_blocks: Vec::new(),
+        };
+
+        let mut offset = 0;
+        while offset < extent_size {
+            let count = (extent_size - offset).min(VERIFY_CHUNK_BLOCKS);
+            let contexts = extent.get_block_contexts(offset, count)?;
+            let read = extent.read(
+                JobId(0),
+                ExtentReadRequest {
+                    offset: BlockOffset(offset),
+                    data: BytesMut::with_capacity(
+                        (count * block_size) as usize,
+                    ),
+                },
+            )?;
+
+            let blocks = contexts
+                .into_iter()
+                .zip(read.data.chunks(block_size as usize));
+            for (block, (context, data)) in (offset..).zip(blocks) {
+                // Blocks that were never written have nothing to check
+                let Some(context) = context else {
+                    continue;
+                };
+                result.blocks_checked += 1;
+
+                let hash = match &context.block_context.encryption_context {
+                    Some(ctx) => {
+                        integrity_hash(&[&ctx.nonce[..], &ctx.tag[..], data])
+                    }
+                    None => integrity_hash(&[data]),
+                };
+
+                if hash != context.block_context.hash
+                    || integrity_hash(&[data]) != context.on_disk_hash
+                {
+                    result.corrupt_blocks.push(block);
+                }
+            }
+
+            offset += count;
+        }
+
+        Ok(result)
+    }
+
     pub fn region_def(&self) -> (u64, Block, u32) {
         (
             self.def.block_size(),
@@ -1107,35 +1259,185 @@ impl Region {
     }
 }
 
+/// How many times to try downloading a repair file before giving up
+const REPAIR_FILE_ATTEMPTS: u32 = 5;
+
 /**
  * Given:
- *   The stream returned to us from the progenitor endpoint
+ *   The URL of a file on a repair server
  *   A local File, already created and opened,
- * Stream the data from the endpoint into the file.
- * When the stream is completed, fsync the file.
+ * Download the file into the local one.  If the download fails part way,
+ * ask for the rest with a Range request rather than starting again.
+ * When the download is completed, fsync the file.
  */
-pub async fn save_stream_to_file(
+pub async fn save_url_to_file(
+    client: &reqwest::Client,
+    url: &str,
     mut file: File,
-    mut stream: repair_client::ByteStream,
+    transfer: &mut RepairTransfer,
+    log: &Logger,
+) -> Result<(), CrucibleError> {
+    let start = std::time::Instant::now();
+    let mut offset = 0;
+
+    loop {
+        match fetch_range(client, url, &mut file, &mut offset, transfer).await {
+            Ok(()) => break,
+            Err(e) => {
+                if transfer.retries + 1 >= REPAIR_FILE_ATTEMPTS {
+                    transfer.elapsed = start.elapsed();
+                    return Err(e);
+                }
+                transfer.retries += 1;
+                warn!(
+                    log,
+                    "repair {}: failed after {} bytes, resuming: {}",
+                    url,
+                    offset,
+                    e
+                );
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+    transfer.elapsed = start.elapsed();
+
+    if let Err(e) = file.sync_all() {
+        crucible_bail!(IoError, "repair {:?}: fsync failure: {:?}", file, e);
+    }
+    Ok(())
+}
+
+/**
+ * Request the part of a file from `offset` onwards, and write it into `file`
+ * at that offset, advancing `offset` as data arrives.
+ */
+async fn fetch_range(
+    client: &reqwest::Client,
+    url: &str,
+    file: &mut File,
+    offset: &mut u64,
+    transfer: &mut RepairTransfer,
 ) -> Result<(), CrucibleError> {
+    let mut request = client.get(url);
+    if *offset > 0 {
+        request = request
+            .header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+
+    let mut response = match request.send().await {
+        Ok(r) => r,
+        Err(e) => {
+            crucible_bail!(RepairRequestError, "repair {}: {:?}", url, e);
+        }
+    };
+
+    match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => {
+            // Make sure we were sent the range we asked for, and not one
+            // that would land at the wrong place in the file
+            let start = response
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_content_range_start);
+            if start != Some(*offset) {
+                crucible_bail!(
+                    RepairRequestError,
+                    "repair {}: asked for bytes from {}, got range {:?}",
+                    url,
+                    offset,
+                    response.headers().get(reqwest::header::CONTENT_RANGE),
+                );
+            }
+        }
+        reqwest::StatusCode::OK => {
+            // The server sent the whole file, so start again
+            if *offset > 0 {
+                file.set_len(0)?;
+                *offset = 0;
+            }
+        }
+        status => {
+            crucible_bail!(
+                RepairRequestError,
+                "repair {}: unexpected status {}",
+                url,
+                status
+            );
+        }
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+
     loop {
-        match stream.try_next().await {
+        match response.chunk().await {
             Ok(Some(bytes)) => {
                 file.write_all(&bytes)?;
+                *offset += bytes.len() as u64;
+                transfer.bytes += bytes.len() as u64;
             }
             Ok(None) => break,
             Err(e) => {
                 crucible_bail!(
                     RepairStreamError,
-                    "repair {:?}: stream error: {:?}",
-                    file,
+                    "repair {}: stream error: {:?}",
+                    url,
                     e
                 );
             }
         }
     }
-    if let Err(e) = file.sync_all() {
-        crucible_bail!(IoError, "repair {:?}: fsync failure: {:?}", file, e);
+    Ok(())
+}
+
+/**
+ * Parse the value of a `Content-Range` header, `bytes <first>-<last>/<len>`,
+ * returning the first byte of the range.
+ */
+fn parse_content_range_start(value: &str) -> Option<u64> {
+    let (range, _len) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end: u64 = end.trim().parse().ok()?;
+    if end < start {
+        return None;
+    }
+    Some(start)
+}
+
+/**
+ * Check a copied extent file against the size and digest the repair server
+ * gave for it.
+ */
+fn check_file_digest(
+    copy_dir: &Path,
+    eid: ExtentId,
+    extent_type: ExtentType,
+    digest: &repair_client::types::ExtentFileDigest,
+) -> Result<(), CrucibleError> {
+    let mut path = copy_dir.join(extent_file_name(eid, ExtentType::Data));
+    path.set_extension(format!("{}", extent_type));
+
+    let size = path.metadata()?.len();
+    if size != digest.size {
+        crucible_bail!(
+            RepairFilesInvalid,
+            "repair file {:?} is {} bytes, expected {}",
+            path,
+            size,
+            digest.size,
+        );
+    }
+
+    let sha256 = file_sha256(&path)?;
+    if sha256 != digest.sha256 {
+        crucible_bail!(
+            RepairFilesInvalid,
+            "repair file {:?} has digest {}, expected {}",
+            path,
+            sha256,
+            digest.sha256,
+        );
     }
     Ok(())
 }
@@ -1949,6 +2251,33 @@ pub(crate) mod test {
         let _ = Region::open(&dir, false, false, &csl());
     }
 
+    #[test]
+    fn extend_interrupted_before_config() {
+        let dir = tempdir().unwrap();
+        let mut r = Region::create(&dir, new_region_options(), csl()).unwrap();
+        r.extend(3, Backend::RawFile).unwrap();
+
+        // Pretend that an extend to 5 extents crashed after creating extent
+        // 3 and part of extent 4, but before updating the region config.
+        let mut def = r.def();
+        def.set_extent_count(5);
+        drop(r);
+        Extent::create(dir.path(), &def, ExtentId(3), Backend::RawFile)
+            .unwrap();
+        std::fs::write(extent_path(dir.path(), ExtentId(4)), b"torn").unwrap();
+
+        // The region still opens at its old size, and extending it again
+        // replaces the leftovers.
+        let mut r = Region::open(&dir, false, false, &csl()).unwrap();
+        assert_eq!(r.def().extent_count(), 3);
+        r.extend(5, Backend::RawFile).unwrap();
+        drop(r);
+
+        let r = Region::open(&dir, false, false, &csl()).unwrap();
+        assert_eq!(r.def().extent_count(), 5);
+        assert_eq!(r.get_opened_extent(ExtentId(4)).number, ExtentId(4));
+    }
+
     #[test]
     #[should_panic]
     fn bad_import_region() {
@@ -2145,6 +2474,17 @@ pub(crate) mod test {
         assert_eq!(buffer, responses.data);
     }
 
+    #[test]
+    fn parse_content_range_header() {
+        assert_eq!(parse_content_range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(parse_content_range_start("bytes 0-0/1"), Some(0));
+
+        assert_eq!(parse_content_range_start("bytes */200"), None);
+        assert_eq!(parse_content_range_start("bytes 100-99/200"), None);
+        assert_eq!(parse_content_range_start("100-199/200"), None);
+        assert_eq!(parse_content_range_start("bytes 100-199"), None);
+    }
+
     #[test]
     fn test_big_write_migrate() -> Result<()> {
         let log = csl();
@@ -3482,6 +3822,31 @@ pub(crate) mod test {
         assert_eq!(&responses.data, &data[(9 * 512)..(28 * 512)],);
     }
 
+    fn test_verify_finds_corrupt_block(backend: Backend) {
+        let (dir, mut region, _data) = prepare_random_region(backend);
+        region.region_flush(1, 0, &None, JobId(0), None).unwrap();
+
+        for eid in (0..3).map(ExtentId) {
+            let result = region.verify_extent(eid).unwrap();
+            assert_eq!(result.blocks_checked, 10);
+            assert!(result.corrupt_blocks.is_empty());
+        }
+        drop(region);
+
+        // Flip a byte in the middle of extent 1's block 3
+        let path = extent_path(&dir, ExtentId(1));
+        let mut data = std::fs::read(&path).unwrap();
+        data[3 * 512 + 100] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        let results =
+            crate::verify_region(dir.path().to_path_buf(), csl()).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].corrupt_blocks.is_empty());
+        assert_eq!(results[1].corrupt_blocks, vec![3]);
+        assert!(results[2].corrupt_blocks.is_empty());
+    }
+
     fn prepare_writes(
         offsets: std::ops::Range<usize>,
         data: &mut [u8],
@@ -3663,7 +4028,8 @@ pub(crate) mod test {
                 test_write_single_large_contiguous_span_extents,
                 test_write_unwritten_single_large_contiguous,
                 test_write_unwritten_single_large_contiguous_span_extents,
-                test_read_single_large_contiguous_span_extents
+                test_read_single_large_contiguous_span_extents,
+                test_verify_finds_corrupt_block
             );
         };
 
@@ -1,22 +1,32 @@
 // Copyright 2022 Oxide Computer Company
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
 use dropshot::ApiDescription;
 use dropshot::ConfigDropshot;
 use dropshot::HandlerTaskMode;
 use dropshot::HttpError;
 use dropshot::HttpResponseOk;
+use dropshot::HttpResponseUpdatedNoContent;
 use dropshot::HttpServerStarter;
 use dropshot::RequestContext;
-use dropshot::{endpoint, Path};
+use dropshot::{endpoint, Path, Query};
 use http::{Response, StatusCode};
 use hyper::Body;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncSeekExt;
 
 use super::*;
-use crate::extent::{extent_dir, extent_file_name, extent_path, ExtentType};
+use crate::extent::{
+    extent_dir, extent_file_name, extent_path, file_sha256, ExtentType,
+};
+use crucible_common::audit::{AuditLog, AuditRecords};
+use crucible_common::metrics::PrometheusText;
+use crucible_common::x509::TLSContext;
+use repair_client::Client;
 
 /**
  * Our context is the root of the region we want to serve.
@@ -26,6 +36,52 @@ pub struct FileServerContext {
     read_only: bool,
     region_definition: RegionDefinition,
     downstairs: DownstairsHandle,
+    dss: DsStatOuter,
+    tls: Option<Arc<TLSContext>>,
+    audit: Arc<AuditLog>,
+    proxy_peers: Option<Arc<ProxyPeers>>,
+}
+
+impl FileServerContext {
+    /// Checks that a request came through the TLS proxy, if there is one
+    fn authorize(
+        &self,
+        request: &dropshot::RequestInfo,
+    ) -> Result<(), HttpError> {
+        match &self.proxy_peers {
+            Some(peers) if !peers.contains(&request.remote_addr()) => {
+                Err(HttpError::for_client_error(
+                    None,
+                    StatusCode::FORBIDDEN,
+                    "repair requests must be made over TLS".to_string(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The local ends of the connections that [`tls_proxy`] has open to the API
+/// server.
+///
+/// With TLS, the API server listens on loopback, where any local process
+/// could reach it without a certificate, so it only answers requests that
+/// arrive on one of these connections.
+#[derive(Debug, Default)]
+struct ProxyPeers(std::sync::Mutex<HashSet<SocketAddr>>);
+
+impl ProxyPeers {
+    fn insert(&self, addr: SocketAddr) {
+        self.0.lock().unwrap().insert(addr);
+    }
+
+    fn remove(&self, addr: &SocketAddr) {
+        self.0.lock().unwrap().remove(addr);
+    }
+
+    fn contains(&self, addr: &SocketAddr) -> bool {
+        self.0.lock().unwrap().contains(addr)
+    }
 }
 
 pub fn write_openapi<W: Write>(f: &mut W) -> Result<()> {
@@ -38,25 +94,57 @@ fn build_api() -> ApiDescription<Arc<FileServerContext>> {
     let mut api = ApiDescription::new();
     api.register(get_extent_file).unwrap();
     api.register(get_files_for_extent).unwrap();
+    api.register(get_file_digests_for_extent).unwrap();
     api.register(get_region_info).unwrap();
     api.register(get_region_mode).unwrap();
     api.register(extent_repair_ready).unwrap();
     api.register(get_work).unwrap();
+    api.register(get_connections).unwrap();
+    api.register(disconnect_upstairs).unwrap();
+    api.register(get_repair_stats).unwrap();
+    api.register(get_metrics).unwrap();
+    api.register(get_audit).unwrap();
+    api.register(get_tls).unwrap();
+    api.register(reload_tls).unwrap();
 
     api
 }
 
 /// Returns Ok(listen address) if everything launched ok, Err otherwise
+///
+/// If `tls` is given, clients connecting with TLS must present a certificate
+/// that chains to the region's root.  Dropshot can't verify client
+/// certificates itself, so the API server then listens on loopback only, and
+/// connections to `addr` are checked here and passed through to it.  The API
+/// server refuses requests on any other connection.  Clients that don't use
+/// TLS are passed through as well, unless the downstairs was set to require
+/// TLS for repair.
 pub fn repair_main(
     ds: &Downstairs,
     addr: SocketAddr,
+    tls: Option<Arc<TLSContext>>,
     log: &Logger,
 ) -> Result<SocketAddr, String> {
+    if let Some(tls) = &tls {
+        tls.get_server_config()
+            .map_err(|e| format!("repair server TLS config: {e:?}"))?;
+    }
+
+    let bind_address = if tls.is_some() {
+        let loopback = match addr {
+            SocketAddr::V4(_) => IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+        };
+        SocketAddr::new(loopback, 0)
+    } else {
+        addr
+    };
+
     /*
      * We must specify a configuration with a bind address.
      */
     let config_dropshot = ConfigDropshot {
-        bind_address: addr,
+        bind_address,
         request_body_max_bytes: 1024,
         default_handler_task_mode: HandlerTaskMode::Detached,
         log_headers: vec![],
@@ -75,13 +163,26 @@ pub fn repair_main(
     let read_only = ds.flags.read_only;
     let region_definition = ds.region.def();
     let handle = ds.handle();
+    let dss = ds.dss.clone();
+    let audit = ds.audit.clone();
+    let proxy_peers = tls.as_ref().map(|_| Arc::new(ProxyPeers::default()));
 
-    info!(log, "Repair listens on {} for path:{:?}", addr, region_dir);
+    info!(
+        log,
+        "Repair listens on {} for path:{:?} with TLS:{}",
+        addr,
+        region_dir,
+        tls.is_some()
+    );
     let context = FileServerContext {
         region_dir,
         read_only,
         region_definition,
         downstairs: handle,
+        dss,
+        tls: tls.clone(),
+        audit,
+        proxy_peers: proxy_peers.clone(),
     };
 
     /*
@@ -95,7 +196,253 @@ pub fn repair_main(
 
     tokio::spawn(server);
 
-    Ok(local_addr)
+    let (Some(tls), Some(proxy_peers)) = (tls, proxy_peers) else {
+        return Ok(local_addr);
+    };
+
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(|l| {
+            l.set_nonblocking(true)?;
+            tokio::net::TcpListener::from_std(l)
+        })
+        .map_err(|e| format!("failed to bind repair TLS listener: {e}"))?;
+    let tls_addr = listener
+        .local_addr()
+        .map_err(|e| format!("repair TLS listener address: {e}"))?;
+
+    tokio::spawn(tls_proxy(
+        listener,
+        tls,
+        ds.repair_tls_only,
+        local_addr,
+        proxy_peers,
+        log.clone(),
+    ));
+
+    Ok(tls_addr)
+}
+
+/**
+ * Accept TLS connections on `listener`, and pass the ones whose client
+ * certificate checks out through to the API server at `server`, recording
+ * each connection to it in `peers` while it's open.  Each connection uses
+ * the TLS material current when it's accepted.
+ *
+ * Unless `tls_only` is set, connections that don't start with a TLS
+ * handshake are passed through too.
+ */
+async fn tls_proxy(
+    listener: tokio::net::TcpListener,
+    tls: Arc<TLSContext>,
+    tls_only: bool,
+    server: SocketAddr,
+    peers: Arc<ProxyPeers>,
+    log: Logger,
+) {
+    loop {
+        let (sock, raddr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(log, "repair TLS listener accept failed: {e}");
+                continue;
+            }
+        };
+
+        let acceptor = match tls.get_server_config() {
+            Ok(config) => tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+            Err(e) => {
+                error!(log, "repair server TLS config: {e:?}");
+                continue;
+            }
+        };
+        let log = log.clone();
+        let peers = peers.clone();
+        tokio::spawn(async move {
+            // A TLS connection starts with a handshake record
+            let mut first = [0u8; 1];
+            let is_tls = match sock.peek(&mut first).await {
+                Ok(1) => first[0] == 0x16,
+                Ok(_) => return,
+                Err(e) => {
+                    debug!(log, "repair connection from {raddr} failed: {e}");
+                    return;
+                }
+            };
+
+            if !is_tls {
+                if tls_only {
+                    warn!(
+                        log,
+                        "rejecting repair connection from {raddr}: the \
+                        client must use TLS with a certificate signed by \
+                        this region's root"
+                    );
+                } else {
+                    // Downstairs from before the repair server used TLS
+                    // still need to repair from us during an upgrade.
+                    proxy(sock, raddr, server, &peers, &log).await;
+                }
+                return;
+            }
+
+            let tls = match acceptor.accept(sock).await {
+                Ok(tls) => tls,
+                Err(e) => {
+                    // The usual cause is a client without a certificate from
+                    // our root.
+                    warn!(
+                        log,
+                        "rejecting repair connection from {raddr}: {e}; \
+                        the client must use TLS with a certificate signed \
+                        by this region's root"
+                    );
+                    return;
+                }
+            };
+
+            proxy(tls, raddr, server, &peers, &log).await;
+        });
+    }
+}
+
+/**
+ * Pass the connection `sock` from `raddr` through to the API server at
+ * `server`, recording the connection to it in `peers` while it's open.
+ */
+async fn proxy<S>(
+    mut sock: S,
+    raddr: SocketAddr,
+    server: SocketAddr,
+    peers: &ProxyPeers,
+    log: &Logger,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut upstream = match tokio::net::TcpStream::connect(server).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!(log, "repair connection to {server} failed: {e}");
+            return;
+        }
+    };
+
+    let peer = match upstream.local_addr() {
+        Ok(peer) => peer,
+        Err(e) => {
+            error!(log, "repair connection to {server} failed: {e}");
+            return;
+        }
+    };
+
+    peers.insert(peer);
+    if let Err(e) =
+        tokio::io::copy_bidirectional(&mut sock, &mut upstream).await
+    {
+        debug!(log, "repair connection from {raddr} ended: {e}");
+    }
+    peers.remove(&peer);
+}
+
+/// How a downstairs reaches other downstairs' repair servers
+#[derive(Clone, Debug)]
+pub struct RepairClient {
+    /// The client, and the TLS generation it was built for
+    client: Arc<std::sync::Mutex<(u64, reqwest::Client)>>,
+    tls: Option<Arc<TLSContext>>,
+    /// The name repair servers' certificates must be issued for
+    server_name: Option<String>,
+    /// Whether repair servers that don't speak TLS are refused, rather than
+    /// reached over plain HTTP
+    tls_only: bool,
+}
+
+impl RepairClient {
+    /// If `tls` is given, connect with TLS and present its certificate, as
+    /// repair servers started with TLS expect.  Their certificates must then
+    /// be issued for `server_name`, or if there is no name, just chain to
+    /// our root.  Unless `tls_only` is set, repair servers that don't speak
+    /// TLS are still reached over plain HTTP.
+    pub fn new(
+        tls: Option<Arc<TLSContext>>,
+        server_name: Option<String>,
+        tls_only: bool,
+    ) -> Result<Self> {
+        let generation = tls.as_ref().map(|t| t.generation()).unwrap_or(0);
+        let client = Self::build(tls.as_deref(), server_name.as_deref())?;
+
+        Ok(RepairClient {
+            client: Arc::new(std::sync::Mutex::new((generation, client))),
+            tls,
+            server_name,
+            tls_only,
+        })
+    }
+
+    fn build(
+        tls: Option<&TLSContext>,
+        server_name: Option<&str>,
+    ) -> Result<reqwest::Client> {
+        let mut builder = reqwest::ClientBuilder::new()
+            .connect_timeout(std::time::Duration::from_secs(15))
+            .timeout(std::time::Duration::from_secs(15));
+
+        if let Some(tls) = tls {
+            builder = builder.use_preconfigured_tls(
+                tls.get_repair_client_config(server_name)?,
+            );
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// The base URL of the repair server at `addr`
+    ///
+    /// With TLS, a repair server that doesn't complete a handshake is taken
+    /// to be from a release whose repair server didn't use TLS, and is
+    /// reached over plain HTTP instead, unless TLS is required.
+    pub async fn url(&self, addr: SocketAddr) -> String {
+        if self.tls.is_none() {
+            return format!("http://{addr}");
+        }
+
+        let url = format!("https://{addr}");
+        if self.tls_only {
+            return url;
+        }
+
+        match self.api(&url).get_region_mode().await {
+            Err(repair_client::Error::CommunicationError(e))
+                if e.is_connect() =>
+            {
+                format!("http://{addr}")
+            }
+            _ => url,
+        }
+    }
+
+    /// A progenitor client for the repair server at `url`
+    pub fn api(&self, url: &str) -> Client {
+        Client::new_with_client(url, self.http())
+    }
+
+    /// The HTTP client, rebuilt first if the TLS material has been reloaded
+    /// since it was made.
+    pub fn http(&self) -> reqwest::Client {
+        let mut client = self.client.lock().unwrap();
+        if let Some(tls) = &self.tls {
+            let generation = tls.generation();
+            if client.0 != generation {
+                // If this fails, keep using the old client: its connections
+                // still work until the old certificate expires.
+                if let Ok(c) =
+                    Self::build(Some(tls), self.server_name.as_deref())
+                {
+                    *client = (generation, c);
+                }
+            }
+        }
+        client.1.clone()
+    }
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -123,6 +470,10 @@ pub struct FileSpec {
     file_type: FileType,
 }
 
+/// Get one of an extent's files
+///
+/// A `Range: bytes=<start>-[<end>]` header asks for only part of the file, so
+/// that an interrupted copy can be resumed.
 #[endpoint {
     method = GET,
     path = "/newextent/{eid}/{file_type}",
@@ -131,6 +482,14 @@ async fn get_extent_file(
     rqctx: RequestContext<Arc<FileServerContext>>,
     path: Path<FileSpec>,
 ) -> Result<Response<Body>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    let range = rqctx
+        .request
+        .headers()
+        .get(http::header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_range);
+
     let fs = path.into_inner();
     let eid = ExtentId(fs.eid);
 
@@ -149,10 +508,32 @@ async fn get_extent_file(
         FileType::Data => (),
     };
 
-    get_a_file(extent_path).await
+    get_a_file(extent_path, range).await
 }
 
-async fn get_a_file(path: PathBuf) -> Result<Response<Body>, HttpError> {
+/**
+ * Parse the value of a `Range` header asking for a single range of bytes,
+ * returning the first byte and (if given) the last.  Anything else, such as
+ * several ranges or a suffix range, is not supported: per RFC 9110 we may
+ * ignore the header then and send the whole file.
+ */
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse().ok()?),
+    };
+    match end {
+        Some(end) if end < start => None,
+        _ => Some((start, end)),
+    }
+}
+
+async fn get_a_file(
+    path: PathBuf,
+    range: Option<(u64, Option<u64>)>,
+) -> Result<Response<Body>, HttpError> {
     /*
      * Make sure our file is neither a link nor a directory.
      */
@@ -174,19 +555,54 @@ async fn get_a_file(path: PathBuf) -> Result<Response<Body>, HttpError> {
             "Expected a file, found a directory".to_string(),
         ))
     } else {
-        let file = tokio::fs::File::open(&path).await.map_err(|e| {
+        let mut file = tokio::fs::File::open(&path).await.map_err(|e| {
             HttpError::for_bad_request(
                 None,
                 format!("file {:?}: {:#}", path, e),
             )
         })?;
-
-        let file_stream = hyper_staticfile::FileBytesStream::new(file);
+        let len = m.len();
         let content_type = "application/octet-stream".to_string();
 
+        let Some((start, end)) = range else {
+            let file_stream = hyper_staticfile::FileBytesStream::new(file);
+
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(http::header::CONTENT_TYPE, content_type)
+                .header(http::header::ACCEPT_RANGES, "bytes")
+                .body(file_stream.into_body())?);
+        };
+
+        if start >= len {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(http::header::CONTENT_RANGE, format!("bytes */{len}"))
+                .body(Body::empty())?);
+        }
+        let end = end.unwrap_or(len - 1).min(len - 1);
+
+        file.seek(std::io::SeekFrom::Start(start))
+            .await
+            .map_err(|e| {
+                HttpError::for_internal_error(format!(
+                    "file {:?} seek to {}: {:#}",
+                    path, start, e
+                ))
+            })?;
+        let file_stream = hyper_staticfile::FileBytesStream::new_with_limit(
+            file,
+            end - start + 1,
+        );
+
         Ok(Response::builder()
-            .status(StatusCode::OK)
+            .status(StatusCode::PARTIAL_CONTENT)
             .header(http::header::CONTENT_TYPE, content_type)
+            .header(http::header::ACCEPT_RANGES, "bytes")
+            .header(
+                http::header::CONTENT_RANGE,
+                format!("bytes {start}-{end}/{len}"),
+            )
             .body(file_stream.into_body())?)
     }
 }
@@ -200,6 +616,7 @@ async fn extent_repair_ready(
     rqctx: RequestContext<Arc<FileServerContext>>,
     path: Path<Eid>,
 ) -> Result<HttpResponseOk<bool>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
     let eid: usize = path.into_inner().eid as usize;
     let downstairs = &rqctx.context().downstairs;
 
@@ -229,8 +646,70 @@ async fn get_files_for_extent(
     rqctx: RequestContext<Arc<FileServerContext>>,
     path: Path<Eid>,
 ) -> Result<HttpResponseOk<Vec<String>>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    let eid = ExtentId(path.into_inner().eid);
+    let extent_dir = checked_extent_dir(&rqctx.context().region_dir, eid)?;
+    let files = extent_file_list(extent_dir, eid)?;
+    Ok(HttpResponseOk(files))
+}
+
+/// One of the files that make up an extent
+#[derive(Serialize, JsonSchema)]
+pub struct ExtentFileDigest {
+    name: String,
+    size: u64,
+    /// Hex SHA-256 digest of the file's contents
+    sha256: String,
+}
+
+/**
+ * Get the list of files related to an extent, with the size and digest of
+ * each.
+ *
+ * The extent should be closed (see `repair-ready`) so that its files don't
+ * change between this call and fetching them.
+ */
+#[endpoint {
+    method = GET,
+    path = "/extent/{eid}/digests",
+}]
+async fn get_file_digests_for_extent(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+    path: Path<Eid>,
+) -> Result<HttpResponseOk<Vec<ExtentFileDigest>>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
     let eid = ExtentId(path.into_inner().eid);
-    let extent_dir = extent_dir(rqctx.context().region_dir.clone(), eid);
+    let extent_dir = checked_extent_dir(&rqctx.context().region_dir, eid)?;
+    let files = extent_file_list(extent_dir.clone(), eid)?;
+
+    // Hashing a large extent takes a while, so keep it off the executor
+    let digests = tokio::task::spawn_blocking(move || {
+        files
+            .into_iter()
+            .map(|name| {
+                let path = extent_dir.join(&name);
+                let size = path.metadata()?.len();
+                let sha256 = file_sha256(&path)?;
+                Ok(ExtentFileDigest { name, size, sha256 })
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(|e| HttpError::for_internal_error(e.to_string()))?
+    .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+
+    Ok(HttpResponseOk(digests))
+}
+
+/**
+ * Return the directory holding an extent's files, checking that it is a
+ * directory and not a symlink.
+ */
+fn checked_extent_dir(
+    region_dir: &std::path::Path,
+    eid: ExtentId,
+) -> Result<PathBuf, HttpError> {
+    let extent_dir = extent_dir(region_dir, eid);
 
     // Some sanity checking on the extent path
     let m = extent_dir.symlink_metadata().map_err(|e| {
@@ -250,8 +729,7 @@ async fn get_files_for_extent(
             format!("Expected {:?} to be a directory", extent_dir),
         ))
     } else {
-        let files = extent_file_list(extent_dir, eid)?;
-        Ok(HttpResponseOk(files))
+        Ok(extent_dir)
     }
 }
 
@@ -293,6 +771,7 @@ fn extent_file_list(
 async fn get_region_info(
     rqctx: RequestContext<Arc<FileServerContext>>,
 ) -> Result<HttpResponseOk<crucible_common::RegionDefinition>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
     let region_definition = rqctx.context().region_definition;
 
     Ok(HttpResponseOk(region_definition))
@@ -306,6 +785,7 @@ async fn get_region_info(
 async fn get_region_mode(
     rqctx: RequestContext<Arc<FileServerContext>>,
 ) -> Result<HttpResponseOk<bool>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
     let read_only = rqctx.context().read_only;
 
     Ok(HttpResponseOk(read_only))
@@ -319,6 +799,7 @@ async fn get_region_mode(
 async fn get_work(
     rqctx: RequestContext<Arc<FileServerContext>>,
 ) -> Result<HttpResponseOk<bool>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
     let downstairs = &rqctx.context().downstairs;
     downstairs
         .show_work()
@@ -326,6 +807,171 @@ async fn get_work(
         .map_err(|e| HttpError::for_internal_error(e.to_string()))
 }
 
+/// Per-connection statistics for each upstairs attached to this downstairs
+#[endpoint {
+    method = GET,
+    path = "/connections",
+}]
+async fn get_connections(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+) -> Result<HttpResponseOk<Vec<UpstairsConnectionStats>>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    Ok(HttpResponseOk(rqctx.context().dss.connections()))
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SessionPath {
+    session_id: Uuid,
+}
+
+/// Forcibly disconnect the upstairs with the given session id
+#[endpoint {
+    method = POST,
+    path = "/connections/{session_id}/disconnect",
+}]
+async fn disconnect_upstairs(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+    path: Path<SessionPath>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    let session_id = path.into_inner().session_id;
+    let found = rqctx
+        .context()
+        .downstairs
+        .disconnect(session_id)
+        .await
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+
+    if found {
+        Ok(HttpResponseUpdatedNoContent())
+    } else {
+        Err(HttpError::for_not_found(
+            None,
+            format!("no upstairs with session id {session_id}"),
+        ))
+    }
+}
+
+/// Totals for the extent files this downstairs has copied from others
+#[endpoint {
+    method = GET,
+    path = "/repair-stats",
+}]
+async fn get_repair_stats(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+) -> Result<HttpResponseOk<RepairTransferStats>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    Ok(HttpResponseOk(rqctx.context().dss.repair_transfers()))
+}
+
+/// Our stats, in the Prometheus text format
+#[endpoint {
+    method = GET,
+    path = "/metrics",
+}]
+async fn get_metrics(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+) -> Result<Response<Body>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    let page = rqctx.context().dss.prometheus();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, PrometheusText::CONTENT_TYPE)
+        .body(page.into())?)
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AuditParams {
+    /// Only return records with a sequence number after this one
+    after: Option<u64>,
+}
+
+/// Lifecycle events recorded since this downstairs started
+///
+/// Only the most recent records are kept in memory; if some were discarded,
+/// `truncated` is set and the rest are in the audit log file.
+#[endpoint {
+    method = GET,
+    path = "/audit",
+}]
+async fn get_audit(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+    query: Query<AuditParams>,
+) -> Result<HttpResponseOk<AuditRecords>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    let after = query.into_inner().after.unwrap_or(0);
+    Ok(HttpResponseOk(rqctx.context().audit.after(after)))
+}
+
+/// The TLS certificates this downstairs is using
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct TlsStatus {
+    /// When our certificate expires
+    pub cert_not_after: Option<DateTime<Utc>>,
+    /// When the soonest-expiring root certificate expires
+    pub root_not_after: Option<DateTime<Utc>>,
+    /// How many times the certificates have been reloaded
+    pub reloads: u64,
+}
+
+impl TlsStatus {
+    fn new(tls: &TLSContext) -> TlsStatus {
+        let expiry = tls.expiry();
+        TlsStatus {
+            cert_not_after: expiry.cert_not_after.map(DateTime::from),
+            root_not_after: expiry.root_not_after.map(DateTime::from),
+            reloads: tls.generation(),
+        }
+    }
+}
+
+fn tls_context(
+    rqctx: &RequestContext<Arc<FileServerContext>>,
+) -> Result<&Arc<TLSContext>, HttpError> {
+    rqctx.context().tls.as_ref().ok_or_else(|| {
+        HttpError::for_bad_request(
+            None,
+            "this downstairs isn't using TLS".to_string(),
+        )
+    })
+}
+
+/// The TLS certificates in use, and when they expire
+#[endpoint {
+    method = GET,
+    path = "/tls",
+}]
+async fn get_tls(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+) -> Result<HttpResponseOk<TlsStatus>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    Ok(HttpResponseOk(TlsStatus::new(tls_context(&rqctx)?)))
+}
+
+/// Read the TLS certificate, key and root PEM files again
+///
+/// New connections use the new certificates, established ones are left
+/// alone.  If the files can't be loaded, the current certificates are kept.
+#[endpoint {
+    method = POST,
+    path = "/tls/reload",
+}]
+async fn reload_tls(
+    rqctx: RequestContext<Arc<FileServerContext>>,
+) -> Result<HttpResponseOk<TlsStatus>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    let tls = tls_context(&rqctx)?;
+    if let Err(e) = tls.reload() {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("failed to reload TLS certificates: {e:?}"),
+        ));
+    }
+    info!(rqctx.log, "reloaded TLS certificates: {:?}", tls.expiry());
+
+    Ok(HttpResponseOk(TlsStatus::new(tls)))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -416,6 +1062,31 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn range_header_parsing() {
+        assert_eq!(parse_range("bytes=100-"), Some((100, None)));
+        assert_eq!(parse_range("bytes=0-99"), Some((0, Some(99))));
+        assert_eq!(parse_range("bytes=5-5"), Some((5, Some(5))));
+
+        // Unsupported or malformed ranges are ignored
+        assert_eq!(parse_range("bytes=-100"), None);
+        assert_eq!(parse_range("bytes=0-9,20-29"), None);
+        assert_eq!(parse_range("bytes=10-5"), None);
+        assert_eq!(parse_range("items=0-9"), None);
+    }
+
+    #[test]
+    fn extent_file_digest() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"abc")?;
+        assert_eq!(
+            file_sha256(&path)?,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_crucible_repair_openapi() {
         let mut raw = Vec::new();
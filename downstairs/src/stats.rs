@@ -2,9 +2,13 @@
 
 use super::*;
 
+pub use crucible_common::metrics::{LatencyHistogram, StageLatencySummary};
+use crucible_common::metrics::{MetricType, PrometheusText, StageLatency};
+use crucible_common::x509::TLSContext;
 use omicron_common::api::internal::nexus::ProducerEndpoint;
 use omicron_common::api::internal::nexus::ProducerKind;
 use oximeter::{
+    histogram::Histogram,
     types::{Cumulative, Sample},
     Metric, MetricsError, Producer, Target,
 };
@@ -12,6 +16,9 @@ use oximeter_producer::{
     Config, ConfigLogging, ConfigLoggingIfExists, ConfigLoggingLevel,
     LogConfig, Server,
 };
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 // These structs are used to construct the required stats for Oximeter.
 #[derive(Debug, Copy, Clone, Target)]
@@ -43,6 +50,188 @@ pub struct Flush {
     #[datum]
     pub count: Cumulative<i64>,
 }
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct RepairFiles {
+    // Count of extent files this downstairs has copied from another for repair
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct RepairBytes {
+    // Bytes received from other downstairs while copying extent files
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct RepairRetries {
+    // Count of extent file downloads resumed after failing part way
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct RepairTime {
+    // Total time spent copying extent files, in microseconds
+    #[datum]
+    pub total_us: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct TlsCertExpiry {
+    // Seconds until our TLS certificate expires, negative once it has
+    #[datum]
+    pub seconds: i64,
+}
+
+// Per-connection stats, for when several (read-only) upstairs are connected
+#[derive(Debug, Copy, Clone, Target)]
+pub struct CrucibleDownstairsConnection {
+    // The UUID of the downstairs
+    pub downstairs_uuid: Uuid,
+    // The UUID of the connected upstairs
+    pub upstairs_uuid: Uuid,
+    // The session of the connected upstairs
+    pub session_id: Uuid,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct ConnectionRead {
+    // Count of reads completed for this connection
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct ConnectionWrite {
+    // Count of writes completed for this connection
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct ConnectionFlush {
+    // Count of flushes completed for this connection
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct ConnectionBytesRead {
+    // Bytes read for this connection
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct ConnectionBytesWritten {
+    // Bytes written for this connection
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct ConnectionLatency {
+    // Total time spent performing jobs for this connection, in microseconds
+    #[datum]
+    pub total_us: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct ConnectionQueueDepth {
+    // Jobs received from this connection which have not yet completed
+    #[datum]
+    pub jobs: i64,
+}
+#[derive(Debug, Clone, Metric)]
+pub struct ConnectionIoLatency {
+    // The stage of the job: "queue", "io" or "total"
+    pub stage: String,
+    // The type of job, e.g. "read"
+    pub op: String,
+    // Time jobs for this connection spent in this stage, in microseconds
+    #[datum]
+    pub latency: Histogram<u64>,
+}
+
+/// Statistics for a single upstairs connection
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct UpstairsConnectionStats {
+    pub upstairs_id: Uuid,
+    pub session_id: Uuid,
+    pub gen: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    /// Jobs other than reads, writes, and flushes (e.g. live repair)
+    pub other: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Jobs received from this upstairs which have not yet completed
+    pub queue_depth: u64,
+    /// Time spent performing each job
+    pub latency: LatencyHistogram,
+    /// Time spent performing each job, by job type
+    pub latency_by_op: BTreeMap<String, LatencyHistogram>,
+    /// Estimated percentiles of the time jobs spent waiting to start
+    /// (`queue`), being performed (`io`), and in total, by job type
+    pub stage_latency: Vec<StageLatencySummary>,
+}
+
+/// The progress of copying one extent file from another downstairs
+#[derive(Debug, Default, Clone)]
+pub struct RepairTransfer {
+    /// Bytes received, including any that were received again after a retry
+    pub bytes: u64,
+    /// How many times the download was resumed after failing
+    pub retries: u32,
+    pub elapsed: Duration,
+}
+
+/// Totals for the extent files this downstairs has copied for repair
+#[derive(
+    Debug, Default, Clone, Serialize, Deserialize, JsonSchema, PartialEq,
+)]
+pub struct RepairTransferStats {
+    /// Files copied successfully
+    pub files: u64,
+    /// Files that could not be copied, or failed their digest check
+    pub failed_files: u64,
+    pub bytes: u64,
+    pub retries: u64,
+    /// Time spent copying files, in microseconds
+    pub transfer_us: u64,
+    /// Average transfer rate across all copies, in bytes per second
+    pub bytes_per_sec: u64,
+}
+
+// Stats for one upstairs connection, both for Oximeter and the repair server
+#[derive(Clone, Debug)]
+struct ConnectionStat {
+    stat_name: CrucibleDownstairsConnection,
+    info: UpstairsConnectionStats,
+    read_count: ConnectionRead,
+    write_count: ConnectionWrite,
+    flush_count: ConnectionFlush,
+    bytes_read: ConnectionBytesRead,
+    bytes_written: ConnectionBytesWritten,
+    latency: ConnectionLatency,
+    queue_depth: ConnectionQueueDepth,
+    stage_latency: StageLatency,
+    io_latency: BTreeMap<(&'static str, &'static str), ConnectionIoLatency>,
+}
+
+impl ConnectionStat {
+    /// Records how long a job of type `op` spent in one stage
+    fn add_latency(
+        &mut self,
+        stage: &'static str,
+        op: &'static str,
+        latency: Duration,
+    ) {
+        self.stage_latency.record(stage, op, latency);
+        let m = self.io_latency.entry((stage, op)).or_insert_with(|| {
+            ConnectionIoLatency {
+                stage: stage.to_string(),
+                op: op.to_string(),
+                latency: Histogram::new(&LatencyHistogram::bucket_bounds_us())
+                    .unwrap(),
+            }
+        });
+        // Sampling can only fail for values which can't be ordered
+        let _ = m.latency.sample(latency.as_micros() as u64);
+    }
+}
 
 // All the counter stats in one struct.
 #[derive(Clone, Debug)]
@@ -52,6 +241,19 @@ pub struct DsCountStat {
     write_count: Write,
     read_count: Read,
     flush_count: Flush,
+
+    // Active upstairs connections, keyed by session id
+    connections: HashMap<Uuid, ConnectionStat>,
+
+    // Extent files copied from other downstairs
+    repair: RepairTransferStats,
+    repair_files: RepairFiles,
+    repair_bytes: RepairBytes,
+    repair_retries: RepairRetries,
+    repair_time: RepairTime,
+
+    // TLS material, if we're using it, to report when it expires
+    tls: Option<Arc<TLSContext>>,
 }
 
 impl DsCountStat {
@@ -62,6 +264,13 @@ impl DsCountStat {
             write_count: Default::default(),
             read_count: Default::default(),
             flush_count: Default::default(),
+            connections: HashMap::new(),
+            repair: Default::default(),
+            repair_files: Default::default(),
+            repair_bytes: Default::default(),
+            repair_retries: Default::default(),
+            repair_time: Default::default(),
+            tls: None,
         }
     }
 }
@@ -100,8 +309,181 @@ impl DsStatOuter {
         *datum += 1;
     }
 
+    /// Starts tracking stats for a newly active upstairs connection
+    pub(crate) fn add_upstairs(&mut self, c: &UpstairsConnection) {
+        let mut dss = self.ds_stat_wrap.lock().unwrap();
+        let stat_name = CrucibleDownstairsConnection {
+            downstairs_uuid: dss.stat_name.downstairs_uuid,
+            upstairs_uuid: c.upstairs_id,
+            session_id: c.session_id,
+        };
+        let info = UpstairsConnectionStats {
+            upstairs_id: c.upstairs_id,
+            session_id: c.session_id,
+            gen: c.gen,
+            reads: 0,
+            writes: 0,
+            flushes: 0,
+            other: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            queue_depth: 0,
+            latency: LatencyHistogram::default(),
+            latency_by_op: BTreeMap::new(),
+            stage_latency: Vec::new(),
+        };
+        dss.connections.insert(
+            c.session_id,
+            ConnectionStat {
+                stat_name,
+                info,
+                read_count: Default::default(),
+                write_count: Default::default(),
+                flush_count: Default::default(),
+                bytes_read: Default::default(),
+                bytes_written: Default::default(),
+                latency: Default::default(),
+                queue_depth: Default::default(),
+                stage_latency: StageLatency::default(),
+                io_latency: BTreeMap::new(),
+            },
+        );
+    }
+
+    /// Stops tracking stats for an upstairs connection
+    pub(crate) fn remove_upstairs(&mut self, c: &UpstairsConnection) {
+        let mut dss = self.ds_stat_wrap.lock().unwrap();
+        dss.connections.remove(&c.session_id);
+    }
+
+    /// Records the number of outstanding jobs for an upstairs connection
+    pub(crate) fn set_queue_depth(
+        &mut self,
+        c: &UpstairsConnection,
+        jobs: usize,
+    ) {
+        let mut dss = self.ds_stat_wrap.lock().unwrap();
+        if let Some(cs) = dss.connections.get_mut(&c.session_id) {
+            cs.info.queue_depth = jobs as u64;
+            *cs.queue_depth.datum_mut() = jobs as i64;
+        }
+    }
+
+    /// Returns stats for every active upstairs connection
+    pub fn connections(&self) -> Vec<UpstairsConnectionStats> {
+        let dss = self.ds_stat_wrap.lock().unwrap();
+        let mut out: Vec<_> = dss
+            .connections
+            .values()
+            .map(|cs| UpstairsConnectionStats {
+                stage_latency: cs.stage_latency.summaries(),
+                ..cs.info.clone()
+            })
+            .collect();
+        out.sort_by_key(|c| (c.upstairs_id, c.session_id));
+        out
+    }
+
+    /// Records the copy of an extent file from another downstairs, whether or
+    /// not it succeeded
+    pub(crate) fn on_repair_transfer(&mut self, t: &RepairTransfer, ok: bool) {
+        let mut dss = self.ds_stat_wrap.lock().unwrap();
+        let us = t.elapsed.as_micros() as u64;
+
+        let r = &mut dss.repair;
+        if ok {
+            r.files += 1;
+        } else {
+            r.failed_files += 1;
+        }
+        r.bytes += t.bytes;
+        r.retries += t.retries as u64;
+        r.transfer_us += us;
+        if r.transfer_us > 0 {
+            r.bytes_per_sec =
+                (r.bytes as u128 * 1_000_000 / r.transfer_us as u128) as u64;
+        }
+
+        if ok {
+            *dss.repair_files.datum_mut() += 1;
+        }
+        *dss.repair_bytes.datum_mut() += t.bytes as i64;
+        *dss.repair_retries.datum_mut() += t.retries as i64;
+        *dss.repair_time.datum_mut() += us as i64;
+    }
+
+    /// Report the expiry of our TLS certificate from now on
+    pub(crate) fn set_tls(&mut self, tls: Arc<TLSContext>) {
+        self.ds_stat_wrap.lock().unwrap().tls = Some(tls);
+    }
+
+    /// Returns totals for extent files copied for repair
+    pub fn repair_transfers(&self) -> RepairTransferStats {
+        self.ds_stat_wrap.lock().unwrap().repair.clone()
+    }
+
+    /// Records a completed job against its upstairs connection
+    fn on_connection_complete(
+        &mut self,
+        c: &UpstairsConnection,
+        m: &Message,
+        bytes_written: usize,
+        queued: Option<Duration>,
+        latency: Duration,
+    ) {
+        let mut dss = self.ds_stat_wrap.lock().unwrap();
+        let Some(cs) = dss.connections.get_mut(&c.session_id) else {
+            return;
+        };
+        match m {
+            Message::FlushAck { .. } => {
+                cs.info.flushes += 1;
+                *cs.flush_count.datum_mut() += 1;
+            }
+            Message::WriteAck { .. } | Message::WriteUnwrittenAck { .. } => {
+                cs.info.writes += 1;
+                cs.info.bytes_written += bytes_written as u64;
+                *cs.write_count.datum_mut() += 1;
+                *cs.bytes_written.datum_mut() += bytes_written as i64;
+            }
+            Message::ReadResponse { data, .. } => {
+                cs.info.reads += 1;
+                cs.info.bytes_read += data.len() as u64;
+                *cs.read_count.datum_mut() += 1;
+                *cs.bytes_read.datum_mut() += data.len() as i64;
+            }
+            _ => cs.info.other += 1,
+        }
+        let op = job_type(m);
+        cs.info.latency.record(latency);
+        cs.info
+            .latency_by_op
+            .entry(op.to_string())
+            .or_default()
+            .record(latency);
+        *cs.latency.datum_mut() += latency.as_micros() as i64;
+
+        cs.add_latency("io", op, latency);
+        if let Some(queued) = queued {
+            cs.add_latency("queue", op, queued);
+            cs.add_latency("total", op, queued + latency);
+        }
+    }
+
     /// Marks this job as complete, updating our stats and firing `cdt` probes
-    pub fn on_complete(&mut self, m: &Message) {
+    ///
+    /// `bytes_written` is the size of the job's write payload (if any),
+    /// `queued` is how long the job waited to start after arriving (if
+    /// known), and `latency` is how long the job took to perform.
+    pub(crate) fn on_complete(
+        &mut self,
+        c: &UpstairsConnection,
+        m: &Message,
+        bytes_written: usize,
+        queued: Option<Duration>,
+        latency: Duration,
+    ) {
+        self.on_connection_complete(c, m, bytes_written, queued, latency);
         match m {
             Message::FlushAck { job_id, .. } => {
                 cdt::submit__flush__done!(|| job_id.0);
@@ -131,6 +513,209 @@ impl DsStatOuter {
             _ => (),
         }
     }
+
+    /// Renders our stats in the Prometheus text format
+    pub fn prometheus(&self) -> String {
+        let dss = self.ds_stat_wrap.lock().unwrap();
+        let region_id = dss.stat_name.downstairs_uuid.to_string();
+        let labels = [("region_id", region_id.as_str())];
+        let mut p = PrometheusText::new();
+
+        let counters = [
+            (
+                "crucible_downstairs_connections_total",
+                "Connections started by an upstairs",
+                dss.up_connect_count.datum().value(),
+            ),
+            (
+                "crucible_downstairs_reads_total",
+                "Reads completed",
+                dss.read_count.datum().value(),
+            ),
+            (
+                "crucible_downstairs_writes_total",
+                "Writes completed",
+                dss.write_count.datum().value(),
+            ),
+            (
+                "crucible_downstairs_flushes_total",
+                "Flushes completed",
+                dss.flush_count.datum().value(),
+            ),
+        ];
+        for (name, help, value) in counters {
+            p.family(name, MetricType::Counter, help);
+            p.sample(name, &labels, value);
+        }
+
+        let r = &dss.repair;
+        let repair = [
+            (
+                "crucible_downstairs_repair_files_total",
+                "Extent files copied from another downstairs",
+                r.files,
+            ),
+            (
+                "crucible_downstairs_repair_failed_files_total",
+                "Extent files which could not be copied",
+                r.failed_files,
+            ),
+            (
+                "crucible_downstairs_repair_bytes_total",
+                "Bytes received while copying extent files",
+                r.bytes,
+            ),
+            (
+                "crucible_downstairs_repair_retries_total",
+                "Extent file downloads resumed after failing part way",
+                r.retries,
+            ),
+        ];
+        for (name, help, value) in repair {
+            p.family(name, MetricType::Counter, help);
+            p.sample(name, &labels, value);
+        }
+        let name = "crucible_downstairs_repair_seconds_total";
+        p.family(name, MetricType::Counter, "Time spent copying extent files");
+        p.sample(name, &labels, r.transfer_us as f64 / 1e6);
+
+        if let Some(seconds) = dss.tls.as_ref().and_then(|tls| {
+            tls.expiry()
+                .cert_secs_remaining(std::time::SystemTime::now())
+        }) {
+            let name = "crucible_downstairs_tls_cert_expiry_seconds";
+            p.family(
+                name,
+                MetricType::Gauge,
+                "Seconds until our TLS certificate expires",
+            );
+            p.sample(name, &labels, seconds);
+        }
+
+        // Per-connection stats, in a stable order
+        let mut conns: Vec<_> = dss.connections.values().collect();
+        conns.sort_by_key(|cs| (cs.info.upstairs_id, cs.info.session_id));
+        let conn_labels: Vec<_> = conns
+            .iter()
+            .map(|cs| {
+                let c = &cs.info;
+                (cs, c.upstairs_id.to_string(), c.session_id.to_string())
+            })
+            .collect();
+        let per_conn: [(
+            &str,
+            &str,
+            MetricType,
+            fn(&UpstairsConnectionStats) -> u64,
+        ); 7] = [
+            (
+                "crucible_downstairs_upstairs_reads_total",
+                "Reads completed for this upstairs",
+                MetricType::Counter,
+                |c| c.reads,
+            ),
+            (
+                "crucible_downstairs_upstairs_writes_total",
+                "Writes completed for this upstairs",
+                MetricType::Counter,
+                |c| c.writes,
+            ),
+            (
+                "crucible_downstairs_upstairs_flushes_total",
+                "Flushes completed for this upstairs",
+                MetricType::Counter,
+                |c| c.flushes,
+            ),
+            (
+                "crucible_downstairs_upstairs_other_jobs_total",
+                "Other jobs (e.g. live repair) completed for this upstairs",
+                MetricType::Counter,
+                |c| c.other,
+            ),
+            (
+                "crucible_downstairs_upstairs_read_bytes_total",
+                "Bytes read for this upstairs",
+                MetricType::Counter,
+                |c| c.bytes_read,
+            ),
+            (
+                "crucible_downstairs_upstairs_written_bytes_total",
+                "Bytes written for this upstairs",
+                MetricType::Counter,
+                |c| c.bytes_written,
+            ),
+            (
+                "crucible_downstairs_upstairs_queue_depth",
+                "Jobs received from this upstairs which have not completed",
+                MetricType::Gauge,
+                |c| c.queue_depth,
+            ),
+        ];
+        for (name, help, kind, f) in per_conn {
+            p.family(name, kind, help);
+            for (c, upstairs_id, session_id) in &conn_labels {
+                let labels = [
+                    ("region_id", region_id.as_str()),
+                    ("upstairs_id", upstairs_id.as_str()),
+                    ("session_id", session_id.as_str()),
+                ];
+                p.sample(name, &labels, f(&c.info));
+            }
+        }
+
+        let name = "crucible_downstairs_job_latency_seconds";
+        p.family(
+            name,
+            MetricType::Histogram,
+            "Time spent performing each job, by job type",
+        );
+        for (c, upstairs_id, session_id) in &conn_labels {
+            for (op, h) in &c.info.latency_by_op {
+                let labels = [
+                    ("region_id", region_id.as_str()),
+                    ("upstairs_id", upstairs_id.as_str()),
+                    ("session_id", session_id.as_str()),
+                    ("op", op.as_str()),
+                ];
+                p.histogram(name, &labels, h);
+            }
+        }
+
+        let name = "crucible_downstairs_io_latency_seconds";
+        p.family(
+            name,
+            MetricType::Histogram,
+            "Time jobs spent in each stage, by job type",
+        );
+        for (c, upstairs_id, session_id) in &conn_labels {
+            for (stage, op, h) in c.stage_latency.iter() {
+                let labels = [
+                    ("region_id", region_id.as_str()),
+                    ("upstairs_id", upstairs_id.as_str()),
+                    ("session_id", session_id.as_str()),
+                    ("stage", stage),
+                    ("op", op),
+                ];
+                p.histogram(name, &labels, h);
+            }
+        }
+
+        p.finish()
+    }
+}
+
+/// Names the type of job that `m` completes, for per-job-type stats
+fn job_type(m: &Message) -> &'static str {
+    match m {
+        Message::FlushAck { .. } => "flush",
+        Message::WriteAck { .. } => "write",
+        Message::WriteUnwrittenAck { .. } => "write_unwritten",
+        Message::ReadResponse { .. } => "read",
+        Message::ExtentLiveCloseAck { .. } => "extent_close",
+        Message::ExtentLiveRepairAckId { .. } => "extent_repair",
+        Message::ExtentLiveAckId { .. } => "extent_live",
+        _ => "other",
+    }
 }
 
 // This trait is what is called to update the data to send to Oximeter.
@@ -145,12 +730,37 @@ impl Producer for DsStatOuter {
         let dss = self.ds_stat_wrap.lock().unwrap();
 
         let name = &dss.stat_name;
-        let data = vec![
+        let mut data = vec![
             Sample::new(name, &dss.up_connect_count)?,
             Sample::new(name, &dss.flush_count)?,
             Sample::new(name, &dss.write_count)?,
             Sample::new(name, &dss.read_count)?,
+            Sample::new(name, &dss.repair_files)?,
+            Sample::new(name, &dss.repair_bytes)?,
+            Sample::new(name, &dss.repair_retries)?,
+            Sample::new(name, &dss.repair_time)?,
         ];
+        if let Some(seconds) = dss.tls.as_ref().and_then(|tls| {
+            tls.expiry()
+                .cert_secs_remaining(std::time::SystemTime::now())
+        }) {
+            data.push(Sample::new(name, &TlsCertExpiry { seconds })?);
+        }
+        for cs in dss.connections.values() {
+            let name = &cs.stat_name;
+            data.extend([
+                Sample::new(name, &cs.read_count)?,
+                Sample::new(name, &cs.write_count)?,
+                Sample::new(name, &cs.flush_count)?,
+                Sample::new(name, &cs.bytes_read)?,
+                Sample::new(name, &cs.bytes_written)?,
+                Sample::new(name, &cs.latency)?,
+                Sample::new(name, &cs.queue_depth)?,
+            ]);
+            for m in cs.io_latency.values() {
+                data.push(Sample::new(name, m)?);
+            }
+        }
 
         // Yield the available samples.
         Ok(Box::new(data.into_iter()))
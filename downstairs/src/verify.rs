@@ -0,0 +1,47 @@
+// Copyright 2024 Oxide Computer Company
+use super::*;
+use serde::{Deserialize, Serialize};
+
+/// The result of checking one extent's blocks against their stored contexts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtentVerify {
+    pub extent: u32,
+    /// Blocks that have been written, and so have a context to check against
+    pub blocks_checked: u64,
+    /// Offsets within the extent of blocks whose data does not match their
+    /// context
+    pub corrupt_blocks: Vec<u64>,
+}
+
+/*
+ * Open a region read-only, and check the data of every written block against
+ * the hashes in its block context.
+ *
+ * If a downstairs is running on this region, a block written while it is
+ * being checked may be reported as corrupt; verify again to confirm.
+ */
+pub fn verify_region(
+    region_dir: PathBuf,
+    log: Logger,
+) -> Result<Vec<ExtentVerify>> {
+    let mut region = Region::open(region_dir, false, true, &log)?;
+
+    let mut results = Vec::new();
+    for eid in (0..region.def().extent_count()).map(ExtentId) {
+        let result = region.verify_extent(eid)?;
+
+        if !result.corrupt_blocks.is_empty() {
+            warn!(
+                log,
+                "extent {} has {} corrupt blocks: {:?}",
+                eid,
+                result.corrupt_blocks.len(),
+                result.corrupt_blocks,
+            );
+        }
+
+        results.push(result);
+    }
+
+    Ok(results)
+}
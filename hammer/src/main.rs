@@ -10,8 +10,7 @@ use crucible::*;
 
 use std::io::{Read, Seek, SeekFrom, Write};
 
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
+use crucible_common::otel;
 
 // https://stackoverflow.com/questions/29504514/whats-the-best-way-to-compare-2-vectors-or-strings-element-by-element
 fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
@@ -31,9 +30,14 @@ pub struct Opt {
     #[clap(short, long, action)]
     verify_isolation: bool,
 
+    /// OTLP collector to export tracing spans to
     #[clap(long, action)]
     tracing_endpoint: Option<String>,
 
+    /// Fraction of guest IOs to trace, if exporting spans
+    #[clap(long, default_value_t = 1.0, action)]
+    trace_sample_rate: f64,
+
     #[clap(short, long, action)]
     key: Option<String>,
 
@@ -88,22 +92,20 @@ async fn main() -> Result<()> {
         key_pem: opt.key_pem,
         root_cert_pem: opt.root_cert_pem,
         control: opt.control,
+        control_token: None,
         read_only: false,
+        client_timeouts: None,
+        replica: None,
+        capture: None,
+        audit_log: None,
     };
 
     if let Some(tracing_endpoint) = opt.tracing_endpoint {
-        let tracer = opentelemetry_jaeger::new_agent_pipeline()
-            .with_endpoint(tracing_endpoint)
-            .with_service_name("crucible-hammer")
-            .install_simple()
-            .expect("Error initializing Jaeger exporter");
-
-        let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-
-        tracing_subscriber::registry()
-            .with(telemetry)
-            .try_init()
-            .expect("Error init tracing subscriber");
+        otel::init_tracing(
+            "crucible-hammer",
+            &tracing_endpoint,
+            opt.trace_sample_rate,
+        )?;
 
         println!("Set up tracing!");
     }
@@ -275,5 +277,6 @@ async fn main() -> Result<()> {
         tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
     }
 
+    otel::shutdown_tracing();
     Ok(())
 }
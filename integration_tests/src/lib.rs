@@ -309,7 +309,12 @@ mod test {
                 key_pem: None,
                 root_cert_pem: None,
                 control: None,
+                control_token: None,
                 read_only,
+                client_timeouts: None,
+                replica: None,
+                capture: None,
+                audit_log: None,
             };
 
             Ok(TestDownstairsSet {
@@ -88,7 +88,12 @@ async fn main() -> Result<()> {
         key_pem: opt.key_pem,
         root_cert_pem: opt.root_cert_pem,
         control: None,
+        control_token: None,
         read_only: false,
+        client_timeouts: None,
+        replica: None,
+        capture: None,
+        audit_log: None,
     };
 
     let (guest, mut io) = Guest::new(None);
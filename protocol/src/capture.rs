@@ -0,0 +1,347 @@
+// Copyright 2024 Oxide Computer Company
+//! Capture files, recording the `Message`s sent and received on a connection
+//!
+//! A capture file starts with `CAPTURE_MAGIC`, followed by one record per
+//! message:
+//!
+//! ```text
+//! u64 (LE)   when the message was sent or received, in ns since the epoch
+//! u8         Direction
+//! ...        the message, framed as by `CrucibleEncoder`
+//! ```
+//!
+//! Messages are always stored in the current encoding (whatever version was
+//! negotiated on the wire), so a plain `CrucibleDecoder` can read them back.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio_util::codec::Decoder;
+
+use crate::{CrucibleDecoder, CrucibleEncoder, Message, MAX_FRM_LEN};
+
+/// Magic bytes (and format version) at the start of every capture file
+pub const CAPTURE_MAGIC: [u8; 8] = *b"CRUCAP01";
+
+/// Records which may be waiting for the writer thread before capturing stops
+const CAPTURE_QUEUE_LEN: usize = 1024;
+
+/// Which way a message went, as seen by whoever recorded it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent = 0,
+    Received = 1,
+}
+
+/// Where capture files go, and what they contain
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    /// Directory in which a capture file is created for each connection
+    pub dir: PathBuf,
+
+    /// Replace read and write data with zeros
+    pub redact: bool,
+}
+
+impl CaptureConfig {
+    /// Creates a capture file for one connection
+    ///
+    /// The file is named `<name>-<ns since the epoch>.crucap`, so that
+    /// reconnections don't overwrite earlier captures.
+    pub fn create(&self, name: &str) -> std::io::Result<Capture> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let path = self.dir.join(format!("{name}-{now}.crucap"));
+        Capture::create(&path, self.redact)
+    }
+}
+
+/// A capture file being written
+///
+/// One of these is shared by a connection's `CrucibleDecoder` and
+/// `MessageWriter`.  Records are encoded by the caller and handed to a
+/// dedicated thread, which does the (buffered) file IO, so that capturing
+/// never blocks an async task.  If writing to the file fails, or the thread
+/// falls more than `CAPTURE_QUEUE_LEN` records behind, capturing stops but the
+/// connection carries on.
+///
+/// Dropping the `Capture` waits for queued records to be written.
+#[derive(Debug)]
+pub struct Capture {
+    tx: Mutex<Option<SyncSender<Bytes>>>,
+    writer: Option<JoinHandle<()>>,
+    redact: bool,
+}
+
+impl Capture {
+    pub fn create(path: &Path, redact: bool) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&CAPTURE_MAGIC)?;
+        file.flush()?;
+        let (tx, rx) = sync_channel(CAPTURE_QUEUE_LEN);
+        let writer = std::thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || Self::write_records(file, rx))?;
+        Ok(Capture {
+            tx: Mutex::new(Some(tx)),
+            writer: Some(writer),
+            redact,
+        })
+    }
+
+    /// Writes records until every sender is gone or writing fails
+    ///
+    /// The file is flushed whenever the queue is empty, so it is never more
+    /// than a queue's worth behind the connection.
+    fn write_records(mut file: BufWriter<File>, rx: Receiver<Bytes>) {
+        while let Ok(record) = rx.recv() {
+            for record in std::iter::once(record).chain(rx.try_iter()) {
+                if file.write_all(&record).is_err() {
+                    return;
+                }
+            }
+            if file.flush().is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Queues a message to be appended to the capture file
+    pub fn record(&self, direction: Direction, m: &Message) {
+        let mut tx = self.tx.lock().unwrap();
+        let Some(t) = tx.as_ref() else {
+            return;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut buf = BytesMut::new();
+        buf.put_u64_le(now.as_nanos() as u64);
+        buf.put_u8(direction as u8);
+        let redacted = if self.redact { redacted(m) } else { None };
+        let r = match &redacted {
+            Some(m) => CrucibleEncoder::encode_frame(m, &mut buf),
+            None => CrucibleEncoder::encode_frame(m, &mut buf),
+        };
+
+        // A message too large to encode would already have failed on the
+        // wire, so just leave it out.
+        if r.is_ok() && t.try_send(buf.freeze()).is_err() {
+            *tx = None;
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        // Hang up, then wait for the writer thread to drain the queue
+        self.tx.get_mut().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Returns a copy of `m` with its bulk data zeroed, if it has any
+fn redacted(m: &Message) -> Option<Message> {
+    let m = match m {
+        Message::Write { header, data } => Message::Write {
+            header: header.clone(),
+            data: Bytes::from(vec![0u8; data.len()]),
+        },
+        Message::WriteUnwritten { header, data } => Message::WriteUnwritten {
+            header: header.clone(),
+            data: Bytes::from(vec![0u8; data.len()]),
+        },
+        Message::ReadResponse { header, data } => Message::ReadResponse {
+            header: header.clone(),
+            data: BytesMut::zeroed(data.len()),
+        },
+        _ => return None,
+    };
+    Some(m)
+}
+
+/// One message read back from a capture file
+#[derive(Debug, PartialEq)]
+pub struct CaptureRecord {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+/// Reads the records of a capture file in order
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Checks the capture file header, and returns a reader for its records
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let mut magic = [0u8; CAPTURE_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != CAPTURE_MAGIC {
+            bail!("not a capture file (bad magic {magic:x?})");
+        }
+        Ok(CaptureReader { reader })
+    }
+
+    /// Returns the next record, or `None` at the end of the file
+    pub fn next_record(&mut self) -> anyhow::Result<Option<CaptureRecord>> {
+        // time (8), direction (1), frame length (4)
+        let mut head = [0u8; 13];
+        if self.reader.read(&mut head[..1])? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut head[1..])?;
+
+        let time = u64::from_le_bytes(head[..8].try_into().unwrap());
+        let direction = match head[8] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            d => bail!("bad direction {d}"),
+        };
+        let len = u32::from_le_bytes(head[9..].try_into().unwrap()) as usize;
+        if !(4..=MAX_FRM_LEN).contains(&len) {
+            bail!("bad frame length {len}");
+        }
+
+        let mut frame = BytesMut::zeroed(len);
+        frame[..4].copy_from_slice(&head[9..]);
+        self.reader.read_exact(&mut frame[4..])?;
+
+        // Use a fresh decoder each time, so that a captured `YesItsMe` can't
+        // switch it to an older version.
+        let message = CrucibleDecoder::new()
+            .decode(&mut frame)?
+            .ok_or_else(|| anyhow!("incomplete frame"))?;
+
+        Ok(Some(CaptureRecord {
+            time: UNIX_EPOCH + Duration::from_nanos(time),
+            direction,
+            message,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = anyhow::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{JobId, WriteHeader};
+    use crucible_common::BlockIndex;
+    use uuid::Uuid;
+
+    fn write(data: &[u8]) -> Message {
+        Message::Write {
+            header: WriteHeader {
+                upstairs_id: Uuid::new_v4(),
+                session_id: Uuid::new_v4(),
+                job_id: JobId(1000),
+                dependencies: vec![JobId(999)],
+                start: BlockIndex(7),
+                contexts: vec![],
+            },
+            data: Bytes::copy_from_slice(data),
+        }
+    }
+
+    fn read_back(path: &Path) -> Vec<CaptureRecord> {
+        CaptureReader::new(File::open(path).unwrap())
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn capture_round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("crucap-rt-{}.crucap", std::process::id()));
+        let messages = [write(&[1, 2, 3, 4]), Message::Ruok, Message::Imok];
+
+        let before = SystemTime::now();
+        let capture = Capture::create(&path, false).unwrap();
+        capture.record(Direction::Received, &messages[0]);
+        capture.record(Direction::Sent, &messages[1]);
+        capture.record(Direction::Received, &messages[2]);
+        drop(capture);
+
+        let records = read_back(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        for (r, m) in records.iter().zip(&messages) {
+            assert_eq!(&r.message, m);
+            assert!(r.time >= before);
+        }
+        assert_eq!(records[0].direction, Direction::Received);
+        assert_eq!(records[1].direction, Direction::Sent);
+    }
+
+    #[test]
+    fn capture_redacts_data() {
+        let path = std::env::temp_dir()
+            .join(format!("crucap-redact-{}.crucap", std::process::id()));
+        let m = write(&[1, 2, 3, 4]);
+
+        let capture = Capture::create(&path, true).unwrap();
+        capture.record(Direction::Sent, &m);
+        drop(capture);
+
+        let records = read_back(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message, redacted(&m).unwrap());
+        let Message::Write { data, .. } = &records[0].message else {
+            panic!("expected a write");
+        };
+        assert_eq!(&data[..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn capture_flushes_while_open() {
+        let path = std::env::temp_dir()
+            .join(format!("crucap-flush-{}.crucap", std::process::id()));
+
+        let capture = Capture::create(&path, false).unwrap();
+        capture.record(Direction::Sent, &Message::Ruok);
+
+        // The writer thread flushes once it has caught up, without waiting
+        // for the capture to be dropped.
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while read_back(&path).is_empty() {
+            assert!(std::time::Instant::now() < deadline, "never flushed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(capture);
+
+        let records = read_back(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message, Message::Ruok);
+    }
+
+    #[test]
+    fn capture_rejects_other_files() {
+        let r = CaptureReader::new(&b"not a capture"[..]);
+        assert!(r.is_err());
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2024 Oxide Computer Company
+//! Earlier message formats, for peers one `MessageVersion` behind us
+//!
+//! Only the messages whose encoding changed need a type here; everything
+//! else is sent the same way in every supported version.
+//!
+//! The handshake messages are handled separately: they're exchanged before a
+//! version is agreed on, so new fields are only ever appended to them, and
+//! are treated as absent when a peer doesn't send them.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    BlockContext, Capabilities, JobId, Message, MessageDiscriminants,
+    ReadBlockContext, ReadResponseHeader,
+};
+use crucible_common::{integrity_hash, CrucibleError};
+
+/// `ReadResponseHeader` as of `MessageVersion::V10`, with an
+/// `Option<BlockContext>` rather than a `ReadBlockContext` for each block
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub(crate) struct ReadResponseHeaderV10 {
+    pub upstairs_id: Uuid,
+    pub session_id: Uuid,
+    pub job_id: JobId,
+    pub blocks: Result<Vec<Option<BlockContext>>, CrucibleError>,
+}
+
+impl ReadResponseHeaderV10 {
+    /// Converts a current header for sending to an older upstairs
+    ///
+    /// Encrypted blocks no longer carry an integrity hash, but older upstairs
+    /// check one, so it's recomputed here from the nonce, tag and block data.
+    pub fn from_current(h: ReadResponseHeader, data: &[u8]) -> Self {
+        let blocks = h.blocks.map(|blocks| {
+            let block_size = data.len().checked_div(blocks.len()).unwrap_or(0);
+            blocks
+                .into_iter()
+                .enumerate()
+                .map(|(i, b)| match b {
+                    ReadBlockContext::Empty => None,
+                    ReadBlockContext::Unencrypted { hash } => {
+                        Some(BlockContext {
+                            hash,
+                            encryption_context: None,
+                        })
+                    }
+                    ReadBlockContext::Encrypted { ctx } => {
+                        let block = &data[i * block_size..(i + 1) * block_size];
+                        Some(BlockContext {
+                            hash: integrity_hash(&[
+                                &ctx.nonce[..],
+                                &ctx.tag[..],
+                                block,
+                            ]),
+                            encryption_context: Some(ctx),
+                        })
+                    }
+                })
+                .collect()
+        });
+
+        ReadResponseHeaderV10 {
+            upstairs_id: h.upstairs_id,
+            session_id: h.session_id,
+            job_id: h.job_id,
+            blocks,
+        }
+    }
+
+    /// Converts a header from an older downstairs
+    pub fn into_current(self) -> ReadResponseHeader {
+        let blocks = self.blocks.map(|blocks| {
+            blocks
+                .into_iter()
+                .map(|b| match b {
+                    None => ReadBlockContext::Empty,
+                    Some(BlockContext {
+                        encryption_context: Some(ctx),
+                        ..
+                    }) => ReadBlockContext::Encrypted { ctx },
+                    Some(BlockContext {
+                        hash,
+                        encryption_context: None,
+                    }) => ReadBlockContext::Unencrypted { hash },
+                })
+                .collect()
+        });
+
+        ReadResponseHeader {
+            upstairs_id: self.upstairs_id,
+            session_id: self.session_id,
+            job_id: self.job_id,
+            blocks,
+        }
+    }
+}
+
+/// The fields of `Message::HereIAm` that every peer sends
+#[derive(Deserialize)]
+struct HereIAmBase {
+    version: u32,
+    upstairs_id: Uuid,
+    session_id: Uuid,
+    gen: u64,
+    read_only: bool,
+    encrypted: bool,
+    alternate_versions: Vec<u32>,
+}
+
+/// The fields of `Message::YesItsMe` that every peer sends
+#[derive(Deserialize)]
+struct YesItsMeBase {
+    version: u32,
+    repair_addr: SocketAddr,
+}
+
+/// Decodes a `HereIAm` or `YesItsMe`, which may lack trailing `capabilities`
+pub(crate) fn decode_handshake(buf: &[u8]) -> Result<Message, bincode::Error> {
+    let mut rd = buf;
+    let discriminant: MessageDiscriminants =
+        bincode::deserialize_from(&mut rd)?;
+    let m = match discriminant {
+        MessageDiscriminants::HereIAm => {
+            let b: HereIAmBase = bincode::deserialize_from(&mut rd)?;
+            Message::HereIAm {
+                version: b.version,
+                upstairs_id: b.upstairs_id,
+                session_id: b.session_id,
+                gen: b.gen,
+                read_only: b.read_only,
+                encrypted: b.encrypted,
+                alternate_versions: b.alternate_versions,
+                capabilities: trailing_capabilities(&mut rd)?,
+            }
+        }
+        MessageDiscriminants::YesItsMe => {
+            let b: YesItsMeBase = bincode::deserialize_from(&mut rd)?;
+            Message::YesItsMe {
+                version: b.version,
+                repair_addr: b.repair_addr,
+                capabilities: trailing_capabilities(&mut rd)?,
+            }
+        }
+        d => panic!("{d:?} is not a handshake message"),
+    };
+    Ok(m)
+}
+
+/// Reads `capabilities` if the peer sent them, or returns an empty set
+fn trailing_capabilities(
+    rd: &mut &[u8],
+) -> Result<Capabilities, bincode::Error> {
+    if rd.is_empty() {
+        Ok(Capabilities::empty())
+    } else {
+        bincode::deserialize_from(rd)
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright 2024 Oxide Computer Company
+//! Optional compression of bulk data on the wire
+//!
+//! Once both sides of a connection have negotiated a compression capability,
+//! the bulk data of `Write`, `WriteUnwritten` and `ReadResponse` messages may
+//! be compressed.  That's decided for each message: data smaller than
+//! `COMPRESSION_THRESHOLD` is sent as-is, as is encrypted data (which won't
+//! compress) and anything that doesn't get smaller.
+//!
+//! A compressed frame sets `COMPRESSED_FRAME` in its length prefix, and puts
+//! the algorithm and the uncompressed length before the usual message body:
+//!
+//! ```text
+//! u32 (LE)   frame length | COMPRESSED_FRAME
+//! u8         Compression
+//! u64 (LE)   uncompressed length of the bulk data
+//! ...        the message, with its bulk data replaced by the compressed bytes
+//! ```
+//!
+//! Only the bulk data is transformed: block hashes in the message header are
+//! always those of the uncompressed data.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use bytes::BytesMut;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{Capabilities, Message, ReadBlockContext, MAX_FRM_LEN};
+
+/// Flag set in the length prefix of a compressed frame
+///
+/// Frames are never longer than `MAX_FRM_LEN`, so the top bit is free.
+pub(crate) const COMPRESSED_FRAME: u32 = 1 << 31;
+
+/// Bytes between the length prefix and the body of a compressed frame
+pub(crate) const COMPRESSED_PREFIX_LEN: usize = 9;
+
+/// Bulk data smaller than this is never compressed
+pub const COMPRESSION_THRESHOLD: usize = 4096;
+
+/// zstd level to compress with, trading ratio for speed
+const ZSTD_LEVEL: i32 = 1;
+
+/// A compression algorithm for bulk data
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl Compression {
+    /// The capability that both sides must have to use this algorithm
+    pub const fn capability(self) -> Capabilities {
+        match self {
+            Compression::Lz4 => Capabilities::COMPRESS_LZ4,
+            Compression::Zstd => Capabilities::COMPRESS_ZSTD,
+        }
+    }
+
+    /// Picks the algorithm to use given a connection's capabilities
+    ///
+    /// The downstairs normally offers at most one; if both were negotiated,
+    /// zstd wins for its better ratio.
+    pub fn negotiated(capabilities: Capabilities) -> Option<Self> {
+        [Compression::Zstd, Compression::Lz4]
+            .into_iter()
+            .find(|c| capabilities.contains(c.capability()))
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Lz4 => lz4_flex::block::compress(data),
+            // Compressing into a growable buffer only fails on allocation
+            // failure, which would abort us anyways
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)
+                .expect("zstd compression failed"),
+        }
+    }
+
+    fn decompress(self, data: &[u8], len: usize) -> anyhow::Result<BytesMut> {
+        let mut out = BytesMut::zeroed(len);
+        let n = match self {
+            Compression::Lz4 => {
+                lz4_flex::block::decompress_into(data, &mut out[..])?
+            }
+            Compression::Zstd => {
+                zstd::bulk::decompress_to_buffer(data, &mut out[..])?
+            }
+        };
+        if n != len {
+            bail!("{self} data decompressed to {n} bytes, expected {len}");
+        }
+        Ok(out)
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => {
+                Err(format!("unknown compression {s:?}, expected lz4 or zstd"))
+            }
+        }
+    }
+}
+
+/// Running totals of the compression done on a connection
+///
+/// Shared by a connection's encoder and decoder, and possibly kept across
+/// reconnections, so both directions are counted together.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    messages: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+    cpu_ns: AtomicU64,
+}
+
+impl CompressionStats {
+    fn record(&self, uncompressed: usize, compressed: usize, time: Duration) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.uncompressed_bytes
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.compressed_bytes
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+        self.cpu_ns
+            .fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CompressionCounts {
+        CompressionCounts {
+            messages: self.messages.load(Ordering::Relaxed),
+            uncompressed_bytes: self.uncompressed_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+            cpu_us: self.cpu_ns.load(Ordering::Relaxed) / 1000,
+        }
+    }
+}
+
+/// A point-in-time copy of `CompressionStats`
+///
+/// Only messages that were actually sent or received compressed count.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+)]
+pub struct CompressionCounts {
+    pub messages: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+    /// Time spent compressing and decompressing
+    pub cpu_us: u64,
+}
+
+impl CompressionCounts {
+    /// Uncompressed bytes per compressed byte, if anything was compressed
+    pub fn ratio(&self) -> Option<f64> {
+        (self.compressed_bytes != 0).then(|| {
+            self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+        })
+    }
+}
+
+/// Bulk data that has been compressed for the wire
+pub(crate) struct CompressedData {
+    pub algorithm: Compression,
+    pub uncompressed_len: usize,
+    pub data: Vec<u8>,
+}
+
+impl CompressedData {
+    /// Compresses the bulk data of `m`, if that's worth doing
+    pub fn new(
+        m: &Message,
+        algorithm: Compression,
+        stats: &CompressionStats,
+    ) -> Option<Self> {
+        let data = compressible_data(m)?;
+        if data.len() < COMPRESSION_THRESHOLD {
+            return None;
+        }
+
+        let start = Instant::now();
+        let compressed = algorithm.compress(data);
+        if compressed.len() >= data.len() {
+            return None;
+        }
+        stats.record(data.len(), compressed.len(), start.elapsed());
+        Some(CompressedData {
+            algorithm,
+            uncompressed_len: data.len(),
+            data: compressed,
+        })
+    }
+
+    /// Returns the bytes that go between the length prefix and the body
+    pub fn prefix(&self) -> [u8; COMPRESSED_PREFIX_LEN] {
+        let mut out = [0u8; COMPRESSED_PREFIX_LEN];
+        out[0] = self.algorithm as u8;
+        out[1..].copy_from_slice(&(self.uncompressed_len as u64).to_le_bytes());
+        out
+    }
+}
+
+/// Returns the bulk data of `m`, unless it has none or it's encrypted
+fn compressible_data(m: &Message) -> Option<&[u8]> {
+    match m {
+        Message::Write { header, data }
+        | Message::WriteUnwritten { header, data } => header
+            .contexts
+            .iter()
+            .all(|c| c.encryption_context.is_none())
+            .then_some(&data[..]),
+        Message::ReadResponse { header, data } => match &header.blocks {
+            Ok(blocks)
+                if !blocks.iter().any(|b| {
+                    matches!(b, ReadBlockContext::Encrypted { .. })
+                }) =>
+            {
+                Some(&data[..])
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The prefix of a compressed frame, which says how to decompress it
+pub(crate) struct CompressedPrefix {
+    algorithm: Compression,
+    uncompressed_len: usize,
+}
+
+impl CompressedPrefix {
+    /// Removes the prefix from the front of a compressed frame's body
+    pub fn take(buf: &mut BytesMut) -> anyhow::Result<Self> {
+        if buf.len() < COMPRESSED_PREFIX_LEN {
+            bail!("compressed frame is too short");
+        }
+        let prefix = buf.split_to(COMPRESSED_PREFIX_LEN);
+        let algorithm = Compression::from_u8(prefix[0])
+            .ok_or_else(|| anyhow!("unknown compression {}", prefix[0]))?;
+        let uncompressed_len =
+            u64::from_le_bytes(prefix[1..].try_into().unwrap()) as usize;
+        if uncompressed_len > MAX_FRM_LEN {
+            bail!("compressed data expands to {uncompressed_len} bytes");
+        }
+        Ok(CompressedPrefix {
+            algorithm,
+            uncompressed_len,
+        })
+    }
+
+    /// Replaces the compressed bulk data of `m` with the original
+    pub fn decompress(
+        &self,
+        m: Message,
+        stats: &CompressionStats,
+    ) -> anyhow::Result<Message> {
+        let start = Instant::now();
+        let d = |data: &[u8]| {
+            let out = self.algorithm.decompress(data, self.uncompressed_len)?;
+            stats.record(out.len(), data.len(), start.elapsed());
+            anyhow::Ok(out)
+        };
+        let m = match m {
+            Message::Write { header, data } => Message::Write {
+                header,
+                data: d(&data)?.freeze(),
+            },
+            Message::WriteUnwritten { header, data } => {
+                Message::WriteUnwritten {
+                    header,
+                    data: d(&data)?.freeze(),
+                }
+            }
+            Message::ReadResponse { header, data } => Message::ReadResponse {
+                header,
+                data: d(&data)?,
+            },
+            m => bail!("compressed frame for {m}, which has no bulk data"),
+        };
+        Ok(m)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        BlockContext, ConnectionVersion, CrucibleDecoder, CrucibleEncoder,
+        EncryptionContext, JobId, MessageWriter, WriteHeader,
+    };
+    use bytes::Bytes;
+    use crucible_common::BlockIndex;
+    use std::sync::Arc;
+    use tokio_util::codec::{Decoder, Encoder};
+    use uuid::Uuid;
+
+    fn write(data: Vec<u8>, encrypted: bool) -> Message {
+        let ctx = BlockContext {
+            hash: 123,
+            encryption_context: encrypted.then_some(EncryptionContext {
+                nonce: [1; 12],
+                tag: [2; 16],
+            }),
+        };
+        Message::Write {
+            header: WriteHeader {
+                upstairs_id: Uuid::new_v4(),
+                session_id: Uuid::new_v4(),
+                job_id: JobId(1000),
+                dependencies: vec![],
+                start: BlockIndex(0),
+                contexts: vec![ctx; data.len() / 512],
+            },
+            data: Bytes::from(data),
+        }
+    }
+
+    fn connection(c: Compression) -> ConnectionVersion {
+        let version = ConnectionVersion::new();
+        version.set_capabilities(c.capability());
+        version
+    }
+
+    fn is_compressed(frame: &[u8]) -> bool {
+        let len = u32::from_le_bytes(frame[..4].try_into().unwrap());
+        len & COMPRESSED_FRAME != 0
+    }
+
+    #[test]
+    fn rt_compressed_write() -> anyhow::Result<()> {
+        for c in [Compression::Lz4, Compression::Zstd] {
+            let stats = Arc::new(CompressionStats::default());
+            let version =
+                ConnectionVersion::with_compression_stats(stats.clone());
+            version.set_capabilities(c.capability());
+            assert_eq!(version.compression(), Some(c));
+            let mut enc = CrucibleEncoder::with_version(version.clone());
+            let mut dec = CrucibleDecoder::with_version(version);
+
+            let input = write(vec![7u8; 64 * 1024], false);
+            let mut buf = BytesMut::new();
+            enc.encode(&input, &mut buf)?;
+            assert!(is_compressed(&buf));
+            assert!(buf.len() < 64 * 1024);
+            assert_eq!(dec.decode(&mut buf)?, Some(input));
+
+            // Compressed once, then decompressed once
+            let counts = stats.snapshot();
+            assert_eq!(counts.messages, 2);
+            assert_eq!(counts.uncompressed_bytes, 2 * 64 * 1024);
+            assert!(counts.ratio().unwrap() > 1.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn small_or_encrypted_data_is_not_compressed() -> anyhow::Result<()> {
+        let mut enc =
+            CrucibleEncoder::with_version(connection(Compression::Lz4));
+        for input in [
+            write(vec![7u8; COMPRESSION_THRESHOLD / 2], false),
+            write(vec![7u8; 64 * 1024], true),
+        ] {
+            let mut buf = BytesMut::new();
+            enc.encode(&input, &mut buf)?;
+            assert!(!is_compressed(&buf));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn not_compressed_unless_negotiated() -> anyhow::Result<()> {
+        let mut enc = CrucibleEncoder::new();
+        let mut buf = BytesMut::new();
+        enc.encode(&write(vec![7u8; 64 * 1024], false), &mut buf)?;
+        assert!(!is_compressed(&buf));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_writer_compresses() -> anyhow::Result<()> {
+        let version = connection(Compression::Zstd);
+        let mut fw = MessageWriter::with_version(Vec::new(), version.clone());
+        let input = write(vec![7u8; 64 * 1024], false);
+        fw.send(input.clone()).await?;
+
+        let mut buf = BytesMut::from(&fw.into_inner()[..]);
+        assert!(is_compressed(&buf));
+        let mut dec = CrucibleDecoder::with_version(version);
+        assert_eq!(dec.decode(&mut buf)?, Some(input));
+        Ok(())
+    }
+
+    #[test]
+    fn compression_names() {
+        for c in [Compression::Lz4, Compression::Zstd] {
+            assert_eq!(c.to_string().parse::<Compression>(), Ok(c));
+        }
+        assert!("gzip".parse::<Compression>().is_err());
+        assert_eq!(
+            Compression::negotiated(Capabilities::COMPRESSION),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::negotiated(Capabilities::empty()), None);
+    }
+}
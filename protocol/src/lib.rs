@@ -1,6 +1,8 @@
 // Copyright 2021 Oxide Computer Company
 use std::cmp::Ordering;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
 
 use anyhow::bail;
 use bytes::{BufMut, BytesMut};
@@ -14,6 +16,18 @@ const MAX_FRM_LEN: usize = 100 * 1024 * 1024; // 100M
 
 use crucible_common::{BlockIndex, CrucibleError, ExtentId, RegionDefinition};
 
+pub mod capture;
+mod compat;
+mod compression;
+use capture::{Capture, Direction};
+use compat::ReadResponseHeaderV10;
+use compression::{
+    CompressedData, CompressedPrefix, COMPRESSED_FRAME, COMPRESSED_PREFIX_LEN,
+};
+pub use compression::{
+    Compression, CompressionCounts, CompressionStats, COMPRESSION_THRESHOLD,
+};
+
 /// Wrapper type for a job ID
 ///
 /// A job ID is used to identify a specific job to the downstairs.  It is used
@@ -89,16 +103,29 @@ impl std::fmt::Display for ReconciliationId {
 pub struct ClientId(u8);
 
 impl ClientId {
+    /// Largest number of downstairs that may make up a single region set
+    pub const MAX_CLIENTS: usize = 8;
+
     /// Builds a new client ID
     ///
     /// # Panics
-    /// If `i >= 3`, the ID is invalid and this constructor will panic
+    /// If `i >= ClientId::MAX_CLIENTS`, the ID is invalid and this constructor
+    /// will panic
     pub fn new(i: u8) -> Self {
-        assert!(i < 3);
+        assert!((i as usize) < Self::MAX_CLIENTS);
         Self(i)
     }
+    /// Iterates over the client IDs of a (default) three-way region set
     pub fn iter() -> impl Iterator<Item = Self> {
-        (0..3).map(Self)
+        Self::iter_n(3)
+    }
+    /// Iterates over the client IDs of a region set with `n` members
+    ///
+    /// # Panics
+    /// If `n > ClientId::MAX_CLIENTS`
+    pub fn iter_n(n: usize) -> impl Iterator<Item = Self> {
+        assert!(n <= Self::MAX_CLIENTS);
+        (0..n as u8).map(Self)
     }
     pub fn get(&self) -> u8 {
         self.0
@@ -218,6 +245,236 @@ impl MessageVersion {
  */
 pub const CRUCIBLE_MESSAGE_VERSION: u32 = 11;
 
+/**
+ * Every Message version we can speak, newest first.  Keeping the previous
+ * version here lets upstairs and downstairs be upgraded one at a time.
+ *
+ * When bumping CRUCIBLE_MESSAGE_VERSION, replace the oldest version here,
+ * and teach `CrucibleEncoder` / `CrucibleDecoder` (and `MessageWriter`) how
+ * to convert whatever changed, with the old types in `compat.rs`.
+ */
+pub const SUPPORTED_MESSAGE_VERSIONS: [u32; 2] =
+    [MessageVersion::V11 as u32, MessageVersion::V10 as u32];
+
+/// Picks the newest version that we and a peer offering `version` and
+/// `alternate_versions` both speak, or `None` if there isn't one.
+pub fn negotiate_version(
+    version: u32,
+    alternate_versions: &[u32],
+) -> Option<u32> {
+    SUPPORTED_MESSAGE_VERSIONS
+        .into_iter()
+        .find(|v| *v == version || alternate_versions.contains(v))
+}
+
+/// The Message version and capabilities in use on one connection
+///
+/// Connections start out at `CRUCIBLE_MESSAGE_VERSION`, with no capabilities.
+/// The version and capabilities picked by the downstairs take effect as soon
+/// as its `YesItsMe` has been encoded (or decoded, on the upstairs), so the
+/// encoder and decoder for a connection should share one of these to switch
+/// together.
+#[derive(Clone, Debug)]
+pub struct ConnectionVersion(Arc<ConnectionVersionInner>);
+
+#[derive(Debug)]
+struct ConnectionVersionInner {
+    version: AtomicU32,
+    capabilities: AtomicU64,
+    compression_stats: Arc<CompressionStats>,
+}
+
+impl ConnectionVersion {
+    pub fn new() -> Self {
+        Self::with_compression_stats(Arc::default())
+    }
+
+    /// Builds a connection state that counts compression in `stats`
+    pub fn with_compression_stats(stats: Arc<CompressionStats>) -> Self {
+        Self(Arc::new(ConnectionVersionInner {
+            version: AtomicU32::new(CRUCIBLE_MESSAGE_VERSION),
+            capabilities: AtomicU64::new(0),
+            compression_stats: stats,
+        }))
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.version.load(AtomicOrdering::Acquire)
+    }
+
+    /// Switches to `version`, if it's one we support
+    pub fn set(&self, version: u32) {
+        if SUPPORTED_MESSAGE_VERSIONS.contains(&version) {
+            self.0.version.store(version, AtomicOrdering::Release);
+        }
+    }
+
+    /// Returns the capabilities in use on this connection
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_bits(
+            self.0.capabilities.load(AtomicOrdering::Acquire),
+        )
+    }
+
+    /// Switches to the capabilities in `c` that we support
+    pub fn set_capabilities(&self, c: Capabilities) {
+        let c = c.intersection(CRUCIBLE_CAPABILITIES);
+        self.0.capabilities.store(c.bits(), AtomicOrdering::Release);
+    }
+
+    /// Switches to the version and capabilities in a `YesItsMe`, ignoring
+    /// any other message
+    fn observe(&self, m: &Message) {
+        if let Message::YesItsMe {
+            version,
+            capabilities,
+            ..
+        } = m
+        {
+            self.set(*version);
+            self.set_capabilities(*capabilities);
+        }
+    }
+
+    /// Whether `ReadResponse` uses the `MessageVersion::V10` header
+    fn is_v10(&self) -> bool {
+        self.get() == MessageVersion::V10 as u32
+    }
+
+    /// Returns the compression to use for bulk data, if any
+    ///
+    /// Peers on `MessageVersion::V10` predate capabilities, so never get
+    /// compressed data.
+    pub fn compression(&self) -> Option<Compression> {
+        if self.is_v10() {
+            None
+        } else {
+            Compression::negotiated(self.capabilities())
+        }
+    }
+
+    /// Compresses the bulk data of `m`, if that's enabled and worth doing
+    fn compress(&self, m: &Message) -> Option<CompressedData> {
+        CompressedData::new(m, self.compression()?, &self.0.compression_stats)
+    }
+
+    /// Restores the bulk data of a message from a compressed frame
+    fn decompress(
+        &self,
+        prefix: &CompressedPrefix,
+        m: Message,
+    ) -> anyhow::Result<Message> {
+        prefix.decompress(m, &self.0.compression_stats)
+    }
+}
+
+impl Default for ConnectionVersion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A set of optional protocol features
+///
+/// Each side lists what it supports in `HereIAm` / `YesItsMe`, and a feature
+/// is only used on a connection if both sides have it.  Unlike a change to
+/// the `Message` enum, adding a capability doesn't need a version bump: a
+/// peer that doesn't know about a bit simply won't echo it back.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Every capability with a name, for logging and the control server
+    ///
+    /// Bits should never be reused once assigned, even if a feature is later
+    /// dropped, because older peers may still advertise them.
+    const NAMES: &'static [(Capabilities, &'static str)] = &[
+        (Self::COMPRESS_LZ4, "compress-lz4"),
+        (Self::COMPRESS_ZSTD, "compress-zstd"),
+        (Self::TRACE_CONTEXT, "trace-context"),
+    ];
+
+    /// Bulk data may be compressed with lz4
+    pub const COMPRESS_LZ4: Self = Self(1);
+
+    /// Bulk data may be compressed with zstd
+    pub const COMPRESS_ZSTD: Self = Self(2);
+
+    /// Every compression capability
+    pub const COMPRESSION: Self = Self::COMPRESS_LZ4.union(Self::COMPRESS_ZSTD);
+
+    /// Jobs may be preceded by a `Message::TraceContext`
+    pub const TRACE_CONTEXT: Self = Self(4);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns the features supported by both `self` and `other`
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Returns the features in `self` but not in `other`
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the name of each capability in this set
+    ///
+    /// Bits that we don't know about are listed as `bit N`.
+    pub fn names(self) -> Vec<String> {
+        let mut out = vec![];
+        let mut known = Self::empty();
+        for (c, name) in Self::NAMES {
+            if self.contains(*c) {
+                out.push(name.to_string());
+            }
+            known = known.union(*c);
+        }
+        let unknown = self.0 & !known.0;
+        out.extend(
+            (0..u64::BITS)
+                .filter(|i| unknown & (1 << i) != 0)
+                .map(|i| format!("bit {i}")),
+        );
+        out
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}]", self.names().join(", "))
+    }
+}
+
+/// Capabilities that this build supports
+pub const CRUCIBLE_CAPABILITIES: Capabilities =
+    Capabilities::COMPRESSION.union(Capabilities::TRACE_CONTEXT);
+
 /*
  * If you add or change the Message enum, you must also increment the
  * CRUCIBLE_MESSAGE_VERSION.  It's just a few lines above you, why not
@@ -249,6 +506,9 @@ pub enum Message {
         encrypted: bool,
         // Additional Message versions this upstairs supports.
         alternate_versions: Vec<u32>,
+        // Optional features this upstairs supports.  This must stay the
+        // last field: older downstairs ignore it, and don't send it.
+        capabilities: Capabilities,
     },
     /**
      * This is the first message (when things are good) that the downstairs
@@ -259,6 +519,9 @@ pub enum Message {
         version: u32,
         // The IP:Port that repair commands will use to communicate.
         repair_addr: SocketAddr,
+        // Optional features supported by both sides, which may be used on
+        // this connection.  This must stay the last field, as in `HereIAm`.
+        capabilities: Capabilities,
     },
 
     /*
@@ -544,6 +807,16 @@ pub enum Message {
      * Misc
      */
     Unknown(u32, BytesMut),
+
+    /// Trace context for the job that follows, as a W3C `traceparent`
+    ///
+    /// This is only sent if both sides have `Capabilities::TRACE_CONTEXT`,
+    /// so it doesn't need a version bump; it goes after `Unknown` so that
+    /// existing messages keep their tags.
+    TraceContext {
+        job_id: JobId,
+        traceparent: String,
+    },
 }
 /*
  * If you just added or changed the Message enum above, you must also
@@ -624,6 +897,7 @@ impl Message {
             Message::ReadRequest { .. } => None,
             Message::WriteUnwritten { .. } => None,
             Message::Unknown(..) => None,
+            Message::TraceContext { .. } => None,
 
             Message::ExtentError { error, .. } => Some(error),
             Message::ErrorReport { error, .. } => Some(error),
@@ -722,6 +996,12 @@ pub struct MessageWriter<W> {
 
     /// Scratch space for the raw header
     header: Vec<u8>,
+
+    /// Message version to encode with
+    version: ConnectionVersion,
+
+    /// Capture file recording every message sent, if any
+    capture: Option<Arc<Capture>>,
 }
 
 impl<W> MessageWriter<W>
@@ -731,13 +1011,26 @@ where
     /// Builds a new `MessageWriter`
     #[inline]
     pub fn new(writer: W) -> Self {
+        Self::with_version(writer, ConnectionVersion::new())
+    }
+
+    /// Builds a new `MessageWriter` encoding with a shared version
+    #[inline]
+    pub fn with_version(writer: W, version: ConnectionVersion) -> Self {
         Self {
             writer,
             scratch: BytesMut::new(),
             header: vec![],
+            version,
+            capture: None,
         }
     }
 
+    /// Records every message sent from now on in a capture file
+    pub fn set_capture(&mut self, capture: Arc<Capture>) {
+        self.capture = Some(capture);
+    }
+
     /// Removes the inner type
     #[inline]
     pub fn into_inner(self) -> W {
@@ -749,8 +1042,12 @@ where
         discriminant: MessageDiscriminants,
         header: H,
         data: B,
+        compressed: Option<CompressedData>,
     ) -> Result<(), CrucibleError> {
-        let data = data.as_ref();
+        let data = match &compressed {
+            Some(c) => &c.data[..],
+            None => data.as_ref(),
+        };
         use tokio::io::AsyncWriteExt;
 
         self.header.clear();
@@ -766,9 +1063,17 @@ where
         )
         .unwrap();
 
+        // Compressed frames have a prefix before the body, and a flag in
+        // their length
+        let mut flags = 0;
+        if let Some(c) = &compressed {
+            self.header.splice(4..4, c.prefix());
+            flags = COMPRESSED_FRAME;
+        }
+
         // Patch the length
         let len: u32 = (self.header.len() + data.len()).try_into().unwrap();
-        self.header[0..4].copy_from_slice(&len.to_le_bytes());
+        self.header[0..4].copy_from_slice(&(len | flags).to_le_bytes());
 
         // write_all_vectored would save a syscall, but is nightly-only
         self.writer.write_all(&self.header).await?;
@@ -782,21 +1087,30 @@ where
     pub async fn send(&mut self, m: Message) -> Result<(), CrucibleError> {
         use tokio::io::AsyncWriteExt;
 
+        if let Some(c) = &self.capture {
+            c.record(Direction::Sent, &m);
+        }
+
         let discriminant = MessageDiscriminants::from(&m);
+        let compressed = self.version.compress(&m);
         match m {
             Message::Write { header, data } => {
-                self.send_raw(discriminant, header, data).await
+                self.send_raw(discriminant, header, data, compressed).await
             }
             Message::WriteUnwritten { header, data } => {
-                self.send_raw(discriminant, header, data).await
+                self.send_raw(discriminant, header, data, compressed).await
+            }
+            Message::ReadResponse { header, data } if self.version.is_v10() => {
+                let header = ReadResponseHeaderV10::from_current(header, &data);
+                self.send_raw(discriminant, header, data, None).await
             }
             Message::ReadResponse { header, data } => {
-                self.send_raw(discriminant, header, data).await
+                self.send_raw(discriminant, header, data, compressed).await
             }
             m => {
                 // Serialize into our local BytesMut, to avoid allocation churn
                 self.scratch.clear();
-                let mut e = CrucibleEncoder::new();
+                let mut e = CrucibleEncoder::with_version(self.version.clone());
                 e.encode(m, &mut self.scratch)?;
                 self.writer.write_all(&self.scratch).await?;
                 Ok(())
@@ -806,11 +1120,103 @@ where
 }
 
 #[derive(Debug)]
-pub struct CrucibleEncoder {}
+pub struct CrucibleEncoder {
+    version: ConnectionVersion,
+}
 
 impl CrucibleEncoder {
     pub fn new() -> Self {
-        CrucibleEncoder {}
+        Self::with_version(ConnectionVersion::new())
+    }
+
+    /// Builds an encoder using a version shared with the connection's decoder
+    pub fn with_version(version: ConnectionVersion) -> Self {
+        CrucibleEncoder { version }
+    }
+
+    /// Encodes `m` as a frame, converting it for an older version if need be
+    fn encode_message(
+        &mut self,
+        m: &Message,
+        dst: &mut BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        match m {
+            Message::ReadResponse { header, data } if self.version.is_v10() => {
+                let header =
+                    ReadResponseHeaderV10::from_current(header.clone(), data);
+                Self::encode_frame(
+                    &(MessageDiscriminants::ReadResponse, header, &data[..]),
+                    dst,
+                )?;
+            }
+            m => match self.version.compress(m) {
+                Some(c) => Self::encode_compressed(m, &c, dst)?,
+                None => Self::encode_frame(m, dst)?,
+            },
+        }
+
+        self.version.observe(m);
+        Ok(())
+    }
+
+    /// Encodes `m` as a compressed frame, with `c` as its bulk data
+    fn encode_compressed(
+        m: &Message,
+        c: &CompressedData,
+        dst: &mut BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        let d = MessageDiscriminants::from(m);
+        match m {
+            Message::Write { header, .. }
+            | Message::WriteUnwritten { header, .. } => {
+                Self::encode_compressed_frame(&(d, header, &c.data[..]), c, dst)
+            }
+            Message::ReadResponse { header, .. } => {
+                Self::encode_compressed_frame(&(d, header, &c.data[..]), c, dst)
+            }
+            m => bail!("cannot compress {m}, which has no bulk data"),
+        }
+    }
+
+    fn encode_compressed_frame<T: serde::Serialize>(
+        body: &T,
+        c: &CompressedData,
+        dst: &mut BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        let len =
+            CrucibleEncoder::serialized_size(body)? + COMPRESSED_PREFIX_LEN;
+        if len > MAX_FRM_LEN {
+            bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
+        }
+
+        let before = dst.len();
+        dst.reserve(len);
+        dst.put_u32_le(len as u32 | COMPRESSED_FRAME);
+        dst.put_slice(&c.prefix());
+        bincode::serialize_into(dst.writer(), body)?;
+        debug_assert_eq!(dst.len() - before, len);
+
+        Ok(())
+    }
+
+    fn encode_frame<T: serde::Serialize>(
+        m: &T,
+        dst: &mut BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        let len = CrucibleEncoder::serialized_size(m)?;
+        if len > MAX_FRM_LEN {
+            // Bail out before creating a frame that the decoder will refuse to
+            // deserialize
+            bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
+        }
+
+        let before = dst.len();
+        dst.reserve(len);
+        dst.put_u32_le(len as u32);
+        bincode::serialize_into(dst.writer(), m)?;
+        debug_assert_eq!(dst.len() - before, len);
+
+        Ok(())
     }
 
     fn serialized_size<T: serde::Serialize>(
@@ -954,20 +1360,7 @@ impl Encoder<Message> for CrucibleEncoder {
         m: Message,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        let len = CrucibleEncoder::serialized_size(&m)?;
-        if len > MAX_FRM_LEN {
-            // Bail out before creating a frame that the decoder will refuse to
-            // deserialize
-            bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
-        }
-
-        let before = dst.len();
-        dst.reserve(len);
-        dst.put_u32_le(len as u32);
-        bincode::serialize_into(dst.writer(), &m)?;
-        debug_assert_eq!(dst.len() - before, len);
-
-        Ok(())
+        self.encode_message(&m, dst)
     }
 }
 
@@ -979,28 +1372,33 @@ impl Encoder<&Message> for CrucibleEncoder {
         m: &Message,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        let len = CrucibleEncoder::serialized_size(m)?;
-        if len > MAX_FRM_LEN {
-            // Bail out before creating a frame that the decoder will refuse to
-            // deserialize
-            bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
-        }
-
-        let before = dst.len();
-        dst.reserve(len);
-        dst.put_u32_le(len as u32);
-        bincode::serialize_into(dst.writer(), &m)?;
-        debug_assert_eq!(dst.len() - before, len);
-
-        Ok(())
+        self.encode_message(m, dst)
     }
 }
 
-pub struct CrucibleDecoder {}
+pub struct CrucibleDecoder {
+    version: ConnectionVersion,
+
+    /// Capture file recording every message received, if any
+    capture: Option<Arc<Capture>>,
+}
 
 impl CrucibleDecoder {
     pub fn new() -> Self {
-        CrucibleDecoder {}
+        Self::with_version(ConnectionVersion::new())
+    }
+
+    /// Builds a decoder using a version shared with the connection's encoder
+    pub fn with_version(version: ConnectionVersion) -> Self {
+        CrucibleDecoder {
+            version,
+            capture: None,
+        }
+    }
+
+    /// Records every message received from now on in a capture file
+    pub fn set_capture(&mut self, capture: Arc<Capture>) {
+        self.capture = Some(capture);
     }
 
     fn decode_raw<H: for<'a> Deserialize<'a>, F: Fn(H, BytesMut) -> Message>(
@@ -1045,7 +1443,9 @@ impl Decoder for CrucibleDecoder {
          */
         let mut length_bytes = [0u8; 4];
         length_bytes.copy_from_slice(&src[0..4]);
-        let len = u32::from_le_bytes(length_bytes) as usize;
+        let len = u32::from_le_bytes(length_bytes);
+        let compressed = len & COMPRESSED_FRAME != 0;
+        let len = (len & !COMPRESSED_FRAME) as usize;
 
         if len > MAX_FRM_LEN {
             bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
@@ -1065,7 +1465,15 @@ impl Decoder for CrucibleDecoder {
         // This leaves `src` pointing to the beginning of the next packet (which
         // may not exist yet), and `buf` pointing to just our bincode-serialized
         // `Message`.
-        let buf = src.split_to(len).split_off(4);
+        let mut buf = src.split_to(len).split_off(4);
+
+        // A compressed frame has a prefix saying how to decompress it, before
+        // the usual bincode-serialized `Message`.
+        let prefix = if compressed {
+            Some(CompressedPrefix::take(&mut buf)?)
+        } else {
+            None
+        };
 
         // Deserialize just the discriminant.  This will let us decide whether
         // to use a specialized strategy for deserializing Messages that contain
@@ -1086,15 +1494,35 @@ impl Decoder for CrucibleDecoder {
                     data: data.freeze(),
                 })
             }
+            MessageDiscriminants::ReadResponse if self.version.is_v10() => {
+                Self::decode_raw(buf, |header: ReadResponseHeaderV10, data| {
+                    Message::ReadResponse {
+                        header: header.into_current(),
+                        data,
+                    }
+                })
+            }
             MessageDiscriminants::ReadResponse => {
                 Self::decode_raw(buf, |header, data| Message::ReadResponse {
                     header,
                     data,
                 })
             }
+            MessageDiscriminants::HereIAm | MessageDiscriminants::YesItsMe => {
+                compat::decode_handshake(&buf)
+            }
             _ => bincode::deserialize_from(&buf[..]),
         }?;
 
+        let message = match prefix {
+            Some(p) => self.version.decompress(&p, message)?,
+            None => message,
+        };
+
+        self.version.observe(&message);
+        if let Some(c) = &self.capture {
+            c.record(Direction::Received, &message);
+        }
         Ok(Some(message))
     }
 }
@@ -1128,6 +1556,7 @@ mod tests {
             read_only: false,
             encrypted: true,
             alternate_versions: Vec::new(),
+            capabilities: Capabilities::empty(),
         };
         assert_eq!(input, round_trip(&input)?);
         Ok(())
@@ -1138,6 +1567,7 @@ mod tests {
         let input = Message::YesItsMe {
             version: 20000,
             repair_addr: "127.0.0.1:123".parse().unwrap(),
+            capabilities: Capabilities::empty(),
         };
         assert_eq!(input, round_trip(&input)?);
         Ok(())
@@ -1186,6 +1616,18 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_trace_context() -> Result<()> {
+        let input = Message::TraceContext {
+            job_id: JobId(1000),
+            traceparent:
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                    .to_string(),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
     #[test]
     fn correctly_detect_truncated_message() -> Result<()> {
         let mut encoder = CrucibleEncoder::new();
@@ -1199,6 +1641,7 @@ mod tests {
             read_only: true,
             encrypted: false,
             alternate_versions: Vec::new(),
+            capabilities: Capabilities::empty(),
         };
         let mut buffer = BytesMut::new();
 
@@ -1271,6 +1714,7 @@ mod tests {
             read_only: true,
             encrypted: false,
             alternate_versions: vec![8, 9],
+            capabilities: Capabilities::from_bits(0x102),
         };
         let encoded = bincode::serialize(&m).unwrap();
         assert_eq!(
@@ -1293,6 +1737,7 @@ mod tests {
                 2, 0, 0, 0, 0, 0, 0, 0, // alternate_versions.len()
                 8, 0, 0, 0, // alternate_versions[0]
                 9, 0, 0, 0, // alternate_versions[1]
+                2, 1, 0, 0, 0, 0, 0, 0, // capabilities
             ]
         );
     }
@@ -1306,6 +1751,7 @@ mod tests {
         let m = Message::YesItsMe {
             version: 123,
             repair_addr: "127.0.0.1:123".parse().unwrap(),
+            capabilities: Capabilities::empty(),
         };
         let encoded = bincode::serialize(&m).unwrap();
         assert_eq!(
@@ -1317,6 +1763,198 @@ mod tests {
         );
     }
 
+    /// Peers that predate capabilities send handshakes without them
+    #[test]
+    fn handshake_without_capabilities() -> Result<()> {
+        let upstairs_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+        let repair_addr: SocketAddr = "127.0.0.1:123".parse().unwrap();
+        let old = [
+            bincode::serialize(&(
+                MessageDiscriminants::HereIAm,
+                11u32,
+                upstairs_id,
+                session_id,
+                3u64,
+                false,
+                true,
+                vec![10u32],
+            ))?,
+            bincode::serialize(&(
+                MessageDiscriminants::YesItsMe,
+                10u32,
+                repair_addr,
+            ))?,
+        ];
+
+        let mut buf = BytesMut::new();
+        for m in &old {
+            buf.put_u32_le(m.len() as u32 + 4);
+            buf.extend_from_slice(m);
+        }
+        let mut dec = CrucibleDecoder::new();
+        assert_eq!(
+            dec.decode(&mut buf)?,
+            Some(Message::HereIAm {
+                version: 11,
+                upstairs_id,
+                session_id,
+                gen: 3,
+                read_only: false,
+                encrypted: true,
+                alternate_versions: vec![10],
+                capabilities: Capabilities::empty(),
+            })
+        );
+        assert_eq!(
+            dec.decode(&mut buf)?,
+            Some(Message::YesItsMe {
+                version: 10,
+                repair_addr,
+                capabilities: Capabilities::empty(),
+            })
+        );
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn capability_names() {
+        let c = Capabilities::from_bits(1 << 40);
+        assert_eq!(c.names(), vec!["bit 40".to_string()]);
+        assert_eq!(c.to_string(), "[bit 40]");
+        let both = c.union(Capabilities::from_bits(1));
+        assert_eq!(both.intersection(Capabilities::from_bits(3)).bits(), 1);
+        assert!(both.contains(c));
+        assert!(!c.contains(both));
+        assert!(Capabilities::empty().names().is_empty());
+    }
+
+    #[test]
+    fn negotiate_newest_common_version() {
+        let cur = CRUCIBLE_MESSAGE_VERSION;
+        assert_eq!(negotiate_version(cur, &[]), Some(cur));
+        assert_eq!(negotiate_version(cur + 1, &[cur, cur - 1]), Some(cur));
+        assert_eq!(negotiate_version(cur - 1, &[]), Some(cur - 1));
+        assert_eq!(negotiate_version(cur - 2, &[cur - 1]), Some(cur - 1));
+        assert_eq!(negotiate_version(cur - 2, &[cur - 3]), None);
+        assert_eq!(negotiate_version(cur + 1, &[]), None);
+    }
+
+    fn read_response() -> Message {
+        let ctx = EncryptionContext {
+            nonce: [1; 12],
+            tag: [2; 16],
+        };
+        Message::ReadResponse {
+            header: ReadResponseHeader {
+                upstairs_id: Uuid::new_v4(),
+                session_id: Uuid::new_v4(),
+                job_id: JobId(10),
+                blocks: Ok(vec![
+                    ReadBlockContext::Empty,
+                    ReadBlockContext::Unencrypted { hash: 123 },
+                    ReadBlockContext::Encrypted { ctx },
+                ]),
+            },
+            data: BytesMut::from(&[0u8, 0, 3, 3, 7, 7][..]),
+        }
+    }
+
+    #[test]
+    fn rt_read_response_v10() -> Result<()> {
+        let version = ConnectionVersion::new();
+        version.set(MessageVersion::V10 as u32);
+        let mut enc = CrucibleEncoder::with_version(version.clone());
+        let mut dec = CrucibleDecoder::with_version(version);
+
+        let input = read_response();
+        let mut buf = BytesMut::new();
+        enc.encode(&input, &mut buf)?;
+        assert_eq!(dec.decode(&mut buf)?, Some(input));
+        Ok(())
+    }
+
+    /// A `ReadResponse` encoded for a V10 peer must be what a V10 downstairs
+    /// would have sent, including the hash of encrypted blocks.
+    #[test]
+    fn read_response_v10_encoding() -> Result<()> {
+        let version = ConnectionVersion::new();
+        version.set(MessageVersion::V10 as u32);
+        let mut enc = CrucibleEncoder::with_version(version);
+
+        let input = read_response();
+        let mut buf = BytesMut::new();
+        enc.encode(&input, &mut buf)?;
+
+        let Message::ReadResponse { header, data } = input else {
+            unreachable!();
+        };
+        let ctx = EncryptionContext {
+            nonce: [1; 12],
+            tag: [2; 16],
+        };
+        let old = ReadResponseHeaderV10 {
+            upstairs_id: header.upstairs_id,
+            session_id: header.session_id,
+            job_id: header.job_id,
+            blocks: Ok(vec![
+                None,
+                Some(BlockContext {
+                    hash: 123,
+                    encryption_context: None,
+                }),
+                Some(BlockContext {
+                    hash: crucible_common::integrity_hash(&[
+                        &ctx.nonce[..],
+                        &ctx.tag[..],
+                        &[7u8, 7][..],
+                    ]),
+                    encryption_context: Some(ctx),
+                }),
+            ]),
+        };
+        let expected = bincode::serialize(&(
+            MessageDiscriminants::ReadResponse,
+            old,
+            &data[..],
+        ))?;
+        assert_eq!(&buf[4..], &expected[..]);
+        Ok(())
+    }
+
+    /// Both halves of a connection switch version once `YesItsMe` is seen
+    #[test]
+    fn yes_its_me_switches_version() -> Result<()> {
+        let old = MessageVersion::V10 as u32;
+        let ds_version = ConnectionVersion::new();
+        let mut ds_enc = CrucibleEncoder::with_version(ds_version.clone());
+        let up_version = ConnectionVersion::new();
+        let mut up_dec = CrucibleDecoder::with_version(up_version.clone());
+
+        let mut buf = BytesMut::new();
+        ds_enc.encode(
+            Message::YesItsMe {
+                version: old,
+                repair_addr: "127.0.0.1:123".parse().unwrap(),
+                capabilities: Capabilities::empty(),
+            },
+            &mut buf,
+        )?;
+        assert_eq!(ds_version.get(), old);
+        up_dec.decode(&mut buf)?.unwrap();
+        assert_eq!(up_version.get(), old);
+
+        let input = read_response();
+        ds_enc.encode(&input, &mut buf)?;
+        assert_eq!(up_dec.decode(&mut buf)?, Some(input));
+
+        // Versions we don't speak are ignored
+        up_version.set(old - 1);
+        assert_eq!(up_version.get(), old);
+        Ok(())
+    }
+
     #[test]
     fn read_block_context_size() {
         let m = ReadBlockContext::Encrypted {
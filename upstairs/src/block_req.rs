@@ -4,11 +4,25 @@ use tokio::sync::oneshot;
 
 #[must_use]
 #[derive(Debug)]
-pub(crate) struct BlockRes<T = (), E = CrucibleError>(
-    Option<oneshot::Sender<Result<T, E>>>,
-);
+pub(crate) struct BlockRes<T = (), E = CrucibleError> {
+    sender: Option<oneshot::Sender<Result<T, E>>>,
+    /// When the request was made, for latency stats
+    created: Instant,
+    /// Span of the caller when the request was made, for tracing
+    span: tracing::Span,
+}
 
 impl<T, E> BlockRes<T, E> {
+    /// Returns when the request was made
+    pub fn created(&self) -> Instant {
+        self.created
+    }
+
+    /// Returns the span in which the request was made
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
     /// Consume this BlockRes and send Ok to the receiver
     pub fn send_ok(self, t: T) {
         self.send_result(Ok(t))
@@ -22,13 +36,17 @@ impl<T, E> BlockRes<T, E> {
     /// Consume this BlockRes and send a Result to the receiver
     pub fn send_result(mut self, result: Result<T, E>) {
         // XXX this eats the result!
-        let _ = self.0.take().expect("sender was populated").send(result);
+        let _ = self
+            .sender
+            .take()
+            .expect("sender was populated")
+            .send(result);
     }
 }
 
 impl<T, E> Drop for BlockRes<T, E> {
     fn drop(&mut self) {
-        if self.0.is_some() {
+        if self.sender.is_some() {
             // During normal operation, we expect to reply to every BlockOp, so
             // we'll fire a DTrace probe here.
             cdt::up__block__req__dropped!();
@@ -51,7 +69,12 @@ impl<T, E> BlockOpWaiter<T, E> {
     /// Create associated `BlockOpWaiter`/`BlockRes` pair
     pub fn pair() -> (Self, BlockRes<T, E>) {
         let (send, recv) = oneshot::channel();
-        (Self { recv }, BlockRes(Some(send)))
+        let res = BlockRes {
+            sender: Some(send),
+            created: Instant::now(),
+            span: tracing::Span::current(),
+        };
+        (Self { recv }, res)
     }
 
     /// Consume this BlockOpWaiter and wait on the message
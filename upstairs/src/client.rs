@@ -1,28 +1,36 @@
 // Copyright 2023 Oxide Computer Company
 use crate::{
     cdt, integrity_hash, live_repair::ExtentInfo, upstairs::UpstairsConfig,
-    upstairs::UpstairsState, ClientIOStateCount, ClientId, CrucibleDecoder,
-    CrucibleError, DownstairsIO, DsState, EncryptionContext, IOState, IOop,
-    JobId, Message, RawReadResponse, ReconcileIO, RegionDefinitionStatus,
-    RegionMetadata, Validation,
+    upstairs::UpstairsState, ClientIOStateCount, ClientId, ClientTimeoutOpts,
+    CrucibleDecoder, CrucibleError, DownstairsIO, DsState, EncryptionContext,
+    IOState, IOop, JobId, Message, RawReadResponse, ReconcileIO,
+    RegionDefinitionStatus, RegionMetadata, Validation,
 };
 use crucible_common::{
-    deadline_secs, verbose_timeout, x509::TLSContext, ExtentId,
+    audit::AuditEvent, deadline_secs, metrics::LatencyHistogram,
+    verbose_timeout, x509::TLSContext, ExtentId,
 };
 use crucible_protocol::{
-    MessageWriter, ReconciliationId, CRUCIBLE_MESSAGE_VERSION,
+    capture::{Capture, CaptureConfig},
+    Capabilities, Compression, CompressionCounts, CompressionStats,
+    ConnectionVersion, MessageWriter, ReconciliationId, CRUCIBLE_CAPABILITIES,
+    CRUCIBLE_MESSAGE_VERSION, SUPPORTED_MESSAGE_VERSIONS,
 };
 
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     net::SocketAddr,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::Instant,
 };
 
+use anyhow::bail;
 use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use slog::{debug, error, info, o, warn, Logger};
 use tokio::{
     net::{TcpSocket, TcpStream},
@@ -38,11 +46,160 @@ const TIMEOUT_SECS: f32 = 15.0;
 // How many timeouts will we tolerate before we disconnect from a downstairs.
 const TIMEOUT_LIMIT: usize = 3;
 const PING_INTERVAL_SECS: f32 = 5.0;
+// With adaptive timeouts, the inactivity timeout is stretched to cover the
+// ping interval plus this many smoothed round-trip times.
+const ADAPTIVE_RTT_MULTIPLIER: f32 = 10.0;
 
 /// Total time before a client is timed out
 #[cfg(test)]
 pub const CLIENT_TIMEOUT_SECS: f32 = TIMEOUT_SECS * TIMEOUT_LIMIT as f32;
 
+/// Resolved connection timeouts and limits for each downstairs client
+///
+/// These are built from the optional [`ClientTimeoutOpts`] in `CrucibleOpts`,
+/// with the built-in defaults filling in any gaps.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct ClientTimeouts {
+    /// How long we wait before logging that a downstairs has gone quiet
+    pub timeout_secs: f32,
+    /// How many consecutive timeouts we tolerate before disconnecting
+    pub timeout_limit: usize,
+    /// How often we send a `Ruok` ping to each downstairs
+    pub ping_interval_secs: f32,
+    /// Stretch the timeout based on each downstairs' observed round-trip time
+    pub adaptive: bool,
+    /// Max number of outstanding jobs before an offline downstairs is faulted
+    pub io_outstanding_max_jobs: usize,
+    /// Max number of outstanding bytes before an offline downstairs is faulted
+    pub io_outstanding_max_bytes: u64,
+}
+
+impl Default for ClientTimeouts {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl ClientTimeouts {
+    pub(crate) fn new(opts: Option<&ClientTimeoutOpts>) -> Self {
+        let opts = opts.copied().unwrap_or_default();
+        Self {
+            timeout_secs: opts.timeout_secs.unwrap_or(TIMEOUT_SECS),
+            timeout_limit: opts.timeout_limit.unwrap_or(TIMEOUT_LIMIT),
+            ping_interval_secs: opts
+                .ping_interval_secs
+                .unwrap_or(PING_INTERVAL_SECS),
+            adaptive: opts.adaptive.unwrap_or(false),
+            io_outstanding_max_jobs: opts
+                .io_outstanding_max_jobs
+                .unwrap_or(crate::IO_OUTSTANDING_MAX_JOBS),
+            io_outstanding_max_bytes: opts
+                .io_outstanding_max_bytes
+                .unwrap_or(crate::IO_OUTSTANDING_MAX_BYTES),
+        }
+    }
+
+    /// Checks that the configured values make sense together
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if !self.timeout_secs.is_finite() || self.timeout_secs <= 0.0 {
+            bail!("timeout_secs must be positive, not {}", self.timeout_secs);
+        }
+        if self.timeout_limit == 0 {
+            bail!("timeout_limit must be at least 1");
+        }
+        if !self.ping_interval_secs.is_finite()
+            || self.ping_interval_secs <= 0.0
+        {
+            bail!(
+                "ping_interval_secs must be positive, not {}",
+                self.ping_interval_secs
+            );
+        }
+        // The inactivity timer is reset by every message, including the
+        // `Imok` reply to our pings; if we ping less often than we time out,
+        // an idle (but healthy) downstairs would be disconnected.
+        if self.ping_interval_secs >= self.timeout_secs {
+            bail!(
+                "ping_interval_secs ({}) must be less than timeout_secs ({})",
+                self.ping_interval_secs,
+                self.timeout_secs
+            );
+        }
+        if self.io_outstanding_max_jobs == 0 {
+            bail!("io_outstanding_max_jobs must be at least 1");
+        }
+        if self.io_outstanding_max_bytes == 0 {
+            bail!("io_outstanding_max_bytes must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Returns the length of a single inactivity period, in seconds
+    ///
+    /// If adaptive timeouts are enabled and we have a round-trip estimate, the
+    /// configured timeout is stretched (but never shrunk) to accommodate a slow
+    /// link.
+    pub(crate) fn inactivity_secs(&self, rtt: Option<Duration>) -> f32 {
+        match rtt {
+            Some(rtt) if self.adaptive => self.timeout_secs.max(
+                self.ping_interval_secs
+                    + rtt.as_secs_f32() * ADAPTIVE_RTT_MULTIPLIER,
+            ),
+            _ => self.timeout_secs,
+        }
+    }
+}
+
+/// Round-trip time estimator for a single downstairs
+///
+/// This is shared between the tx side of the IO task (which sends `Ruok`) and
+/// the rx side (which receives `Imok`), and is read by the main task when
+/// reporting stats.
+#[derive(Debug, Default)]
+pub(crate) struct ClientRtt {
+    /// Time at which our most recent unanswered `Ruok` was sent
+    ping_sent: Mutex<Option<Instant>>,
+
+    /// Smoothed round-trip time in microseconds, or 0 if not yet measured
+    srtt_us: AtomicU64,
+}
+
+impl ClientRtt {
+    /// Records that a `Ruok` was just sent
+    fn ping_sent(&self) {
+        *self.ping_sent.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Forgets any outstanding ping, e.g. because we have reconnected
+    fn clear_ping(&self) {
+        self.ping_sent.lock().unwrap().take();
+    }
+
+    /// Records that an `Imok` was received, updating the smoothed estimate
+    fn pong_received(&self) {
+        let Some(t) = self.ping_sent.lock().unwrap().take() else {
+            return;
+        };
+        let sample = (t.elapsed().as_micros() as u64).max(1);
+        let prev = self.srtt_us.load(Ordering::Relaxed);
+        // Standard exponentially-weighted moving average, with α = 1/8
+        let next = if prev == 0 {
+            sample
+        } else {
+            (prev * 7 + sample) / 8
+        };
+        self.srtt_us.store(next, Ordering::Relaxed);
+    }
+
+    /// Returns the smoothed round-trip time, if one has been measured
+    pub(crate) fn get(&self) -> Option<Duration> {
+        match self.srtt_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+}
+
 /// Handle to a running I/O task
 ///
 /// The I/O task is "thin"; it simply forwards messages around.  The task
@@ -141,6 +298,11 @@ pub(crate) struct DownstairsClient {
     /// This is set to `None` during initialization
     pub(crate) repair_addr: Option<SocketAddr>,
 
+    /// Optional features that both we and the downstairs support
+    ///
+    /// This is set from `YesItsMe`, and cleared when reconnecting
+    capabilities: Capabilities,
+
     /// TLS context (if present)
     ///
     /// This is passed as a pointer to minimize copies
@@ -191,6 +353,12 @@ pub(crate) struct DownstairsClient {
 
     /// Per-client delay, shared with the [`DownstairsClient`]
     client_delay_us: Arc<AtomicU64>,
+
+    /// Round-trip time estimate, shared with the IO task
+    rtt: Arc<ClientRtt>,
+
+    /// Compression done on every connection, shared with the IO task
+    compression_stats: Arc<CompressionStats>,
 }
 
 impl DownstairsClient {
@@ -202,8 +370,9 @@ impl DownstairsClient {
         tls_context: Option<Arc<crucible_common::x509::TLSContext>>,
     ) -> Self {
         let client_delay_us = Arc::new(AtomicU64::new(0));
+        let rtt = Arc::new(ClientRtt::default());
+        let compression_stats = Arc::new(CompressionStats::default());
         Self {
-            cfg,
             client_task: Self::new_io_task(
                 target_addr,
                 false, // do not delay in starting the task
@@ -211,8 +380,13 @@ impl DownstairsClient {
                 client_id,
                 tls_context.clone(),
                 client_delay_us.clone(),
+                cfg.timeouts,
+                rtt.clone(),
+                compression_stats.clone(),
+                cfg.capture.clone(),
                 &log,
             ),
+            cfg,
             client_id,
             region_uuid: None,
             negotiation_state: NegotiationState::Start,
@@ -221,6 +395,7 @@ impl DownstairsClient {
             log,
             target_addr,
             repair_addr: None,
+            capabilities: Capabilities::empty(),
             state: DsState::New,
             last_flush: JobId(0),
             stats: DownstairsStats::default(),
@@ -232,6 +407,8 @@ impl DownstairsClient {
             bytes_outstanding: 0,
             connection_id: ConnectionId(0),
             client_delay_us,
+            rtt,
+            compression_stats,
         }
     }
 
@@ -242,6 +419,8 @@ impl DownstairsClient {
     #[cfg(test)]
     fn test_default() -> Self {
         let client_delay_us = Arc::new(AtomicU64::new(0));
+        let rtt = Arc::new(ClientRtt::default());
+        let compression_stats = Arc::new(CompressionStats::default());
         let cfg = Arc::new(UpstairsConfig {
             encryption_context: None,
             upstairs_id: Uuid::new_v4(),
@@ -249,6 +428,12 @@ impl DownstairsClient {
             generation: std::sync::atomic::AtomicU64::new(1),
             read_only: false,
             lossy: false,
+            timeouts: ClientTimeouts::default(),
+            region_set_size: crate::DEFAULT_REGION_SET_SIZE,
+            capture: None,
+            audit: Arc::new(crucible_common::audit::AuditLog::new(
+                crucible_common::audit::AuditSource::Upstairs,
+            )),
         });
         Self {
             cfg,
@@ -261,6 +446,7 @@ impl DownstairsClient {
             log: crucible_common::build_logger(),
             target_addr: None,
             repair_addr: None,
+            capabilities: Capabilities::empty(),
             state: DsState::New,
             last_flush: JobId(0),
             stats: DownstairsStats::default(),
@@ -272,6 +458,8 @@ impl DownstairsClient {
             bytes_outstanding: 0,
             connection_id: ConnectionId(0),
             client_delay_us,
+            rtt,
+            compression_stats,
         }
     }
 
@@ -281,27 +469,33 @@ impl DownstairsClient {
             && matches!(self.state, DsState::Active | DsState::LiveRepair)
     }
 
-    /// Choose which `ClientAction` to apply
+    /// Polls for the next `ClientAction` to apply
     ///
-    /// This function is called from within a top-level `select!`, so not only
-    /// must the select expressions be cancel safe, but the **bodies** must also
-    /// be cancel-safe.  This is why we simply return a single value in the body
-    /// of each statement.
+    /// This function is polled from within a top-level `select!` (by way of
+    /// `Downstairs::select`), so it must be cancel safe; receiving from the
+    /// client response channel is.
     ///
-    /// This function will wait forever if we have asked for the client task to
-    /// stop, so it should only be called in a higher-level `select!`.
-    pub(crate) async fn select(&mut self) -> ClientAction {
+    /// This function will return `Poll::Pending` forever if we have asked for
+    /// the client task to stop, so it should only be polled from a
+    /// higher-level `select!`.
+    pub(crate) fn poll_select(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<ClientAction> {
         loop {
-            let out = match self.client_task.client_response_rx.recv().await {
-                Some(c) => c.into(),
-                None => break ClientAction::ChannelClosed,
+            let out = match self.client_task.client_response_rx.poll_recv(cx) {
+                std::task::Poll::Ready(Some(c)) => c.into(),
+                std::task::Poll::Ready(None) => {
+                    break std::task::Poll::Ready(ClientAction::ChannelClosed)
+                }
+                std::task::Poll::Pending => break std::task::Poll::Pending,
             };
             // Ignore client responses if we have told the client to exit (we
             // still propagate other ClientAction variants, e.g. TaskStopped).
             if self.client_task.client_stop_tx.is_some()
                 || !matches!(out, ClientAction::Response(..))
             {
-                break out;
+                break std::task::Poll::Ready(out);
             }
         }
     }
@@ -315,7 +509,10 @@ impl DownstairsClient {
             gen: self.cfg.generation(),
             read_only: self.cfg.read_only,
             encrypted: self.cfg.encrypted(),
-            alternate_versions: vec![],
+            // Older versions we can still speak, so that an older downstairs
+            // can pick one
+            alternate_versions: SUPPORTED_MESSAGE_VERSIONS[1..].to_vec(),
+            capabilities: CRUCIBLE_CAPABILITIES,
         });
     }
 
@@ -398,6 +595,7 @@ impl DownstairsClient {
     ) -> IOop {
         let old_state = self.set_job_state(job, IOState::InProgress);
         assert_eq!(old_state, IOState::New);
+        job.sent[self.client_id] = Some(Instant::now());
 
         let mut out = job.work.clone();
         if self.dependencies_need_cleanup() {
@@ -475,7 +673,7 @@ impl DownstairsClient {
     pub(crate) fn begin_reconcile(&mut self) {
         info!(self.log, "Transition from {} to Reconcile", self.state);
         assert_eq!(self.state, DsState::WaitQuorum);
-        self.state = DsState::Reconcile;
+        self.set_state(DsState::Reconcile, "reconciliation_started");
     }
 
     /// Go through the list of dependencies and remove any jobs that this
@@ -555,7 +753,7 @@ impl DownstairsClient {
 
         // Jobs are skipped and replayed in `Downstairs::reinitialize`, which is
         // (probably) the caller of this function.
-        self.state = new_state;
+        self.set_state(new_state, "connection_lost");
     }
 
     /// Checks whether this Downstairs is ready for the upstairs to deactivate
@@ -580,8 +778,9 @@ impl DownstairsClient {
 
     /// Switches the client state to Deactivated and stops the IO task
     pub(crate) fn deactivate(&mut self, up_state: &UpstairsState) {
-        self.checked_state_transition(up_state, DsState::Deactivated);
-        self.halt_io_task(ClientStopReason::Deactivated)
+        let reason = ClientStopReason::Deactivated;
+        self.transition(up_state, DsState::Deactivated, reason.cause());
+        self.halt_io_task(reason)
     }
 
     /// Resets this Downstairs and start a fresh connection
@@ -594,6 +793,7 @@ impl DownstairsClient {
         // entirely; the repair address could have changed in any of these
         // cases.
         self.repair_addr = None;
+        self.capabilities = Capabilities::empty();
 
         if auto_promote {
             self.promote_state = Some(PromoteState::Waiting);
@@ -605,7 +805,7 @@ impl DownstairsClient {
         // TODO this is an awkward special case!
         if self.state == DsState::Disconnected {
             info!(self.log, "Disconnected -> New");
-            self.state = DsState::New;
+            self.set_state(DsState::New, "reconnecting");
         }
 
         self.connection_id.update();
@@ -635,10 +835,15 @@ impl DownstairsClient {
             self.client_id,
             self.tls_context.clone(),
             self.client_delay_us.clone(),
+            self.cfg.timeouts,
+            self.rtt.clone(),
+            self.compression_stats.clone(),
+            self.cfg.capture.clone(),
             &self.log,
         );
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new_io_task(
         target: Option<SocketAddr>,
         delay: bool,
@@ -646,6 +851,10 @@ impl DownstairsClient {
         client_id: ClientId,
         tls_context: Option<Arc<TLSContext>>,
         client_delay_us: Arc<AtomicU64>,
+        timeouts: ClientTimeouts,
+        rtt: Arc<ClientRtt>,
+        compression_stats: Arc<CompressionStats>,
+        capture: Option<CaptureConfig>,
         log: &Logger,
     ) -> ClientTaskHandle {
         #[cfg(test)]
@@ -657,6 +866,10 @@ impl DownstairsClient {
                 client_id,
                 tls_context,
                 client_delay_us,
+                timeouts,
+                rtt,
+                compression_stats,
+                capture,
                 log,
             )
         } else {
@@ -671,10 +884,15 @@ impl DownstairsClient {
             client_id,
             tls_context,
             client_delay_us,
+            timeouts,
+            rtt,
+            compression_stats,
+            capture,
             log,
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new_network_task(
         target: SocketAddr,
         delay: bool,
@@ -682,6 +900,10 @@ impl DownstairsClient {
         client_id: ClientId,
         tls_context: Option<Arc<TLSContext>>,
         client_delay_us: Arc<AtomicU64>,
+        timeouts: ClientTimeouts,
+        rtt: Arc<ClientRtt>,
+        compression_stats: Arc<CompressionStats>,
+        capture: Option<CaptureConfig>,
         log: &Logger,
     ) -> ClientTaskHandle {
         // Messages in flight are limited by backpressure, so we can use
@@ -715,6 +937,10 @@ impl DownstairsClient {
                 },
                 delay,
                 client_delay_us,
+                timeouts,
+                rtt,
+                compression_stats,
+                capture,
                 log,
             };
             c.run().await
@@ -835,14 +1061,44 @@ impl DownstairsClient {
         self.state
     }
 
+    /// Returns the optional features enabled on this connection
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Returns the algorithm in use on the current connection, and the
+    /// compression done with this downstairs so far
+    pub(crate) fn compression(
+        &self,
+    ) -> (Option<Compression>, CompressionCounts) {
+        (
+            Compression::negotiated(self.capabilities),
+            self.compression_stats.snapshot(),
+        )
+    }
+
+    /// Returns the smoothed round-trip time to this downstairs, if known
+    pub(crate) fn round_trip_time(&self) -> Option<Duration> {
+        self.rtt.get()
+    }
+
+    /// Returns the current length of an inactivity period, in seconds
+    ///
+    /// The connection is dropped after `timeout_limit` of these periods pass
+    /// without hearing from the downstairs.
+    pub(crate) fn inactivity_secs(&self) -> f32 {
+        self.cfg.timeouts.inactivity_secs(self.rtt.get())
+    }
+
     /// Sets the current state to `DsState::FailedReconcile`
     pub(crate) fn set_failed_reconcile(&mut self, up_state: &UpstairsState) {
         info!(
             self.log,
             "Transition from {} to FailedReconcile", self.state
         );
-        self.checked_state_transition(up_state, DsState::FailedReconcile);
-        self.restart_connection(up_state, ClientStopReason::FailedReconcile)
+        let reason = ClientStopReason::FailedReconcile;
+        self.transition(up_state, DsState::FailedReconcile, reason.cause());
+        self.restart_connection(up_state, reason)
     }
 
     pub(crate) fn restart_connection(
@@ -879,14 +1135,14 @@ impl DownstairsClient {
             new_state,
         );
 
-        self.checked_state_transition(up_state, new_state);
+        self.transition(up_state, new_state, reason.cause());
         self.halt_io_task(reason);
     }
 
     /// Sets the current state to `DsState::Active`
     pub(crate) fn set_active(&mut self) {
         info!(self.log, "Transition from {} to Active", self.state);
-        self.state = DsState::Active;
+        self.set_state(DsState::Active, "reconciliation_done");
     }
 
     pub(crate) fn enqueue(
@@ -951,10 +1207,11 @@ impl DownstairsClient {
         self.target_addr = Some(new);
 
         self.region_metadata = None;
-        self.checked_state_transition(up_state, DsState::Replacing);
+        let reason = ClientStopReason::Replacing;
+        self.transition(up_state, DsState::Replacing, reason.cause());
         self.stats.replaced += 1;
 
-        self.halt_io_task(ClientStopReason::Replacing);
+        self.halt_io_task(reason);
     }
 
     /// Sets `self.state` to `new_state`, with logging and validity checking
@@ -975,12 +1232,15 @@ impl DownstairsClient {
     /// function does not change anything about the state or any other internal
     /// variables.
     ///
+    /// `cause` is recorded with the transition in the audit log.
+    ///
     /// # Panics
     /// If the transition is not valid
-    pub(crate) fn checked_state_transition(
+    pub(crate) fn transition(
         &mut self,
         up_state: &UpstairsState,
         new_state: DsState,
+        cause: &str,
     ) {
         // TODO this should probably be private!
         info!(self.log, "ds_transition from {} to {new_state}", self.state);
@@ -1063,7 +1323,9 @@ impl DownstairsClient {
                     | DsState::Reconcile
                     | DsState::LiveRepair => {} // Okay
 
-                    DsState::LiveRepairReady if self.cfg.read_only => {} // Okay
+                    DsState::LiveRepairReady
+                        if self.cfg.read_only
+                            || self.cfg.region_set_size == 1 => {} // Okay
 
                     _ => {
                         panic_invalid();
@@ -1158,7 +1420,7 @@ impl DownstairsClient {
                 old_state,
                 new_state,
             );
-            self.state = new_state;
+            self.set_state(new_state, cause);
         } else {
             warn!(
                 self.log,
@@ -1167,6 +1429,37 @@ impl DownstairsClient {
         }
     }
 
+    /// Sets `self.state` to `new_state` for tests, without giving a cause
+    ///
+    /// # Panics
+    /// If the transition is not valid
+    #[cfg(test)]
+    pub(crate) fn checked_state_transition(
+        &mut self,
+        up_state: &UpstairsState,
+        new_state: DsState,
+    ) {
+        self.transition(up_state, new_state, "test")
+    }
+
+    /// Sets `self.state`, recording the change in the audit log
+    ///
+    /// This does no checking; use [`Self::transition`] for that.
+    fn set_state(&mut self, new_state: DsState, cause: &str) {
+        if self.state != new_state {
+            self.cfg.audit(
+                Some(self.client_id),
+                AuditEvent::DsState {
+                    target: self.target_addr,
+                    old: self.state.name(),
+                    new: new_state.name(),
+                    cause: cause.to_owned(),
+                },
+            );
+        }
+        self.state = new_state;
+    }
+
     /// Remove all jobs from `self.new_jobs`
     ///
     /// This is only useful when marking the downstairs as faulted or similar
@@ -1186,8 +1479,9 @@ impl DownstairsClient {
     ) {
         if restart_task {
             assert_eq!(self.state, DsState::LiveRepair);
-            self.checked_state_transition(up_state, DsState::Faulted);
-            self.halt_io_task(ClientStopReason::FailedLiveRepair);
+            let reason = ClientStopReason::FailedLiveRepair;
+            self.transition(up_state, DsState::Faulted, reason.cause());
+            self.halt_io_task(reason);
         } else {
             // Someone else (i.e. receiving an error upon IO completion) already
             // restarted the IO task and kicked us out of the live-repair state,
@@ -1204,7 +1498,7 @@ impl DownstairsClient {
         up_state: &UpstairsState,
         reason: ClientStopReason,
     ) {
-        self.checked_state_transition(up_state, DsState::Faulted);
+        self.transition(up_state, DsState::Faulted, reason.cause());
         self.halt_io_task(reason);
     }
 
@@ -1214,7 +1508,7 @@ impl DownstairsClient {
     /// If this client is not in `DsState::LiveRepair`
     pub(crate) fn finish_repair(&mut self, up_state: &UpstairsState) {
         assert_eq!(self.state, DsState::LiveRepair);
-        self.checked_state_transition(up_state, DsState::Active);
+        self.transition(up_state, DsState::Active, "live_repair_done");
         self.repair_info = None;
         self.stats.live_repair_completed += 1;
     }
@@ -1246,6 +1540,11 @@ impl DownstairsClient {
         let mut jobs_completed_ok = job.state_count().completed_ok();
         let mut ackable = false;
 
+        // Writes and flushes need a majority of the region set, while repair
+        // operations need every downstairs to reply.
+        let all_clients = job.state.len() as u64;
+        let quorum = crate::write_quorum(job.state.len()) as u64;
+
         let new_state = match &responses {
             Ok(..) => {
                 // Messages have already been decrypted out-of-band
@@ -1275,6 +1574,15 @@ impl DownstairsClient {
                 self.client_id, ds_id, old_state, job
             );
         }
+        if let Some(sent) = job.sent[self.client_id].take() {
+            let now = Instant::now();
+            job.replied = Some(now);
+            self.stats
+                .job_latency
+                .entry(job.work.job_type())
+                .or_default()
+                .record(now.saturating_duration_since(sent));
+        }
 
         if let IOState::Error(e) = new_state {
             // Some errors can be returned without considering the Downstairs
@@ -1390,8 +1698,8 @@ impl DownstairsClient {
                 /*
                  * Write and WriteUnwritten IOs have no action here
                  * If this job was LiveRepair, we should never get here,
-                 * as those jobs should never be acked before all three
-                 * are done.
+                 * as those jobs should never be acked before all
+                 * downstairs are done.
                  */
                 IOop::Write { .. } | IOop::WriteUnwritten { .. } => {}
                 IOop::ExtentFlushClose { .. }
@@ -1456,7 +1764,7 @@ impl DownstairsClient {
                     assert!(read_data.blocks.is_empty());
                     assert!(read_data.data.is_empty());
                     assert!(extent_info.is_none());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == quorum {
                         ackable = true;
                         cdt::up__to__ds__write__done!(|| job.guest_id.0);
                     }
@@ -1465,7 +1773,7 @@ impl DownstairsClient {
                     assert!(read_data.blocks.is_empty());
                     assert!(read_data.data.is_empty());
                     assert!(extent_info.is_none());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == quorum {
                         ackable = true;
                         cdt::up__to__ds__write__unwritten__done!(|| job
                             .guest_id
@@ -1480,17 +1788,17 @@ impl DownstairsClient {
                     assert!(extent_info.is_none());
                     /*
                      * If we are deactivating or have requested a
-                     * snapshot, then we want an ACK from all three
-                     * downstairs, not the usual two.
+                     * snapshot, then we want an ACK from all
+                     * downstairs, not the usual quorum.
                      *
                      * TODO here for handling the case where one (or two,
                      * or three! gasp!) downstairs are Offline.
                      */
                     let ack_at_num_jobs =
                         if deactivate || snapshot_details.is_some() {
-                            3
+                            all_clients
                         } else {
-                            2
+                            quorum
                         };
 
                     if jobs_completed_ok == ack_at_num_jobs {
@@ -1514,7 +1822,7 @@ impl DownstairsClient {
                         );
                     }
 
-                    if jobs_completed_ok == 3 {
+                    if jobs_completed_ok == all_clients {
                         debug!(self.log, "ExtentFlushClose {ds_id} AckReady");
                         ackable = true;
                     }
@@ -1522,7 +1830,7 @@ impl DownstairsClient {
                 IOop::ExtentLiveRepair { .. } => {
                     assert!(read_data.blocks.is_empty());
                     assert!(read_data.data.is_empty());
-                    if jobs_completed_ok == 3 {
+                    if jobs_completed_ok == all_clients {
                         debug!(self.log, "ExtentLiveRepair AckReady {ds_id}");
                         ackable = true;
                     }
@@ -1530,7 +1838,7 @@ impl DownstairsClient {
                 IOop::ExtentLiveReopen { .. } => {
                     assert!(read_data.blocks.is_empty());
                     assert!(read_data.data.is_empty());
-                    if jobs_completed_ok == 3 {
+                    if jobs_completed_ok == all_clients {
                         debug!(self.log, "ExtentLiveReopen AckReady {ds_id}");
                         ackable = true;
                     }
@@ -1538,7 +1846,7 @@ impl DownstairsClient {
                 IOop::ExtentLiveNoOp { .. } => {
                     assert!(read_data.blocks.is_empty());
                     assert!(read_data.data.is_empty());
-                    if jobs_completed_ok == 3 {
+                    if jobs_completed_ok == all_clients {
                         debug!(self.log, "ExtentLiveNoOp AckReady {ds_id}");
                         ackable = true;
                     }
@@ -1551,29 +1859,35 @@ impl DownstairsClient {
     /// Mark this client as disabled and halt its IO task
     ///
     /// The IO task will automatically restart in the main event handler
-    pub(crate) fn disable(&mut self, up_state: &UpstairsState) {
-        self.checked_state_transition(up_state, DsState::Disabled);
+    pub(crate) fn disable(&mut self, up_state: &UpstairsState, cause: &str) {
+        self.transition(up_state, DsState::Disabled, cause);
         self.halt_io_task(ClientStopReason::Disabled);
     }
 
     /// Skips from `LiveRepairReady` to `Active`; a no-op otherwise
     ///
     /// # Panics
-    /// If this downstairs is not read-only
+    /// If this downstairs is not read-only or the only one in its region set
     pub(crate) fn skip_live_repair(&mut self, up_state: &UpstairsState) {
         if self.state == DsState::LiveRepairReady {
-            assert!(self.cfg.read_only);
+            assert!(self.cfg.read_only || self.cfg.region_set_size == 1);
             // TODO: could we do this transition early, by automatically
             // skipping LiveRepairReady if read-only?
-            self.checked_state_transition(up_state, DsState::Active);
-            self.stats.ro_lr_skipped += 1;
+            self.transition(up_state, DsState::Active, "live_repair_skipped");
+            if self.cfg.read_only {
+                self.stats.ro_lr_skipped += 1;
+            }
         }
     }
 
     /// Moves from `LiveRepairReady` to `LiveRepair`; a no-op otherwise
     pub(crate) fn start_live_repair(&mut self, up_state: &UpstairsState) {
         if self.state == DsState::LiveRepairReady {
-            self.checked_state_transition(up_state, DsState::LiveRepair);
+            self.transition(
+                up_state,
+                DsState::LiveRepair,
+                "live_repair_started",
+            );
         }
     }
 
@@ -1684,6 +1998,7 @@ impl DownstairsClient {
             Message::YesItsMe {
                 version,
                 repair_addr,
+                capabilities,
             } => {
                 if self.negotiation_state != NegotiationState::Start {
                     error!(self.log, "got version already");
@@ -1693,16 +2008,17 @@ impl DownstairsClient {
                     );
                     return Ok(false);
                 }
-                if version != CRUCIBLE_MESSAGE_VERSION {
+                if !SUPPORTED_MESSAGE_VERSIONS.contains(&version) {
                     error!(
                         self.log,
-                        "expected version {}, got {}",
-                        CRUCIBLE_MESSAGE_VERSION,
+                        "expected one of versions {:?}, got {}",
+                        SUPPORTED_MESSAGE_VERSIONS,
                         version
                     );
-                    self.checked_state_transition(
+                    self.transition(
                         up_state,
                         DsState::BadVersion,
+                        "unsupported_version",
                     );
                     self.restart_connection(
                         up_state,
@@ -1710,8 +2026,23 @@ impl DownstairsClient {
                     );
                     return Ok(false);
                 }
+                if version != CRUCIBLE_MESSAGE_VERSION {
+                    warn!(
+                        self.log,
+                        "downstairs is using older message version {}", version
+                    );
+                }
                 self.negotiation_state = NegotiationState::WaitForPromote;
                 self.repair_addr = Some(repair_addr);
+
+                // Only use features that we asked for, even if the
+                // downstairs claims to support more.
+                self.capabilities =
+                    capabilities.intersection(CRUCIBLE_CAPABILITIES);
+                info!(
+                    self.log,
+                    "negotiated capabilities {}", self.capabilities
+                );
                 match self.promote_state {
                     Some(PromoteState::Waiting) => {
                         self.send(Message::PromoteToActive {
@@ -1731,9 +2062,10 @@ impl DownstairsClient {
                             || (self.state == DsState::Replaced
                                 && !matches!(up_state, UpstairsState::Active))
                         {
-                            self.checked_state_transition(
+                            self.transition(
                                 up_state,
                                 DsState::WaitActive,
+                                "negotiated",
                             );
                         } else {
                             warn!(
@@ -1749,9 +2081,10 @@ impl DownstairsClient {
                     }
                     None => {
                         // Nothing to do here, wait for set_active_request
-                        self.checked_state_transition(
+                        self.transition(
                             up_state,
                             DsState::WaitActive,
+                            "negotiated",
                         );
                     }
                 }
@@ -1762,7 +2095,11 @@ impl DownstairsClient {
                     "downstairs version is {version}, \
                      ours is {CRUCIBLE_MESSAGE_VERSION}"
                 );
-                self.checked_state_transition(up_state, DsState::BadVersion);
+                self.transition(
+                    up_state,
+                    DsState::BadVersion,
+                    "version_mismatch",
+                );
                 self.restart_connection(
                     up_state,
                     ClientStopReason::Incompatible,
@@ -1840,7 +2177,7 @@ impl DownstairsClient {
                             String::new()
                         },
                     );
-                    self.checked_state_transition(up_state, DsState::New);
+                    self.transition(up_state, DsState::New, "promote_mismatch");
                     if !match_gen {
                         let gen_error = format!(
                             "Generation requested:{} found:{}",
@@ -2084,11 +2421,11 @@ impl DownstairsClient {
                 // if the client was coming back from Offline.
                 //
                 // XXX should we remove this state?
-                self.checked_state_transition(up_state, DsState::Replay);
+                self.transition(up_state, DsState::Replay, "reconnected");
 
                 // Immediately set the state to Active, since we've already
                 // copied over the jobs.
-                self.checked_state_transition(up_state, DsState::Active);
+                self.transition(up_state, DsState::Active, "replayed");
 
                 self.negotiation_state = NegotiationState::Done;
             }
@@ -2108,9 +2445,10 @@ impl DownstairsClient {
                 }
                 match self.state {
                     DsState::WaitActive => {
-                        self.checked_state_transition(
+                        self.transition(
                             up_state,
                             DsState::WaitQuorum,
+                            "extent_versions",
                         );
                     }
                     DsState::Replacing => {
@@ -2126,9 +2464,10 @@ impl DownstairsClient {
                         return Ok(false); // TODO should we trigger set_inactive?
                     }
                     DsState::Faulted | DsState::Replaced => {
-                        self.checked_state_transition(
+                        self.transition(
                             up_state,
                             DsState::LiveRepairReady,
+                            "extent_versions",
                         );
                     }
                     s => panic!("downstairs in invalid state {s}"),
@@ -2344,6 +2683,10 @@ pub(crate) struct DownstairsStats {
 
     /// Count of downstairs replacements
     pub replaced: usize,
+
+    /// Time from sending each job to the downstairs until it replied, by job
+    /// type
+    pub job_latency: BTreeMap<&'static str, LatencyHistogram>,
 }
 
 /// When the upstairs halts the IO client task, it must provide a reason
@@ -2389,6 +2732,31 @@ pub(crate) enum ClientStopReason {
     OfflineDeactivated,
 }
 
+impl ClientStopReason {
+    /// Returns the reason as given for a state change in the audit log
+    pub(crate) fn cause(&self) -> &'static str {
+        match self {
+            ClientStopReason::Replacing => "replacing",
+            ClientStopReason::Disabled => "disabled",
+            ClientStopReason::FailedReconcile => "failed_reconcile",
+            ClientStopReason::IOError => "io_error",
+            ClientStopReason::BadNegotiationOrder => "bad_negotiation_order",
+            ClientStopReason::Incompatible => "incompatible",
+            ClientStopReason::FailedLiveRepair => "failed_live_repair",
+            ClientStopReason::TooManyOutstandingJobs => {
+                "too_many_outstanding_jobs"
+            }
+            ClientStopReason::TooManyOutstandingBytes => {
+                "too_many_outstanding_bytes"
+            }
+            ClientStopReason::Deactivated => "deactivated",
+            #[cfg(test)]
+            ClientStopReason::RequestedFault => "requested_fault",
+            ClientStopReason::OfflineDeactivated => "offline_deactivated",
+        }
+    }
+}
+
 /// Response received from the I/O task
 #[derive(Debug)]
 pub(crate) enum ClientResponse {
@@ -2477,6 +2845,18 @@ struct ClientIoTask {
     /// Shared handle to receive per-client backpressure delay
     client_delay_us: Arc<AtomicU64>,
 
+    /// Timeouts and ping interval for this connection
+    timeouts: ClientTimeouts,
+
+    /// Round-trip time estimate, updated by our pings
+    rtt: Arc<ClientRtt>,
+
+    /// Compression done on every connection
+    compression_stats: Arc<CompressionStats>,
+
+    /// Where to record each connection's messages, if anywhere
+    capture: Option<CaptureConfig>,
+
     log: Logger,
 }
 
@@ -2669,17 +3049,49 @@ impl ClientIoTask {
 
             let sock = connector.connect(server_name, tcp).await.unwrap();
             let (read, write) = tokio::io::split(sock);
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = MessageWriter::new(write);
+            let version = ConnectionVersion::with_compression_stats(
+                self.compression_stats.clone(),
+            );
+            let mut fr = FramedRead::new(
+                read,
+                CrucibleDecoder::with_version(version.clone()),
+            );
+            let mut fw = MessageWriter::with_version(write, version);
+            if let Some(c) = self.open_capture() {
+                fr.decoder_mut().set_capture(c.clone());
+                fw.set_capture(c);
+            }
             self.cmd_loop(fr, fw).await
         } else {
             let (read, write) = tcp.into_split();
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = MessageWriter::new(write);
+            let version = ConnectionVersion::with_compression_stats(
+                self.compression_stats.clone(),
+            );
+            let mut fr = FramedRead::new(
+                read,
+                CrucibleDecoder::with_version(version.clone()),
+            );
+            let mut fw = MessageWriter::with_version(write, version);
+            if let Some(c) = self.open_capture() {
+                fr.decoder_mut().set_capture(c.clone());
+                fw.set_capture(c);
+            }
             self.cmd_loop(fr, fw).await
         }
     }
 
+    /// Creates a capture file for a new connection, if we're capturing
+    fn open_capture(&self) -> Option<Arc<Capture>> {
+        let c = self.capture.as_ref()?;
+        match c.create(&format!("client{}", self.client_id)) {
+            Ok(capture) => Some(Arc::new(capture)),
+            Err(e) => {
+                warn!(self.log, "could not create capture in {:?}: {e}", c.dir);
+                None
+            }
+        }
+    }
+
     async fn cmd_loop<R, W>(
         &mut self,
         fr: FramedRead<R, crucible_protocol::CrucibleDecoder>,
@@ -2699,6 +3111,9 @@ impl ClientIoTask {
             .send(ClientResponse::Connected)
             .expect("client_response_tx closed unexpectedly");
 
+        // Any ping from a previous connection will never be answered
+        self.rtt.clear_ping();
+
         // Spawn a separate task to receive data over the network, so that we
         // can always make progress and keep the socket buffer from filling up.
         self.recv_task.handle = Some(tokio::spawn(rx_loop(
@@ -2706,9 +3121,12 @@ impl ClientIoTask {
             fr,
             self.log.clone(),
             self.client_id,
+            self.timeouts,
+            self.rtt.clone(),
         )));
 
-        let mut ping_interval = deadline_secs(PING_INTERVAL_SECS);
+        let ping_interval_secs = self.timeouts.ping_interval_secs;
+        let mut ping_interval = deadline_secs(ping_interval_secs);
         let mut ping_count = 0u64;
         loop {
             tokio::select! {
@@ -2732,10 +3150,11 @@ impl ClientIoTask {
                 }
 
                 _ = sleep_until(ping_interval) => {
-                    ping_interval = deadline_secs(PING_INTERVAL_SECS);
+                    ping_interval = deadline_secs(ping_interval_secs);
                     ping_count += 1;
                     cdt::ds__ping__sent!(|| (ping_count, self.client_id.get()));
 
+                    self.rtt.ping_sent();
                     let m = Message::Ruok;
                     if let Err(e) = self.write(&mut fw, m).await {
                         break e;
@@ -2838,15 +3257,23 @@ async fn rx_loop<R>(
     mut fr: FramedRead<R, crucible_protocol::CrucibleDecoder>,
     log: Logger,
     cid: ClientId,
+    timeouts: ClientTimeouts,
+    rtt: Arc<ClientRtt>,
 ) -> ClientRunResult
 where
     R: tokio::io::AsyncRead + std::marker::Unpin + std::marker::Send + 'static,
 {
     loop {
+        // Recompute the timeout on every pass, since it may have been
+        // stretched by a new round-trip measurement.
+        let timeout_secs = timeouts.inactivity_secs(rtt.get());
         tokio::select! {
             f = fr.next() => {
                 match f {
                     Some(Ok(m)) => {
+                        if matches!(m, Message::Imok) {
+                            rtt.pong_received();
+                        }
                         update_net_done_probes(&m, cid);
                         if let Err(e) =
                             response_tx.send(ClientResponse::Message(m))
@@ -2869,7 +3296,11 @@ where
                     }
                 }
             }
-            _ = verbose_timeout(TIMEOUT_SECS, TIMEOUT_LIMIT, log.clone()) => {
+            _ = verbose_timeout(
+                timeout_secs,
+                timeouts.timeout_limit,
+                log.clone(),
+            ) => {
                 warn!(log, "inactivity timeout");
                 break ClientRunResult::Timeout;
             }
@@ -3418,4 +3849,101 @@ mod test {
             DsState::Faulted,
         );
     }
+
+    #[test]
+    fn client_timeouts_default() {
+        let t = ClientTimeouts::new(None);
+        assert_eq!(t.timeout_secs, TIMEOUT_SECS);
+        assert_eq!(t.timeout_limit, TIMEOUT_LIMIT);
+        assert_eq!(t.ping_interval_secs, PING_INTERVAL_SECS);
+        assert!(!t.adaptive);
+        assert_eq!(t.io_outstanding_max_jobs, crate::IO_OUTSTANDING_MAX_JOBS);
+        assert_eq!(t.io_outstanding_max_bytes, crate::IO_OUTSTANDING_MAX_BYTES);
+        t.validate().unwrap();
+    }
+
+    #[test]
+    fn client_timeouts_partial_override() {
+        let t = ClientTimeouts::new(Some(&ClientTimeoutOpts {
+            timeout_secs: Some(2.0),
+            ping_interval_secs: Some(0.5),
+            io_outstanding_max_jobs: Some(100),
+            ..Default::default()
+        }));
+        assert_eq!(t.timeout_secs, 2.0);
+        assert_eq!(t.timeout_limit, TIMEOUT_LIMIT);
+        assert_eq!(t.ping_interval_secs, 0.5);
+        assert_eq!(t.io_outstanding_max_jobs, 100);
+        assert_eq!(t.io_outstanding_max_bytes, crate::IO_OUTSTANDING_MAX_BYTES);
+        t.validate().unwrap();
+    }
+
+    #[test]
+    fn client_timeouts_invalid() {
+        let bad = [
+            ClientTimeoutOpts {
+                timeout_secs: Some(0.0),
+                ..Default::default()
+            },
+            ClientTimeoutOpts {
+                timeout_limit: Some(0),
+                ..Default::default()
+            },
+            ClientTimeoutOpts {
+                timeout_secs: Some(5.0),
+                ping_interval_secs: Some(5.0),
+                ..Default::default()
+            },
+            ClientTimeoutOpts {
+                io_outstanding_max_bytes: Some(0),
+                ..Default::default()
+            },
+        ];
+        for opts in bad {
+            assert!(ClientTimeouts::new(Some(&opts)).validate().is_err());
+        }
+    }
+
+    #[test]
+    fn client_timeouts_adaptive() {
+        let mut t = ClientTimeouts::new(None);
+        let slow = Some(Duration::from_secs(2));
+
+        // Without adaptive timeouts, the RTT is ignored
+        assert_eq!(t.inactivity_secs(slow), TIMEOUT_SECS);
+
+        // With adaptive timeouts, a slow link stretches the timeout...
+        t.adaptive = true;
+        assert_eq!(t.inactivity_secs(None), TIMEOUT_SECS);
+        assert_eq!(
+            t.inactivity_secs(slow),
+            PING_INTERVAL_SECS + 2.0 * ADAPTIVE_RTT_MULTIPLIER
+        );
+
+        // ...but a fast link never shrinks it
+        assert_eq!(
+            t.inactivity_secs(Some(Duration::from_micros(100))),
+            TIMEOUT_SECS
+        );
+    }
+
+    #[test]
+    fn client_rtt_smoothing() {
+        let rtt = ClientRtt::default();
+        assert!(rtt.get().is_none());
+
+        // A pong without a ping is ignored
+        rtt.pong_received();
+        assert!(rtt.get().is_none());
+
+        rtt.ping_sent();
+        rtt.pong_received();
+        let first = rtt.get().unwrap();
+
+        // Pings from an old connection are forgotten
+        rtt.ping_sent();
+        rtt.clear_ping();
+        rtt.pong_received();
+        assert_eq!(rtt.get().unwrap(), first);
+    }
 }
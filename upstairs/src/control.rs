@@ -1,4 +1,9 @@
 // Copyright 2022 Oxide Computer Company
+use chrono::{DateTime, Utc};
+use crucible_common::audit::{AuditLog, AuditRecords};
+use crucible_common::metrics::{
+    LatencyHistogram, MetricType, PrometheusText, StageLatencySummary,
+};
 use dropshot::endpoint;
 use dropshot::ApiDescription;
 use dropshot::ConfigDropshot;
@@ -6,10 +11,20 @@ use dropshot::HandlerTaskMode;
 use dropshot::HttpError;
 use dropshot::HttpResponseOk;
 use dropshot::HttpServerStarter;
+use dropshot::Path;
+use dropshot::Query;
 use dropshot::RequestContext;
+use dropshot::TypedBody;
+use http::{Response, StatusCode};
+use hyper::Body;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::broadcast;
+use tokio::time::MissedTickBehavior;
 
 use super::*;
 
@@ -17,6 +32,15 @@ pub(crate) fn build_api() -> ApiDescription<UpstairsInfo> {
     let mut api = ApiDescription::new();
     api.register(upstairs_fill_info).unwrap();
     api.register(downstairs_work_queue).unwrap();
+    api.register(reload_tls).unwrap();
+    api.register(stream_stats).unwrap();
+    api.register(get_audit).unwrap();
+    api.register(get_metrics).unwrap();
+    api.register(fault_downstairs).unwrap();
+    api.register(replace_downstairs).unwrap();
+    api.register(flush).unwrap();
+    api.register(deactivate).unwrap();
+    api.register(abort_live_repair).unwrap();
 
     api
 }
@@ -72,6 +96,41 @@ pub async fn start(
 pub(crate) enum ControlRequest {
     DownstairsWorkQueue(oneshot::Sender<DownstairsWork>),
     UpstairsStats(oneshot::Sender<UpstairsStats>),
+    DownstairsState(oneshot::Sender<Vec<DsState>>),
+    Op {
+        op: ControlOp,
+        done: oneshot::Sender<Result<ControlOpStarted, CrucibleError>>,
+    },
+}
+
+/// A change to the upstairs requested through the control server
+#[derive(Debug)]
+pub(crate) enum ControlOp {
+    FaultDownstairs {
+        client_id: u8,
+        /// Fault it even if that leaves too few active downstairs for writes
+        force: bool,
+    },
+    ReplaceDownstairs {
+        id: Uuid,
+        old: SocketAddr,
+        new: SocketAddr,
+    },
+    Flush,
+    Deactivate,
+    AbortLiveRepair,
+}
+
+/// The upstairs' reply to a `ControlOp`, once the operation has started
+pub(crate) struct ControlOpStarted {
+    /// State of each downstairs before the operation
+    pub before: Vec<DsState>,
+
+    /// What happened to a replacement request
+    pub replace: Option<ReplaceResult>,
+
+    /// Resolves once a flush or deactivation has finished
+    pub wait: Option<BlockOpWaiter<()>>,
 }
 
 impl std::fmt::Debug for ControlRequest {
@@ -83,6 +142,12 @@ impl std::fmt::Debug for ControlRequest {
             ControlRequest::UpstairsStats(..) => {
                 f.debug_struct("UpstairsStats").finish()
             }
+            ControlRequest::DownstairsState(..) => {
+                f.debug_struct("DownstairsState").finish()
+            }
+            ControlRequest::Op { op, .. } => {
+                f.debug_struct("Op").field("op", op).finish()
+            }
         }
     }
 }
@@ -93,17 +158,114 @@ impl std::fmt::Debug for ControlRequest {
 pub struct UpstairsInfo {
     /// Channel used to send messages to the upstairs
     up: mpsc::Sender<ControlRequest>,
+
+    /// TLS material used to connect to the downstairs, if any
+    tls: Option<Arc<crucible_common::x509::TLSContext>>,
+
+    /// Bearer token required by the write endpoints, which are disabled if
+    /// this is `None`
+    token: Option<ControlToken>,
+
+    /// Summaries of jobs as they're acked, for `stream_stats`
+    jobs: broadcast::Sender<WorkSummary>,
+
+    /// Counters that we also send to oximeter, for `get_metrics`
+    counters: crate::stats::UpStatOuter,
+    upstairs_id: Uuid,
+
+    /// Lifecycle events, for `get_audit`
+    audit: Arc<AuditLog>,
 }
 
 impl UpstairsInfo {
     /**
      * Return a new UpstairsInfo.
      */
-    pub(crate) fn new(up: &crate::upstairs::Upstairs) -> UpstairsInfo {
+    pub(crate) fn new(
+        up: &crate::upstairs::Upstairs,
+        token: Option<ControlToken>,
+    ) -> UpstairsInfo {
         UpstairsInfo {
             up: up.control_tx.clone(),
+            tls: up.tls_context.clone(),
+            token,
+            jobs: up.downstairs.job_events(),
+            counters: up.stats.clone(),
+            upstairs_id: up.cfg.upstairs_id,
+            audit: up.cfg.audit.clone(),
         }
     }
+
+    /// Checks that a request to a write endpoint carries our token
+    fn authorize(
+        &self,
+        request: &dropshot::RequestInfo,
+    ) -> Result<(), HttpError> {
+        let Some(token) = &self.token else {
+            return Err(HttpError::for_client_error(
+                None,
+                http::StatusCode::FORBIDDEN,
+                "write endpoints are disabled without a control token"
+                    .to_string(),
+            ));
+        };
+        let offered = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        // Compare in constant time, so the time taken doesn't tell a caller
+        // how much of a guessed token was right.
+        let matches = offered.is_some_and(|offered| {
+            bool::from(offered.as_bytes().ct_eq(token.as_str().as_bytes()))
+        });
+        if !matches {
+            return Err(HttpError::for_client_error(
+                None,
+                http::StatusCode::UNAUTHORIZED,
+                "missing or incorrect bearer token".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Asks the upstairs to carry out `op`, then reports how the downstairs
+    /// states changed once it has finished
+    async fn apply(&self, op: ControlOp) -> Result<ControlOpResult, HttpError> {
+        let (tx, rx) = oneshot::channel();
+        self.up
+            .send(ControlRequest::Op { op, done: tx })
+            .await
+            .unwrap();
+        let started = rx.await.unwrap()?;
+        if let Some(w) = started.wait {
+            w.wait().await?;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.up
+            .send(ControlRequest::DownstairsState(tx))
+            .await
+            .unwrap();
+        let after = rx.await.unwrap();
+
+        let transitions = started
+            .before
+            .iter()
+            .zip(after.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (old, new))| DsTransition {
+                client_id: i as u8,
+                old: *old,
+                new: *new,
+            })
+            .collect();
+        Ok(ControlOpResult {
+            transitions,
+            replace: started.replace,
+        })
+    }
 }
 
 /**
@@ -118,11 +280,87 @@ pub(crate) struct UpstairsStats {
     pub ds_jobs: usize,
     pub reconcile_done: usize,
     pub reconcile_needed: usize,
+    pub reconcile_aborted: usize,
     pub extents_repaired: Vec<usize>,
     pub extents_confirmed: Vec<usize>,
     pub extent_limit: Vec<Option<usize>>,
     pub live_repair_completed: Vec<usize>,
     pub live_repair_aborted: Vec<usize>,
+    pub client_timeouts: ClientTimeouts,
+    pub ds_round_trip_us: Vec<Option<u64>>,
+    pub ds_timeout_secs: Vec<f32>,
+    pub ds_capabilities: Vec<Vec<String>>,
+    pub ds_compression: Vec<CompressionStatus>,
+    pub ds_io_count: Vec<ClientIOStateCount>,
+    pub ds_connected: Vec<usize>,
+    pub ds_replaced: Vec<usize>,
+    pub ds_delay_us: Vec<usize>,
+    /// Time from sending each job to a downstairs until it replied, by job
+    /// type
+    pub ds_job_latency: Vec<BTreeMap<String, LatencyHistogram>>,
+    /// Estimated percentiles of the time jobs spent in each stage of the
+    /// upstairs, by job type
+    pub io_latency: Vec<StageLatencySummary>,
+    pub next_job_id: JobId,
+    pub backpressure_us: u64,
+    pub write_bytes_out: u64,
+    pub replica: Option<crate::replica::ReplicaStats>,
+    pub tls: Option<TlsStatus>,
+}
+
+/**
+ * The TLS certificates used to connect to the downstairs, and when they
+ * expire
+ */
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct TlsStatus {
+    pub cert_not_after: Option<DateTime<Utc>>,
+    pub root_not_after: Option<DateTime<Utc>>,
+    pub reloads: u64,
+}
+
+impl TlsStatus {
+    pub(crate) fn new(tls: &crucible_common::x509::TLSContext) -> TlsStatus {
+        let expiry = tls.expiry();
+        TlsStatus {
+            cert_not_after: expiry.cert_not_after.map(DateTime::from),
+            root_not_after: expiry.root_not_after.map(DateTime::from),
+            reloads: tls.generation(),
+        }
+    }
+}
+
+/**
+ * Compression of read and write data sent to and received from one
+ * downstairs, summed over every connection
+ */
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct CompressionStatus {
+    /// Algorithm in use on the current connection, if any
+    pub algorithm: Option<crucible_protocol::Compression>,
+    pub messages: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+    /// Uncompressed bytes per compressed byte
+    pub ratio: Option<f64>,
+    /// Time spent compressing and decompressing
+    pub cpu_us: u64,
+}
+
+impl CompressionStatus {
+    pub(crate) fn new(
+        algorithm: Option<crucible_protocol::Compression>,
+        counts: crucible_protocol::CompressionCounts,
+    ) -> CompressionStatus {
+        CompressionStatus {
+            algorithm,
+            messages: counts.messages,
+            uncompressed_bytes: counts.uncompressed_bytes,
+            compressed_bytes: counts.compressed_bytes,
+            ratio: counts.ratio(),
+            cpu_us: counts.cpu_us,
+        }
+    }
 }
 
 /**
@@ -149,6 +387,564 @@ async fn upstairs_fill_info(
     Ok(HttpResponseOk(out))
 }
 
+/**
+ * Read the TLS certificate, key and root PEM files again
+ *
+ * New connections to the downstairs use the new certificates.  If the files
+ * can't be loaded, the current ones are kept.
+ */
+#[endpoint {
+    method = POST,
+    path = "/tls/reload",
+    unpublished = false,
+}]
+async fn reload_tls(
+    rqctx: RequestContext<UpstairsInfo>,
+) -> Result<HttpResponseOk<TlsStatus>, HttpError> {
+    rqctx.context().authorize(&rqctx.request)?;
+    let Some(tls) = &rqctx.context().tls else {
+        return Err(HttpError::for_bad_request(
+            None,
+            "this upstairs isn't using TLS".to_string(),
+        ));
+    };
+
+    if let Err(e) = tls.reload() {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("failed to reload TLS certificates: {e:?}"),
+        ));
+    }
+    info!(rqctx.log, "reloaded TLS certificates: {:?}", tls.expiry());
+
+    Ok(HttpResponseOk(TlsStatus::new(tls)))
+}
+
+impl UpstairsStats {
+    /// Adds our gauges, per-downstairs counters and latency histograms to a
+    /// page of Prometheus metrics
+    fn prometheus(&self, upstairs_id: &str, p: &mut PrometheusText) {
+        let labels = [("upstairs_id", upstairs_id)];
+        let gauges = [
+            (
+                "crucible_upstairs_guest_jobs",
+                "Jobs on the guest work queue",
+                self.up_jobs as f64,
+            ),
+            (
+                "crucible_upstairs_ds_jobs",
+                "Jobs on the downstairs work queue",
+                self.ds_jobs as f64,
+            ),
+            (
+                "crucible_upstairs_backpressure_seconds",
+                "Delay currently applied to each guest write",
+                self.backpressure_us as f64 / 1e6,
+            ),
+            (
+                "crucible_upstairs_write_bytes_outstanding",
+                "Bytes of writes in flight to the downstairs",
+                self.write_bytes_out as f64,
+            ),
+            (
+                "crucible_upstairs_reconcile_repaired",
+                "Extents repaired during initial reconciliation",
+                self.reconcile_done as f64,
+            ),
+            (
+                "crucible_upstairs_reconcile_needed",
+                "Extents still needing repair during initial reconciliation",
+                self.reconcile_needed as f64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            p.family(name, MetricType::Gauge, help);
+            p.sample(name, &labels, value);
+        }
+        let name = "crucible_upstairs_reconcile_aborted_total";
+        p.family(name, MetricType::Counter, "Failed initial reconciliations");
+        p.sample(name, &labels, self.reconcile_aborted);
+
+        // Every per-downstairs metric is labelled with its client ID and
+        // current state
+        let clients: Vec<(String, String)> = self
+            .ds_state
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (i.to_string(), s.to_string()))
+            .collect();
+        let client_labels = |i: usize| {
+            [
+                ("upstairs_id", upstairs_id),
+                ("client_id", clients[i].0.as_str()),
+                ("ds_state", clients[i].1.as_str()),
+            ]
+        };
+
+        let name = "crucible_upstairs_ds_io_jobs";
+        p.family(name, MetricType::Gauge, "Downstairs jobs in each state");
+        for (i, c) in self.ds_io_count.iter().enumerate() {
+            for (io_state, n) in [
+                ("new", c.new),
+                ("in_progress", c.in_progress),
+                ("done", c.done),
+                ("skipped", c.skipped),
+                ("error", c.error),
+            ] {
+                let [a, b, s] = client_labels(i);
+                p.sample(name, &[a, b, s, ("io_state", io_state)], n);
+            }
+        }
+
+        let counters: [(&str, &str, &Vec<usize>); 6] = [
+            (
+                "crucible_upstairs_ds_connections_total",
+                "Times the upstairs has connected to this downstairs",
+                &self.ds_connected,
+            ),
+            (
+                "crucible_upstairs_ds_replacements_total",
+                "Times this downstairs has been replaced",
+                &self.ds_replaced,
+            ),
+            (
+                "crucible_upstairs_ds_live_repairs_completed_total",
+                "Live-repairs completed on this downstairs",
+                &self.live_repair_completed,
+            ),
+            (
+                "crucible_upstairs_ds_live_repairs_aborted_total",
+                "Live-repairs aborted on this downstairs",
+                &self.live_repair_aborted,
+            ),
+            (
+                "crucible_upstairs_ds_extents_repaired_total",
+                "Extents live-repaired on this downstairs",
+                &self.extents_repaired,
+            ),
+            (
+                "crucible_upstairs_ds_extents_confirmed_total",
+                "Extents checked by live-repair without needing repair",
+                &self.extents_confirmed,
+            ),
+        ];
+        for (name, help, values) in counters {
+            p.family(name, MetricType::Counter, help);
+            for (i, v) in values.iter().enumerate() {
+                p.sample(name, &client_labels(i), v);
+            }
+        }
+
+        let name = "crucible_upstairs_ds_delay_seconds";
+        p.family(
+            name,
+            MetricType::Gauge,
+            "Delay applied to keep this downstairs in step with the others",
+        );
+        for (i, us) in self.ds_delay_us.iter().enumerate() {
+            p.sample(name, &client_labels(i), *us as f64 / 1e6);
+        }
+
+        let name = "crucible_upstairs_ds_round_trip_seconds";
+        p.family(
+            name,
+            MetricType::Gauge,
+            "Smoothed round-trip time of pings to this downstairs",
+        );
+        for (i, us) in self.ds_round_trip_us.iter().enumerate() {
+            if let Some(us) = us {
+                p.sample(name, &client_labels(i), *us as f64 / 1e6);
+            }
+        }
+
+        let compression: [(&str, &str, fn(&CompressionStatus) -> u64); 2] = [
+            (
+                "crucible_upstairs_ds_uncompressed_bytes_total",
+                "Bulk data bytes before compression",
+                |c| c.uncompressed_bytes,
+            ),
+            (
+                "crucible_upstairs_ds_compressed_bytes_total",
+                "Bulk data bytes after compression",
+                |c| c.compressed_bytes,
+            ),
+        ];
+        for (name, help, f) in compression {
+            p.family(name, MetricType::Counter, help);
+            for (i, c) in self.ds_compression.iter().enumerate() {
+                p.sample(name, &client_labels(i), f(c));
+            }
+        }
+
+        let name = "crucible_upstairs_ds_job_latency_seconds";
+        p.family(
+            name,
+            MetricType::Histogram,
+            "Time from sending a job to this downstairs until it replied",
+        );
+        for (i, by_op) in self.ds_job_latency.iter().enumerate() {
+            for (op, h) in by_op {
+                let [a, b, s] = client_labels(i);
+                p.histogram(name, &[a, b, s, ("op", op.as_str())], h);
+            }
+        }
+    }
+}
+
+/**
+ * Our stats, in the Prometheus text format
+ */
+#[endpoint {
+    method = GET,
+    path = "/metrics",
+    unpublished = false,
+}]
+async fn get_metrics(
+    rqctx: RequestContext<UpstairsInfo>,
+) -> Result<Response<Body>, HttpError> {
+    let api_context = rqctx.context();
+    let Some(stats) = fetch_stats(&api_context.up).await else {
+        return Err(CrucibleError::UpstairsInactive.into());
+    };
+
+    let mut p = PrometheusText::new();
+    api_context.counters.prometheus(&mut p);
+    stats.prometheus(&api_context.upstairs_id.to_string(), &mut p);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, PrometheusText::CONTENT_TYPE)
+        .body(p.finish().into())?)
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct AuditParams {
+    /// Only return records with a sequence number after this one
+    after: Option<u64>,
+}
+
+/**
+ * Lifecycle events recorded since the upstairs started
+ *
+ * Only the most recent records are kept in memory; if some were discarded,
+ * `truncated` is set and the rest are in the audit log file.
+ */
+#[endpoint {
+    method = GET,
+    path = "/audit",
+    unpublished = false,
+}]
+async fn get_audit(
+    rqctx: RequestContext<UpstairsInfo>,
+    query: Query<AuditParams>,
+) -> Result<HttpResponseOk<AuditRecords>, HttpError> {
+    let after = query.into_inner().after.unwrap_or(0);
+    Ok(HttpResponseOk(rqctx.context().audit.after(after)))
+}
+
+/// Shortest interval allowed between `stats` events
+const MIN_STREAM_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Deserialize, JsonSchema)]
+struct StreamParams {
+    /// Milliseconds between `stats` events (default 1000, at least 100)
+    interval_ms: Option<u64>,
+    /// Comma-separated `UpstairsStats` fields to include in `stats` events
+    /// (default: all of them)
+    fields: Option<String>,
+    /// Also send a `job` event, holding a `WorkSummary`, as each job is acked
+    jobs: Option<bool>,
+}
+
+/**
+ * Stream `UpstairsStats` snapshots, and optionally acked jobs, as
+ * server-sent events
+ *
+ * Each snapshot is a `stats` event, and each job a `job` event.  If the
+ * stream falls behind the jobs, a `lagged` event gives how many it skipped.
+ */
+#[endpoint {
+    method = GET,
+    path = "/stats/stream",
+    unpublished = false,
+}]
+async fn stream_stats(
+    rqctx: RequestContext<UpstairsInfo>,
+    query: Query<StreamParams>,
+) -> Result<Response<Body>, HttpError> {
+    let api_context = rqctx.context();
+    let params = query.into_inner();
+
+    let interval = Duration::from_millis(params.interval_ms.unwrap_or(1000));
+    if interval < MIN_STREAM_INTERVAL {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "interval_ms must be at least {}",
+                MIN_STREAM_INTERVAL.as_millis()
+            ),
+        ));
+    }
+    let fields: Vec<String> = params
+        .fields
+        .iter()
+        .flat_map(|f| f.split(','))
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect();
+
+    // Check the field names before we commit to a stream
+    let Some(stats) = fetch_stats(&api_context.up).await else {
+        return Err(CrucibleError::UpstairsInactive.into());
+    };
+    let stats = serde_json::to_value(stats).unwrap();
+    if let Err(f) = select_fields(stats, &fields) {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("unknown UpstairsStats field {f:?}"),
+        ));
+    }
+
+    let mut jobs = params
+        .jobs
+        .unwrap_or(false)
+        .then(|| api_context.jobs.subscribe());
+    let up = api_context.up.clone();
+    let log = rqctx.log.clone();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let event = tokio::select! {
+                _ = ticker.tick() => {
+                    let Some(stats) = fetch_stats(&up).await else {
+                        break;
+                    };
+                    let stats = serde_json::to_value(stats).unwrap();
+                    // The field names were checked above
+                    let data = select_fields(stats, &fields).unwrap();
+                    sse_event("stats", &data)
+                }
+                r = next_job(&mut jobs) => match r {
+                    Ok(job) => {
+                        let data = serde_json::to_value(job).unwrap();
+                        sse_event("job", &data)
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        sse_event("lagged", &n.into())
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+            if sender.send_data(event).await.is_err() {
+                // The client went away
+                break;
+            }
+        }
+        info!(log, "stats stream finished");
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "text/event-stream")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .body(body)?)
+}
+
+/// Asks the upstairs for its stats, returning `None` if it has gone away
+async fn fetch_stats(
+    up: &mpsc::Sender<ControlRequest>,
+) -> Option<UpstairsStats> {
+    let (tx, rx) = oneshot::channel();
+    up.send(ControlRequest::UpstairsStats(tx)).await.ok()?;
+    rx.await.ok()
+}
+
+/// Waits for the next acked job, or forever if we aren't streaming jobs
+async fn next_job(
+    jobs: &mut Option<broadcast::Receiver<WorkSummary>>,
+) -> Result<WorkSummary, broadcast::error::RecvError> {
+    match jobs {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Keeps only the given fields of a serialized `UpstairsStats` (or all of
+/// them, if `fields` is empty), returning the first unknown field name
+fn select_fields(
+    stats: serde_json::Value,
+    fields: &[String],
+) -> Result<serde_json::Value, String> {
+    if fields.is_empty() {
+        return Ok(stats);
+    }
+    let serde_json::Value::Object(mut all) = stats else {
+        panic!("UpstairsStats must serialize as an object");
+    };
+    let mut out = serde_json::Map::new();
+    for f in fields {
+        let v = all.remove(f).ok_or_else(|| f.clone())?;
+        out.insert(f.clone(), v);
+    }
+    Ok(out.into())
+}
+
+/// Formats one server-sent event
+fn sse_event(event: &str, data: &serde_json::Value) -> bytes::Bytes {
+    format!("event: {event}\ndata: {data}\n\n").into()
+}
+
+/**
+ * A downstairs that changed state while handling a control request
+ */
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct DsTransition {
+    pub client_id: u8,
+    pub old: DsState,
+    pub new: DsState,
+}
+
+/**
+ * The result of a request to one of the write endpoints
+ */
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct ControlOpResult {
+    /// Downstairs whose state changed, from just before the request until it
+    /// finished
+    pub transitions: Vec<DsTransition>,
+    /// What happened to a replacement request
+    pub replace: Option<ReplaceResult>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ClientPath {
+    client_id: u8,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct FaultParams {
+    /// Fault the downstairs even if that leaves too few active downstairs to
+    /// complete writes
+    force: Option<bool>,
+}
+
+/**
+ * Mark a downstairs as faulted, which drops its connection and leads to a
+ * live-repair once it reconnects
+ *
+ * This is refused if it would leave fewer active downstairs than a write
+ * needs, unless `force` is set.
+ */
+#[endpoint {
+    method = POST,
+    path = "/downstairs/{client_id}/fault",
+    unpublished = false,
+}]
+async fn fault_downstairs(
+    rqctx: RequestContext<UpstairsInfo>,
+    path: Path<ClientPath>,
+    query: Query<FaultParams>,
+) -> Result<HttpResponseOk<ControlOpResult>, HttpError> {
+    let api_context = rqctx.context();
+    api_context.authorize(&rqctx.request)?;
+
+    let op = ControlOp::FaultDownstairs {
+        client_id: path.into_inner().client_id,
+        force: query.into_inner().force.unwrap_or(false),
+    };
+    Ok(HttpResponseOk(api_context.apply(op).await?))
+}
+
+/**
+ * Replace the downstairs at `old` with a new one at `new`
+ */
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct ReplaceRequest {
+    pub id: Uuid,
+    pub old: SocketAddr,
+    pub new: SocketAddr,
+}
+
+/**
+ * Start replacing a downstairs, as `Volume::target_replace` does
+ */
+#[endpoint {
+    method = POST,
+    path = "/downstairs/replace",
+    unpublished = false,
+}]
+async fn replace_downstairs(
+    rqctx: RequestContext<UpstairsInfo>,
+    body: TypedBody<ReplaceRequest>,
+) -> Result<HttpResponseOk<ControlOpResult>, HttpError> {
+    let api_context = rqctx.context();
+    api_context.authorize(&rqctx.request)?;
+
+    let ReplaceRequest { id, old, new } = body.into_inner();
+    let op = ControlOp::ReplaceDownstairs { id, old, new };
+    Ok(HttpResponseOk(api_context.apply(op).await?))
+}
+
+/**
+ * Send a flush to the downstairs, returning once it has finished
+ */
+#[endpoint {
+    method = POST,
+    path = "/flush",
+    unpublished = false,
+}]
+async fn flush(
+    rqctx: RequestContext<UpstairsInfo>,
+) -> Result<HttpResponseOk<ControlOpResult>, HttpError> {
+    let api_context = rqctx.context();
+    api_context.authorize(&rqctx.request)?;
+
+    Ok(HttpResponseOk(api_context.apply(ControlOp::Flush).await?))
+}
+
+/**
+ * Deactivate the upstairs, returning once every downstairs has finished
+ * its outstanding work
+ */
+#[endpoint {
+    method = POST,
+    path = "/deactivate",
+    unpublished = false,
+}]
+async fn deactivate(
+    rqctx: RequestContext<UpstairsInfo>,
+) -> Result<HttpResponseOk<ControlOpResult>, HttpError> {
+    let api_context = rqctx.context();
+    api_context.authorize(&rqctx.request)?;
+
+    Ok(HttpResponseOk(
+        api_context.apply(ControlOp::Deactivate).await?,
+    ))
+}
+
+/**
+ * Abort the live-repair in progress
+ *
+ * The downstairs being repaired is faulted, and repair starts again from
+ * the beginning once it reconnects.
+ */
+#[endpoint {
+    method = POST,
+    path = "/live-repair/abort",
+    unpublished = false,
+}]
+async fn abort_live_repair(
+    rqctx: RequestContext<UpstairsInfo>,
+) -> Result<HttpResponseOk<ControlOpResult>, HttpError> {
+    let api_context = rqctx.context();
+    api_context.authorize(&rqctx.request)?;
+
+    let op = ControlOp::AbortLiveRepair;
+    Ok(HttpResponseOk(api_context.apply(op).await?))
+}
+
 /**
  * `DownstairsWork` holds the information gathered from the downstairs
  */
@@ -186,8 +982,9 @@ async fn downstairs_work_queue(
 #[cfg(test)]
 mod test {
     use openapiv3::OpenAPI;
+    use serde_json::json;
 
-    use super::build_api;
+    use super::{build_api, select_fields, sse_event};
 
     #[test]
     fn test_crucible_control_openapi() {
@@ -211,4 +1008,25 @@ mod test {
             &actual,
         );
     }
+
+    #[test]
+    fn test_select_fields() {
+        let stats = json!({ "up_jobs": 3, "ds_jobs": 4, "ds_state": [] });
+        assert_eq!(select_fields(stats.clone(), &[]).unwrap(), stats);
+
+        let fields = vec!["ds_jobs".to_string(), "up_jobs".to_string()];
+        assert_eq!(
+            select_fields(stats.clone(), &fields).unwrap(),
+            json!({ "up_jobs": 3, "ds_jobs": 4 })
+        );
+
+        let fields = vec!["up_jobs".to_string(), "bogus".to_string()];
+        assert_eq!(select_fields(stats, &fields).unwrap_err(), "bogus");
+    }
+
+    #[test]
+    fn test_sse_event() {
+        let e = sse_event("stats", &json!({ "up_jobs": 3 }));
+        assert_eq!(&e[..], b"event: stats\ndata: {\"up_jobs\":3}\n\n");
+    }
 }
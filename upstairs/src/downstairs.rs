@@ -3,7 +3,7 @@ use std::{
     collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     net::SocketAddr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
@@ -11,7 +11,8 @@ use crate::{
     client::{ClientAction, ClientStopReason, DownstairsClient},
     guest::GuestWork,
     live_repair::ExtentInfo,
-    stats::UpStatOuter,
+    replica::{Replica, ReplicaOp, ReplicaStats},
+    stats::{IoStage, UpStatOuter},
     upstairs::{UpstairsConfig, UpstairsState},
     AckStatus, ActiveJobs, AllocRingBuffer, ClientData, ClientIOStateCount,
     ClientId, ClientMap, CrucibleError, DownstairsIO, DownstairsMend, DsState,
@@ -21,9 +22,10 @@ use crate::{
     Validation, WorkSummary,
 };
 use crucible_common::{
-    impacted_blocks::ImpactedAddr, BlockIndex, BlockOffset, ExtentId,
+    audit::AuditEvent, impacted_blocks::ImpactedAddr, otel, BlockIndex,
+    BlockOffset, ExtentId,
 };
-use crucible_protocol::WriteHeader;
+use crucible_protocol::{Capabilities, WriteHeader};
 
 use rand::prelude::*;
 use ringbuffer::RingBuffer;
@@ -58,7 +60,8 @@ cfg_if::cfg_if! {
 ///
 /// This data structure is responsible for tracking outstanding jobs from the
 /// perspective of the (3x) downstairs.  It contains a list of all active jobs,
-/// as well as three `DownstairsClient` with per-client data.
+/// as well as one `DownstairsClient` (normally three) per downstairs in the
+/// region set with per-client data.
 #[derive(Debug)]
 pub(crate) struct Downstairs {
     /// Shared configuration
@@ -134,11 +137,25 @@ pub(crate) struct Downstairs {
     /// This must be handled after every event
     ackable_work: BTreeSet<JobId>,
 
+    /// Optional asynchronous replica, fed with retired writes and flushes
+    replica: Option<Replica>,
+
+    /// Summaries of jobs as they're acked, for the control server's stream
+    job_events: tokio::sync::broadcast::Sender<WorkSummary>,
+
+    /// Stats for Oximeter and the control server, shared with the Upstairs
+    stats: UpStatOuter,
+
     /// A reqwest client, to be reused when creating Nexus clients
     #[cfg(feature = "notify-nexus")]
     reqwest_client: reqwest::Client,
 }
 
+/// Number of acked jobs buffered for each control server stream
+///
+/// A stream that falls further behind than this skips the oldest jobs.
+const JOB_EVENT_CAPACITY: usize = 1024;
+
 /// Helper struct to contain a count of backpressure bytes
 #[derive(Debug)]
 struct BackpressureBytes(u64);
@@ -296,21 +313,20 @@ impl Downstairs {
         cfg: Arc<UpstairsConfig>,
         ds_target: ClientMap<SocketAddr>,
         tls_context: Option<Arc<crucible_common::x509::TLSContext>>,
+        stats: UpStatOuter,
         log: Logger,
     ) -> Self {
-        let mut clients = [None, None, None];
-        for i in ClientId::iter() {
-            clients[i.get() as usize] = Some(DownstairsClient::new(
+        let clients = ClientData::from_fn(cfg.region_set_size, |i| {
+            DownstairsClient::new(
                 i,
                 cfg.clone(),
                 ds_target.get(&i).copied(),
                 log.new(o!("client" => i.get().to_string())),
                 tls_context.clone(),
-            ));
-        }
-        let clients = clients.map(Option::unwrap);
+            )
+        });
         Self {
-            clients: ClientData(clients),
+            clients,
             backpressure_config: DownstairsBackpressureConfig {
                 // start byte-based backpressure at 25 MiB of difference
                 bytes_start: 25 * 1024 * 1024,
@@ -344,6 +360,9 @@ impl Downstairs {
             log: log.new(o!("" => "downstairs".to_string())),
             ackable_work: BTreeSet::new(),
             repair: None,
+            replica: None,
+            job_events: tokio::sync::broadcast::channel(JOB_EVENT_CAPACITY).0,
+            stats,
 
             #[cfg(feature = "notify-nexus")]
             reqwest_client: reqwest::ClientBuilder::new()
@@ -360,6 +379,14 @@ impl Downstairs {
     /// client tasks won't start!
     #[cfg(test)]
     pub fn test_default() -> Self {
+        Self::test_with_clients(crate::DEFAULT_REGION_SET_SIZE)
+    }
+
+    /// Build a `Downstairs` with `n` clients for simple tests
+    ///
+    /// Like [`Downstairs::test_default`], the client tasks won't start.
+    #[cfg(test)]
+    pub fn test_with_clients(n: usize) -> Self {
         let log = crucible_common::build_logger();
         let cfg = Arc::new(UpstairsConfig {
             upstairs_id: Uuid::new_v4(),
@@ -368,11 +395,18 @@ impl Downstairs {
             read_only: false,
             encryption_context: None,
             lossy: false,
+            timeouts: crate::client::ClientTimeouts::default(),
+            region_set_size: n,
+            capture: None,
+            audit: Arc::new(crucible_common::audit::AuditLog::new(
+                crucible_common::audit::AuditSource::Upstairs,
+            )),
         });
 
-        let mut ds = Self::new(cfg, ClientMap::new(), None, log);
+        let stats = UpStatOuter::new(cfg.upstairs_id);
+        let mut ds = Self::new(cfg, ClientMap::new(n), None, stats, log);
         // Create a fake repair address so this field is populated.
-        for cid in ClientId::iter() {
+        for cid in ClientId::iter_n(n) {
             ds.clients[cid].repair_addr =
                 Some("127.0.0.1:1234".parse().unwrap());
         }
@@ -386,28 +420,25 @@ impl Downstairs {
     /// be cancel-safe.  This is why we simply return a single value in the body
     /// of each statement.
     pub(crate) async fn select(&mut self) -> DownstairsAction {
-        // Split borrow of the clients
-        let [ca, cb, cc] = &mut self.clients.0;
-        tokio::select! {
-            action = ca.select() => {
-                DownstairsAction::Client {
-                    client_id: ClientId::new(0),
-                    action
-                }
-            }
-            action = cb.select() => {
-                DownstairsAction::Client {
-                    client_id: ClientId::new(1),
-                    action
-                }
-            }
-            action = cc.select() => {
-                DownstairsAction::Client {
-                    client_id: ClientId::new(2),
-                    action
+        // Start polling from a random client, so that (like `tokio::select!`)
+        // we don't favor any particular downstairs.
+        let n = self.clients.len();
+        let start = rand::thread_rng().gen_range(0..n);
+        std::future::poll_fn(|cx| {
+            for i in (start..n).chain(0..start) {
+                let client_id = ClientId::new(i as u8);
+                if let std::task::Poll::Ready(action) =
+                    self.clients[client_id].poll_select(cx)
+                {
+                    return std::task::Poll::Ready(DownstairsAction::Client {
+                        client_id,
+                        action,
+                    });
                 }
             }
-        }
+            std::task::Poll::Pending
+        })
+        .await
     }
 
     /// Checks whether we have ackable work
@@ -416,17 +447,13 @@ impl Downstairs {
     }
 
     /// Send back acks for all jobs that are `AckReady`
-    pub(crate) fn ack_jobs(
-        &mut self,
-        gw: &mut GuestWork,
-        up_stats: &UpStatOuter,
-    ) {
+    pub(crate) fn ack_jobs(&mut self, gw: &mut GuestWork) {
         debug!(self.log, "ack_jobs called in Downstairs");
 
         let ack_list = std::mem::take(&mut self.ackable_work);
         let jobs_checked = ack_list.len();
         for ds_id_done in ack_list.iter() {
-            self.ack_job(*ds_id_done, gw, up_stats);
+            self.ack_job(*ds_id_done, gw);
         }
         debug!(self.log, "ack_ready handled {jobs_checked} jobs");
     }
@@ -437,12 +464,7 @@ impl Downstairs {
     ///
     /// This is public for the sake of unit testing, but shouldn't be called
     /// outside of this module normally.
-    fn ack_job(
-        &mut self,
-        ds_id: JobId,
-        gw: &mut GuestWork,
-        up_stats: &UpStatOuter,
-    ) {
+    fn ack_job(&mut self, ds_id: JobId, gw: &mut GuestWork) {
         debug!(self.log, "ack_jobs process {}", ds_id);
 
         let done = self.ds_active.get_mut(&ds_id).unwrap();
@@ -455,10 +477,28 @@ impl Downstairs {
 
         done.acked = true;
         let r = done.result();
-        Self::cdt_gw_work_done(done, up_stats);
+        Self::cdt_gw_work_done(done, &self.stats);
+        if self.job_events.receiver_count() > 0 {
+            // An error just means every receiver went away meanwhile
+            let _ = self.job_events.send(done.io_summarize());
+        }
         debug!(self.log, "[A] ack job {}:{}", ds_id, gw_id);
 
-        gw.gw_ds_complete(gw_id, ds_id, data, r);
+        let op = done.work.job_type();
+        let created = done.created;
+        let ready = done.replied.unwrap_or(created);
+        let requested = gw.gw_ds_complete(gw_id, ds_id, data, r);
+
+        self.stats.add_latency(IoStage::Ack, op, ready.elapsed());
+        if let Some(requested) = requested {
+            self.stats.add_latency(
+                IoStage::Guest,
+                op,
+                created.saturating_duration_since(requested),
+            );
+            self.stats
+                .add_latency(IoStage::Total, op, requested.elapsed());
+        }
 
         self.retire_check(ds_id);
     }
@@ -727,10 +767,30 @@ impl Downstairs {
                     }
                 }
             };
+            if let Some(traceparent) = self.traceparent(new_id, client_id) {
+                self.clients[client_id].send(Message::TraceContext {
+                    job_id: new_id,
+                    traceparent,
+                });
+            }
             self.clients[client_id].send(message)
         }
     }
 
+    /// Returns the trace context to send to a client along with a job
+    ///
+    /// This is `None` unless the job's span is being recorded and the client
+    /// can attach its own spans to it.
+    fn traceparent(&self, ds_id: JobId, client_id: ClientId) -> Option<String> {
+        if !self.clients[client_id]
+            .capabilities()
+            .contains(Capabilities::TRACE_CONTEXT)
+        {
+            return None;
+        }
+        otel::traceparent(&self.ds_active.get(&ds_id)?.span)
+    }
+
     /// Mark this request as in progress for this client, and return the
     /// relevant [`IOOp`] with updated dependencies.
     ///
@@ -757,10 +817,14 @@ impl Downstairs {
             return None;
         }
 
-        Some(
-            self.clients[client_id]
-                .in_progress(job, self.repair.as_ref().map(|r| r.min_id)),
-        )
+        let out = self.clients[client_id]
+            .in_progress(job, self.repair.as_ref().map(|r| r.min_id));
+        self.stats.add_latency(
+            IoStage::Send,
+            job.work.job_type(),
+            job.created.elapsed(),
+        );
+        Some(out)
     }
 
     /// Reinitialize the given client
@@ -795,7 +859,7 @@ impl Downstairs {
         // Restart the IO task for that specific client
         self.clients[client_id].reinitialize(auto_promote);
 
-        for i in ClientId::iter() {
+        for i in self.clients.ids() {
             // Clear per-client delay, because we're starting a new session
             self.clients[i].set_delay_us(0);
         }
@@ -1072,6 +1136,13 @@ impl Downstairs {
                 id: Uuid::new_v4(),
                 reconcile_task_list_index: 0,
             });
+            self.cfg.audit(
+                None,
+                AuditEvent::ReconciliationStarted {
+                    id: self.reconcile.as_ref().unwrap().id,
+                    extents: reconcile_list.mend.len() as u64,
+                },
+            );
 
             #[cfg(feature = "notify-nexus")]
             {
@@ -1147,7 +1218,7 @@ impl Downstairs {
         // Begin setting up live-repair state
         let mut repair_downstairs = vec![];
         let mut source_downstairs = None;
-        for cid in ClientId::iter() {
+        for cid in self.clients.ids() {
             match self.clients[cid].state() {
                 DsState::LiveRepair => {
                     repair_downstairs.push(cid);
@@ -1200,10 +1271,20 @@ impl Downstairs {
             state,
         });
 
-        info!(
-            self.log,
-            "starting repair {}",
-            self.repair.as_ref().unwrap().id
+        let repair = self.repair.as_ref().unwrap();
+        info!(self.log, "starting repair {}", repair.id);
+        self.cfg.audit(
+            None,
+            AuditEvent::LiveRepairStarted {
+                id: repair.id,
+                source: repair.source_downstairs.get(),
+                repairing: repair
+                    .repair_downstairs
+                    .iter()
+                    .map(|c| c.get())
+                    .collect(),
+                extents: u64::from(extent_count),
+            },
         );
 
         #[cfg(feature = "notify-nexus")]
@@ -1475,6 +1556,13 @@ impl Downstairs {
                         self.clients[*c].finish_repair(up_state);
                     }
                 }
+                self.cfg.audit(
+                    None,
+                    AuditEvent::LiveRepairFinished {
+                        id: repair.id,
+                        aborted: repair.aborting_repair,
+                    },
+                );
 
                 #[cfg(feature = "notify-nexus")]
                 {
@@ -1496,7 +1584,7 @@ impl Downstairs {
         noop_id: JobId,
         gw_noop_id: GuestWorkId,
     ) {
-        let nio = Self::create_noop_io(noop_id, deps, gw_noop_id);
+        let nio = self.create_noop_io(noop_id, deps, gw_noop_id);
 
         cdt::gw__noop__start!(|| (gw_noop_id.0));
         gw.insert(gw_noop_id, noop_id);
@@ -1504,6 +1592,7 @@ impl Downstairs {
     }
 
     fn create_noop_io(
+        &self,
         ds_id: JobId,
         dependencies: Vec<JobId>,
         gw_id: GuestWorkId,
@@ -1514,7 +1603,11 @@ impl Downstairs {
             ds_id,
             guest_id: gw_id,
             work: noop_ioop,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -1558,7 +1651,7 @@ impl Downstairs {
         source: ClientId,
         repair: &[ClientId],
     ) -> DownstairsIO {
-        assert!(repair.len() < 3);
+        assert!(repair.len() < self.clients.len());
 
         let mut need_repair = Vec::new();
         debug!(self.log, "Get repair info for {} source", source);
@@ -1593,7 +1686,7 @@ impl Downstairs {
             for &cid in repair.iter() {
                 self.clients[cid].stats.extents_confirmed += 1;
             }
-            Self::create_noop_io(repair_id, repair_deps, gw_repair_id)
+            self.create_noop_io(repair_id, repair_deps, gw_repair_id)
         } else {
             info!(
                 self.log,
@@ -1604,7 +1697,7 @@ impl Downstairs {
             }
             let repair_address = self.clients[source].repair_addr.unwrap();
 
-            Self::create_repair_io(
+            self.create_repair_io(
                 repair_id,
                 repair_deps,
                 gw_repair_id,
@@ -1618,6 +1711,7 @@ impl Downstairs {
 
     #[allow(clippy::too_many_arguments)]
     fn create_repair_io(
+        &self,
         ds_id: JobId,
         dependencies: Vec<JobId>,
         gw_id: GuestWorkId,
@@ -1638,7 +1732,11 @@ impl Downstairs {
             ds_id,
             guest_id: gw_id,
             work: repair_ioop,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -1765,6 +1863,7 @@ impl Downstairs {
 
     /// Creates a [DownstairsIO] job for an [IOop::ExtentLiveReopen]
     fn create_reopen_io(
+        &self,
         eid: ExtentId,
         ds_id: JobId,
         dependencies: Vec<JobId>,
@@ -1779,7 +1878,11 @@ impl Downstairs {
             ds_id,
             guest_id: gw_id,
             work: reopen_ioop,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -1799,7 +1902,7 @@ impl Downstairs {
         gw_reopen_id: GuestWorkId,
     ) {
         let reopen_io =
-            Self::create_reopen_io(eid, reopen_id, deps, gw_reopen_id);
+            self.create_reopen_io(eid, reopen_id, deps, gw_reopen_id);
 
         cdt::gw__reopen__start!(|| (gw_reopen_id.0, eid.0));
 
@@ -1837,7 +1940,11 @@ impl Downstairs {
             ds_id,
             guest_id: gw_id,
             work: aread,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -1921,7 +2028,11 @@ impl Downstairs {
             ds_id,
             guest_id: gw_id,
             work: awrite,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -1952,7 +2063,11 @@ impl Downstairs {
             ds_id,
             guest_id: gw_id,
             work: close_ioop,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -2055,47 +2170,55 @@ impl Downstairs {
              * Send repair command to bad extents
              * Reopen extent.
              */
-            self.reconcile_task_list.push_back(ReconcileIO::new(
-                rep_id,
-                Message::ExtentFlush {
-                    repair_id: rep_id,
-                    extent_id: ext,
-                    client_id: ef.source,
-                    flush_number: max_flush,
-                    gen_number: max_gen,
-                },
-            ));
+            self.reconcile_task_list
+                .push_back(ReconcileIO::with_clients(
+                    self.clients.len(),
+                    rep_id,
+                    Message::ExtentFlush {
+                        repair_id: rep_id,
+                        extent_id: ext,
+                        client_id: ef.source,
+                        flush_number: max_flush,
+                        gen_number: max_gen,
+                    },
+                ));
             rep_id.0 += 1;
 
-            self.reconcile_task_list.push_back(ReconcileIO::new(
-                rep_id,
-                Message::ExtentClose {
-                    repair_id: rep_id,
-                    extent_id: ext,
-                },
-            ));
+            self.reconcile_task_list
+                .push_back(ReconcileIO::with_clients(
+                    self.clients.len(),
+                    rep_id,
+                    Message::ExtentClose {
+                        repair_id: rep_id,
+                        extent_id: ext,
+                    },
+                ));
             rep_id.0 += 1;
 
             let repair = self.clients[ef.source].repair_addr.unwrap();
-            self.reconcile_task_list.push_back(ReconcileIO::new(
-                rep_id,
-                Message::ExtentRepair {
-                    repair_id: rep_id,
-                    extent_id: ext,
-                    source_client_id: ef.source,
-                    source_repair_address: repair,
-                    dest_clients: ef.dest,
-                },
-            ));
+            self.reconcile_task_list
+                .push_back(ReconcileIO::with_clients(
+                    self.clients.len(),
+                    rep_id,
+                    Message::ExtentRepair {
+                        repair_id: rep_id,
+                        extent_id: ext,
+                        source_client_id: ef.source,
+                        source_repair_address: repair,
+                        dest_clients: ef.dest,
+                    },
+                ));
             rep_id.0 += 1;
 
-            self.reconcile_task_list.push_back(ReconcileIO::new(
-                rep_id,
-                Message::ExtentReopen {
-                    repair_id: rep_id,
-                    extent_id: ext,
-                },
-            ));
+            self.reconcile_task_list
+                .push_back(ReconcileIO::with_clients(
+                    self.clients.len(),
+                    rep_id,
+                    Message::ExtentReopen {
+                        repair_id: rep_id,
+                        extent_id: ext,
+                    },
+                ));
             rep_id.0 += 1;
         }
 
@@ -2256,7 +2379,15 @@ impl Downstairs {
         self.reconcile_task_list = VecDeque::new();
         self.reconcile_current_work = None;
 
-        if self.reconcile.is_some() {
+        if let Some(reconcile) = &self.reconcile {
+            self.cfg.audit(
+                None,
+                AuditEvent::ReconciliationFinished {
+                    id: reconcile.id,
+                    aborted: true,
+                },
+            );
+
             #[cfg(feature = "notify-nexus")]
             {
                 let reconcile = self.reconcile.as_ref().unwrap();
@@ -2292,7 +2423,14 @@ impl Downstairs {
 
         if from_state == DsState::Reconcile {
             // reconciliation completed
-            assert!(self.reconcile.is_some());
+            let reconcile = self.reconcile.as_ref().unwrap();
+            self.cfg.audit(
+                None,
+                AuditEvent::ReconciliationFinished {
+                    id: reconcile.id,
+                    aborted: false,
+                },
+            );
 
             #[cfg(feature = "notify-nexus")]
             {
@@ -2311,20 +2449,17 @@ impl Downstairs {
         }
     }
 
-    /// Compares region metadata from all three clients and builds a mend list
+    /// Compares region metadata from all clients and builds a mend list
     ///
     /// # Panics
     /// If any downstairs client does not have region metadata populated
     fn mismatch_list(&self) -> Option<DownstairsMend> {
-        let c = |i| {
-            self.clients[ClientId::new(i)]
-                .region_metadata
-                .as_ref()
-                .unwrap()
-        };
+        let meta = ClientData::from_fn(self.clients.len(), |i| {
+            self.clients[i].region_metadata.as_ref().unwrap()
+        });
 
         let log = self.log.new(o!("" => "mend".to_string()));
-        DownstairsMend::new(c(0), c(1), c(2), log)
+        DownstairsMend::from_regions(&meta, log)
     }
 
     pub(crate) fn submit_flush(
@@ -2361,7 +2496,11 @@ impl Downstairs {
             ds_id: next_id,
             guest_id: gw_id,
             work: flush,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -2451,7 +2590,7 @@ impl Downstairs {
             .insert(eid, (repair_ids, deps));
     }
 
-    /// Create and submit a read job to the three clients
+    /// Create and submit a read job to the downstairs clients
     pub(crate) fn submit_read(
         &mut self,
         guest_id: GuestWorkId,
@@ -2488,7 +2627,11 @@ impl Downstairs {
             ds_id,
             guest_id,
             work: aread,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(self.clients.len(), IOState::New),
+            sent: ClientData::with_len(self.clients.len(), None),
+            created: Instant::now(),
+            replied: None,
+            span: tracing::Span::none(),
             acked: false,
             replay: false,
             data: None,
@@ -2565,11 +2708,12 @@ impl Downstairs {
     /// - for Write/WriteUnwritten ops, add their size to the write byte
     ///   counter for backpressure calculations
     fn enqueue(&mut self, mut io: DownstairsIO) {
+        io.span = io.new_span();
         let mut skipped = 0;
         let last_repair_extent = self.last_repair_extent();
 
         // Send the job to each client!
-        for cid in ClientId::iter() {
+        for cid in self.clients.ids() {
             let job_state =
                 self.clients[cid].enqueue(&mut io, last_repair_extent);
             if matches!(job_state, IOState::Skipped) {
@@ -2587,11 +2731,12 @@ impl Downstairs {
         let ds_id = io.ds_id;
         self.ds_active.insert(ds_id, io);
 
-        if skipped == 3 {
+        let all_skipped = skipped == self.clients.len();
+        if all_skipped {
             warn!(self.log, "job {} skipped on all downstairs", &ds_id);
         }
 
-        if skipped == 3 || is_write {
+        if all_skipped || is_write {
             let job = self.ds_active.get_mut(&ds_id).unwrap();
             assert!(!job.acked);
             self.ackable_work.insert(ds_id);
@@ -2610,7 +2755,7 @@ impl Downstairs {
     /// used for that purpose, it will panic if [io] is not a repair-related
     /// operation
     fn enqueue_repair(&mut self, mut io: DownstairsIO) {
-        for cid in ClientId::iter() {
+        for cid in self.clients.ids() {
             assert_eq!(io.state[cid], IOState::New);
 
             let current = self.clients[cid].state();
@@ -2644,6 +2789,7 @@ impl Downstairs {
             io.work
         );
 
+        io.span = io.new_span();
         let ds_id = io.ds_id;
         debug!(self.log, "Enqueue repair job {}", ds_id);
         self.ds_active.insert(ds_id, io);
@@ -2715,7 +2861,7 @@ impl Downstairs {
         // Check for and Block a replacement if any (other) downstairs are
         // in any of these states as we don't want to take more than one
         // downstairs offline at the same time.
-        for client_id in ClientId::iter() {
+        for client_id in self.clients.ids() {
             if client_id == old_client_id {
                 continue;
             }
@@ -2741,12 +2887,80 @@ impl Downstairs {
         // Skip all outstanding jobs for this client
         self.skip_all_jobs(old_client_id);
 
+        self.cfg
+            .audit(Some(old_client_id), AuditEvent::Replaced { old, new });
+
         // Clear the client state and restart the IO task
         self.clients[old_client_id].replace(up_state, new);
 
         Ok(ReplaceResult::Started)
     }
 
+    /// Attaches an asynchronous replica, which will receive retired jobs
+    pub(crate) fn set_replica(&mut self, replica: Replica) {
+        self.replica = Some(replica);
+    }
+
+    /// Returns the replica's status, if one is configured
+    pub(crate) fn replica_stats(&self) -> Option<ReplicaStats> {
+        self.replica.as_ref().map(|r| r.stats())
+    }
+
+    /// Forwards a retired job to the replica (if present)
+    ///
+    /// Reads and repair operations are not forwarded, since they don't change
+    /// the region's contents; neither are jobs which no downstairs completed.
+    fn forward_to_replica(&mut self, job: DownstairsIO) {
+        let Some(replica) = self.replica.as_mut() else {
+            return;
+        };
+        if job.state_count().done == 0 {
+            return;
+        }
+        let op = match job.work {
+            IOop::Write {
+                start_eid,
+                start_offset,
+                blocks,
+                data,
+                ..
+            } => ReplicaOp::Write {
+                start_eid,
+                start_offset,
+                blocks,
+                data,
+                unwritten: false,
+            },
+            IOop::WriteUnwritten {
+                start_eid,
+                start_offset,
+                blocks,
+                data,
+                ..
+            } => ReplicaOp::Write {
+                start_eid,
+                start_offset,
+                blocks,
+                data,
+                unwritten: true,
+            },
+            IOop::Flush {
+                flush_number,
+                gen_number,
+                ..
+            } => ReplicaOp::Flush {
+                flush_number,
+                gen_number,
+            },
+            IOop::Read { .. }
+            | IOop::ExtentFlushClose { .. }
+            | IOop::ExtentLiveRepair { .. }
+            | IOop::ExtentLiveReopen { .. }
+            | IOop::ExtentLiveNoOp { .. } => return,
+        };
+        replica.submit(op);
+    }
+
     pub(crate) fn check_gone_too_long(
         &mut self,
         client_id: ClientId,
@@ -2754,13 +2968,14 @@ impl Downstairs {
     ) {
         let byte_count = self.clients[client_id].total_bytes_outstanding();
         let work_count = self.clients[client_id].total_live_work();
-        let failed = if work_count > crate::IO_OUTSTANDING_MAX_JOBS {
+        let timeouts = &self.cfg.timeouts;
+        let failed = if work_count > timeouts.io_outstanding_max_jobs {
             warn!(
                 self.log,
                 "downstairs failed, too many outstanding jobs {work_count}"
             );
             Some(ClientStopReason::TooManyOutstandingJobs)
-        } else if byte_count as u64 > crate::IO_OUTSTANDING_MAX_BYTES {
+        } else if byte_count as u64 > timeouts.io_outstanding_max_bytes {
             warn!(
                 self.log,
                 "downstairs failed, too many outstanding bytes {byte_count}"
@@ -2805,7 +3020,9 @@ impl Downstairs {
                     retire_check.push(*ds_id);
                 } else {
                     let wc = job.state_count();
-                    if (wc.error + wc.skipped + wc.done) == 3 {
+                    if (wc.error + wc.skipped + wc.done)
+                        == job.state.len() as u64
+                    {
                         info!(
                             self.log,
                             "[{}] notify = true for {}", client_id, ds_id
@@ -2832,6 +3049,32 @@ impl Downstairs {
         self.clients[client_id].clear_new_jobs();
     }
 
+    /// Returns a sender from which the control server can subscribe to
+    /// summaries of jobs as they're acked
+    pub(crate) fn job_events(
+        &self,
+    ) -> tokio::sync::broadcast::Sender<WorkSummary> {
+        self.job_events.clone()
+    }
+
+    /// Aborts an in-progress live-repair at the operator's request
+    ///
+    /// Returns `false` if there's no live-repair running (or it's already
+    /// being aborted).
+    pub(crate) fn abort_live_repair(
+        &mut self,
+        up_state: &UpstairsState,
+    ) -> bool {
+        match &self.repair {
+            Some(repair) if !repair.aborting_repair => {
+                warn!(self.log, "aborting live-repair due to control request");
+                self.abort_repair(up_state);
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Aborts an in-progress live-repair
     ///
     /// The live-repair may continue after this point to clean up reserved jobs,
@@ -2848,7 +3091,7 @@ impl Downstairs {
                 // around to LiveRepairReady yet.
                 c.state() == DsState::Faulted
         }));
-        for i in ClientId::iter() {
+        for i in self.clients.ids() {
             match self.clients[i].state() {
                 DsState::LiveRepair => {
                     self.skip_all_jobs(i);
@@ -2890,11 +3133,11 @@ impl Downstairs {
     }
 
     /// This request is now complete on all peers, but is it ready to retire?
-    /// Only when a flush is complete on all three downstairs do we check to
+    /// Only when a flush is complete on all downstairs do we check to
     /// see if we can remove jobs. Double check that all write jobs have
     /// finished and panic if not.
     ///
-    /// Note we don't retire jobs until all three downstairs have returned
+    /// Note we don't retire jobs until all downstairs have returned
     /// from the same flush because the Upstairs replays all jobs since
     /// the last flush if a downstairs goes away and then comes back.
     /// This includes reads because they can be in the deps list for
@@ -2907,8 +3150,9 @@ impl Downstairs {
 
         // Only a completed flush will remove jobs from the active queue -
         // currently we have to keep everything around for use during replay
-        let wc = self.ds_active.get(&ds_id).unwrap().state_count();
-        if (wc.error + wc.skipped + wc.done) == 3 {
+        let job = self.ds_active.get(&ds_id).unwrap();
+        let wc = job.state_count();
+        if (wc.error + wc.skipped + wc.done) == job.state.len() as u64 {
             assert!(!self.completed.contains(&ds_id));
             assert_eq!(wc.active, 0);
 
@@ -2918,6 +3162,10 @@ impl Downstairs {
             // `retired`, then remove them in bulk after checking the list.
             let mut retired = Vec::new();
 
+            // If a write or flush stays on the queue while later jobs are
+            // retired, the replica would see them out of order.
+            let mut replica_out_of_order = false;
+
             for (&id, job) in &self.ds_active {
                 if id > ds_id {
                     break;
@@ -2937,11 +3185,20 @@ impl Downstairs {
                         ds_id,
                         wc,
                     );
+                    replica_out_of_order |= matches!(
+                        job.work,
+                        IOop::Write { .. }
+                            | IOop::WriteUnwritten { .. }
+                            | IOop::Flush { .. }
+                    );
                     continue;
                 }
 
                 // Assert the job is actually done, then complete it
-                assert_eq!(wc.error + wc.skipped + wc.done, 3);
+                assert_eq!(
+                    wc.error + wc.skipped + wc.done,
+                    job.state.len() as u64
+                );
                 assert!(!self.completed.contains(&id));
                 assert!(job.acked);
                 assert_eq!(job.ds_id, id);
@@ -2950,7 +3207,7 @@ impl Downstairs {
                 self.completed.push(id);
                 let summary = job.io_summarize();
                 self.completed_jobs.push(summary);
-                for cid in ClientId::iter() {
+                for cid in self.clients.ids() {
                     let old_state = &job.state[cid];
                     self.clients[cid].io_state_count.decr(old_state);
                 }
@@ -2970,11 +3227,17 @@ impl Downstairs {
                     );
                     self.write_bytes_outstanding.decrement(&mut job);
                 }
+                self.forward_to_replica(job);
+            }
+            if replica_out_of_order {
+                if let Some(r) = self.replica.as_mut() {
+                    r.fail(format!("job left on queue when retiring {ds_id}"));
+                }
             }
 
             debug!(self.log, "[rc] retire {} clears {:?}", ds_id, retired);
             // Only keep track of skipped jobs at or above the flush.
-            for cid in ClientId::iter() {
+            for cid in self.clients.ids() {
                 self.clients[cid].skipped_jobs.retain(|&x| x >= ds_id);
             }
         }
@@ -2988,22 +3251,18 @@ impl Downstairs {
     /// Prints a summary of active work to `stdout`
     pub(crate) fn show_all_work(&self) {
         print!("States:");
-        for cid in ClientId::iter() {
+        for cid in self.clients.ids() {
             print!(" {}", self.clients[cid].state());
         }
         println!();
-        println!(
-            "{0:>5} {1:>8} {2:>5} {3:>7} {4:>7} {5:>5} {6:>5} {7:>5} {8:>7}",
-            "GW_ID",
-            "ACK",
-            "DSID",
-            "TYPE",
-            "BKS/EXT",
-            "DS:0",
-            "DS:1",
-            "DS:2",
-            "REPLAY",
+        print!(
+            "{0:>5} {1:>8} {2:>5} {3:>7} {4:>7}",
+            "GW_ID", "ACK", "DSID", "TYPE", "BKS/EXT",
         );
+        for cid in self.clients.ids() {
+            print!(" {0:>5}", format!("DS:{cid}"));
+        }
+        println!(" {0:>7}", "REPLAY");
 
         for (id, job) in &self.ds_active {
             let ack = if job.acked {
@@ -3053,7 +3312,7 @@ impl Downstairs {
                 job.guest_id, ack, id, job_type, blocks_or_extent
             );
 
-            for cid in ClientId::iter() {
+            for cid in self.clients.ids() {
                 let state = &job.state[cid];
                 // XXX I have no idea why this is two spaces instead of
                 // one...
@@ -3071,22 +3330,19 @@ impl Downstairs {
         println!();
     }
 
-    /// Collects stats from the three `DownstairsClient`s
+    /// Collects stats from each `DownstairsClient`
     pub fn collect_stats<T, F: Fn(&DownstairsClient) -> T>(
         &self,
         f: F,
-    ) -> [T; 3] {
-        [
-            f(&self.clients[ClientId::new(0)]),
-            f(&self.clients[ClientId::new(1)]),
-            f(&self.clients[ClientId::new(2)]),
-        ]
+    ) -> Vec<T> {
+        self.clients.iter().map(f).collect()
     }
 
     pub fn io_state_count(&self) -> IOStateCount {
-        let d = self.collect_stats(|c| c.io_state_count);
         let f = |g: fn(ClientIOStateCount) -> u32| {
-            ClientData([g(d[0]), g(d[1]), g(d[2])])
+            ClientData::from_fn(self.clients.len(), |i| {
+                g(self.clients[i].io_state_count)
+            })
         };
         IOStateCount {
             new: f(|d| d.new),
@@ -3457,6 +3713,7 @@ impl Downstairs {
             assert_eq!(reads.blocks.len(), read_validations.len());
         }
 
+        let sent = job.sent[client_id];
         if self.clients[client_id].process_io_completion(
             job,
             responses,
@@ -3466,17 +3723,26 @@ impl Downstairs {
         ) {
             self.ackable_work.insert(ds_id);
         }
+        if let (Some(sent), Some(replied)) = (sent, job.replied) {
+            self.stats.add_latency(
+                IoStage::Downstairs,
+                job.work.job_type(),
+                replied.saturating_duration_since(sent),
+            );
+        }
 
-        // Write bytes no longer count for backpressure once all 3x downstairs
+        // Write bytes no longer count for backpressure once all downstairs
         // have returned (although they'll continue to be stored until they are
         // retired by the next flush).
         let wc = job.state_count();
-        if (wc.error + wc.skipped + wc.done) == 3 {
+        let all_done =
+            (wc.error + wc.skipped + wc.done) == job.state.len() as u64;
+        if all_done {
             self.write_bytes_outstanding.decrement(job);
         }
 
         /*
-         * If all 3 jobs are done, we can check here to see if we can
+         * If all jobs are done, we can check here to see if we can
          * remove this job from the DS list. If we have completed the ack
          * to the guest, then there will be no more work on this job
          * but messages may still be unprocessed.
@@ -3492,7 +3758,7 @@ impl Downstairs {
             // If we are a write or a flush with one success, then
             // we must switch our state to failed.  This condition is
             // handled when we check the job result.
-            if (wc.error + wc.skipped + wc.done) == 3 {
+            if all_done {
                 self.ackable_work.insert(ds_id);
                 debug!(self.log, "[{}] Set AckReady {}", client_id, job.ds_id);
             }
@@ -3507,8 +3773,11 @@ impl Downstairs {
                     "Saw CrucibleError::UpstairsInactive on client {}!",
                     client_id
                 );
-                self.clients[client_id]
-                    .checked_state_transition(up_state, DsState::Disabled);
+                self.clients[client_id].transition(
+                    up_state,
+                    DsState::Disabled,
+                    "upstairs_inactive",
+                );
                 // TODO should we also restart the IO task here?
             }
             Some(CrucibleError::DecryptionError) => {
@@ -3545,12 +3814,14 @@ impl Downstairs {
                     // Walk the active job list and mark any that were
                     // new or in progress to skipped.
                     self.skip_all_jobs(client_id);
-                    self.clients[client_id]
-                        .checked_state_transition(up_state, DsState::Faulted);
-                    self.clients[client_id].restart_connection(
+                    let reason = ClientStopReason::IOError;
+                    self.clients[client_id].transition(
                         up_state,
-                        ClientStopReason::IOError,
+                        DsState::Faulted,
+                        reason.cause(),
                     );
+                    self.clients[client_id]
+                        .restart_connection(up_state, reason);
                 }
             }
             None => {
@@ -3866,8 +4137,8 @@ impl Downstairs {
         let max_jobs = jobs.into_iter().max().unwrap();
         let max_bytes = bytes.into_iter().max().unwrap();
 
-        let mut delays = ClientData::new(None);
-        for i in ClientId::iter() {
+        let mut delays = ClientData::with_len(self.clients.len(), None);
+        for i in self.clients.ids() {
             let c = &self.clients[i];
             if matches!(c.state(), DsState::Active) {
                 // These values represent how much **faster** we are than the
@@ -3904,7 +4175,7 @@ impl Downstairs {
         delays.iter_mut().flatten().for_each(|c| *c -= min_delay);
 
         // Apply delay to clients
-        for i in ClientId::iter() {
+        for i in self.clients.ids() {
             if let Some(delay) = delays[i] {
                 self.clients[i].set_delay_us(delay);
             } else {
@@ -4475,7 +4746,7 @@ impl Downstairs {
 /// Configuration for per-client backpressure
 ///
 /// Per-client backpressure adds an artificial delay to the client queues, to
-/// keep the three clients relatively in sync.  The delay is varied based on two
+/// keep the clients relatively in sync.  The delay is varied based on two
 /// metrics:
 ///
 /// - number of write bytes outstanding
@@ -4504,12 +4775,13 @@ pub(crate) mod test {
     use super::Downstairs;
     use crate::{
         downstairs::{LiveRepairData, LiveRepairState, ReconcileData},
-        guest::GuestWork,
+        guest::{GuestBlockRes, GuestWork},
         live_repair::ExtentInfo,
+        stats::IoStage,
         upstairs::UpstairsState,
-        ClientId, CrucibleError, DownstairsIO, DsState, ExtentFix, GuestWorkId,
-        IOState, IOop, ImpactedAddr, ImpactedBlocks, JobId, RawReadResponse,
-        ReconcileIO, ReconciliationId, SnapshotDetails,
+        BlockOpWaiter, ClientId, CrucibleError, DownstairsIO, DsState,
+        ExtentFix, GuestWorkId, IOState, IOop, ImpactedAddr, ImpactedBlocks,
+        JobId, RawReadResponse, ReconcileIO, ReconciliationId, SnapshotDetails,
     };
 
     use bytes::BytesMut;
@@ -4571,9 +4843,9 @@ pub(crate) mod test {
         }
     }
 
-    /// Helper function to set all 3x clients as active, legally
+    /// Helper function to set all clients as active, legally
     pub(crate) fn set_all_active(ds: &mut Downstairs) {
-        for i in ClientId::iter() {
+        for i in ds.clients.ids() {
             ds.clients[i].checked_state_transition(
                 &UpstairsState::Initializing,
                 DsState::WaitActive,
@@ -4633,6 +4905,53 @@ pub(crate) mod test {
         assert_eq!(ds.completed.len(), 1);
     }
 
+    #[test]
+    fn work_records_latency() {
+        let mut ds = Downstairs::test_default();
+        let mut gw = GuestWork::default();
+
+        let (_waiter, res) = BlockOpWaiter::pair();
+        let (_, next_id) = gw.submit_job(
+            |gw_id| ds.submit_flush(gw_id, None),
+            Some(GuestBlockRes::Other(res)),
+        );
+        for cid in ClientId::iter() {
+            ds.in_progress(next_id, cid);
+        }
+        assert!(!ds.process_ds_completion(
+            next_id,
+            ClientId::new(1),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert!(job.sent[ClientId::new(0)].is_some());
+        assert!(job.sent[ClientId::new(1)].is_none());
+        assert!(ds.clients[ClientId::new(0)].stats.job_latency.is_empty());
+        let latency = &ds.clients[ClientId::new(1)].stats.job_latency;
+        assert_eq!(latency.len(), 1);
+        assert_eq!(latency["flush"].count(), 1);
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(2),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        ds.ack_jobs(&mut gw);
+
+        let count = |stage| ds.stats.latency(stage, "flush").map(|h| h.count());
+        assert_eq!(count(IoStage::Send), Some(3));
+        assert_eq!(count(IoStage::Downstairs), Some(2));
+        assert_eq!(count(IoStage::Guest), Some(1));
+        assert_eq!(count(IoStage::Ack), Some(1));
+        assert_eq!(count(IoStage::Total), Some(1));
+        assert!(ds.stats.latency(IoStage::Total, "read").is_none());
+    }
+
     // Ensure that a snapshot requires all three downstairs to return Ok
     #[test]
     fn work_flush_snapshot_needs_three() {
@@ -4685,6 +5004,290 @@ pub(crate) mod test {
         assert_eq!(ds.completed.len(), 1);
     }
 
+    // A two-way mirror acks a flush once either downstairs returns
+    #[test]
+    fn work_flush_two_way_needs_one() {
+        let mut ds = Downstairs::test_with_clients(2);
+        set_all_active(&mut ds);
+
+        let next_id = ds.create_and_enqueue_generic_flush(None);
+
+        ds.in_progress(next_id, ClientId::new(0));
+        ds.in_progress(next_id, ClientId::new(1));
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(0),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert_eq!(ds.ackable_work.len(), 1);
+        ds.ack(next_id);
+
+        // It isn't retired until the other side has returned too
+        ds.retire_check(next_id);
+        assert!(ds.completed.is_empty());
+
+        assert!(!ds.process_ds_completion(
+            next_id,
+            ClientId::new(1),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert_eq!(ds.completed.len(), 1);
+    }
+
+    // With only two downstairs, an error on one side is survivable, but that
+    // side is faulted so that it's repaired from the other.
+    #[test]
+    fn work_flush_two_way_one_error_faults() {
+        let mut ds = Downstairs::test_with_clients(2);
+        set_all_active(&mut ds);
+
+        let next_id = ds.create_and_enqueue_generic_flush(None);
+
+        ds.in_progress(next_id, ClientId::new(0));
+        ds.in_progress(next_id, ClientId::new(1));
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(0),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert_eq!(ds.ackable_work.len(), 1);
+
+        assert!(!ds.process_ds_completion(
+            next_id,
+            ClientId::new(1),
+            Err(CrucibleError::GenericError("bad".to_string())),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert!(ds.ds_active.get(&next_id).unwrap().result().is_ok());
+        assert_eq!(ds.clients[ClientId::new(1)].state(), DsState::Faulted);
+    }
+
+    // A two-way mirror keeps going with one downstairs away: a flush (or
+    // write) which completes on the other side succeeds.
+    #[test]
+    fn flush_io_two_way_single_skip_works() {
+        let mut ds = Downstairs::test_with_clients(2);
+        set_all_active(&mut ds);
+        ds.clients[ClientId::new(1)]
+            .checked_state_transition(&UpstairsState::Active, DsState::Faulted);
+
+        let next_id = ds.create_and_enqueue_generic_flush(None);
+        assert!(ds.in_progress(next_id, ClientId::new(0)).is_some());
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(0),
+            Ok(Default::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert_eq!(ds.ackable_work.len(), 1);
+        assert!(ds.ds_active.get(&next_id).unwrap().result().is_ok());
+    }
+
+    #[test]
+    fn write_two_way_single_skip_works() {
+        let mut ds = Downstairs::test_with_clients(2);
+        set_all_active(&mut ds);
+        ds.clients[ClientId::new(0)]
+            .checked_state_transition(&UpstairsState::Active, DsState::Faulted);
+
+        let next_id = ds.create_and_enqueue_generic_write_eob(false);
+        assert!(ds.in_progress(next_id, ClientId::new(1)).is_some());
+
+        ds.process_ds_completion(
+            next_id,
+            ClientId::new(1),
+            Ok(Default::default()),
+            &UpstairsState::Active,
+            None,
+        );
+        assert!(ds.ds_active.get(&next_id).unwrap().result().is_ok());
+    }
+
+    // A five-way region set acks a flush once three downstairs return
+    #[test]
+    fn work_flush_five_way_quorum() {
+        let mut ds = Downstairs::test_with_clients(5);
+        set_all_active(&mut ds);
+
+        let next_id = ds.create_and_enqueue_generic_flush(None);
+
+        for cid in ClientId::iter_n(5) {
+            ds.in_progress(next_id, cid);
+        }
+
+        for cid in ClientId::iter_n(2) {
+            assert!(!ds.process_ds_completion(
+                next_id,
+                cid,
+                Ok(RawReadResponse::default()),
+                &UpstairsState::Active,
+                None,
+            ));
+            assert!(ds.ackable_work.is_empty());
+        }
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(2),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert_eq!(ds.ackable_work.len(), 1);
+        assert!(ds.ds_active.get(&next_id).unwrap().result().is_ok());
+
+        ds.ack(next_id);
+        for cid in [ClientId::new(3), ClientId::new(4)] {
+            assert!(!ds.process_ds_completion(
+                next_id,
+                cid,
+                Ok(RawReadResponse::default()),
+                &UpstairsState::Active,
+                None,
+            ));
+        }
+
+        // The flush is only retired once all five have returned
+        assert_eq!(ds.completed.len(), 1);
+    }
+
+    // A five-way region set tolerates two errors on a flush
+    #[test]
+    fn work_flush_five_way_two_errors_ok() {
+        let mut ds = Downstairs::test_with_clients(5);
+        set_all_active(&mut ds);
+
+        let next_id = ds.create_and_enqueue_generic_flush(None);
+
+        for cid in ClientId::iter_n(5) {
+            ds.in_progress(next_id, cid);
+        }
+
+        for cid in ClientId::iter_n(2) {
+            assert!(!ds.process_ds_completion(
+                next_id,
+                cid,
+                Err(CrucibleError::GenericError("bad".to_string())),
+                &UpstairsState::Active,
+                None,
+            ));
+        }
+        for cid in [ClientId::new(2), ClientId::new(3)] {
+            assert!(!ds.process_ds_completion(
+                next_id,
+                cid,
+                Ok(RawReadResponse::default()),
+                &UpstairsState::Active,
+                None,
+            ));
+        }
+        assert!(ds.ackable_work.is_empty());
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(4),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert_eq!(ds.ackable_work.len(), 1);
+        assert!(ds.ds_active.get(&next_id).unwrap().result().is_ok());
+
+        ds.ack(next_id);
+        ds.retire_check(next_id);
+        assert_eq!(ds.completed.len(), 1);
+    }
+
+    // A five-way region set can't ack a flush after three errors
+    #[test]
+    fn work_flush_five_way_three_errors_equals_fail() {
+        let mut ds = Downstairs::test_with_clients(5);
+        set_all_active(&mut ds);
+
+        let next_id = ds.create_and_enqueue_generic_flush(None);
+
+        for cid in ClientId::iter_n(5) {
+            ds.in_progress(next_id, cid);
+        }
+
+        for cid in ClientId::iter_n(3) {
+            assert!(!ds.process_ds_completion(
+                next_id,
+                cid,
+                Err(CrucibleError::GenericError("bad".to_string())),
+                &UpstairsState::Active,
+                None,
+            ));
+        }
+        assert!(!ds.process_ds_completion(
+            next_id,
+            ClientId::new(3),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert!(ds.ackable_work.is_empty());
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(4),
+            Ok(RawReadResponse::default()),
+            &UpstairsState::Active,
+            None,
+        ));
+        assert_eq!(ds.ackable_work.len(), 1);
+        assert!(ds.ds_active.get(&next_id).unwrap().result().is_err());
+    }
+
+    // Reads in a five-way region set are acked by the first downstairs and
+    // validated against the rest
+    #[test]
+    fn work_read_five_way() {
+        let mut ds = Downstairs::test_with_clients(5);
+
+        let next_id = ds.create_and_enqueue_generic_read_eob();
+
+        for cid in ClientId::iter_n(5) {
+            ds.in_progress(next_id, cid);
+        }
+
+        let response = || Ok(build_read_response(&[]));
+
+        assert!(ds.process_ds_completion(
+            next_id,
+            ClientId::new(0),
+            response(),
+            &UpstairsState::Active,
+            None,
+        ));
+        ds.ack(next_id);
+
+        for cid in ClientId::iter_n(5).skip(1) {
+            assert!(!ds.process_ds_completion(
+                next_id,
+                cid,
+                response(),
+                &UpstairsState::Active,
+                None,
+            ));
+        }
+
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert_eq!(job.state_count().done, 5);
+        assert!(job.result().is_ok());
+    }
+
     #[test]
     fn work_flush_one_error_then_ok() {
         let mut ds = Downstairs::test_default();
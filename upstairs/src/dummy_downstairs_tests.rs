@@ -22,6 +22,7 @@ use crucible_common::ExtentId;
 use crucible_common::RegionDefinition;
 use crucible_common::RegionOptions;
 use crucible_protocol::ClientId;
+use crucible_protocol::ConnectionVersion;
 use crucible_protocol::CrucibleDecoder;
 use crucible_protocol::CrucibleEncoder;
 use crucible_protocol::JobId;
@@ -29,6 +30,8 @@ use crucible_protocol::Message;
 use crucible_protocol::ReadBlockContext;
 use crucible_protocol::ReadResponseHeader;
 use crucible_protocol::WriteHeader;
+use crucible_protocol::CRUCIBLE_CAPABILITIES;
+use crucible_protocol::CRUCIBLE_MESSAGE_VERSION;
 
 use bytes::BytesMut;
 use futures::SinkExt;
@@ -137,7 +140,8 @@ impl DownstairsHandle {
             gen: _,
             read_only,
             encrypted: _,
-            alternate_versions: _,
+            alternate_versions,
+            capabilities,
         } = &packet
         {
             info!(
@@ -151,9 +155,15 @@ impl DownstairsHandle {
                 panic!("read only mismatch!");
             }
 
+            let v = self.cfg.message_version;
+            if *version != v && !alternate_versions.contains(&v) {
+                panic!("upstairs does not support message version {v}");
+            }
+
             self.send(Message::YesItsMe {
-                version: *version,
+                version: v,
                 repair_addr: self.repair_addr,
+                capabilities: capabilities.intersection(CRUCIBLE_CAPABILITIES),
             })
             .unwrap();
         } else {
@@ -349,6 +359,9 @@ pub struct DownstairsConfig {
     gen_numbers: Vec<u64>,
     flush_numbers: Vec<u64>,
     dirty_bits: Vec<bool>,
+
+    /// Message version to reply with in `YesItsMe`
+    message_version: u32,
 }
 
 impl DownstairsConfig {
@@ -385,8 +398,15 @@ impl DownstairsConfig {
 
             let (read, write) = sock.into_split();
 
-            let mut fr = FramedRead::new(read, CrucibleDecoder::new());
-            let mut fw = FramedWrite::new(write, CrucibleEncoder::new());
+            // The encoder switches to the negotiated version when our
+            // `YesItsMe` goes out, and the decoder follows along.
+            let version = ConnectionVersion::default();
+            let mut fr = FramedRead::new(
+                read,
+                CrucibleDecoder::with_version(version.clone()),
+            );
+            let mut fw =
+                FramedWrite::new(write, CrucibleEncoder::with_version(version));
 
             loop {
                 tokio::select! {
@@ -479,11 +499,16 @@ const DEFAULT_EXTENT_COUNT: u32 = 25;
 
 impl TestHarness {
     pub async fn new() -> TestHarness {
-        Self::new_(false).await
+        Self::new_(false, CRUCIBLE_MESSAGE_VERSION).await
     }
 
     pub async fn new_ro() -> TestHarness {
-        Self::new_(true).await
+        Self::new_(true, CRUCIBLE_MESSAGE_VERSION).await
+    }
+
+    /// Builds a harness where DS1 speaks the given message version
+    pub async fn new_ds1_version(message_version: u32) -> TestHarness {
+        Self::new_(false, message_version).await
     }
 
     pub fn ds1(&mut self) -> &mut DownstairsHandle {
@@ -504,15 +529,22 @@ impl TestHarness {
             gen_numbers: vec![0u64; DEFAULT_EXTENT_COUNT as usize],
             flush_numbers: vec![0u64; DEFAULT_EXTENT_COUNT as usize],
             dirty_bits: vec![false; DEFAULT_EXTENT_COUNT as usize],
+
+            message_version: CRUCIBLE_MESSAGE_VERSION,
         }
     }
 
-    async fn new_(read_only: bool) -> TestHarness {
+    async fn new_(read_only: bool, ds1_message_version: u32) -> TestHarness {
         let log = csl();
 
         let cfg = Self::default_config(read_only);
 
-        let ds1 = cfg.clone().start(log.new(o!("downstairs" => 1))).await;
+        let ds1 = DownstairsConfig {
+            message_version: ds1_message_version,
+            ..cfg.clone()
+        }
+        .start(log.new(o!("downstairs" => 1)))
+        .await;
         let ds2 = cfg.clone().start(log.new(o!("downstairs" => 2))).await;
         let ds3 = cfg.clone().start(log.new(o!("downstairs" => 3))).await;
 
@@ -628,6 +660,34 @@ where
     idx.map(|i| l.remove(i))
 }
 
+/// Test that a downstairs speaking the previous message version can serve
+/// reads alongside current ones
+#[tokio::test]
+async fn test_read_from_previous_message_version() {
+    let mut harness =
+        TestHarness::new_ds1_version(CRUCIBLE_MESSAGE_VERSION - 1).await;
+
+    let h = harness.spawn(|guest| async move {
+        let mut buffer = Buffer::new(1, 512);
+        guest.read(BlockIndex(0), &mut buffer).await.unwrap();
+        buffer
+    });
+
+    // DS1 replies first, so its (old-format) response is the one that
+    // completes the read.
+    harness.ds1().ack_read().await;
+    harness.ds2.ack_read().await;
+    harness.ds3.ack_read().await;
+
+    let buffer = h.await.unwrap();
+    assert!(buffer.iter().all(|b| *b == 0));
+
+    let ds = harness.guest.downstairs_state().await.unwrap();
+    assert_eq!(ds[ClientId::new(0)], DsState::Active);
+    assert_eq!(ds[ClientId::new(1)], DsState::Active);
+    assert_eq!(ds[ClientId::new(2)], DsState::Active);
+}
+
 /// Test that replay occurs after a downstairs disconnects and reconnects
 #[tokio::test]
 async fn test_replay_occurs() {
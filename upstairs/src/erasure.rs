@@ -0,0 +1,1850 @@
+// Copyright 2024 Oxide Computer Company
+
+//! Erasure-coded region sets
+//!
+//! Rather than mirroring every block, an erasure-coded region set groups
+//! logical blocks into stripes of `data` blocks, and stores those blocks
+//! (one per fragment) alongside `parity` Reed-Solomon parity blocks.  Any
+//! `data` of the fragments are enough to recover the stripe, so the set
+//! survives the loss of `parity` downstairs while only using
+//! `(data + parity) / data` times the raw capacity (1.5x for a 4+2 set,
+//! versus 3x for a three-way mirror).
+//!
+//! Logical block `b` lives in stripe `b / data` and column `b % data`; that
+//! column's data fragment stores it at block `b / data`.  Parity fragment `j`
+//! stores parity block `j` of stripe `s` at block `s`.
+//!
+//! Each fragment is its own single-downstairs [`Guest`](crate::Guest), so
+//! encryption and `BlockContext` hashes are applied per fragment by that
+//! fragment's Upstairs.  Each fragment's Upstairs gets its own id, derived
+//! from the region set's by `fragment_id`.
+//!
+//! Like RAID-6, there's no journal: a crash between fragment writes can leave
+//! a stripe's parity out of date with its data (the "write hole").  To tell
+//! whether that may have happened, the last block of every fragment holds a
+//! marker (see [`Marker`]), which is dirty while the set is active and clean
+//! once it has been deactivated with everything flushed.  Activating a set
+//! whose marker is dirty starts a scrub in the background, which checks parity
+//! against the data fragments and rewrites only the blocks that don't match;
+//! every fragment stays in use meanwhile.  The scrub waits until every data
+//! fragment is available, since it can't check parity against data that
+//! would have to be reconstructed from that parity.
+//!
+//! A fragment has a single downstairs, so its Upstairs can't live-repair it
+//! after it comes back from a fault; writes that it missed meanwhile are lost.
+//! Instead, the fragment reports that it needs a rebuild (see
+//! [`BlockIO::take_needs_rebuild`]), and we stop reading from it until it has
+//! been rebuilt from the others.  The marker also records which fragments are
+//! being rebuilt, since nothing else would tell the next Upstairs.  Similarly,
+//! a fragment's Upstairs acks writes while its downstairs is away, so that it
+//! can replay them later; rather than leave the only copy of a write in
+//! memory, we treat a fragment which can't persist writes (see
+//! [`BlockIO::can_persist_writes`]) as failed, and rebuild it once it's back.
+//!
+//! Writing part of a stripe has to read the rest of it to recompute parity,
+//! so writes, rebuilds, and scrubs lock the stripes that they touch.
+use super::*;
+
+use futures::future::join_all;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::MutexGuard;
+
+/// Largest supported number of fragments (data plus parity) in a stripe
+pub const MAX_FRAGMENTS: usize = 32;
+
+/// Number of stripes that are rebuilt (or scrubbed) at a time
+const REBUILD_STRIPES: u64 = 128;
+
+/// Number of locks shared by stripes
+///
+/// Stripe `s` uses lock `s % STRIPE_LOCKS`, so writes to nearby stripes
+/// proceed in parallel without a lock per stripe.
+const STRIPE_LOCKS: usize = 64;
+
+/// Returns the Upstairs id for fragment `f` of the region set `id`
+///
+/// Fragments must have distinct ids, since each is its own Upstairs; deriving
+/// them (rather than picking new ones) keeps them the same across restarts.
+/// Only the low bits are changed, so the result is still a valid v4 UUID.
+pub(crate) fn fragment_id(id: Uuid, f: usize) -> Uuid {
+    Uuid::from_u128(id.as_u128() ^ (f as u128 + 1))
+}
+
+/// Arithmetic in GF(2^8), using the polynomial `x^8 + x^4 + x^3 + x^2 + 1`
+mod gf {
+    const POLY: u16 = 0x11d;
+
+    const fn tables() -> ([u8; 512], [u8; 256]) {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= POLY;
+            }
+            i += 1;
+        }
+        (exp, log)
+    }
+
+    const TABLES: ([u8; 512], [u8; 256]) = tables();
+    static EXP: [u8; 512] = TABLES.0;
+    static LOG: [u8; 256] = TABLES.1;
+
+    pub(super) fn mul(a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+        }
+    }
+
+    /// Returns the multiplicative inverse of `a`
+    ///
+    /// # Panics
+    /// If `a` is zero
+    pub(super) fn inv(a: u8) -> u8 {
+        assert_ne!(a, 0);
+        EXP[255 - LOG[a as usize] as usize]
+    }
+
+    /// Computes `out += c * input` for every byte
+    pub(super) fn mul_acc(out: &mut [u8], c: u8, input: &[u8]) {
+        let table: [u8; 256] = std::array::from_fn(|x| mul(c, x as u8));
+        for (o, i) in out.iter_mut().zip(input) {
+            *o ^= table[*i as usize];
+        }
+    }
+}
+
+/// Systematic Reed-Solomon code over GF(2^8)
+///
+/// The encoding matrix is the identity (for data fragments) on top of a Cauchy
+/// matrix (for parity fragments), so any `data` of its rows form an invertible
+/// matrix.
+#[derive(Debug, Clone)]
+pub(crate) struct ReedSolomon {
+    data: usize,
+    parity: usize,
+
+    /// `parity` rows of `data` coefficients each
+    matrix: Vec<Vec<u8>>,
+}
+
+impl ReedSolomon {
+    pub(crate) fn new(
+        data: usize,
+        parity: usize,
+    ) -> Result<Self, CrucibleError> {
+        if data == 0 || parity == 0 || data + parity > MAX_FRAGMENTS {
+            crucible_bail!(
+                GenericError,
+                "invalid erasure coding {data}+{parity}; need at least one \
+                 data and parity fragment, and at most {MAX_FRAGMENTS} total",
+            );
+        }
+        let matrix = (0..parity)
+            .map(|j| {
+                (0..data).map(|i| gf::inv(((data + j) ^ i) as u8)).collect()
+            })
+            .collect();
+        Ok(Self {
+            data,
+            parity,
+            matrix,
+        })
+    }
+
+    /// Returns the encoding matrix row for the given fragment
+    fn row(&self, fragment: usize) -> Vec<u8> {
+        if fragment < self.data {
+            (0..self.data).map(|i| u8::from(i == fragment)).collect()
+        } else {
+            self.matrix[fragment - self.data].clone()
+        }
+    }
+
+    /// Computes parity for equal-length data shards
+    ///
+    /// Coding is bytewise, so each shard may hold any number of stripes.
+    pub(crate) fn encode(&self, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        assert_eq!(data.len(), self.data);
+        let len = data[0].len();
+        assert!(data.iter().all(|d| d.len() == len));
+
+        self.matrix
+            .iter()
+            .map(|row| {
+                let mut out = vec![0u8; len];
+                for (c, d) in row.iter().zip(data) {
+                    gf::mul_acc(&mut out, *c, d);
+                }
+                out
+            })
+            .collect()
+    }
+
+    /// Fills in any missing shards (data and parity) from those present
+    ///
+    /// Returns an error if fewer than `data` shards are present.
+    pub(crate) fn reconstruct(
+        &self,
+        shards: &mut [Option<Vec<u8>>],
+    ) -> Result<(), CrucibleError> {
+        assert_eq!(shards.len(), self.data + self.parity);
+        if shards.iter().all(Option::is_some) {
+            return Ok(());
+        }
+
+        let present: Vec<usize> = shards
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.is_some())
+            .map(|(i, _)| i)
+            .take(self.data)
+            .collect();
+        if present.len() < self.data {
+            crucible_bail!(
+                GenericError,
+                "erasure coding needs {} fragments, only {} available",
+                self.data,
+                present.len()
+            );
+        }
+
+        let decode = invert(present.iter().map(|&f| self.row(f)).collect())
+            .expect("Cauchy submatrix must be invertible");
+        let len = shards[present[0]].as_ref().unwrap().len();
+        for (i, coefs) in decode.iter().enumerate() {
+            if shards[i].is_some() {
+                continue;
+            }
+            let mut out = vec![0u8; len];
+            for (c, &f) in coefs.iter().zip(&present) {
+                gf::mul_acc(&mut out, *c, shards[f].as_ref().unwrap());
+            }
+            shards[i] = Some(out);
+        }
+
+        if shards[self.data..].iter().any(Option::is_none) {
+            let data: Vec<&[u8]> = shards[..self.data]
+                .iter()
+                .map(|s| s.as_deref().unwrap())
+                .collect();
+            let parity = self.encode(&data);
+            for (s, p) in shards[self.data..].iter_mut().zip(parity) {
+                if s.is_none() {
+                    *s = Some(p);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Inverts a square matrix over GF(2^8) by Gauss-Jordan elimination
+fn invert(mut m: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = m.len();
+    let mut inv: Vec<Vec<u8>> = (0..n)
+        .map(|i| (0..n).map(|j| u8::from(i == j)).collect())
+        .collect();
+
+    for col in 0..n {
+        let pivot = (col..n).find(|&r| m[r][col] != 0)?;
+        m.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let p = gf::inv(m[col][col]);
+        m[col].iter_mut().for_each(|v| *v = gf::mul(*v, p));
+        inv[col].iter_mut().for_each(|v| *v = gf::mul(*v, p));
+
+        let (pm, pi) = (m[col].clone(), inv[col].clone());
+        for (r, (mr, ir)) in m.iter_mut().zip(inv.iter_mut()).enumerate() {
+            let f = mr[col];
+            if r == col || f == 0 {
+                continue;
+            }
+            for (v, p) in mr.iter_mut().zip(&pm) {
+                *v ^= gf::mul(f, *p);
+            }
+            for (v, p) in ir.iter_mut().zip(&pi) {
+                *v ^= gf::mul(f, *p);
+            }
+        }
+    }
+    Some(inv)
+}
+
+/// Implement BlockIO for an erasure-coded set of fragments
+///
+/// Fragments are usually single-downstairs `Guest`s, but any `BlockIO` with
+/// persistent ownership will do.
+pub struct ErasureBlockIO {
+    inner: Arc<ErasureInner>,
+}
+
+struct ErasureInner {
+    uuid: Uuid,
+    block_size: u64,
+
+    /// Number of blocks in each fragment (i.e. number of stripes, plus one
+    /// block for the [`Marker`])
+    fragment_blocks: u64,
+
+    rs: ReedSolomon,
+
+    /// Data fragments, followed by parity fragments
+    fragments: Vec<Arc<dyn BlockIO + Send + Sync>>,
+
+    /// Fragments that may be out of date, and are not read from until they
+    /// have been rebuilt
+    rebuilding: Vec<AtomicBool>,
+
+    /// Set when a write to a fragment fails during its rebuild, so that the
+    /// rebuild makes another pass
+    stale: Vec<AtomicBool>,
+
+    /// Set while a task is rebuilding the fragment
+    ///
+    /// This is separate from `rebuilding`, which stays set when a rebuild is
+    /// stopped by deactivation, so that it's resumed on the next activation.
+    rebuild_task: Vec<AtomicBool>,
+
+    /// Set when the region set may have a torn stripe, until a scrub has
+    /// checked every stripe's parity
+    scrub_pending: AtomicBool,
+
+    /// Set while a task is scrubbing parity
+    scrub_task: AtomicBool,
+
+    /// Set by deactivation, to stop rebuilds and scrubs
+    stopped: AtomicBool,
+
+    /// Locks for stripes, shared round-robin; see [`STRIPE_LOCKS`]
+    stripe_locks: Vec<Mutex<()>>,
+
+    /// Serializes writes of the [`Marker`]
+    marker_lock: Mutex<()>,
+
+    log: Logger,
+}
+
+/// State of the region set, kept in the last block of every fragment
+///
+/// Activation uses this to decide whether parity must be scrubbed, and which
+/// fragments must be rebuilt: a fragment which missed writes only reports it
+/// (through [`BlockIO::take_needs_rebuild`]) to the Upstairs that was running
+/// at the time.
+///
+/// Markers are combined conservatively (rebuilds are the union of every
+/// marker's, and any dirty marker from a fragment that isn't being rebuilt
+/// means the set is dirty), so a fragment with an old marker can't hide
+/// anything recorded by the others.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Marker {
+    /// Set while the region set is active, and until a scrub has finished
+    dirty: bool,
+
+    /// Bitmap of fragments which must be rebuilt before they are read
+    rebuild: u32,
+}
+
+impl Marker {
+    const MAGIC: &'static [u8; 16] = b"crucible erasure";
+    const VERSION: u8 = 1;
+
+    fn encode(&self, block_size: usize) -> BytesMut {
+        let mut out = vec![0u8; block_size];
+        out[..16].copy_from_slice(Self::MAGIC);
+        out[16] = Self::VERSION;
+        out[17] = u8::from(self.dirty);
+        out[18..22].copy_from_slice(&self.rebuild.to_le_bytes());
+        BytesMut::from(&out[..])
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 22
+            || &data[..16] != Self::MAGIC
+            || data[16] != Self::VERSION
+        {
+            return None;
+        }
+        Some(Self {
+            dirty: data[17] != 0,
+            rebuild: u32::from_le_bytes(data[18..22].try_into().unwrap()),
+        })
+    }
+}
+
+impl ErasureBlockIO {
+    pub fn new(
+        id: Uuid,
+        block_size: u64,
+        fragment_blocks: u64,
+        data: usize,
+        parity: usize,
+        fragments: Vec<Arc<dyn BlockIO + Send + Sync>>,
+        log: Logger,
+    ) -> Result<Self, CrucibleError> {
+        let rs = ReedSolomon::new(data, parity)?;
+        if fragments.len() != data + parity {
+            crucible_bail!(
+                GenericError,
+                "erasure coding {data}+{parity} needs {} fragments, got {}",
+                data + parity,
+                fragments.len()
+            );
+        } else if fragment_blocks < 2 {
+            crucible_bail!(
+                GenericError,
+                "fragments of {fragment_blocks} blocks are too small",
+            );
+        }
+        let n = fragments.len();
+        let flags = || (0..n).map(|_| AtomicBool::new(false)).collect();
+        Ok(Self {
+            inner: Arc::new(ErasureInner {
+                uuid: id,
+                block_size,
+                fragment_blocks,
+                rs,
+                rebuilding: flags(),
+                stale: flags(),
+                rebuild_task: flags(),
+                fragments,
+                scrub_pending: false.into(),
+                scrub_task: false.into(),
+                stopped: false.into(),
+                stripe_locks: (0..STRIPE_LOCKS)
+                    .map(|_| Mutex::new(()))
+                    .collect(),
+                marker_lock: Mutex::new(()),
+                log,
+            }),
+        })
+    }
+
+    /// Returns the indices of fragments which are currently being rebuilt
+    pub fn rebuilding(&self) -> Vec<usize> {
+        self.inner
+            .rebuilding
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.load(Ordering::Acquire))
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+impl ErasureInner {
+    fn data(&self) -> usize {
+        self.rs.data
+    }
+
+    fn parity(&self) -> usize {
+        self.rs.parity
+    }
+
+    /// Returns the number of stripes, leaving each fragment's last block for
+    /// the [`Marker`]
+    fn stripe_count(&self) -> u64 {
+        self.fragment_blocks - 1
+    }
+
+    /// Returns the range of stripes touched by the given range of blocks
+    fn stripes(&self, offset: u64, count: u64) -> Range<u64> {
+        let k = self.data() as u64;
+        offset / k..(offset + count).div_ceil(k)
+    }
+
+    /// Returns the error for a fragment which can't persist writes right now
+    fn cannot_persist(f: usize) -> CrucibleError {
+        CrucibleError::IoError(format!("fragment {f} can't persist writes"))
+    }
+
+    /// Locks the given stripes against writes, rebuilds, and scrubs
+    ///
+    /// Stripes share the locks round-robin, and the locks are always taken in
+    /// order, so that callers with overlapping stripes can't deadlock.
+    async fn lock_stripes(
+        &self,
+        stripes: &Range<u64>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let n = STRIPE_LOCKS as u64;
+        let end = stripes.end.min(stripes.start + n);
+        let mut locks: Vec<usize> =
+            (stripes.start..end).map(|s| (s % n) as usize).collect();
+        locks.sort_unstable();
+
+        let mut guards = Vec::with_capacity(locks.len());
+        for i in locks {
+            guards.push(self.stripe_locks[i].lock().await);
+        }
+        guards
+    }
+
+    /// Reads the given stripes from a set of fragments, in parallel
+    ///
+    /// Fragments which are being rebuilt (or can't persist writes, or fail to
+    /// read) are returned as `None`, as are fragments which turn out to need a
+    /// rebuild.
+    async fn read_fragments(
+        self: &Arc<Self>,
+        which: Range<usize>,
+        stripes: &Range<u64>,
+    ) -> Vec<Option<Buffer>> {
+        let bs = self.block_size as usize;
+        let count = (stripes.end - stripes.start) as usize;
+        join_all(which.map(|f| async move {
+            if self.rebuilding[f].load(Ordering::Acquire)
+                || !self.fragments[f].can_persist_writes()
+            {
+                return None;
+            }
+            let mut buf = Buffer::new(count, bs);
+            let r = self.fragments[f]
+                .read(BlockIndex(stripes.start), &mut buf)
+                .await;
+
+            // Check after the read, so that we catch a fragment that came back
+            // while it was in flight.
+            if self.fragments[f].take_needs_rebuild() {
+                warn!(self.log, "fragment {f} missed writes");
+                self.start_rebuild(f);
+                return None;
+            }
+            match r {
+                Ok(()) => Some(buf),
+                Err(e) => {
+                    warn!(self.log, "read from fragment {f} failed: {e}");
+                    None
+                }
+            }
+        }))
+        .await
+    }
+
+    /// Reads every data column for the given stripes, one `Buffer` per column
+    ///
+    /// Columns which can't be read from their fragment are reconstructed from
+    /// parity.  We don't know whether a reconstructed block was ever written,
+    /// so it is considered owned if any parity block for its stripe is owned.
+    async fn read_stripes(
+        self: &Arc<Self>,
+        stripes: &Range<u64>,
+    ) -> Result<Vec<Buffer>, CrucibleError> {
+        let (k, bs) = (self.data(), self.block_size as usize);
+        let mut cols = self.read_fragments(0..k, stripes).await;
+        if cols.iter().all(Option::is_some) {
+            return Ok(cols.into_iter().map(Option::unwrap).collect());
+        }
+
+        let parity =
+            self.read_fragments(k..self.fragments.len(), stripes).await;
+        let count = (stripes.end - stripes.start) as usize;
+        let owned: Vec<bool> = (0..count)
+            .map(|s| parity.iter().flatten().any(|p| p.owned_ref()[s] != 0))
+            .collect();
+
+        let mut shards: Vec<Option<Vec<u8>>> = cols
+            .iter()
+            .chain(&parity)
+            .map(|b| b.as_ref().map(|b| b.to_vec()))
+            .collect();
+        self.rs.reconstruct(&mut shards)?;
+
+        for (col, shard) in cols.iter_mut().zip(shards) {
+            if col.is_none() {
+                let mut buf = Buffer::new(count, bs);
+                buf.write_if_owned(0, &shard.unwrap(), &owned);
+                *col = Some(buf);
+            }
+        }
+        Ok(cols.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Computes parity for a set of data columns
+    fn encode(&self, cols: &[Buffer]) -> Vec<Vec<u8>> {
+        let data: Vec<&[u8]> = cols.iter().map(|c| &c[..]).collect();
+        self.rs.encode(&data)
+    }
+
+    /// Writes data columns and parity for the blocks in `blocks`
+    ///
+    /// Only blocks within that range are written to the data fragments, so
+    /// that their ownership is preserved; parity is written for every stripe
+    /// touched.  If `unwritten` is set, data fragments are written with
+    /// `write_unwritten` instead.
+    ///
+    /// Up to `parity` fragments may fail (which starts a rebuild for them)
+    /// before the write as a whole fails.  A fragment which can't persist
+    /// writes counts as failed, rather than holding our only copy of a write
+    /// in its Upstairs' memory.
+    ///
+    /// The fragments are written in parallel, with nothing recording that the
+    /// stripe is mid-write; see the module docs for how a crash here is
+    /// handled.
+    async fn write_stripes(
+        self: &Arc<Self>,
+        blocks: Range<u64>,
+        cols: &[Buffer],
+        unwritten: bool,
+    ) -> Result<(), CrucibleError> {
+        let k = self.data() as u64;
+        let bs = self.block_size as usize;
+        let stripes = self.stripes(blocks.start, blocks.end - blocks.start);
+        let parity = self.encode(cols);
+
+        let mut writes = vec![];
+        for (c, col) in cols.iter().enumerate() {
+            let c = c as u64;
+            // First and last blocks in this column that are in range
+            let first = blocks.start + (c + k - blocks.start % k) % k;
+            if first >= blocks.end {
+                continue;
+            }
+            let last = blocks.end - 1 - ((blocks.end - 1) % k + k - c) % k;
+            let rows = (first / k - stripes.start) as usize
+                ..(last / k - stripes.start + 1) as usize;
+            let data = BytesMut::from(&col[rows.start * bs..rows.end * bs]);
+            writes.push((c as usize, first / k, data, unwritten));
+        }
+        for (j, p) in parity.into_iter().enumerate() {
+            let data = BytesMut::from(&p[..]);
+            writes.push((self.data() + j, stripes.start, data, false));
+        }
+
+        let results = join_all(writes.into_iter().map(
+            |(f, offset, data, unwritten)| async move {
+                let fragment = &self.fragments[f];
+                let r = if !fragment.can_persist_writes() {
+                    Err(Self::cannot_persist(f))
+                } else if unwritten {
+                    fragment.write_unwritten(BlockIndex(offset), data).await
+                } else {
+                    fragment.write(BlockIndex(offset), data).await
+                };
+                (f, r)
+            },
+        ))
+        .await;
+
+        self.check_results("write", results)
+    }
+
+    /// Writes each run of blocks selected by `which` from `data` (which holds
+    /// blocks starting at `start`) to fragment `f`
+    ///
+    /// Returns the number of blocks written.
+    async fn write_runs(
+        &self,
+        f: usize,
+        start: u64,
+        which: &[bool],
+        data: &[u8],
+    ) -> Result<u64, CrucibleError> {
+        let bs = self.block_size as usize;
+        let mut written = 0;
+        let mut b = 0;
+        while b < which.len() {
+            let count = which[b..].iter().take_while(|w| **w).count();
+            if count > 0 {
+                self.fragments[f]
+                    .write(
+                        BlockIndex(start + b as u64),
+                        BytesMut::from(&data[b * bs..(b + count) * bs]),
+                    )
+                    .await?;
+                written += count as u64;
+            }
+            b += count.max(1);
+        }
+        Ok(written)
+    }
+
+    /// Checks per-fragment results, starting a rebuild of failed fragments
+    /// (and of fragments which report that they need one)
+    ///
+    /// Returns the first error if more than `parity` fragments failed.
+    fn check_results(
+        self: &Arc<Self>,
+        what: &str,
+        results: Vec<(usize, Result<(), CrucibleError>)>,
+    ) -> Result<(), CrucibleError> {
+        let mut first_err = None;
+        let mut failed = 0;
+        for (f, r) in results {
+            if self.fragments[f].take_needs_rebuild() {
+                warn!(self.log, "fragment {f} missed writes");
+                self.start_rebuild(f);
+            }
+            if let Err(e) = r {
+                warn!(self.log, "{what} to fragment {f} failed: {e}");
+                failed += 1;
+                first_err.get_or_insert(e);
+                self.start_rebuild(f);
+            }
+        }
+        match first_err {
+            Some(e) if failed > self.parity() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the bitmap of fragments being rebuilt
+    fn rebuild_bitmap(&self) -> u32 {
+        self.rebuilding
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.load(Ordering::Acquire))
+            .fold(0, |bits, (f, _)| bits | 1 << f)
+    }
+
+    /// Reads the [`Marker`] from every fragment that has a valid one
+    async fn read_markers(&self) -> Vec<(usize, Marker)> {
+        let bs = self.block_size as usize;
+        let last = BlockIndex(self.stripe_count());
+        let markers = join_all(self.fragments.iter().enumerate().map(
+            |(f, fragment)| async move {
+                let mut buf = Buffer::new(1, bs);
+                match fragment.read(last, &mut buf).await {
+                    Ok(()) if buf.owned_ref()[0] != 0 => {
+                        let m = Marker::decode(&buf[..]);
+                        if m.is_none() {
+                            warn!(self.log, "fragment {f} has a bad marker");
+                        }
+                        m.map(|m| (f, m))
+                    }
+                    Ok(()) => None,
+                    Err(e) => {
+                        warn!(self.log, "reading marker of fragment {f}: {e}");
+                        None
+                    }
+                }
+            },
+        ))
+        .await;
+        markers.into_iter().flatten().collect()
+    }
+
+    /// Writes the [`Marker`] to every fragment that isn't being rebuilt, and
+    /// flushes it, which also makes everything written before it durable
+    ///
+    /// The marker is clean if `clean` is set and no scrub is pending.  A
+    /// fragment which doesn't take the marker can't be trusted to hold what
+    /// was written before it, so we start rebuilding it and write the marker
+    /// again (recording that) to the others.
+    ///
+    /// Returns an error if more than `parity` fragments need a rebuild.
+    async fn write_marker(
+        self: &Arc<Self>,
+        clean: bool,
+    ) -> Result<(), CrucibleError> {
+        let _guard = self.marker_lock.lock().await;
+        let last = BlockIndex(self.stripe_count());
+        loop {
+            let marker = Marker {
+                dirty: !clean || self.scrub_pending.load(Ordering::Acquire),
+                rebuild: self.rebuild_bitmap(),
+            };
+            let data = marker.encode(self.block_size as usize);
+            let targets = (0..self.fragments.len())
+                .filter(|&f| !self.rebuilding[f].load(Ordering::Acquire));
+            let results = join_all(targets.map(|f| {
+                let data = data.clone();
+                async move {
+                    let fragment = &self.fragments[f];
+                    if !fragment.can_persist_writes() {
+                        return (f, Err(Self::cannot_persist(f)));
+                    }
+                    let r = match fragment.write(last, data).await {
+                        Ok(()) => fragment.flush(None).await,
+                        Err(e) => Err(e),
+                    };
+                    (f, r)
+                }
+            }))
+            .await;
+
+            let mut failed = false;
+            for (f, r) in results {
+                if let Err(e) = r {
+                    warn!(self.log, "writing marker to fragment {f}: {e}");
+                    self.start_rebuild(f);
+                    failed = true;
+                }
+            }
+            let rebuilding = self.rebuild_bitmap().count_ones() as usize;
+            if rebuilding > self.parity() {
+                crucible_bail!(
+                    GenericError,
+                    "{rebuilding} fragments need a rebuild, more than the \
+                     {} that parity covers",
+                    self.parity()
+                );
+            } else if !failed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Starts checking parity against the data fragments in the background,
+    /// unless that's already in progress
+    ///
+    /// This closes any write hole left by a crash.  Only blocks whose parity
+    /// doesn't match are rewritten, and every fragment stays in use (providing
+    /// redundancy) meanwhile.
+    fn start_scrub(self: &Arc<Self>) {
+        if self.scrub_task.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let inner = self.clone();
+        tokio::spawn(async move {
+            while !inner.stopped.load(Ordering::Acquire) {
+                match inner.scrub().await {
+                    Ok(n) => {
+                        info!(inner.log, "parity scrub rewrote {n} blocks");
+                        inner.scrub_pending.store(false, Ordering::Release);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!(inner.log, "parity scrub: {e}");
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                    }
+                }
+            }
+            inner.scrub_task.store(false, Ordering::Release);
+        });
+    }
+
+    /// Makes one pass over every stripe, rewriting parity which doesn't match
+    /// the data fragments
+    ///
+    /// Every data fragment must be readable, since we can't check parity
+    /// against data that would have to be reconstructed from it.  Parity
+    /// fragments which are being rebuilt are skipped; their rebuild recomputes
+    /// everything anyway.
+    ///
+    /// Returns the number of parity blocks rewritten.
+    async fn scrub(self: &Arc<Self>) -> Result<u64, CrucibleError> {
+        let (k, bs) = (self.data(), self.block_size as usize);
+        let mut rewritten = 0;
+        let mut start = 0;
+        while start < self.stripe_count() {
+            if self.stopped.load(Ordering::Acquire) {
+                crucible_bail!(GenericError, "region set was deactivated");
+            }
+            let stripes =
+                start..(start + REBUILD_STRIPES).min(self.stripe_count());
+            let count = (stripes.end - stripes.start) as usize;
+            let _guards = self.lock_stripes(&stripes).await;
+
+            let cols = self.read_fragments(0..k, &stripes).await;
+            let Some(cols) = cols.into_iter().collect::<Option<Vec<_>>>()
+            else {
+                crucible_bail!(GenericError, "data fragments are unavailable");
+            };
+            let owned: Vec<bool> = (0..count)
+                .map(|s| cols.iter().any(|c| c.owned_ref()[s] != 0))
+                .collect();
+            let expected = self.encode(&cols);
+
+            let parity =
+                self.read_fragments(k..self.fragments.len(), &stripes).await;
+            for (j, (p, e)) in parity.iter().zip(&expected).enumerate() {
+                let f = k + j;
+                let Some(p) = p else {
+                    if self.rebuilding[f].load(Ordering::Acquire) {
+                        continue;
+                    }
+                    crucible_bail!(
+                        GenericError,
+                        "fragment {} is unavailable",
+                        f
+                    );
+                };
+                let bad: Vec<bool> = (0..count)
+                    .map(|s| {
+                        let block = s * bs..(s + 1) * bs;
+                        owned[s] && p[block.clone()] != e[block]
+                    })
+                    .collect();
+                rewritten += self.write_runs(f, stripes.start, &bad, e).await?;
+            }
+            start = stripes.end;
+        }
+        Ok(rewritten)
+    }
+
+    /// Starts rebuilding the given fragment, if it isn't already in progress
+    ///
+    /// The fragment isn't read from until the rebuild is done.  If the region
+    /// set has been deactivated, the fragment is only marked, and its rebuild
+    /// starts on the next activation.
+    fn start_rebuild(self: &Arc<Self>, f: usize) {
+        self.stale[f].store(true, Ordering::Release);
+        self.rebuilding[f].store(true, Ordering::Release);
+        if self.stopped.load(Ordering::Acquire)
+            || self.rebuild_task[f].swap(true, Ordering::AcqRel)
+        {
+            return;
+        }
+        let inner = self.clone();
+        tokio::spawn(async move {
+            // Nothing but the marker would tell the next Upstairs that this
+            // fragment is stale, so record that before going any further.
+            if let Err(e) = inner.write_marker(false).await {
+                warn!(inner.log, "recording rebuild of fragment {f}: {e}");
+            }
+            let done = loop {
+                if inner.stopped.load(Ordering::Acquire) {
+                    break false;
+                }
+                match inner.rebuild(f).await {
+                    Ok(true) => break true,
+                    Ok(false) => {
+                        info!(inner.log, "fragment {f} changed, rebuilding");
+                    }
+                    Err(e) => {
+                        warn!(inner.log, "rebuild of fragment {f}: {e}");
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                    }
+                }
+            };
+            if !done {
+                info!(inner.log, "rebuild of fragment {f} is stopped");
+                inner.rebuild_task[f].store(false, Ordering::Release);
+                return;
+            }
+            inner.rebuilding[f].store(false, Ordering::Release);
+            inner.rebuild_task[f].store(false, Ordering::Release);
+            info!(inner.log, "rebuild of fragment {f} is done");
+
+            // Someone may have asked for another rebuild after our last pass
+            // (but before we cleared `rebuilding`); otherwise, drop this
+            // fragment from the marker.
+            if inner.stale[f].load(Ordering::Acquire) {
+                inner.start_rebuild(f);
+            } else if !inner.stopped.load(Ordering::Acquire) {
+                if let Err(e) = inner.write_marker(false).await {
+                    warn!(inner.log, "recording rebuild of fragment {f}: {e}");
+                }
+            }
+        });
+    }
+
+    /// Makes one pass at rebuilding a fragment from the others
+    ///
+    /// Only stripes with some owned block are rewritten.  Returns `Ok(false)`
+    /// if writes to the fragment failed (or it missed writes) during the pass,
+    /// so it must be redone.
+    async fn rebuild(
+        self: &Arc<Self>,
+        f: usize,
+    ) -> Result<bool, CrucibleError> {
+        let fragment = &self.fragments[f];
+        self.stale[f].store(false, Ordering::Release);
+        fragment.take_needs_rebuild();
+        fragment.conditional_activate().await?;
+        if !fragment.can_persist_writes() {
+            return Err(Self::cannot_persist(f));
+        }
+        info!(self.log, "rebuilding fragment {f}");
+
+        let mut start = 0;
+        while start < self.stripe_count() {
+            if self.stopped.load(Ordering::Acquire) {
+                crucible_bail!(GenericError, "region set was deactivated");
+            }
+            let stripes =
+                start..(start + REBUILD_STRIPES).min(self.stripe_count());
+            let _guards = self.lock_stripes(&stripes).await;
+            let cols = self.read_stripes(&stripes).await?;
+            let (data, owned): (Vec<u8>, Vec<bool>) = if f < self.data() {
+                let c = &cols[f];
+                let owned = c.owned_ref().iter().map(|o| *o != 0).collect();
+                (c.to_vec(), owned)
+            } else {
+                let owned = (0..stripes.end - stripes.start)
+                    .map(|s| {
+                        cols.iter().any(|c| c.owned_ref()[s as usize] != 0)
+                    })
+                    .collect();
+                let p = self.encode(&cols).swap_remove(f - self.data());
+                (p, owned)
+            };
+            self.write_runs(f, stripes.start, &owned, &data).await?;
+            start = stripes.end;
+        }
+        fragment.flush(None).await?;
+        let missed = fragment.take_needs_rebuild();
+        Ok(!self.stale[f].load(Ordering::Acquire) && !missed)
+    }
+}
+
+#[async_trait]
+impl BlockIO for ErasureBlockIO {
+    /// Activates the fragments, then picks up where the last activation left
+    /// off (according to the [`Marker`]s)
+    async fn activate(&self) -> Result<(), CrucibleError> {
+        let inner = &self.inner;
+        let results =
+            join_all(inner.fragments.iter().map(|f| f.activate())).await;
+
+        // Read the markers before a rebuild started below can rewrite them
+        let markers = inner.read_markers().await;
+        inner.stopped.store(false, Ordering::Release);
+        inner.check_results(
+            "activate",
+            results.into_iter().enumerate().collect(),
+        )?;
+
+        // A fragment that needs a rebuild may have missed later markers, so
+        // only the others say whether we were deactivated cleanly.
+        let rebuild = markers.iter().fold(0, |bits, (_, m)| bits | m.rebuild);
+        let dirty = markers
+            .iter()
+            .any(|(f, m)| m.dirty && rebuild & (1 << f) == 0);
+        for f in 0..inner.fragments.len() {
+            if rebuild & (1 << f) != 0
+                || inner.rebuilding[f].load(Ordering::Acquire)
+            {
+                warn!(inner.log, "resuming rebuild of fragment {f}");
+                inner.start_rebuild(f);
+            }
+        }
+        if dirty {
+            warn!(inner.log, "region set wasn't deactivated cleanly");
+            inner.scrub_pending.store(true, Ordering::Release);
+        }
+
+        // From here on, a crash may tear a stripe
+        inner.write_marker(false).await?;
+        if inner.scrub_pending.load(Ordering::Acquire) {
+            inner.start_scrub();
+        }
+        Ok(())
+    }
+
+    /// Records a clean shutdown in the [`Marker`], then deactivates the
+    /// fragments
+    ///
+    /// Like writes, this succeeds as long as no more than `parity` fragments
+    /// fail; those fragments are rebuilt on the next activation.
+    async fn deactivate(&self) -> Result<(), CrucibleError> {
+        let inner = &self.inner;
+        inner.stopped.store(true, Ordering::Release);
+
+        // Hold every stripe lock, so that nothing is written between the
+        // marker and the fragments being deactivated.
+        let _guards = inner.lock_stripes(&(0..STRIPE_LOCKS as u64)).await;
+        let marked = inner.write_marker(true).await;
+
+        let results =
+            join_all(inner.fragments.iter().map(|f| f.deactivate())).await;
+        let mut first_err = None;
+        let mut failed = 0;
+        for (f, r) in results.into_iter().enumerate() {
+            if let Err(e) = r {
+                warn!(inner.log, "deactivate of fragment {f} failed: {e}");
+                failed += 1;
+                first_err.get_or_insert(e);
+            }
+        }
+        match first_err {
+            Some(e) if failed > inner.parity() => Err(e),
+            _ => marked,
+        }
+    }
+
+    /// We're active if enough fragments are active to read every stripe
+    async fn query_is_active(&self) -> Result<bool, CrucibleError> {
+        let results =
+            join_all(self.inner.fragments.iter().map(|f| f.query_is_active()))
+                .await;
+        let active = results.into_iter().filter(|r| matches!(r, Ok(true)));
+        Ok(active.count() >= self.inner.data())
+    }
+
+    async fn total_size(&self) -> Result<u64, CrucibleError> {
+        let inner = &self.inner;
+        Ok(inner.stripe_count() * inner.data() as u64 * inner.block_size)
+    }
+
+    async fn get_block_size(&self) -> Result<u64, CrucibleError> {
+        Ok(self.inner.block_size)
+    }
+
+    async fn get_uuid(&self) -> Result<Uuid, CrucibleError> {
+        Ok(self.inner.uuid)
+    }
+
+    async fn read(
+        &self,
+        offset: BlockIndex,
+        data: &mut Buffer,
+    ) -> Result<(), CrucibleError> {
+        let bs = self.check_data_size(data.len()).await? as usize;
+        let inner = &self.inner;
+        let k = inner.data() as u64;
+        let count = (data.len() / bs) as u64;
+        if offset.0 + count > inner.stripe_count() * k {
+            crucible_bail!(OffsetInvalid);
+        } else if count == 0 {
+            return Ok(());
+        }
+
+        let stripes = inner.stripes(offset.0, count);
+        let cols = inner.read_stripes(&stripes).await?;
+        for b in 0..count {
+            let lba = offset.0 + b;
+            let col = &cols[(lba % k) as usize];
+            let row = (lba / k - stripes.start) as usize;
+            if col.owned_ref()[row] != 0 {
+                data.write(b as usize * bs, col.block(row));
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        offset: BlockIndex,
+        data: BytesMut,
+    ) -> Result<(), CrucibleError> {
+        self.write_inner(offset, data, false).await
+    }
+
+    async fn write_unwritten(
+        &self,
+        offset: BlockIndex,
+        data: BytesMut,
+    ) -> Result<(), CrucibleError> {
+        self.write_inner(offset, data, true).await
+    }
+
+    async fn flush(
+        &self,
+        snapshot_details: Option<SnapshotDetails>,
+    ) -> Result<(), CrucibleError> {
+        let inner = &self.inner;
+        let results = join_all(inner.fragments.iter().enumerate().map(
+            |(f, fragment)| {
+                let snapshot_details = snapshot_details.clone();
+                async move {
+                    if !fragment.can_persist_writes() {
+                        return (f, Err(ErasureInner::cannot_persist(f)));
+                    }
+                    (f, fragment.flush(snapshot_details).await)
+                }
+            },
+        ))
+        .await;
+        inner.check_results("flush", results)
+    }
+
+    async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
+        let mut wq_counts = WQCounts {
+            up_count: 0,
+            ds_count: 0,
+            active_count: 0,
+        };
+        for fragment in &self.inner.fragments {
+            let sub_wq_counts = fragment.show_work().await?;
+            wq_counts.up_count += sub_wq_counts.up_count;
+            wq_counts.ds_count += sub_wq_counts.ds_count;
+            wq_counts.active_count += sub_wq_counts.active_count;
+        }
+        Ok(wq_counts)
+    }
+
+    /// Replaces a downstairs in one of our fragments, then rebuilds that
+    /// fragment's contents from the others
+    async fn replace_downstairs(
+        &self,
+        id: Uuid,
+        old: SocketAddr,
+        new: SocketAddr,
+    ) -> Result<ReplaceResult, CrucibleError> {
+        for (f, fragment) in self.inner.fragments.iter().enumerate() {
+            let result = fragment.replace_downstairs(id, old, new).await?;
+            match result {
+                ReplaceResult::Started => {
+                    self.inner.start_rebuild(f);
+                    return Ok(result);
+                }
+                ReplaceResult::StartedAlready
+                | ReplaceResult::CompletedAlready
+                | ReplaceResult::VcrMatches => {
+                    return Ok(result);
+                }
+                ReplaceResult::Missing => {
+                    // keep looking!
+                }
+            }
+        }
+        Ok(ReplaceResult::Missing)
+    }
+}
+
+impl ErasureBlockIO {
+    async fn write_inner(
+        &self,
+        offset: BlockIndex,
+        data: BytesMut,
+        unwritten: bool,
+    ) -> Result<(), CrucibleError> {
+        let bs = self.check_data_size(data.len()).await? as usize;
+        let inner = &self.inner;
+        let k = inner.data() as u64;
+        let count = (data.len() / bs) as u64;
+        if offset.0 + count > inner.stripe_count() * k {
+            crucible_bail!(OffsetInvalid);
+        } else if count == 0 {
+            return Ok(());
+        }
+
+        let stripes = inner.stripes(offset.0, count);
+        let _guards = inner.lock_stripes(&stripes).await;
+
+        // Writing whole stripes doesn't need the old contents; otherwise, read
+        // the stripes so that we can recompute their parity.
+        let mut cols = if !unwritten && offset.0 % k == 0 && count % k == 0 {
+            let n = (stripes.end - stripes.start) as usize;
+            (0..k).map(|_| Buffer::new(n, bs)).collect()
+        } else {
+            inner.read_stripes(&stripes).await?
+        };
+
+        for (b, chunk) in data.chunks(bs).enumerate() {
+            let lba = offset.0 + b as u64;
+            let col = &mut cols[(lba % k) as usize];
+            let row = (lba / k - stripes.start) as usize;
+            if !unwritten || col.owned_ref()[row] == 0 {
+                col.write(row * bs, chunk);
+            }
+        }
+
+        inner
+            .write_stripes(offset.0..offset.0 + count, &cols, unwritten)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::prelude::*;
+
+    const BS: usize = 512;
+
+    fn csl() -> Logger {
+        build_logger()
+    }
+
+    /// Returns `n` fragments with room for the given number of stripes
+    fn fragments(n: usize, stripes: usize) -> Vec<Arc<InMemoryBlockIO>> {
+        (0..n)
+            .map(|_| {
+                Arc::new(InMemoryBlockIO::new(
+                    Uuid::new_v4(),
+                    BS as u64,
+                    (stripes + 1) * BS,
+                ))
+            })
+            .collect()
+    }
+
+    fn erasure(
+        data: usize,
+        parity: usize,
+        frags: &[Arc<InMemoryBlockIO>],
+        stripes: u64,
+    ) -> ErasureBlockIO {
+        erasure_of(
+            data,
+            parity,
+            frags
+                .iter()
+                .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+                .collect(),
+            stripes,
+        )
+    }
+
+    fn erasure_of(
+        data: usize,
+        parity: usize,
+        frags: Vec<Arc<dyn BlockIO + Send + Sync>>,
+        stripes: u64,
+    ) -> ErasureBlockIO {
+        ErasureBlockIO::new(
+            Uuid::new_v4(),
+            BS as u64,
+            stripes + 1,
+            data,
+            parity,
+            frags,
+            csl(),
+        )
+        .unwrap()
+    }
+
+    /// Implement BlockIO for a fragment which has gone away
+    struct FailedBlockIO;
+
+    #[async_trait]
+    impl BlockIO for FailedBlockIO {
+        async fn activate(&self) -> Result<(), CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn deactivate(&self) -> Result<(), CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn query_is_active(&self) -> Result<bool, CrucibleError> {
+            Ok(false)
+        }
+        async fn total_size(&self) -> Result<u64, CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn get_block_size(&self) -> Result<u64, CrucibleError> {
+            Ok(BS as u64)
+        }
+        async fn get_uuid(&self) -> Result<Uuid, CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn read(
+            &self,
+            _offset: BlockIndex,
+            _data: &mut Buffer,
+        ) -> Result<(), CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn write(
+            &self,
+            _offset: BlockIndex,
+            _data: BytesMut,
+        ) -> Result<(), CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn write_unwritten(
+            &self,
+            _offset: BlockIndex,
+            _data: BytesMut,
+        ) -> Result<(), CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn flush(
+            &self,
+            _snapshot_details: Option<SnapshotDetails>,
+        ) -> Result<(), CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+        async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
+            Err(CrucibleError::Disconnect)
+        }
+    }
+
+    /// Implement BlockIO for a fragment which can be told that it missed
+    /// writes (as a lone-downstairs `Guest` would be when it comes back) or
+    /// that its downstairs is away, and which records where it's written
+    struct ReturningBlockIO {
+        inner: Arc<InMemoryBlockIO>,
+        missed: AtomicBool,
+        persist: AtomicBool,
+        writes: std::sync::Mutex<Vec<u64>>,
+    }
+
+    impl ReturningBlockIO {
+        fn new(inner: Arc<InMemoryBlockIO>) -> Arc<Self> {
+            Arc::new(Self {
+                inner,
+                missed: AtomicBool::new(false),
+                persist: AtomicBool::new(true),
+                writes: std::sync::Mutex::new(vec![]),
+            })
+        }
+
+        /// Returns the blocks written, other than the marker
+        fn written(&self, stripes: u64) -> Vec<u64> {
+            let writes = self.writes.lock().unwrap();
+            writes.iter().copied().filter(|b| *b < stripes).collect()
+        }
+    }
+
+    #[async_trait]
+    impl BlockIO for ReturningBlockIO {
+        async fn activate(&self) -> Result<(), CrucibleError> {
+            self.inner.activate().await
+        }
+        async fn deactivate(&self) -> Result<(), CrucibleError> {
+            self.inner.deactivate().await
+        }
+        async fn query_is_active(&self) -> Result<bool, CrucibleError> {
+            self.inner.query_is_active().await
+        }
+        async fn total_size(&self) -> Result<u64, CrucibleError> {
+            self.inner.total_size().await
+        }
+        async fn get_block_size(&self) -> Result<u64, CrucibleError> {
+            self.inner.get_block_size().await
+        }
+        async fn get_uuid(&self) -> Result<Uuid, CrucibleError> {
+            self.inner.get_uuid().await
+        }
+        async fn read(
+            &self,
+            offset: BlockIndex,
+            data: &mut Buffer,
+        ) -> Result<(), CrucibleError> {
+            self.inner.read(offset, data).await
+        }
+        async fn write(
+            &self,
+            offset: BlockIndex,
+            data: BytesMut,
+        ) -> Result<(), CrucibleError> {
+            let mut writes = self.writes.lock().unwrap();
+            writes.extend(offset.0..offset.0 + (data.len() / BS) as u64);
+            drop(writes);
+            self.inner.write(offset, data).await
+        }
+        async fn write_unwritten(
+            &self,
+            offset: BlockIndex,
+            data: BytesMut,
+        ) -> Result<(), CrucibleError> {
+            self.inner.write_unwritten(offset, data).await
+        }
+        async fn flush(
+            &self,
+            snapshot_details: Option<SnapshotDetails>,
+        ) -> Result<(), CrucibleError> {
+            self.inner.flush(snapshot_details).await
+        }
+        async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
+            self.inner.show_work().await
+        }
+        fn take_needs_rebuild(&self) -> bool {
+            self.missed.swap(false, Ordering::AcqRel)
+        }
+        fn can_persist_writes(&self) -> bool {
+            self.persist.load(Ordering::Acquire)
+        }
+    }
+
+    async fn wait_for_rebuild(ec: &ErasureBlockIO) {
+        while !ec.rebuilding().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn fragment_ids_are_distinct() {
+        let id = Uuid::new_v4();
+        let ids: Vec<Uuid> =
+            (0..MAX_FRAGMENTS).map(|f| fragment_id(id, f)).collect();
+        for (f, a) in ids.iter().enumerate() {
+            assert_ne!(*a, id);
+            assert_eq!(a.get_version_num(), 4);
+            assert_eq!(*a, fragment_id(id, f));
+            assert!(ids[f + 1..].iter().all(|b| b != a));
+        }
+    }
+
+    #[test]
+    fn reed_solomon_any_k_of_n() {
+        let rs = ReedSolomon::new(4, 2).unwrap();
+        let mut rng = rand::thread_rng();
+        let data: Vec<Vec<u8>> = (0..4)
+            .map(|_| (0..64).map(|_| rng.gen()).collect())
+            .collect();
+        let parity =
+            rs.encode(&data.iter().map(|d| &d[..]).collect::<Vec<_>>());
+        let all: Vec<Vec<u8>> = data.into_iter().chain(parity).collect();
+
+        // Drop every pair of shards, and make sure we get them back
+        for a in 0..6 {
+            for b in a + 1..6 {
+                let mut shards: Vec<Option<Vec<u8>>> =
+                    all.iter().cloned().map(Some).collect();
+                shards[a] = None;
+                shards[b] = None;
+                rs.reconstruct(&mut shards).unwrap();
+                for (s, orig) in shards.iter().zip(&all) {
+                    assert_eq!(s.as_ref().unwrap(), orig);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reed_solomon_too_many_missing() {
+        let rs = ReedSolomon::new(4, 2).unwrap();
+        let mut shards = vec![Some(vec![0u8; 8]); 6];
+        shards[0] = None;
+        shards[3] = None;
+        shards[5] = None;
+        assert!(rs.reconstruct(&mut shards).is_err());
+    }
+
+    #[test]
+    fn reed_solomon_bad_layout() {
+        assert!(ReedSolomon::new(0, 2).is_err());
+        assert!(ReedSolomon::new(4, 0).is_err());
+        assert!(ReedSolomon::new(MAX_FRAGMENTS, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn erasure_write_read_unaligned() -> Result<()> {
+        let frags = fragments(6, 16);
+        let ec = erasure(4, 2, &frags, 16);
+        assert_eq!(ec.total_size().await?, 64 * BS as u64);
+
+        // Write a range which starts and ends in the middle of a stripe
+        let data: Vec<u8> = (0..10 * BS).map(|i| (i / BS) as u8 + 1).collect();
+        ec.write(BlockIndex(3), BytesMut::from(&data[..])).await?;
+
+        let mut buffer = Buffer::new(16, BS);
+        ec.read(BlockIndex(0), &mut buffer).await?;
+        for b in 0..16 {
+            let expected = if (3..13).contains(&b) { b as u8 - 2 } else { 0 };
+            assert!(buffer.block(b).iter().all(|v| *v == expected));
+            assert_eq!(buffer.owned_ref()[b] != 0, (3..13).contains(&b));
+        }
+
+        // Block 13 lives in column 1 of stripe 3
+        let mut buffer = Buffer::new(4, BS);
+        frags[1].read(BlockIndex(3), &mut buffer).await?;
+        assert_eq!(buffer.owned_ref(), &[0, 0, 0, 0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_read_degraded() -> Result<()> {
+        let frags = fragments(6, 16);
+        let ec = erasure(4, 2, &frags, 16);
+        let data: Vec<u8> = (0..64 * BS).map(|i| (i % 251) as u8).collect();
+        ec.write(BlockIndex(0), BytesMut::from(&data[..])).await?;
+
+        // Replace two data fragments with ones which fail every IO
+        let mut broken: Vec<Arc<dyn BlockIO + Send + Sync>> = frags
+            .iter()
+            .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+            .collect();
+        broken[0] = Arc::new(FailedBlockIO);
+        broken[2] = Arc::new(FailedBlockIO);
+        let ec = erasure_of(4, 2, broken, 16);
+
+        let mut buffer = Buffer::new(64, BS);
+        ec.read(BlockIndex(0), &mut buffer).await?;
+        assert_eq!(&buffer[..], &data[..]);
+        assert!(buffer.owned_ref().iter().all(|o| *o != 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_write_degraded() -> Result<()> {
+        let frags = fragments(6, 16);
+        let mut broken: Vec<Arc<dyn BlockIO + Send + Sync>> = frags
+            .iter()
+            .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+            .collect();
+        broken[1] = Arc::new(FailedBlockIO);
+        broken[4] = Arc::new(FailedBlockIO);
+        let ec = erasure_of(4, 2, broken.clone(), 16);
+
+        let data: Vec<u8> = (0..5 * BS).map(|i| (i % 241) as u8).collect();
+        ec.write(BlockIndex(2), BytesMut::from(&data[..])).await?;
+        ec.flush(None).await?;
+
+        let mut buffer = Buffer::new(5, BS);
+        ec.read(BlockIndex(2), &mut buffer).await?;
+        assert_eq!(&buffer[..], &data[..]);
+
+        // A third failure is too many
+        broken[0] = Arc::new(FailedBlockIO);
+        let ec = erasure_of(4, 2, broken, 16);
+        assert!(ec
+            .write(BlockIndex(0), BytesMut::from(&data[..]))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_write_unwritten() -> Result<()> {
+        let frags = fragments(3, 8);
+        let ec = erasure(2, 1, &frags, 8);
+
+        ec.write(BlockIndex(1), BytesMut::from(&[1u8; BS][..]))
+            .await?;
+        ec.write_unwritten(BlockIndex(0), BytesMut::from(&[2u8; 3 * BS][..]))
+            .await?;
+
+        let mut buffer = Buffer::new(3, BS);
+        ec.read(BlockIndex(0), &mut buffer).await?;
+        assert!(buffer.block(0).iter().all(|v| *v == 2));
+        assert!(buffer.block(1).iter().all(|v| *v == 1));
+        assert!(buffer.block(2).iter().all(|v| *v == 2));
+
+        // Parity must match what's on the data fragments
+        let mut buffer = Buffer::new(1, BS);
+        frags[0].read(BlockIndex(1), &mut buffer).await?;
+        let mut parity = Buffer::new(1, BS);
+        frags[2].read(BlockIndex(1), &mut parity).await?;
+        let expected =
+            ReedSolomon::new(2, 1)?.encode(&[&buffer[..], &[0u8; BS][..]]);
+        assert_eq!(&parity[..], &expected[0][..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_rebuild() -> Result<()> {
+        let frags = fragments(6, 16);
+        let ec = erasure(4, 2, &frags, 16);
+        let data: Vec<u8> = (0..20 * BS).map(|i| (i % 239) as u8).collect();
+        ec.write(BlockIndex(8), BytesMut::from(&data[..])).await?;
+
+        // Rebuild a data fragment and a parity fragment onto blank ones
+        for f in [2, 5] {
+            let blank = fragments(1, 16).pop().unwrap();
+            let mut with_blank: Vec<Arc<dyn BlockIO + Send + Sync>> = frags
+                .iter()
+                .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+                .collect();
+            with_blank[f] = blank.clone();
+            let rebuilt = erasure_of(4, 2, with_blank, 16);
+            rebuilt.inner.rebuilding[f].store(true, Ordering::Release);
+            assert!(rebuilt.inner.rebuild(f).await?);
+
+            let mut expected = Buffer::new(16, BS);
+            frags[f].read(BlockIndex(0), &mut expected).await?;
+            let mut actual = Buffer::new(16, BS);
+            blank.read(BlockIndex(0), &mut actual).await?;
+            assert_eq!(actual, expected);
+        }
+
+        // The original set still reads back correctly
+        let mut buffer = Buffer::new(20, BS);
+        ec.read(BlockIndex(8), &mut buffer).await?;
+        assert_eq!(&buffer[..], &data[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_returning_fragment_is_rebuilt() -> Result<()> {
+        let frags = fragments(6, 16);
+        let returning = ReturningBlockIO::new(frags[1].clone());
+        let mut set: Vec<Arc<dyn BlockIO + Send + Sync>> = frags
+            .iter()
+            .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+            .collect();
+        set[1] = returning.clone();
+        let ec = erasure_of(4, 2, set, 16);
+
+        let data: Vec<u8> = (0..64 * BS).map(|i| (i % 233) as u8).collect();
+        ec.write(BlockIndex(0), BytesMut::from(&data[..])).await?;
+
+        // Fragment 1 comes back having missed a write, so what it holds is
+        // stale; reads must not use it.
+        frags[1]
+            .write(BlockIndex(0), BytesMut::from(&[0xff; 16 * BS][..]))
+            .await?;
+        returning.missed.store(true, Ordering::Release);
+
+        let mut buffer = Buffer::new(64, BS);
+        ec.read(BlockIndex(0), &mut buffer).await?;
+        assert_eq!(&buffer[..], &data[..]);
+
+        // Once it's rebuilt, it holds the right data again
+        wait_for_rebuild(&ec).await;
+        let mut buffer = Buffer::new(16, BS);
+        frags[1].read(BlockIndex(0), &mut buffer).await?;
+        for s in 0..16 {
+            let b = s * 4 + 1;
+            assert_eq!(buffer.block(s), &data[b * BS..(b + 1) * BS]);
+        }
+        Ok(())
+    }
+
+    /// Returns the fragments as `BlockIO`s, with fragment `f` replaced
+    fn replacing(
+        frags: &[Arc<InMemoryBlockIO>],
+        f: usize,
+        with: Arc<dyn BlockIO + Send + Sync>,
+    ) -> Vec<Arc<dyn BlockIO + Send + Sync>> {
+        let mut set: Vec<Arc<dyn BlockIO + Send + Sync>> = frags
+            .iter()
+            .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+            .collect();
+        set[f] = with;
+        set
+    }
+
+    async fn wait_for_scrub(ec: &ErasureBlockIO) {
+        while ec.inner.scrub_pending.load(Ordering::Acquire) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Checks that the parity for the first `stripes` stripes of a 2+1 set
+    /// matches its data
+    async fn check_parity(
+        frags: &[Arc<InMemoryBlockIO>],
+        stripes: usize,
+    ) -> Result<bool> {
+        let mut d0 = Buffer::new(stripes, BS);
+        frags[0].read(BlockIndex(0), &mut d0).await?;
+        let mut d1 = Buffer::new(stripes, BS);
+        frags[1].read(BlockIndex(0), &mut d1).await?;
+        let mut parity = Buffer::new(stripes, BS);
+        frags[2].read(BlockIndex(0), &mut parity).await?;
+        let expected = ReedSolomon::new(2, 1)?.encode(&[&d0[..], &d1[..]]);
+        Ok(parity[..] == expected[0][..])
+    }
+
+    #[test]
+    fn marker_round_trip() {
+        let marker = Marker {
+            dirty: true,
+            rebuild: 0b100101,
+        };
+        let data = marker.encode(BS);
+        assert_eq!(Marker::decode(&data), Some(marker));
+        assert_eq!(Marker::decode(&[0u8; BS]), None);
+    }
+
+    #[tokio::test]
+    async fn erasure_dirty_activation_scrubs_parity() -> Result<()> {
+        let frags = fragments(3, 8);
+        let parity = ReturningBlockIO::new(frags[2].clone());
+        let ec = erasure_of(2, 1, replacing(&frags, 2, parity.clone()), 8);
+        ec.activate().await?;
+        ec.write(BlockIndex(0), BytesMut::from(&[1u8; 4 * BS][..]))
+            .await?;
+
+        // A crash after writing the data fragment but not the parity
+        frags[0]
+            .write(BlockIndex(1), BytesMut::from(&[2u8; BS][..]))
+            .await?;
+        parity.writes.lock().unwrap().clear();
+
+        // Every fragment stays in use while parity is checked, and only the
+        // torn stripe is rewritten.
+        let ec = erasure_of(2, 1, replacing(&frags, 2, parity.clone()), 8);
+        ec.activate().await?;
+        assert!(ec.rebuilding().is_empty());
+        wait_for_scrub(&ec).await;
+        assert_eq!(parity.written(8), [1]);
+        assert!(check_parity(&frags, 8).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_clean_activation_skips_scrub() -> Result<()> {
+        let frags = fragments(3, 8);
+        let ec = erasure(2, 1, &frags, 8);
+        ec.activate().await?;
+        ec.write(BlockIndex(0), BytesMut::from(&[1u8; 4 * BS][..]))
+            .await?;
+        ec.deactivate().await?;
+
+        // Nothing was torn, so the next activation doesn't look (and doesn't
+        // notice this change behind its back).
+        frags[0]
+            .write(BlockIndex(1), BytesMut::from(&[2u8; BS][..]))
+            .await?;
+        let ec = erasure(2, 1, &frags, 8);
+        ec.activate().await?;
+        assert!(!ec.inner.scrub_pending.load(Ordering::Acquire));
+        assert!(!ec.inner.scrub_task.load(Ordering::Acquire));
+        assert!(!check_parity(&frags, 8).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_offline_fragment_is_rebuilt() -> Result<()> {
+        let frags = fragments(6, 16);
+        let offline = ReturningBlockIO::new(frags[1].clone());
+        let ec = erasure_of(4, 2, replacing(&frags, 1, offline.clone()), 16);
+        ec.activate().await?;
+
+        // With its downstairs away, a fragment's Upstairs would only hold
+        // writes in memory, so it isn't written at all.
+        offline.persist.store(false, Ordering::Release);
+        let data: Vec<u8> = (0..64 * BS).map(|i| (i % 229) as u8).collect();
+        ec.write(BlockIndex(0), BytesMut::from(&data[..])).await?;
+        assert!(offline.written(16).is_empty());
+        assert_eq!(ec.rebuilding(), [1]);
+
+        let mut buffer = Buffer::new(64, BS);
+        ec.read(BlockIndex(0), &mut buffer).await?;
+        assert_eq!(&buffer[..], &data[..]);
+
+        // It can't be rebuilt before we're deactivated, but the next
+        // activation picks that up from the marker.
+        ec.deactivate().await?;
+        let ec = erasure(4, 2, &frags, 16);
+        ec.activate().await?;
+        assert_eq!(ec.rebuilding(), [1]);
+        assert!(!ec.inner.scrub_pending.load(Ordering::Acquire));
+
+        wait_for_rebuild(&ec).await;
+        let mut buffer = Buffer::new(16, BS);
+        frags[1].read(BlockIndex(0), &mut buffer).await?;
+        for s in 0..16 {
+            let b = s * 4 + 1;
+            assert_eq!(buffer.block(s), &data[b * BS..(b + 1) * BS]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_deactivate_tolerates_parity_failures() -> Result<()> {
+        let frags = fragments(6, 16);
+        let mut set = replacing(&frags, 0, Arc::new(FailedBlockIO));
+        set[5] = Arc::new(FailedBlockIO);
+        let ec = erasure_of(4, 2, set.clone(), 16);
+        ec.deactivate().await?;
+        assert_eq!(ec.rebuilding(), [0, 5]);
+
+        // A third failure is too many
+        set[3] = Arc::new(FailedBlockIO);
+        let ec = erasure_of(4, 2, set, 16);
+        assert!(ec.deactivate().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn erasure_writes_lock_only_their_stripes() -> Result<()> {
+        let frags = fragments(3, 8);
+        let ec = erasure(2, 1, &frags, 8);
+        let block = || BytesMut::from(&[1u8; BS][..]);
+        let guards = ec.inner.lock_stripes(&(0..1)).await;
+
+        // Stripe 1 holds blocks 2 and 3
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            ec.write(BlockIndex(2), block()),
+        )
+        .await??;
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(100),
+            ec.write(BlockIndex(0), block()),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        drop(guards);
+        ec.write(BlockIndex(0), block()).await?;
+        Ok(())
+    }
+}
@@ -3,10 +3,10 @@ use std::{
     collections::HashMap,
     net::SocketAddr,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
@@ -62,6 +62,24 @@ pub(crate) enum GuestBlockRes {
     Other(BlockRes),
 }
 
+impl GuestBlockRes {
+    /// Returns when the guest made the request
+    fn created(&self) -> Instant {
+        match self {
+            GuestBlockRes::Read(_, res) => res.created(),
+            GuestBlockRes::Other(res) => res.created(),
+        }
+    }
+
+    /// Returns the span of the guest IO
+    fn span(&self) -> &tracing::Span {
+        match self {
+            GuestBlockRes::Read(_, res) => res.span(),
+            GuestBlockRes::Other(res) => res.span(),
+        }
+    }
+}
+
 impl GtoS {
     /// Create a new GtoS object where one Guest IO request maps to one
     /// downstairs operation.
@@ -168,13 +186,19 @@ impl GuestWork {
     }
 
     /// Helper function to install new work into the map
+    ///
+    /// `f` is called within the span of the guest IO (if any), so that spans
+    /// for the downstairs jobs that it creates are children of that span.
     pub(crate) fn submit_job<F: FnOnce(GuestWorkId) -> JobId>(
         &mut self,
         f: F,
         res: Option<GuestBlockRes>,
     ) -> (GuestWorkId, JobId) {
         let gw_id = self.next_gw_id();
-        let ds_id = f(gw_id);
+        let ds_id = match &res {
+            Some(res) => res.span().in_scope(|| f(gw_id)),
+            None => f(gw_id),
+        };
 
         self.active.insert(gw_id, GtoS::new(ds_id, res));
         (gw_id, ds_id)
@@ -214,6 +238,9 @@ impl GuestWork {
      * arrived from all the downstairs jobs we created, then we
      * can move forward with finishing up the guest work operation.
      * This may include moving data buffers from completed reads.
+     *
+     * Returns when the guest made the request, or `None` if this work was
+     * on behalf of the Upstairs.
      */
     #[instrument]
     pub(crate) fn gw_ds_complete(
@@ -222,9 +249,10 @@ impl GuestWork {
         ds_id: JobId,
         data: Option<RawReadResponse>,
         result: Result<(), CrucibleError>,
-    ) {
+    ) -> Option<Instant> {
         if let Some(gtos_job) = self.active.remove(&gw_id) {
             assert_eq!(gtos_job.ds_id, ds_id);
+            let created = gtos_job.res.as_ref().map(GuestBlockRes::created);
 
             /*
              * Copy (if present) read data back to the guest buffer they
@@ -233,6 +261,7 @@ impl GuestWork {
             gtos_job.transfer_and_notify(data, result);
 
             self.completed.push(gw_id);
+            created
         } else {
             /*
              * XXX This is just so I can see if ever does happen.
@@ -293,6 +322,14 @@ pub struct Guest {
     /// different tasks).
     backpressure_lock: Mutex<()>,
 
+    /// Set by the IO task when our lone downstairs has come back without
+    /// being live-repaired (shared with the `GuestIoHandle`)
+    needs_rebuild: Arc<AtomicBool>,
+
+    /// Set by the IO task while enough downstairs are active that acked
+    /// writes reach persistent storage (shared with the `GuestIoHandle`)
+    can_persist: Arc<AtomicBool>,
+
     /// Logger for the guest
     log: Logger,
 }
@@ -385,6 +422,8 @@ impl Guest {
         let (req_tx, req_rx) = mpsc::channel(500);
 
         let backpressure_us = Arc::new(AtomicU64::new(0));
+        let needs_rebuild = Arc::new(AtomicBool::new(false));
+        let can_persist = Arc::new(AtomicBool::new(false));
         let limits = GuestLimits {
             iop_limit: None,
             bw_limit: None,
@@ -405,6 +444,8 @@ impl Guest {
             bw_tokens: 0,
             backpressure_us: backpressure_us.clone(),
             backpressure_config: Self::default_backpressure_config(),
+            needs_rebuild: needs_rebuild.clone(),
+            can_persist: can_persist.clone(),
             log: log.clone(),
         };
         let guest = Guest {
@@ -414,6 +455,8 @@ impl Guest {
 
             backpressure_us,
             backpressure_lock: Mutex::new(()),
+            needs_rebuild,
+            can_persist,
             log,
         };
         (guest, io)
@@ -572,6 +615,11 @@ impl BlockIO for Guest {
             .await
     }
 
+    #[instrument(
+        name = "guest_read",
+        skip_all,
+        fields(offset = offset.0, bytes = data.len())
+    )]
     async fn read(
         &self,
         mut offset: BlockIndex,
@@ -645,6 +693,11 @@ impl BlockIO for Guest {
         Ok(())
     }
 
+    #[instrument(
+        name = "guest_write",
+        skip_all,
+        fields(offset = offset.0, bytes = data.len())
+    )]
     async fn write(
         &self,
         mut offset: BlockIndex,
@@ -683,6 +736,11 @@ impl BlockIO for Guest {
         Ok(())
     }
 
+    #[instrument(
+        name = "guest_write_unwritten",
+        skip_all,
+        fields(offset = offset.0, bytes = data.len())
+    )]
     async fn write_unwritten(
         &self,
         offset: BlockIndex,
@@ -703,6 +761,7 @@ impl BlockIO for Guest {
         .await
     }
 
+    #[instrument(name = "guest_flush", skip_all)]
     async fn flush(
         &self,
         snapshot_details: Option<SnapshotDetails>,
@@ -734,6 +793,14 @@ impl BlockIO for Guest {
         })
         .await
     }
+
+    fn take_needs_rebuild(&self) -> bool {
+        self.needs_rebuild.swap(false, Ordering::AcqRel)
+    }
+
+    fn can_persist_writes(&self) -> bool {
+        self.can_persist.load(Ordering::Acquire)
+    }
 }
 
 /// Configuration for iops-per-second limiting
@@ -801,6 +868,12 @@ pub struct GuestIoHandle {
     /// Current backpressure (shared with the `Guest`)
     backpressure_us: Arc<AtomicU64>,
 
+    /// Whether the downstairs missed writes (shared with the `Guest`)
+    needs_rebuild: Arc<AtomicBool>,
+
+    /// Whether acked writes reach enough downstairs (shared with the `Guest`)
+    can_persist: Arc<AtomicBool>,
+
     /// Backpressure configuration, as a starting point and max delay
     backpressure_config: BackpressureConfig,
 
@@ -981,6 +1054,19 @@ impl GuestIoHandle {
         self.backpressure_us.load(Ordering::Acquire)
     }
 
+    /// Tells the `Guest` that our lone downstairs may have missed writes
+    ///
+    /// This must be called before the downstairs is made active again, so
+    /// that anything read from it afterwards is known to be suspect.
+    pub fn set_needs_rebuild(&self) {
+        self.needs_rebuild.store(true, Ordering::Release);
+    }
+
+    /// Tells the `Guest` whether writes acked now will be persisted
+    pub fn set_can_persist(&self, can_persist: bool) {
+        self.can_persist.store(can_persist, Ordering::Release);
+    }
+
     /// Debug function to dump the guest work structure.
     ///
     /// TODO: make this one big dump, where we include the up.work.active
@@ -11,10 +11,11 @@ use std::io::{Read as _, Result as IOResult, Seek, SeekFrom, Write as _};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub use crucible_client_types::{
-    CrucibleOpts, ReplaceResult, VolumeConstructionRequest,
+    ClientTimeoutOpts, ControlToken, CrucibleOpts, ReplaceResult, ReplicaOpts,
+    VolumeConstructionRequest,
 };
 pub use crucible_common::*;
 pub use crucible_protocol::*;
@@ -47,6 +48,9 @@ pub use volume::Volume;
 pub mod in_memory;
 pub use in_memory::InMemoryBlockIO;
 
+pub mod erasure;
+pub use erasure::ErasureBlockIO;
+
 pub mod block_io;
 pub use block_io::{FileBlockIO, ReqwestBlockIO};
 
@@ -82,6 +86,7 @@ use async_trait::async_trait;
 
 mod client;
 mod downstairs;
+mod replica;
 mod upstairs;
 use upstairs::{UpCounters, UpstairsAction};
 
@@ -89,12 +94,16 @@ use upstairs::{UpCounters, UpstairsAction};
 ///
 /// If we exceed this value, the upstairs will give
 /// up and mark the offline downstairs as faulted.
+///
+/// This is the default, which may be overridden by `ClientTimeoutOpts`.
 const IO_OUTSTANDING_MAX_BYTES: u64 = 1024 * 1024 * 1024; // 1 GiB
 
 /// Max number of outstanding IOs between the upstairs and an offline downstairs
 ///
 /// If we exceed this value, the upstairs will give up and mark that offline
 /// downstairs as faulted.
+///
+/// This is the default, which may be overridden by `ClientTimeoutOpts`.
 pub const IO_OUTSTANDING_MAX_JOBS: usize = 10000;
 
 /// The BlockIO trait behaves like a physical NVMe disk (or a virtio virtual
@@ -161,6 +170,28 @@ pub trait BlockIO: Sync {
         panic!("should never hit here!");
     }
 
+    /// Returns `true` once if this has missed writes without reporting an
+    /// error since the last call, so its contents can't be trusted until
+    /// they're rewritten
+    ///
+    /// Only a single-downstairs `Guest` (a fragment of an erasure-coded region
+    /// set) does this: it has no other downstairs to live-repair from when its
+    /// downstairs comes back.
+    fn take_needs_rebuild(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if a write acked now will have reached enough
+    /// downstairs to survive a crash of the Upstairs
+    ///
+    /// A `Guest` acks writes while a downstairs is offline and replays them
+    /// when it comes back; with too few downstairs online, an acked write may
+    /// only exist in the Upstairs' memory.  An erasure-coded region set treats
+    /// such a fragment as failed rather than relying on it.
+    fn can_persist_writes(&self) -> bool {
+        true
+    }
+
     // Common methods for BlockIO
 
     async fn byte_offset_to_block(
@@ -239,7 +270,7 @@ pub type CrucibleBlockIOFuture<'a> = Pin<
 
 /// DTrace probes in the upstairs
 ///
-/// up__status: This tracks the state of each of the three downstairs
+/// up__status: This tracks the state of each of the downstairs
 /// as well as the work queue counts for the upstairs work queue and the
 /// downstairs work queue.
 ///
@@ -385,41 +416,94 @@ mod cdt {
     fn volume__flush__done(_: u32, _: Uuid) {}
 }
 
-/// Array of data associated with three clients, indexed by `ClientId`
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(transparent)]
-pub struct ClientData<T>([T; 3]);
+/// Number of downstairs in a region set, unless configured otherwise
+pub const DEFAULT_REGION_SET_SIZE: usize = 3;
+
+/// Smallest supported region set (a two-way mirror)
+pub const MIN_REGION_SET_SIZE: usize = 2;
+
+/// Returns the number of downstairs which must complete a write or flush
+/// before it is considered durable
+///
+/// This is a strict majority of the region set (two of the default three),
+/// except for a two-way mirror, which only needs one side; otherwise losing
+/// either side would stop all writes.  A side which misses writes is faulted,
+/// either when it returns an error or once it has been away too long, and is
+/// then live-repaired from the other side.  If both sides restart instead,
+/// reconciliation copies from whichever has the newer flush.
+pub(crate) fn write_quorum(n: usize) -> usize {
+    if n == 2 {
+        1
+    } else {
+        n / 2 + 1
+    }
+}
+
+/// Array of data associated with each client in a region set, indexed by
+/// `ClientId`
+///
+/// This is stored inline (one slot per possible client, of which the first
+/// `len` are in use) rather than in a `Vec`, because there's one of these in
+/// every `DownstairsIO`.  It serializes as a list of the slots in use.
+#[derive(Clone)]
+pub struct ClientData<T> {
+    data: [Option<T>; ClientId::MAX_CLIENTS],
+    len: u8,
+}
 
 impl<T> std::ops::Index<ClientId> for ClientData<T> {
     type Output = T;
     fn index(&self, index: ClientId) -> &Self::Output {
-        &self.0[index.get() as usize]
+        self.data[..self.len()][index.get() as usize]
+            .as_ref()
+            .unwrap()
     }
 }
 
 impl<T> std::ops::IndexMut<ClientId> for ClientData<T> {
     fn index_mut(&mut self, index: ClientId) -> &mut Self::Output {
-        &mut self.0[index.get() as usize]
+        let len = self.len();
+        self.data[..len][index.get() as usize].as_mut().unwrap()
     }
 }
 
 impl<T: Clone> ClientData<T> {
+    /// Builds data for a default (three-way) region set
     pub fn new(t: T) -> Self {
-        Self([t.clone(), t.clone(), t])
+        Self::with_len(DEFAULT_REGION_SET_SIZE, t)
+    }
+    /// Builds data for a region set with `n` members
+    pub fn with_len(n: usize, t: T) -> Self {
+        Self::from_fn(n, |_| t.clone())
     }
 }
 impl<T> ClientData<T> {
+    /// Builds data for a region set with `n` members, calling `f` for each
+    pub fn from_fn<F: FnMut(ClientId) -> T>(n: usize, mut f: F) -> Self {
+        assert!(n <= ClientId::MAX_CLIENTS);
+        Self {
+            data: std::array::from_fn(|i| {
+                (i < n).then(|| f(ClientId::new(i as u8)))
+            }),
+            len: n as u8,
+        }
+    }
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.len as usize
     }
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
     pub fn iter(&self) -> impl Iterator<Item = &T> {
-        self.0.iter()
+        self.data[..self.len()].iter().map(|v| v.as_ref().unwrap())
     }
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
-        self.0.iter_mut()
+        let len = self.len();
+        self.data[..len].iter_mut().map(|v| v.as_mut().unwrap())
+    }
+    /// Iterates over the client IDs which have data in this array
+    pub fn ids(&self) -> impl Iterator<Item = ClientId> {
+        ClientId::iter_n(self.len())
     }
     /// Inserts a new value, returning the old value
     pub fn insert(&mut self, c: ClientId, mut v: T) -> T {
@@ -428,14 +512,58 @@ impl<T> ClientData<T> {
     }
 }
 
+impl<T: Debug> Debug for ClientData<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Serialize> Serialize for ClientData<T> {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(self.iter())
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for ClientData<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        d: D,
+    ) -> Result<Self, D::Error> {
+        let v = Vec::<T>::deserialize(d)?;
+        if v.len() > ClientId::MAX_CLIENTS {
+            return Err(serde::de::Error::invalid_length(
+                v.len(),
+                &"no more than ClientId::MAX_CLIENTS elements",
+            ));
+        }
+        let n = v.len();
+        let mut v = v.into_iter();
+        Ok(Self::from_fn(n, |_| v.next().unwrap()))
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for ClientData<T> {
+    fn is_referenceable() -> bool {
+        Vec::<T>::is_referenceable()
+    }
+    fn schema_name() -> String {
+        Vec::<T>::schema_name()
+    }
+    fn json_schema(
+        gen: &mut schemars::gen::SchemaGenerator,
+    ) -> schemars::schema::Schema {
+        Vec::<T>::json_schema(gen)
+    }
+}
+
 /// Map of data associated with clients, keyed by `ClientId`
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(transparent)]
 pub struct ClientMap<T>(ClientData<Option<T>>);
 
 impl<T> ClientMap<T> {
-    fn new() -> Self {
-        Self(ClientData([None, None, None]))
+    /// Builds an empty map for a region set with `n` members
+    fn new(n: usize) -> Self {
+        Self(ClientData::from_fn(n, |_| None))
     }
     /// Inserts a new value, returning the old value (or `None`)
     pub fn insert(&mut self, c: ClientId, v: T) -> Option<T> {
@@ -902,6 +1030,16 @@ impl std::fmt::Display for DsState {
     }
 }
 
+impl DsState {
+    /// Returns the state's name as it's serialized, e.g. `live_repair_ready`
+    pub(crate) fn name(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => s,
+            _ => unreachable!("DsState serializes to a string"),
+        }
+    }
+}
+
 /// Results of validating a single block
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub(crate) enum Validation {
@@ -926,6 +1064,21 @@ struct DownstairsIO {
     /// Map of work status, tracked on a per-client basis
     state: ClientData<IOState>,
 
+    /// When the job was last sent to each client, if it's in progress
+    sent: ClientData<Option<Instant>>,
+
+    /// When the job was created
+    created: Instant,
+
+    /// When a downstairs last replied to the job
+    replied: Option<Instant>,
+
+    /// Tracing span for the job, which lasts until it's retired
+    ///
+    /// This is set by `Downstairs::enqueue`, where it becomes a child of the
+    /// guest IO's span (if any).
+    span: tracing::Span,
+
     /*
      * Has this been acked to the guest yet?
      */
@@ -950,6 +1103,15 @@ struct DownstairsIO {
 }
 
 impl DownstairsIO {
+    /// Builds a span for this job, as a child of the current span
+    fn new_span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "downstairs_job",
+            job_id = self.ds_id.0,
+            op = self.work.job_type(),
+        )
+    }
+
     fn state_count(&self) -> WorkCounts {
         let mut wc: WorkCounts = Default::default();
 
@@ -993,11 +1155,11 @@ impl DownstairsIO {
     pub fn io_summarize(&self) -> WorkSummary {
         let (job_type, num_blocks, deps) = self.work.ioop_summary();
 
-        let mut state = Vec::with_capacity(3);
+        let mut state = Vec::with_capacity(self.state.len());
         /*
          * Convert the possible job states (and handle the None)
          */
-        for cid in ClientId::iter() {
+        for cid in self.state.ids() {
             /*
              * We don't ever expect the job state to return None, but
              * if it does because something else is wrong, I don't want
@@ -1025,16 +1187,19 @@ impl DownstairsIO {
     /// Verify that we have enough valid IO results when considering all
     /// downstairs results before we send back success to the guest.
     ///
-    /// During normal operations, reads can have two failures or skips and still
-    /// return valid data.
+    /// During normal operations, reads can have failures or skips on all but
+    /// one downstairs (two, in a three-way region set) and still return valid
+    /// data.
     ///
     /// Writes are acked to the host right away, before hearing back from the
     /// Downstairs (the so-called "fast ack" optimization), so this function is
     /// never called for them.
     ///
-    /// During normal operations, write_unwritten and flush can have one error
-    /// or skip and still return success to the upstairs (though, the downstairs
-    /// normally will not return error to the upstairs on W/F).
+    /// During normal operations, write_unwritten and flush can have errors or
+    /// skips as long as a majority of the region set succeeded (i.e. one error
+    /// in a three-way region set) and still return success to the upstairs
+    /// (though, the downstairs normally will not return error to the upstairs
+    /// on W/F).
     ///
     /// For repair, we don't permit any errors, but do allow and handle the
     /// "skipped" case for IOs.  This allows us to recover if we are repairing a
@@ -1045,21 +1210,28 @@ impl DownstairsIO {
          */
         let wc = self.state_count();
 
+        // Number of downstairs which may fail while still leaving a quorum
+        let n = self.state.len();
+        let spare = (n - write_quorum(n)) as u64;
+
         let bad_job = match &self.work {
-            IOop::Read { .. } => wc.error == 3,
+            IOop::Read { .. } => wc.error == n as u64,
             IOop::Write { .. }
             | IOop::WriteUnwritten { .. }
-            | IOop::Flush { .. } => wc.skipped + wc.error > 1,
+            | IOop::Flush { .. } => wc.skipped + wc.error > spare,
             IOop::ExtentFlushClose { .. }
             | IOop::ExtentLiveRepair { .. }
             | IOop::ExtentLiveReopen { .. }
-            | IOop::ExtentLiveNoOp { .. } => wc.error >= 1 || wc.skipped > 1,
+            | IOop::ExtentLiveNoOp { .. } => {
+                wc.error >= 1 || wc.skipped > spare
+            }
         };
 
         if bad_job {
             Err(CrucibleError::IoError(format!(
-                "{} out of 3 downstairs failed to complete this IO",
+                "{} out of {} downstairs failed to complete this IO",
                 wc.error + wc.skipped,
+                n,
             )))
         } else {
             Ok(())
@@ -1089,11 +1261,22 @@ struct ReconcileIO {
 }
 
 impl ReconcileIO {
+    /// Builds a reconciliation job for a default (three-way) region set
+    #[cfg(test)]
     fn new(id: ReconciliationId, op: Message) -> ReconcileIO {
+        Self::with_clients(DEFAULT_REGION_SET_SIZE, id, op)
+    }
+
+    /// Builds a reconciliation job for a region set with `n` members
+    fn with_clients(
+        n: usize,
+        id: ReconciliationId,
+        op: Message,
+    ) -> ReconcileIO {
         ReconcileIO {
             id,
             op,
-            state: ClientData::new(IOState::New),
+            state: ClientData::with_len(n, IOState::New),
         }
     }
 }
@@ -1188,6 +1371,20 @@ impl IOop {
         }
     }
 
+    /// Names the type of job, for per-job-type stats
+    fn job_type(&self) -> &'static str {
+        match self {
+            IOop::Read { .. } => "read",
+            IOop::Write { .. } => "write",
+            IOop::WriteUnwritten { .. } => "write_unwritten",
+            IOop::Flush { .. } => "flush",
+            IOop::ExtentFlushClose { .. } => "extent_flush_close",
+            IOop::ExtentLiveRepair { .. } => "extent_live_repair",
+            IOop::ExtentLiveReopen { .. } => "extent_live_reopen",
+            IOop::ExtentLiveNoOp { .. } => "extent_live_noop",
+        }
+    }
+
     /*
      * Report if the IOop is one used during LiveRepair
      */
@@ -1371,7 +1568,7 @@ impl fmt::Display for IOState {
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ClientIOStateCount {
     pub new: u32,
     pub in_progress: u32,
@@ -1410,7 +1607,7 @@ impl ClientIOStateCount {
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IOStateCount {
     pub new: ClientData<u32>,
     pub in_progress: ClientData<u32>,
@@ -1421,7 +1618,11 @@ pub struct IOStateCount {
 
 impl IOStateCount {
     fn show_all(&self) {
-        println!("   STATES      DS:0   DS:1   DS:2   TOTAL");
+        print!("   STATES      ");
+        for cid in self.new.ids() {
+            print!("DS:{}   ", cid);
+        }
+        println!("TOTAL");
         self.show(IOState::New);
         self.show(IOState::InProgress);
         self.show(IOState::Done);
@@ -1460,7 +1661,7 @@ impl IOStateCount {
             }
         }
         let mut sum = 0;
-        for cid in ClientId::iter() {
+        for cid in state_stat.ids() {
             print!("{:4}   ", state_stat[cid]);
             sum += state_stat[cid];
         }
@@ -1675,7 +1876,7 @@ pub struct Arg {
     /// Number of write bytes in flight
     pub write_bytes_out: u64,
     /// State of a downstairs
-    pub ds_state: [DsState; 3],
+    pub ds_state: Vec<DsState>,
     /// Counters for each state of a downstairs job.
     pub ds_io_count: IOStateCount,
     /// Extents repaired during initial reconciliation.
@@ -1685,23 +1886,23 @@ pub struct Arg {
     /// Times we failed the initial reconcile.
     pub ds_reconcile_aborted: usize,
     /// Times we have completed a LiveRepair on a downstairs.
-    pub ds_live_repair_completed: [usize; 3],
+    pub ds_live_repair_completed: Vec<usize>,
     /// Times we aborted a LiveRepair on a downstairs.
-    pub ds_live_repair_aborted: [usize; 3],
+    pub ds_live_repair_aborted: Vec<usize>,
     /// Times the upstairs has connected to a downstairs.
-    pub ds_connected: [usize; 3],
+    pub ds_connected: Vec<usize>,
     /// Times this downstairs has been replaced.
-    pub ds_replaced: [usize; 3],
+    pub ds_replaced: Vec<usize>,
     /// Times we have live repaired an extent on this downstairs.
-    pub ds_extents_repaired: [usize; 3],
+    pub ds_extents_repaired: Vec<usize>,
     /// Times we have live confirmed  an extent on this downstairs.
-    pub ds_extents_confirmed: [usize; 3],
+    pub ds_extents_confirmed: Vec<usize>,
     /// If in Live Repair, the current extent we are repairing.
     pub ds_extent_limit: usize,
     /// Per-client delay to keep them roughly in sync
-    pub ds_delay_us: [usize; 3],
+    pub ds_delay_us: Vec<usize>,
     /// Times we skipped repairing a downstairs because we are read_only.
-    pub ds_ro_lr_skipped: [usize; 3],
+    pub ds_ro_lr_skipped: Vec<usize>,
 }
 
 /*
@@ -1747,6 +1948,8 @@ pub fn up_main(
         None
     };
 
+    client::ClientTimeouts::new(opt.client_timeouts.as_ref()).validate()?;
+
     #[cfg(test)]
     let disable_backpressure = guest.is_queue_backpressure_disabled();
 
@@ -1771,7 +1974,8 @@ pub fn up_main(
 
     if let Some(control) = opt.control {
         let log = up.log.new(o!("task" => "control".to_string()));
-        let up = crate::control::UpstairsInfo::new(&up);
+        let up =
+            crate::control::UpstairsInfo::new(&up, opt.control_token.clone());
         tokio::spawn(async move {
             let r = control::start(up, log.clone(), control).await;
             info!(log, "Control HTTP task finished with {:?}", r);
@@ -39,14 +39,27 @@ pub struct DownstairsMend {
 
 impl DownstairsMend {
     /*
-     * Use the data provided from each downstairs to build a list of extents
-     * that need repair.
+     * Use the data provided from each downstairs in a three-way region set to
+     * build a list of extents that need repair.
      */
+    #[cfg(test)]
     pub fn new(
         c0: &RegionMetadata,
         c1: &RegionMetadata,
         c2: &RegionMetadata,
         log: Logger,
+    ) -> Option<DownstairsMend> {
+        let meta = ClientData::from_fn(3, |i| [c0, c1, c2][i.get() as usize]);
+        Self::from_regions(&meta, log)
+    }
+
+    /*
+     * Use the data provided from each downstairs to build a list of extents
+     * that need repair.
+     */
+    pub fn from_regions(
+        meta: &ClientData<&RegionMetadata>,
+        log: Logger,
     ) -> Option<DownstairsMend> {
         let mut dsm = DownstairsMend {
             mend: HashMap::new(),
@@ -56,42 +69,40 @@ impl DownstairsMend {
          * Sanity check that all fields of the RegionMetadata struct have the
          * same length.  Pick one vec as the standard and compare.
          */
+        let c0 = meta[ClientId::new(0)];
         let match_len = c0.generation.len();
 
-        if match_len != c1.generation.len() || match_len != c2.generation.len()
-        {
+        if meta.iter().any(|c| c.generation.len() != match_len) {
             panic!(
-                "Downstairs: vec len mismatch for generation: {} {} {}",
-                c0.generation.len(),
-                c1.generation.len(),
-                c2.generation.len()
+                "Downstairs: vec len mismatch for generation: {:?}",
+                meta.iter().map(|c| c.generation.len()).collect::<Vec<_>>(),
             );
         }
 
-        if match_len != c0.flush_numbers.len()
-            || match_len != c1.flush_numbers.len()
-            || match_len != c2.flush_numbers.len()
-        {
+        if meta.iter().any(|c| c.flush_numbers.len() != match_len) {
             panic!(
-                "Downstairs: vec len mismatch for flush_number: {} {} {}",
-                c0.flush_numbers.len(),
-                c1.flush_numbers.len(),
-                c2.flush_numbers.len()
+                "Downstairs: vec len mismatch for flush_number: {:?}",
+                meta.iter()
+                    .map(|c| c.flush_numbers.len())
+                    .collect::<Vec<_>>(),
             );
         }
 
-        if match_len != c0.dirty.len()
-            || match_len != c1.dirty.len()
-            || match_len != c2.dirty.len()
-        {
+        if meta.iter().any(|c| c.dirty.len() != match_len) {
             panic!(
-                "Downstairs: vec len mismatch for dirty: {} {} {}",
-                c0.dirty.len(),
-                c1.dirty.len(),
-                c2.dirty.len()
+                "Downstairs: vec len mismatch for dirty: {:?}",
+                meta.iter().map(|c| c.dirty.len()).collect::<Vec<_>>(),
             );
         }
 
+        /*
+         * A lone region (e.g. one fragment of an erasure-coded region set)
+         * has nothing to be reconciled against.
+         */
+        if meta.len() == 1 {
+            return None;
+        }
+
         /*
          * As we walk the extents in our RegionMetadata vec, keep track
          * of which extents we did not find a dirty bit set so we can
@@ -103,13 +114,13 @@ impl DownstairsMend {
         /*
          * Any dirty bit set means that extent needs repair.
          * Pick our source extent, and from that we decide which of the other
-         * two extents also need repair.
+         * extents also need repair.
          */
-        for (i, dirty0) in c0.dirty.iter().enumerate() {
-            if *dirty0 || c1.dirty[i] || c2.dirty[i] {
+        for i in 0..match_len {
+            if meta.iter().any(|c| c.dirty[i]) {
                 info!(log, "Extents {} dirty", i);
                 let log_mrl = log.new(o!("mrl" => "dirty".to_string()));
-                let ef = make_repair_list(i, c0, c1, c2, log_mrl);
+                let ef = make_repair_list(i, meta, log_mrl);
                 dsm.mend.insert(ExtentId(i as u32), ef);
             } else {
                 to_check.push(i);
@@ -123,13 +134,14 @@ impl DownstairsMend {
          */
         let mut second_check = Vec::new();
         for i in to_check.iter() {
-            if c0.flush_numbers[*i] != c1.flush_numbers[*i]
-                || c1.flush_numbers[*i] != c2.flush_numbers[*i]
+            if meta
+                .iter()
+                .any(|c| c.flush_numbers[*i] != c0.flush_numbers[*i])
             {
                 info!(log, "Extent {} has flush number mismatch", i);
                 let log_mrl =
                     log.new(o!("mrl" => "flush_mismatch".to_string()));
-                let ef = make_repair_list(*i, c0, c1, c2, log_mrl);
+                let ef = make_repair_list(*i, meta, log_mrl);
                 dsm.mend.insert(ExtentId(*i as u32), ef);
             } else {
                 second_check.push(*i);
@@ -141,12 +153,10 @@ impl DownstairsMend {
          * any that are not all the same.
          */
         for i in second_check.iter() {
-            if c0.generation[*i] != c1.generation[*i]
-                || c1.generation[*i] != c2.generation[*i]
-            {
+            if meta.iter().any(|c| c.generation[*i] != c0.generation[*i]) {
                 info!(log, "generation number mismatch {}", i);
                 let log_mrl = log.new(o!("mrl" => "gen_mismatch".to_string()));
-                let ef = make_repair_list(*i, c0, c1, c2, log_mrl);
+                let ef = make_repair_list(*i, meta, log_mrl);
                 dsm.mend.insert(ExtentId(*i as u32), ef);
             }
         }
@@ -166,20 +176,18 @@ impl DownstairsMend {
  */
 fn make_repair_list(
     i: usize,
-    c0: &RegionMetadata,
-    c1: &RegionMetadata,
-    c2: &RegionMetadata,
+    meta: &ClientData<&RegionMetadata>,
     log: Logger,
 ) -> ExtentFix {
-    let source = find_source(i, c0, c1, c2, &log);
-    let dest = find_dest(i, source, c0, c1, c2, &log);
+    let source = find_source(i, meta, &log);
+    let dest = find_dest(i, source, meta, &log);
 
     ExtentFix { source, dest }
 }
 
 /*
  * Given the index of an extent that has a mismatch, return the client ID
- * (0, 1, or 2) for the extent that should be the source of the repair
+ * for the extent that should be the source of the repair
  * This requires that you have a mismatch at the provided extent, or the
  * dirty bit is set somewhere.
  *
@@ -194,72 +202,41 @@ fn make_repair_list(
  */
 fn find_source(
     i: usize,
-    c0: &RegionMetadata,
-    c1: &RegionMetadata,
-    c2: &RegionMetadata,
+    meta: &ClientData<&RegionMetadata>,
     log: &Logger,
 ) -> ClientId {
-    /*
-     * All three client IDs are candidates for the max generation number,
-     * remove a client ID as we find it has a lower value than the others.
-     */
-    let mut max_gen =
-        vec![ClientId::new(0), ClientId::new(1), ClientId::new(2)];
-
     info!(log, "First source client ID for extent {}", i);
 
-    let gen0 = c0.generation[i];
-    let gen1 = c1.generation[i];
-    let gen2 = c2.generation[i];
-
-    info!(log, "extent:{}  gens: {} {} {}", i, gen0, gen1, gen2);
-
-    if gen0 != gen1 || gen1 != gen2 {
-        /*
-         * We know at least one of the generation numbers is greater,
-         * so this first check will see if one is the largest.
-         */
-        if gen0 > gen1 && gen0 > gen2 {
-            return ClientId::new(0);
-        } else if gen1 > gen0 && gen1 > gen2 {
-            return ClientId::new(1);
-        } else if gen2 > gen0 && gen2 > gen1 {
-            return ClientId::new(2);
-        }
+    let gens: Vec<u64> = meta.iter().map(|c| c.generation[i]).collect();
+    info!(log, "extent:{}  gens: {:?}", i, gens);
 
-        /*
-         * If we are here, then two gen numbers match, and we need to take
-         * the third lower generation number off the list for comparison
-         * before we move on to try to break the tie with flush numbers.
-         */
-        if gen0 == gen1 {
-            max_gen.remove(2);
-        } else if gen0 == gen2 {
-            max_gen.remove(1);
-        } else {
-            assert_eq!(gen1, gen2);
-            max_gen.remove(0);
-        }
+    /*
+     * All client IDs are candidates for the max generation number; only
+     * keep the client IDs which have the highest value.  If that leaves a
+     * single client, it's our source.
+     */
+    let top_gen = *gens.iter().max().unwrap();
+    let max_gen: Vec<ClientId> = meta
+        .ids()
+        .filter(|sc| meta[*sc].generation[i] == top_gen)
+        .collect();
+    if max_gen.len() == 1 {
+        return max_gen[0];
     }
 
     /*
      * Our generation numbers did not break the tie, either they are all
-     * the same, or we have just two that are the same and have removed
-     * the lower one.  Now look for a flush number that is greater.
+     * the same, or we have several that are the same and have removed
+     * the lower ones.  Now look for a flush number that is greater.
      *
-     * Put the three downstairs RegionMetadata structs into an array with the
-     * index being the client ID.  We use the max_gen vec to see if any
-     * of the remaining client IDs have a higher flush number.
+     * We use the max_gen vec to see if any of the remaining client IDs
+     * have a higher flush number.
      */
-    let rec = ClientData([c0, c1, c2]);
-
     info!(
         log,
-        "extent:{}  flush: {} {} {} scs: {:?}",
+        "extent:{}  flush: {:?} scs: {:?}",
         i,
-        c0.flush_numbers[i],
-        c1.flush_numbers[i],
-        c2.flush_numbers[i],
+        meta.iter().map(|c| c.flush_numbers[i]).collect::<Vec<_>>(),
         max_gen,
     );
 
@@ -267,12 +244,12 @@ fn find_source(
     let mut max_flush = Vec::new();
 
     for sc in max_gen.iter() {
-        if rec[*sc].flush_numbers[i] > max {
-            max = rec[*sc].flush_numbers[i];
+        if meta[*sc].flush_numbers[i] > max {
+            max = meta[*sc].flush_numbers[i];
         }
     }
     for sc in max_gen.iter() {
-        if rec[*sc].flush_numbers[i] == max {
+        if meta[*sc].flush_numbers[i] == max {
             max_flush.push(*sc);
         }
     }
@@ -283,16 +260,18 @@ fn find_source(
     }
 
     /*
-     * To get here we have at least two (and possibly three) extents where
-     * the gen and flush are the same.  All that remains to break the tie is
-     * an extent with the dirty bit set.
+     * To get here we have at least two extents where the gen and flush are
+     * the same.  All that remains to break the tie is an extent with the
+     * dirty bit set.
      */
     info!(
         log,
-        "extent:{}  dirty: {} {} {}", i, c0.dirty[i], c1.dirty[i], c2.dirty[i],
+        "extent:{}  dirty: {:?}",
+        i,
+        meta.iter().map(|c| c.dirty[i]).collect::<Vec<_>>(),
     );
     for sc in max_flush.iter() {
-        if rec[*sc].dirty[i] {
+        if meta[*sc].dirty[i] {
             return *sc;
         }
     }
@@ -300,11 +279,11 @@ fn find_source(
     /*
      * To get here, the mismatch has to be a dirty bit set on an extent
      * that had lower gen or flush numbers and is no longer under
-     * consideration, with the remaining two client extents having
+     * consideration, with the remaining client extents having
      * matching gen and flush numbers.
      */
     info!(log, "No maxes found, left with: {:?}", max_flush);
-    assert_eq!(max_flush.len(), 2);
+    assert!(max_flush.len() >= 2 && max_flush.len() < meta.len());
 
     /*
      * Note that by always returning the lowest element in the vec, we
@@ -322,49 +301,34 @@ fn find_source(
 fn find_dest(
     i: usize,
     source: ClientId,
-    c0: &RegionMetadata,
-    c1: &RegionMetadata,
-    c2: &RegionMetadata,
+    meta: &ClientData<&RegionMetadata>,
     log: &Logger,
 ) -> Vec<ClientId> {
     let mut dest: Vec<ClientId> = Vec::new();
 
-    /*
-     * Put the three downstairs RegionMetadata structs into an array with the
-     * index being the client ID.
-     */
-    let rec = ClientData([c0, c1, c2]);
-
     info!(
         log,
         "find dest for source {} for extent at index {}", source, i
     );
 
-    let to_check = match source.get() {
-        0 => vec![ClientId::new(1), ClientId::new(2)],
-        1 => vec![ClientId::new(0), ClientId::new(2)],
-        2 => vec![ClientId::new(0), ClientId::new(1)],
-        _ => panic!("invalid client ID"),
-    };
-
-    for dc in to_check.iter() {
-        if rec[source].generation[i] != rec[*dc].generation[i] {
-            dest.push(*dc);
+    for dc in meta.ids().filter(|dc| *dc != source) {
+        if meta[source].generation[i] != meta[dc].generation[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} gen", source, dc);
             continue;
         }
-        if rec[source].flush_numbers[i] != rec[*dc].flush_numbers[i] {
-            dest.push(*dc);
+        if meta[source].flush_numbers[i] != meta[dc].flush_numbers[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} flush", source, dc);
             continue;
         }
-        if rec[source].dirty[i] {
-            dest.push(*dc);
+        if meta[source].dirty[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} source flush", source, dc);
             continue;
         }
-        if rec[*dc].dirty[i] {
-            dest.push(*dc);
+        if meta[dc].dirty[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} dc flush", source, dc);
             continue;
         }
@@ -1613,4 +1577,123 @@ mod test {
         // Extent 8  No mismatch
         assert!(fix.mend.is_empty());
     }
+
+    #[test]
+    fn reconcile_two_way() {
+        // Verify reconcile works with a two-way mirrored region set.
+        let d0 = RegionMetadata {
+            generation: vec![1, 1, 1, 1],
+            flush_numbers: vec![2, 3, 2, 2],
+            dirty: vec![false, false, true, false],
+        };
+        let d1 = RegionMetadata {
+            generation: vec![2, 1, 1, 1],
+            flush_numbers: vec![2, 2, 2, 2],
+            dirty: vec![false, false, false, false],
+        };
+        let meta = ClientData::from_fn(2, |i| [&d0, &d1][i.get() as usize]);
+        let mut fix = DownstairsMend::from_regions(&meta, csl()).unwrap();
+
+        // Extent 0, higher generation on client 1
+        let ef = fix.mend.remove(&ExtentId(0)).unwrap();
+        assert_eq!(ef.source, ClientId::new(1));
+        assert_eq!(ef.dest, vec![ClientId::new(0)]);
+
+        // Extent 1, higher flush on client 0
+        let ef = fix.mend.remove(&ExtentId(1)).unwrap();
+        assert_eq!(ef.source, ClientId::new(0));
+        assert_eq!(ef.dest, vec![ClientId::new(1)]);
+
+        // Extent 2, dirty on client 0
+        let ef = fix.mend.remove(&ExtentId(2)).unwrap();
+        assert_eq!(ef.source, ClientId::new(0));
+        assert_eq!(ef.dest, vec![ClientId::new(1)]);
+
+        // Extent 3 No mismatch
+        assert!(fix.mend.is_empty());
+    }
+
+    #[test]
+    fn reconcile_single_region() {
+        // A lone region never needs reconciliation, even if it's dirty.
+        let d0 = RegionMetadata {
+            generation: vec![1, 2, 1],
+            flush_numbers: vec![2, 3, 1],
+            dirty: vec![false, true, false],
+        };
+        let meta = ClientData::from_fn(1, |_| &d0);
+        assert!(DownstairsMend::from_regions(&meta, csl()).is_none());
+    }
+
+    #[test]
+    fn reconcile_five_way() {
+        // Verify reconcile works with a five-way region set.
+        let regions = [
+            RegionMetadata {
+                generation: vec![1, 1, 1, 1],
+                flush_numbers: vec![5, 1, 1, 1],
+                dirty: vec![false, false, false, false],
+            },
+            RegionMetadata {
+                generation: vec![3, 1, 1, 1],
+                flush_numbers: vec![4, 1, 1, 1],
+                dirty: vec![false, false, false, false],
+            },
+            RegionMetadata {
+                generation: vec![3, 1, 1, 1],
+                flush_numbers: vec![6, 1, 1, 1],
+                dirty: vec![false, false, false, false],
+            },
+            RegionMetadata {
+                generation: vec![2, 1, 1, 1],
+                flush_numbers: vec![9, 1, 1, 1],
+                dirty: vec![false, true, false, false],
+            },
+            RegionMetadata {
+                generation: vec![3, 1, 1, 1],
+                flush_numbers: vec![6, 1, 1, 2],
+                dirty: vec![false, false, false, false],
+            },
+        ];
+        let meta = ClientData::from_fn(5, |i| &regions[i.get() as usize]);
+        let mut fix = DownstairsMend::from_regions(&meta, csl()).unwrap();
+
+        // Extent 0, clients 2 and 4 tie on generation and flush, so the
+        // lowest client ID is picked.  Client 4 already matches it.
+        let ef = fix.mend.remove(&ExtentId(0)).unwrap();
+        assert_eq!(ef.source, ClientId::new(2));
+        assert_eq!(
+            ef.dest,
+            vec![ClientId::new(0), ClientId::new(1), ClientId::new(3)]
+        );
+
+        // Extent 1, dirty on client 3, so everyone else gets repaired
+        let ef = fix.mend.remove(&ExtentId(1)).unwrap();
+        assert_eq!(ef.source, ClientId::new(3));
+        assert_eq!(
+            ef.dest,
+            vec![
+                ClientId::new(0),
+                ClientId::new(1),
+                ClientId::new(2),
+                ClientId::new(4)
+            ]
+        );
+
+        // Extent 3, higher flush on client 4
+        let ef = fix.mend.remove(&ExtentId(3)).unwrap();
+        assert_eq!(ef.source, ClientId::new(4));
+        assert_eq!(
+            ef.dest,
+            vec![
+                ClientId::new(0),
+                ClientId::new(1),
+                ClientId::new(2),
+                ClientId::new(3)
+            ]
+        );
+
+        // Extent 2 No mismatch
+        assert!(fix.mend.is_empty());
+    }
 }
@@ -0,0 +1,871 @@
+// Copyright 2024 Oxide Computer Company
+//! Asynchronous replication to a disaster-recovery downstairs
+//!
+//! The replica is a fourth downstairs which is **not** part of the region set:
+//! it never participates in quorum, reconciliation, or live-repair.  Instead,
+//! every write and flush is forwarded to it once the three primary downstairs
+//! have completed the job and it has been retired from the active queue.  This
+//! means that the replica sees exactly the ordered stream of writes and flushes
+//! that the primaries saw, just a little later.
+//!
+//! The replica has its own backpressure: it may only lag behind by a bounded
+//! number of jobs and bytes.  If it falls further behind or returns an error,
+//! it is dropped and stays dropped; the guest never waits for it.  Because a
+//! dropped replica has missed writes, it must be re-seeded from the primaries
+//! before it can be used again.
+//!
+//! If the connection to the replica is lost, the IO task reconnects with
+//! exponential backoff while new jobs queue up (still subject to the lag
+//! limits).  Jobs are kept until the replica acks a later flush, so anything
+//! it may have lost by restarting is sent again on the new connection.
+//!
+//! The replica region must start out as a copy of the primary region (e.g.
+//! cloned from a snapshot).  Since it is a normal downstairs region, it can
+//! later be promoted by listing it as a target in a `VolumeConstructionRequest`.
+use crate::{
+    upstairs::UpstairsConfig, BlockContext, BlockIndex, BlockOffset,
+    CrucibleDecoder, ExtentId, JobId, Message, ReplicaOpts, WriteHeader,
+    CRUCIBLE_MESSAGE_VERSION,
+};
+use crucible_common::{deadline_secs, x509::TLSContext};
+use crucible_protocol::{
+    Capabilities, ConnectionVersion, MessageWriter, SUPPORTED_MESSAGE_VERSIONS,
+};
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    sync::mpsc,
+    time::sleep_until,
+};
+use tokio_util::codec::FramedRead;
+
+/// Default TLS server name expected from the replica downstairs
+const REPLICA_TLS_SERVER_NAME: &str = "replica";
+
+/// Default number of jobs that the replica may lag behind the primaries
+const REPLICA_MAX_LAG_JOBS: usize = 10000;
+
+/// Default number of write bytes that the replica may lag behind the primaries
+const REPLICA_MAX_LAG_BYTES: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// Number of jobs which may be in flight to the replica downstairs at once
+const REPLICA_MAX_IN_FLIGHT: usize = 64;
+
+/// Delay before the first attempt to reconnect to the replica
+const REPLICA_RECONNECT_MIN_SECS: f32 = 1.0;
+
+/// Longest delay between attempts to reconnect to the replica
+const REPLICA_RECONNECT_MAX_SECS: f32 = 60.0;
+
+/// ID of the first job sent to the replica
+const REPLICA_FIRST_JOB: JobId = JobId(1000);
+
+/// An operation to be forwarded to the replica
+#[derive(Clone, Debug)]
+pub(crate) enum ReplicaOp {
+    Write {
+        start_eid: ExtentId,
+        start_offset: BlockOffset,
+        blocks: Vec<BlockContext>,
+        data: Bytes,
+        /// Should this be sent as a `WriteUnwritten`?
+        unwritten: bool,
+    },
+    Flush {
+        flush_number: u64,
+        gen_number: u64,
+    },
+}
+
+impl ReplicaOp {
+    fn bytes(&self) -> u64 {
+        match self {
+            ReplicaOp::Write { data, .. } => data.len() as u64,
+            ReplicaOp::Flush { .. } => 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReplicaState {
+    /// Connecting to (or negotiating with) the replica downstairs
+    Connecting,
+    /// Receiving writes and flushes
+    Active,
+    /// Waiting to reconnect after losing the connection to the replica
+    Reconnecting,
+    /// The replica has been dropped and must be re-seeded
+    Failed,
+}
+
+/// Replica status, as reported through the control server
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct ReplicaStats {
+    pub target: SocketAddr,
+    pub state: ReplicaState,
+    /// Why the replica was dropped, if it has failed
+    pub failure: Option<String>,
+    /// Jobs which have been retired by the primaries but not yet acked by the
+    /// replica
+    pub lag_jobs: u64,
+    /// Write bytes which have been retired by the primaries but not yet acked
+    /// by the replica
+    pub lag_bytes: u64,
+    /// Total number of jobs acked by the replica
+    pub acked_jobs: u64,
+    /// Number of times the IO task has had to reconnect to the replica
+    pub reconnects: u64,
+}
+
+/// State shared between the [`Replica`] handle and its IO task
+#[derive(Debug)]
+struct ReplicaShared {
+    state: Mutex<(ReplicaState, Option<String>)>,
+    lag_jobs: AtomicU64,
+    lag_bytes: AtomicU64,
+    acked_jobs: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+impl ReplicaShared {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new((ReplicaState::Connecting, None)),
+            lag_jobs: AtomicU64::new(0),
+            lag_bytes: AtomicU64::new(0),
+            acked_jobs: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+        }
+    }
+
+    /// Changes state, unless the replica has already failed
+    fn set_state(&self, state: ReplicaState) {
+        let mut s = self.state.lock().unwrap();
+        if s.0 != ReplicaState::Failed {
+            s.0 = state;
+        }
+    }
+
+    /// Marks the replica as failed, returning `true` if this is the first time
+    fn set_failed(&self, reason: String) -> bool {
+        let mut s = self.state.lock().unwrap();
+        if s.0 == ReplicaState::Failed {
+            false
+        } else {
+            *s = (ReplicaState::Failed, Some(reason));
+            true
+        }
+    }
+
+    fn is_failed(&self) -> bool {
+        self.state.lock().unwrap().0 == ReplicaState::Failed
+    }
+
+    fn ack(&self, bytes: u64) {
+        self.lag_jobs.fetch_sub(1, Ordering::Relaxed);
+        self.lag_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.acked_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Handle to the asynchronous replica, owned by the `Downstairs`
+#[derive(Debug)]
+pub(crate) struct Replica {
+    target: SocketAddr,
+    max_lag_jobs: u64,
+    max_lag_bytes: u64,
+
+    /// Queue of operations for the IO task
+    ///
+    /// This is unbounded, but the amount of data in it is limited by
+    /// `max_lag_jobs` and `max_lag_bytes`.  It is set to `None` once the
+    /// replica has been dropped, which also stops the IO task.
+    tx: Option<mpsc::UnboundedSender<ReplicaOp>>,
+
+    shared: Arc<ReplicaShared>,
+    log: Logger,
+}
+
+impl Replica {
+    /// Builds a new replica handle and spawns its IO task
+    pub(crate) fn new(
+        opts: &ReplicaOpts,
+        cfg: Arc<UpstairsConfig>,
+        tls_context: Option<Arc<TLSContext>>,
+        log: Logger,
+    ) -> Self {
+        let (replica, rx) = Self::new_inner(opts, log);
+        let task = ReplicaIoTask {
+            target: replica.target,
+            server_name: opts
+                .tls_server_name
+                .clone()
+                .unwrap_or_else(|| REPLICA_TLS_SERVER_NAME.to_owned()),
+            cfg,
+            tls_context,
+            rx,
+            jobs: ReplicaJobs::new(),
+            backoff_secs: REPLICA_RECONNECT_MIN_SECS,
+            shared: replica.shared.clone(),
+            log: replica.log.clone(),
+        };
+        tokio::spawn(task.run());
+        replica
+    }
+
+    fn new_inner(
+        opts: &ReplicaOpts,
+        log: Logger,
+    ) -> (Self, mpsc::UnboundedReceiver<ReplicaOp>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let replica = Self {
+            target: opts.target,
+            max_lag_jobs: opts.max_lag_jobs.unwrap_or(REPLICA_MAX_LAG_JOBS)
+                as u64,
+            max_lag_bytes: opts.max_lag_bytes.unwrap_or(REPLICA_MAX_LAG_BYTES),
+            tx: Some(tx),
+            shared: Arc::new(ReplicaShared::new()),
+            log,
+        };
+        (replica, rx)
+    }
+
+    /// Forwards a retired operation to the replica
+    ///
+    /// This never blocks: if the replica is too far behind, it is dropped.
+    pub(crate) fn submit(&mut self, op: ReplicaOp) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if self.shared.is_failed() {
+            // The IO task gave up on its own; stop feeding it
+            self.tx = None;
+            return;
+        }
+
+        let bytes = op.bytes();
+        let lag_jobs = self.shared.lag_jobs.load(Ordering::Relaxed) + 1;
+        let lag_bytes = self.shared.lag_bytes.load(Ordering::Relaxed) + bytes;
+        if lag_jobs > self.max_lag_jobs || lag_bytes > self.max_lag_bytes {
+            self.fail(format!(
+                "replica fell too far behind \
+                 ({lag_jobs} jobs, {lag_bytes} bytes)"
+            ));
+            return;
+        }
+
+        self.shared.lag_jobs.fetch_add(1, Ordering::Relaxed);
+        self.shared.lag_bytes.fetch_add(bytes, Ordering::Relaxed);
+        if tx.send(op).is_err() {
+            self.fail("replica task has exited".to_owned());
+        }
+    }
+
+    /// Drops the replica, recording the reason
+    pub(crate) fn fail(&mut self, reason: String) {
+        if self.shared.set_failed(reason.clone()) {
+            warn!(self.log, "dropping replica {}: {reason}", self.target);
+        }
+        // Dropping the sender also stops the IO task
+        self.tx = None;
+    }
+
+    pub(crate) fn stats(&self) -> ReplicaStats {
+        let (state, failure) = self.shared.state.lock().unwrap().clone();
+        ReplicaStats {
+            target: self.target,
+            state,
+            failure,
+            lag_jobs: self.shared.lag_jobs.load(Ordering::Relaxed),
+            lag_bytes: self.shared.lag_bytes.load(Ordering::Relaxed),
+            acked_jobs: self.shared.acked_jobs.load(Ordering::Relaxed),
+            reconnects: self.shared.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Error after which the replica can't be trusted to have our writes
+///
+/// Any other error ends the connection, but not the replica: the IO task
+/// reconnects and sends the jobs which the replica may not have made durable.
+#[derive(Debug)]
+struct Fatal(String);
+
+impl std::fmt::Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+/// Jobs sent to the replica which it may not have made durable yet
+///
+/// A job is kept until the replica acks a later flush, not just until it is
+/// acked itself: if the replica downstairs restarts, writes which it acked but
+/// hadn't flushed are gone, so everything since its last flush is sent again
+/// on the next connection.
+#[derive(Debug)]
+struct ReplicaJobs {
+    /// Each job, and whether the replica has acked it
+    jobs: BTreeMap<JobId, (ReplicaOp, bool)>,
+    /// Number of jobs in `jobs` which haven't been acked
+    unacked: usize,
+    next_id: JobId,
+}
+
+impl ReplicaJobs {
+    fn new() -> Self {
+        Self {
+            jobs: BTreeMap::new(),
+            unacked: 0,
+            next_id: REPLICA_FIRST_JOB,
+        }
+    }
+
+    /// Records a new job, returning its ID
+    fn push(&mut self, op: ReplicaOp) -> JobId {
+        let job_id = self.next_id;
+        self.next_id.0 += 1;
+        self.jobs.insert(job_id, (op, false));
+        self.unacked += 1;
+        job_id
+    }
+
+    /// Returns the dependencies of the given job
+    ///
+    /// Each job depends on the previous one, so the replica applies them in
+    /// exactly the order they were retired.
+    fn dependencies(job_id: JobId) -> Vec<JobId> {
+        if job_id > REPLICA_FIRST_JOB {
+            vec![JobId(job_id.0 - 1)]
+        } else {
+            vec![]
+        }
+    }
+
+    /// Records an ack, returning the sizes of newly acked jobs
+    ///
+    /// A job acked again after being resent isn't counted twice.
+    fn ack(&mut self, job_id: JobId) -> Result<Vec<u64>> {
+        let Some((op, acked)) = self.jobs.get_mut(&job_id) else {
+            bail!(Fatal(format!("replica acked unknown job {job_id}")));
+        };
+        let mut out = vec![];
+        if !*acked {
+            *acked = true;
+            self.unacked -= 1;
+            out.push(op.bytes());
+        }
+        if matches!(op, ReplicaOp::Flush { .. }) {
+            // Everything up to this flush is durable on the replica
+            let newer = self.jobs.split_off(&JobId(job_id.0 + 1));
+            for (_, (op, acked)) in std::mem::replace(&mut self.jobs, newer) {
+                if !acked {
+                    self.unacked -= 1;
+                    out.push(op.bytes());
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Returns the newest job which the replica has made durable
+    ///
+    /// This is sent as the replica's last flush when connecting, so that it
+    /// doesn't wait on the dependencies of the first job that we send.
+    fn last_durable(&self) -> JobId {
+        let next = self.jobs.keys().next().unwrap_or(&self.next_id);
+        JobId(next.0 - 1)
+    }
+}
+
+/// IO task which owns the connection to the replica downstairs
+struct ReplicaIoTask {
+    target: SocketAddr,
+    server_name: String,
+    cfg: Arc<UpstairsConfig>,
+    tls_context: Option<Arc<TLSContext>>,
+    rx: mpsc::UnboundedReceiver<ReplicaOp>,
+    jobs: ReplicaJobs,
+    /// Delay before the next reconnection attempt
+    backoff_secs: f32,
+    shared: Arc<ReplicaShared>,
+    log: Logger,
+}
+
+impl ReplicaIoTask {
+    async fn run(mut self) {
+        loop {
+            let e = match self.run_inner().await {
+                Ok(()) => {
+                    info!(self.log, "replica task is exiting");
+                    return;
+                }
+                Err(e) => e,
+            };
+            if e.downcast_ref::<Fatal>().is_some() {
+                if self.shared.set_failed(e.to_string()) {
+                    warn!(self.log, "replica {} failed: {e}", self.target);
+                }
+                return;
+            }
+
+            self.shared.reconnects.fetch_add(1, Ordering::Relaxed);
+            self.shared.set_state(ReplicaState::Reconnecting);
+            warn!(
+                self.log,
+                "lost replica {}: {e}; reconnecting in {} secs",
+                self.target,
+                self.backoff_secs,
+            );
+            sleep_until(deadline_secs(self.backoff_secs)).await;
+            self.backoff_secs =
+                (self.backoff_secs * 2.0).min(REPLICA_RECONNECT_MAX_SECS);
+
+            // Stop if the replica was dropped (or the upstairs went away)
+            // while we were waiting.
+            if self.shared.is_failed() || Arc::strong_count(&self.shared) == 1 {
+                info!(self.log, "replica task is exiting");
+                return;
+            }
+            self.shared.set_state(ReplicaState::Connecting);
+        }
+    }
+
+    async fn run_inner(&mut self) -> Result<()> {
+        let sock = if self.target.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        info!(self.log, "connecting to replica {}", self.target);
+        let tcp: TcpStream = tokio::select! {
+            _ = sleep_until(deadline_secs(10.0)) => {
+                bail!("timed out connecting to replica");
+            }
+            tcp = sock.connect(self.target) => tcp?,
+        };
+        tcp.set_nodelay(true)?;
+
+        if let Some(tls_context) = &self.tls_context {
+            let config = tls_context.get_client_config()?;
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+            let server_name = tokio_rustls::rustls::ServerName::try_from(
+                self.server_name.as_str(),
+            )
+            .map_err(|_| {
+                Fatal(format!("invalid TLS server name {}", self.server_name))
+            })?;
+            let sock = connector.connect(server_name, tcp).await?;
+            let (read, write) = tokio::io::split(sock);
+            let version = ConnectionVersion::new();
+            let fr = FramedRead::new(
+                read,
+                CrucibleDecoder::with_version(version.clone()),
+            );
+            let fw = MessageWriter::with_version(write, version);
+            self.io_loop(fr, fw).await
+        } else {
+            let (read, write) = tcp.into_split();
+            let version = ConnectionVersion::new();
+            let fr = FramedRead::new(
+                read,
+                CrucibleDecoder::with_version(version.clone()),
+            );
+            let fw = MessageWriter::with_version(write, version);
+            self.io_loop(fr, fw).await
+        }
+    }
+
+    /// Negotiates with the replica, returning its blocks-per-extent
+    async fn negotiate<R, W>(
+        &self,
+        fr: &mut FramedRead<R, CrucibleDecoder>,
+        fw: &mut MessageWriter<W>,
+    ) -> Result<u64>
+    where
+        R: tokio::io::AsyncRead + std::marker::Unpin + std::marker::Send,
+        W: tokio::io::AsyncWrite
+            + std::marker::Unpin
+            + std::marker::Send
+            + 'static,
+    {
+        let cfg = &self.cfg;
+        let limit = inactivity_limit_secs(cfg);
+        fw.send(Message::HereIAm {
+            version: CRUCIBLE_MESSAGE_VERSION,
+            upstairs_id: cfg.upstairs_id,
+            session_id: cfg.session_id,
+            gen: cfg.generation(),
+            read_only: false,
+            encrypted: cfg.encrypted(),
+            alternate_versions: SUPPORTED_MESSAGE_VERSIONS[1..].to_vec(),
+            // Compression is worth offering across racks; the replica's
+            // downstairs decides whether to use it
+            capabilities: Capabilities::COMPRESSION,
+        })
+        .await?;
+        match recv_message(fr, limit).await? {
+            Message::YesItsMe { version, .. }
+                if SUPPORTED_MESSAGE_VERSIONS.contains(&version) => {}
+            Message::VersionMismatch { version } => {
+                bail!(Fatal(format!("replica speaks version {version}")))
+            }
+            m => bail!("expected YesItsMe from replica, got {m}"),
+        }
+
+        fw.send(Message::PromoteToActive {
+            upstairs_id: cfg.upstairs_id,
+            session_id: cfg.session_id,
+            gen: cfg.generation(),
+        })
+        .await?;
+        match recv_message(fr, limit).await? {
+            Message::YouAreNowActive { .. } => (),
+            m => bail!("expected YouAreNowActive from replica, got {m}"),
+        }
+
+        fw.send(Message::RegionInfoPlease).await?;
+        let blocks_per_extent = match recv_message(fr, limit).await? {
+            Message::RegionInfo { region_def } => {
+                info!(self.log, "replica region: {region_def:?}");
+                region_def.extent_size().value
+            }
+            m => bail!("expected RegionInfo from replica, got {m}"),
+        };
+
+        // Jobs that the replica has made durable aren't sent again
+        let last_flush_number = self.jobs.last_durable();
+        fw.send(Message::LastFlush { last_flush_number }).await?;
+        match recv_message(fr, limit).await? {
+            Message::LastFlushAck { .. } => (),
+            m => bail!("expected LastFlushAck from replica, got {m}"),
+        }
+
+        Ok(blocks_per_extent)
+    }
+
+    async fn io_loop<R, W>(
+        &mut self,
+        mut fr: FramedRead<R, CrucibleDecoder>,
+        mut fw: MessageWriter<W>,
+    ) -> Result<()>
+    where
+        R: tokio::io::AsyncRead + std::marker::Unpin + std::marker::Send,
+        W: tokio::io::AsyncWrite
+            + std::marker::Unpin
+            + std::marker::Send
+            + 'static,
+    {
+        let blocks_per_extent = self.negotiate(&mut fr, &mut fw).await?;
+        self.shared.set_state(ReplicaState::Active);
+        self.backoff_secs = REPLICA_RECONNECT_MIN_SECS;
+        info!(self.log, "replica {} is active", self.target);
+
+        // Send anything which the replica may not have made durable
+        let resend: Vec<_> = self
+            .jobs
+            .jobs
+            .iter()
+            .map(|(id, (op, _))| (*id, op.clone()))
+            .collect();
+        if !resend.is_empty() {
+            info!(self.log, "resending {} jobs to replica", resend.len());
+        }
+        for (job_id, op) in resend {
+            let m = self.op_to_message(op, job_id, blocks_per_extent);
+            fw.send(m).await?;
+        }
+
+        let timeouts = self.cfg.timeouts;
+        let limit = inactivity_limit_secs(&self.cfg);
+        let mut ping_deadline = deadline_secs(timeouts.ping_interval_secs);
+        loop {
+            tokio::select! {
+                op = self.rx.recv(),
+                    if self.jobs.unacked < REPLICA_MAX_IN_FLIGHT =>
+                {
+                    let Some(op) = op else {
+                        // The upstairs has dropped us (or is shutting down)
+                        return Ok(());
+                    };
+                    let job_id = self.jobs.push(op.clone());
+                    let m = self.op_to_message(op, job_id, blocks_per_extent);
+                    fw.send(m).await?;
+                }
+                m = recv_message(&mut fr, limit) => {
+                    let (job_id, result) = match m? {
+                        Message::WriteAck { job_id, result, .. }
+                        | Message::WriteUnwrittenAck { job_id, result, .. }
+                        | Message::FlushAck { job_id, result, .. } => {
+                            (job_id, result)
+                        }
+                        Message::Imok => continue,
+                        m => bail!("unexpected message from replica: {m}"),
+                    };
+                    if let Err(e) = result {
+                        bail!(Fatal(format!(
+                            "replica returned error for {job_id}: {e}"
+                        )));
+                    }
+                    for bytes in self.jobs.ack(job_id)? {
+                        self.shared.ack(bytes);
+                    }
+                }
+                _ = sleep_until(ping_deadline) => {
+                    ping_deadline = deadline_secs(timeouts.ping_interval_secs);
+                    fw.send(Message::Ruok).await?;
+                }
+            }
+        }
+    }
+
+    fn op_to_message(
+        &self,
+        op: ReplicaOp,
+        job_id: JobId,
+        blocks_per_extent: u64,
+    ) -> Message {
+        let dependencies = ReplicaJobs::dependencies(job_id);
+        match op {
+            ReplicaOp::Write {
+                start_eid,
+                start_offset,
+                blocks,
+                data,
+                unwritten,
+            } => {
+                let header = WriteHeader {
+                    upstairs_id: self.cfg.upstairs_id,
+                    session_id: self.cfg.session_id,
+                    job_id,
+                    dependencies,
+                    start: BlockIndex(
+                        start_eid.0 as u64 * blocks_per_extent + start_offset.0,
+                    ),
+                    contexts: blocks,
+                };
+                if unwritten {
+                    Message::WriteUnwritten { header, data }
+                } else {
+                    Message::Write { header, data }
+                }
+            }
+            ReplicaOp::Flush {
+                flush_number,
+                gen_number,
+            } => Message::Flush {
+                upstairs_id: self.cfg.upstairs_id,
+                session_id: self.cfg.session_id,
+                job_id,
+                dependencies,
+                flush_number,
+                gen_number,
+                snapshot_details: None,
+                extent_limit: None,
+            },
+        }
+    }
+}
+
+/// Returns how long we wait to hear from the replica before giving up
+fn inactivity_limit_secs(cfg: &UpstairsConfig) -> f32 {
+    cfg.timeouts.timeout_secs * cfg.timeouts.timeout_limit as f32
+}
+
+/// Receives the next message, bailing on errors, disconnection, or timeout
+async fn recv_message<R>(
+    fr: &mut FramedRead<R, CrucibleDecoder>,
+    limit_secs: f32,
+) -> Result<Message>
+where
+    R: tokio::io::AsyncRead + std::marker::Unpin + std::marker::Send,
+{
+    tokio::select! {
+        m = fr.next() => match m {
+            Some(m) => m,
+            None => bail!("replica disconnected"),
+        },
+        _ = sleep_until(deadline_secs(limit_secs)) => {
+            bail!("replica inactivity timeout");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_op(n: usize) -> ReplicaOp {
+        ReplicaOp::Write {
+            start_eid: ExtentId(0),
+            start_offset: BlockOffset(0),
+            blocks: vec![],
+            data: Bytes::from(vec![0u8; n]),
+            unwritten: false,
+        }
+    }
+
+    fn test_opts(
+        max_lag_jobs: Option<usize>,
+        max_lag_bytes: Option<u64>,
+    ) -> ReplicaOpts {
+        ReplicaOpts {
+            target: "127.0.0.1:1234".parse().unwrap(),
+            max_lag_jobs,
+            max_lag_bytes,
+            tls_server_name: None,
+        }
+    }
+
+    #[test]
+    fn replica_lag_accounting() {
+        let log = crucible_common::build_logger();
+        let (mut r, mut rx) = Replica::new_inner(&test_opts(None, None), log);
+
+        r.submit(write_op(512));
+        r.submit(ReplicaOp::Flush {
+            flush_number: 1,
+            gen_number: 1,
+        });
+        let s = r.stats();
+        assert_eq!(s.state, ReplicaState::Connecting);
+        assert_eq!(s.lag_jobs, 2);
+        assert_eq!(s.lag_bytes, 512);
+
+        // Pretend that the IO task has received and acked the write
+        assert!(matches!(rx.try_recv(), Ok(ReplicaOp::Write { .. })));
+        r.shared.ack(512);
+        let s = r.stats();
+        assert_eq!(s.lag_jobs, 1);
+        assert_eq!(s.lag_bytes, 0);
+        assert_eq!(s.acked_jobs, 1);
+    }
+
+    #[test]
+    fn replica_dropped_when_too_far_behind_jobs() {
+        let log = crucible_common::build_logger();
+        let (mut r, mut rx) =
+            Replica::new_inner(&test_opts(Some(2), None), log);
+
+        r.submit(write_op(512));
+        r.submit(write_op(512));
+        assert_eq!(r.stats().state, ReplicaState::Connecting);
+
+        // The third job exceeds our lag limit, so the replica is dropped
+        r.submit(write_op(512));
+        let s = r.stats();
+        assert_eq!(s.state, ReplicaState::Failed);
+        assert!(s.failure.is_some());
+
+        // Further jobs are silently discarded
+        r.submit(write_op(512));
+        assert_eq!(r.stats().lag_jobs, 2);
+
+        // The IO task sees the two queued jobs, then a closed channel
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn replica_dropped_when_too_far_behind_bytes() {
+        let log = crucible_common::build_logger();
+        let (mut r, _rx) =
+            Replica::new_inner(&test_opts(None, Some(1024)), log);
+
+        r.submit(write_op(1024));
+        assert_eq!(r.stats().state, ReplicaState::Connecting);
+        r.submit(write_op(1));
+        assert_eq!(r.stats().state, ReplicaState::Failed);
+    }
+
+    #[test]
+    fn replica_task_failure_stops_submission() {
+        let log = crucible_common::build_logger();
+        let (mut r, mut rx) = Replica::new_inner(&test_opts(None, None), log);
+
+        r.shared.set_failed("connection refused".to_owned());
+        r.submit(write_op(512));
+        assert_eq!(r.stats().lag_jobs, 0);
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+    }
+
+    fn flush_op() -> ReplicaOp {
+        ReplicaOp::Flush {
+            flush_number: 1,
+            gen_number: 1,
+        }
+    }
+
+    #[test]
+    fn replica_jobs_kept_until_flushed() {
+        let mut jobs = ReplicaJobs::new();
+        assert_eq!(jobs.last_durable(), JobId(999));
+
+        let w0 = jobs.push(write_op(512));
+        let f = jobs.push(flush_op());
+        let w1 = jobs.push(write_op(1024));
+        assert_eq!(ReplicaJobs::dependencies(w0), vec![]);
+        assert_eq!(ReplicaJobs::dependencies(f), vec![w0]);
+        assert_eq!(jobs.unacked, 3);
+
+        // An acked write is still resent until a later flush is acked
+        assert_eq!(jobs.ack(w0).unwrap(), vec![512]);
+        assert_eq!(jobs.unacked, 2);
+        assert_eq!(jobs.last_durable(), JobId(999));
+
+        // Acking it again (after a resend) doesn't count it twice
+        assert_eq!(jobs.ack(w0).unwrap(), Vec::<u64>::new());
+
+        // The flush makes everything up to it durable
+        assert_eq!(jobs.ack(f).unwrap(), vec![0]);
+        assert_eq!(jobs.last_durable(), f);
+        assert_eq!(jobs.jobs.keys().copied().collect::<Vec<_>>(), vec![w1]);
+
+        assert_eq!(jobs.ack(w1).unwrap(), vec![1024]);
+        assert_eq!(jobs.unacked, 0);
+        assert_eq!(jobs.last_durable(), f);
+
+        let e = jobs.ack(JobId(5000)).unwrap_err();
+        assert!(e.downcast_ref::<Fatal>().is_some());
+    }
+
+    #[test]
+    fn replica_flush_ack_covers_unacked_jobs() {
+        let mut jobs = ReplicaJobs::new();
+        jobs.push(write_op(512));
+        let f = jobs.push(flush_op());
+
+        // If the flush is acked first, the write is accounted for with it
+        let mut acked = jobs.ack(f).unwrap();
+        acked.sort();
+        assert_eq!(acked, vec![0, 512]);
+        assert_eq!(jobs.unacked, 0);
+        assert_eq!(jobs.last_durable(), f);
+    }
+}
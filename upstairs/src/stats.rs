@@ -1,7 +1,14 @@
 // Copyright 2022 Oxide Computer Company
 use super::*;
+use crucible_common::metrics::{
+    LatencyHistogram, MetricType, PrometheusText, StageLatency,
+    StageLatencySummary,
+};
+use crucible_common::x509::TLSContext;
+use std::collections::BTreeMap;
 
 use oximeter::{
+    histogram::Histogram,
     types::{Cumulative, Sample},
     Metric, MetricsError, Producer, Target,
 };
@@ -72,6 +79,55 @@ pub struct ExtentReopen {
     #[datum]
     pub count: Cumulative<i64>,
 }
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct TlsCertExpiry {
+    /// Seconds until our TLS certificate expires, negative once it has.
+    #[datum]
+    pub seconds: i64,
+}
+#[derive(Debug, Clone, Metric)]
+pub struct IoLatency {
+    /// The stage of the job, from `IoStage`
+    pub stage: String,
+    /// The type of job, e.g. "read"
+    pub op: String,
+    /// Time jobs spent in this stage, in microseconds
+    #[datum]
+    pub latency: Histogram<u64>,
+}
+
+/// The stages of a job's trip through the upstairs, for latency stats
+///
+/// Each stage ends at one of the DTrace probes described in `lib.rs`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum IoStage {
+    /// From the guest's request to the job's creation (`gw__*__start`),
+    /// including time spent queued and encrypting writes
+    Guest,
+    /// From the job's creation to it being sent to a downstairs
+    /// (`ds__*__client__start`)
+    Send,
+    /// From the job being sent to a downstairs to that downstairs' reply
+    /// (`ds__*__client__done`)
+    Downstairs,
+    /// From the last downstairs reply (or the job's creation, for writes
+    /// which are acked straight away) to the guest ack (`gw__*__done`)
+    Ack,
+    /// From the guest's request to the guest ack
+    Total,
+}
+
+impl IoStage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            IoStage::Guest => "guest",
+            IoStage::Send => "send",
+            IoStage::Downstairs => "downstairs",
+            IoStage::Ack => "ack",
+            IoStage::Total => "total",
+        }
+    }
+}
 
 // All the counter stats in one struct.
 #[derive(Clone, Debug)]
@@ -87,6 +143,11 @@ pub struct UpCountStat {
     extent_repair_count: ExtentRepair,
     extent_noop_count: ExtentNoOp,
     extent_reopen_count: ExtentReopen,
+    tls: Option<Arc<TLSContext>>,
+
+    // Time jobs spent in each stage, for the control server and Oximeter
+    stage_latency: StageLatency,
+    io_latency: BTreeMap<(IoStage, &'static str), IoLatency>,
 }
 
 impl UpCountStat {
@@ -103,6 +164,9 @@ impl UpCountStat {
             extent_repair_count: Default::default(),
             extent_noop_count: Default::default(),
             extent_reopen_count: Default::default(),
+            tls: None,
+            stage_latency: StageLatency::default(),
+            io_latency: BTreeMap::new(),
         }
     }
 }
@@ -169,6 +233,141 @@ impl UpStatOuter {
         let datum = ups.extent_reopen_count.datum_mut();
         *datum += 1;
     }
+    pub fn set_tls(&self, tls: Arc<TLSContext>) {
+        let mut ups = self.up_stat_wrap.lock().unwrap();
+        ups.tls = Some(tls);
+    }
+
+    /// Records how long a job of type `op` spent in one stage
+    pub(crate) fn add_latency(
+        &self,
+        stage: IoStage,
+        op: &'static str,
+        latency: Duration,
+    ) {
+        let mut ups = self.up_stat_wrap.lock().unwrap();
+        ups.stage_latency.record(stage.as_str(), op, latency);
+        let m =
+            ups.io_latency
+                .entry((stage, op))
+                .or_insert_with(|| IoLatency {
+                    stage: stage.as_str().to_string(),
+                    op: op.to_string(),
+                    latency: Histogram::new(
+                        &LatencyHistogram::bucket_bounds_us(),
+                    )
+                    .unwrap(),
+                });
+        // Sampling can only fail for values which can't be ordered
+        let _ = m.latency.sample(latency.as_micros() as u64);
+    }
+
+    /// Returns estimated percentiles of the time jobs spent in each stage
+    pub(crate) fn latency_summaries(&self) -> Vec<StageLatencySummary> {
+        self.up_stat_wrap.lock().unwrap().stage_latency.summaries()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn latency(
+        &self,
+        stage: IoStage,
+        op: &str,
+    ) -> Option<LatencyHistogram> {
+        let ups = self.up_stat_wrap.lock().unwrap();
+        ups.stage_latency.get(stage.as_str(), op).cloned()
+    }
+
+    /// Adds our counters to a page of Prometheus metrics
+    pub(crate) fn prometheus(&self, p: &mut PrometheusText) {
+        let ups = self.up_stat_wrap.lock().unwrap();
+        let upstairs_id = ups.stat_name.upstairs_uuid.to_string();
+        let labels = [("upstairs_id", upstairs_id.as_str())];
+
+        let counters = [
+            (
+                "crucible_upstairs_activations_total",
+                "Times this upstairs has activated",
+                ups.activated_count.datum().value(),
+            ),
+            (
+                "crucible_upstairs_writes_total",
+                "Region writes completed",
+                ups.write_count.datum().value(),
+            ),
+            (
+                "crucible_upstairs_write_bytes_total",
+                "Bytes written",
+                ups.write_bytes.datum().value(),
+            ),
+            (
+                "crucible_upstairs_reads_total",
+                "Region reads completed",
+                ups.read_count.datum().value(),
+            ),
+            (
+                "crucible_upstairs_read_bytes_total",
+                "Bytes read",
+                ups.read_bytes.datum().value(),
+            ),
+            (
+                "crucible_upstairs_flushes_total",
+                "Region flushes completed",
+                ups.flush_count.datum().value(),
+            ),
+            (
+                "crucible_upstairs_flush_closes_total",
+                "Extent flush close operations completed",
+                ups.flush_close_count.datum().value(),
+            ),
+            (
+                "crucible_upstairs_extent_repairs_total",
+                "Extent repair operations completed",
+                ups.extent_repair_count.datum().value(),
+            ),
+            (
+                "crucible_upstairs_extent_noops_total",
+                "Extent NoOp operations completed",
+                ups.extent_noop_count.datum().value(),
+            ),
+            (
+                "crucible_upstairs_extent_reopens_total",
+                "Extent reopen operations completed",
+                ups.extent_reopen_count.datum().value(),
+            ),
+        ];
+        for (name, help, value) in counters {
+            p.family(name, MetricType::Counter, help);
+            p.sample(name, &labels, value);
+        }
+
+        if let Some(seconds) = ups.tls.as_ref().and_then(|tls| {
+            tls.expiry()
+                .cert_secs_remaining(std::time::SystemTime::now())
+        }) {
+            let name = "crucible_upstairs_tls_cert_expiry_seconds";
+            p.family(
+                name,
+                MetricType::Gauge,
+                "Seconds until our TLS certificate expires",
+            );
+            p.sample(name, &labels, seconds);
+        }
+
+        let name = "crucible_upstairs_io_latency_seconds";
+        p.family(
+            name,
+            MetricType::Histogram,
+            "Time jobs spent in each stage of the upstairs, by job type",
+        );
+        for (stage, op, h) in ups.stage_latency.iter() {
+            let labels = [
+                ("upstairs_id", upstairs_id.as_str()),
+                ("stage", stage),
+                ("op", op),
+            ];
+            p.histogram(name, &labels, h);
+        }
+    }
 }
 
 // This trait is what is called to update the data to send to Oximeter.
@@ -183,7 +382,7 @@ impl Producer for UpStatOuter {
         let ups = self.up_stat_wrap.lock().unwrap();
 
         let name = &ups.stat_name;
-        let data = vec![
+        let mut data = vec![
             Sample::new(name, &ups.activated_count)?,
             Sample::new(name, &ups.flush_count)?,
             Sample::new(name, &ups.write_count)?,
@@ -195,6 +394,15 @@ impl Producer for UpStatOuter {
             Sample::new(name, &ups.extent_noop_count)?,
             Sample::new(name, &ups.extent_reopen_count)?,
         ];
+        if let Some(seconds) = ups.tls.as_ref().and_then(|tls| {
+            tls.expiry()
+                .cert_secs_remaining(std::time::SystemTime::now())
+        }) {
+            data.push(Sample::new(name, &TlsCertExpiry { seconds })?);
+        }
+        for m in ups.io_latency.values() {
+            data.push(Sample::new(name, m)?);
+        }
 
         // Yield the available samples.
         Ok(Box::new(data.into_iter()))
@@ -96,6 +96,32 @@ pub(crate) mod up_test {
         assert_eq!(span.affected_block_numbers(), &vec![268, 269, 270, 271]);
     }
 
+    #[test]
+    fn write_quorum_sizes() {
+        // A two-way mirror needs either side; larger sets need a majority
+        assert_eq!(write_quorum(2), 1);
+        assert_eq!(write_quorum(3), 2);
+        assert_eq!(write_quorum(4), 3);
+        assert_eq!(write_quorum(5), 3);
+    }
+
+    #[test]
+    fn client_data_serializes_used_slots() {
+        let mut d = ClientData::from_fn(2, |c| c.get() as u32 * 10);
+        assert_eq!(d.len(), 2);
+        assert_eq!(d.insert(ClientId::new(1), 11), 10);
+        assert_eq!(d.iter().copied().collect::<Vec<_>>(), vec![0, 11]);
+
+        let j = serde_json::to_string(&d).unwrap();
+        assert_eq!(j, "[0,11]");
+        let back: ClientData<u32> = serde_json::from_str(&j).unwrap();
+        assert_eq!(back.len(), 2);
+        assert_eq!(back[ClientId::new(1)], 11);
+
+        let too_long = serde_json::to_string(&vec![0u32; 9]).unwrap();
+        assert!(serde_json::from_str::<ClientData<u32>>(&too_long).is_err());
+    }
+
     #[test]
     fn test_iospan_buffer_read_write() {
         let mut span = IOSpan::new(500, 64, 512);
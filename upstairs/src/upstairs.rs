@@ -2,8 +2,8 @@
 //! Data structures specific to Crucible's `struct Upstairs`
 use crate::{
     cdt,
-    client::{ClientAction, ClientRunResult},
-    control::ControlRequest,
+    client::{ClientAction, ClientRunResult, ClientTimeouts},
+    control::{ControlOp, ControlOpStarted, ControlRequest},
     deadline_secs,
     deferred::{
         DeferredBlockOp, DeferredMessage, DeferredQueue, DeferredRead,
@@ -12,12 +12,17 @@ use crate::{
     downstairs::{Downstairs, DownstairsAction},
     extent_from_offset,
     guest::GuestBlockRes,
+    replica::Replica,
     stats::UpStatOuter,
-    BlockOp, BlockRes, Buffer, ClientId, ClientMap, CrucibleOpts, DsState,
-    EncryptionContext, GuestIoHandle, Message, RegionDefinition,
-    RegionDefinitionStatus, SnapshotDetails, WQCounts,
+    BlockOp, BlockOpWaiter, BlockRes, Buffer, ClientId, ClientMap,
+    CrucibleOpts, DsState, EncryptionContext, GuestIoHandle, Message,
+    RegionDefinition, RegionDefinitionStatus, SnapshotDetails, WQCounts,
 };
-use crucible_common::{BlockIndex, CrucibleError};
+use crucible_common::{
+    audit::{AuditEvent, AuditLog, AuditSession, AuditSource},
+    BlockIndex, CrucibleError,
+};
+use crucible_protocol::capture::CaptureConfig;
 use serde::{Deserialize, Serialize};
 
 use std::sync::{
@@ -75,10 +80,22 @@ pub(crate) enum UpstairsState {
     Deactivating(BlockRes),
 }
 
+impl UpstairsState {
+    /// Returns the state's name, as recorded in the audit log
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            UpstairsState::Initializing => "initializing",
+            UpstairsState::GoActive(..) => "go_active",
+            UpstairsState::Active => "active",
+            UpstairsState::Deactivating(..) => "deactivating",
+        }
+    }
+}
+
 /// Crucible upstairs counters
 ///
 /// Counters indicating the upstairs selects path.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 pub struct UpCounters {
     apply: u64,
     action_downstairs: u64,
@@ -223,6 +240,9 @@ pub(crate) struct Upstairs {
     /// Fixed configuration
     pub(crate) cfg: Arc<UpstairsConfig>,
 
+    /// TLS material for connecting to the downstairs, which may be reloaded
+    pub(crate) tls_context: Option<Arc<crucible_common::x509::TLSContext>>,
+
     /// Logger used by the upstairs
     pub(crate) log: Logger,
 
@@ -308,6 +328,18 @@ pub(crate) struct UpstairsConfig {
 
     /// Does this Upstairs throw random errors?
     pub lossy: bool,
+
+    /// Timeouts and limits applied to each downstairs client
+    pub timeouts: ClientTimeouts,
+
+    /// Number of downstairs in this region set
+    pub region_set_size: usize,
+
+    /// Where to record the messages exchanged with each downstairs, if anywhere
+    pub capture: Option<CaptureConfig>,
+
+    /// Where lifecycle events are recorded
+    pub audit: Arc<AuditLog>,
 }
 
 impl UpstairsConfig {
@@ -318,6 +350,17 @@ impl UpstairsConfig {
     pub(crate) fn generation(&self) -> u64 {
         self.generation.load(Ordering::Acquire)
     }
+
+    /// Records a lifecycle event for this session in the audit log
+    pub(crate) fn audit(&self, client_id: Option<ClientId>, event: AuditEvent) {
+        let session = AuditSession {
+            upstairs_id: self.upstairs_id,
+            session_id: self.session_id,
+            generation: self.generation(),
+        };
+        self.audit
+            .record(session, client_id.map(|c| c.get()), event);
+    }
 }
 
 impl Upstairs {
@@ -329,14 +372,29 @@ impl Upstairs {
         tls_context: Option<Arc<crucible_common::x509::TLSContext>>,
     ) -> Self {
         /*
-         * XXX Make sure we have three and only three downstairs
+         * Make sure we have a supported number of downstairs: anything up to
+         * `ClientId::MAX_CLIENTS`.  A lone downstairs is only used for one
+         * fragment of an erasure-coded region set; mirrored region sets are
+         * checked for at least `MIN_REGION_SET_SIZE` when the volume is built.
          */
         #[cfg(not(test))]
-        assert_eq!(opt.target.len(), 3, "bad targets {:?}", opt.target);
+        assert!(
+            (1..=ClientId::MAX_CLIENTS).contains(&opt.target.len()),
+            "bad targets {:?}",
+            opt.target
+        );
+
+        // The region set size is given by the number of targets, except during
+        // some tests where no targets are provided.
+        let region_set_size = if opt.target.is_empty() {
+            crate::DEFAULT_REGION_SET_SIZE
+        } else {
+            opt.target.len()
+        };
 
         // Build the target map, which is either empty (during some tests) or
-        // fully populated with all three targets.
-        let mut ds_target = ClientMap::new();
+        // fully populated with all targets.
+        let mut ds_target = ClientMap::new(region_set_size);
         for (i, v) in opt.target.iter().enumerate() {
             ds_target.insert(ClientId::new(i as u8), *v);
         }
@@ -360,6 +418,9 @@ impl Upstairs {
 
         let uuid = opt.id;
         let stats = UpStatOuter::new(uuid);
+        if let Some(tls) = &tls_context {
+            stats.set_tls(tls.clone());
+        }
         let counters = UpCounters::new();
 
         let rd_status = match expected_region_def {
@@ -377,6 +438,20 @@ impl Upstairs {
         info!(log, "Crucible {} has session id: {}", uuid, session_id);
         info!(log, "Upstairs opts: {}", opt);
 
+        // Losing the audit log file is no reason to refuse to start, so fall
+        // back to keeping records in memory.
+        let audit = opt
+            .audit_log
+            .as_ref()
+            .and_then(|path| {
+                AuditLog::open(AuditSource::Upstairs, path.as_ref())
+                    .map_err(|e| {
+                        warn!(log, "could not open audit log {path}: {e}")
+                    })
+                    .ok()
+            })
+            .unwrap_or_else(|| AuditLog::new(AuditSource::Upstairs));
+
         let cfg = Arc::new(UpstairsConfig {
             encryption_context,
             upstairs_id: uuid,
@@ -384,15 +459,32 @@ impl Upstairs {
             generation: AtomicU64::new(gen),
             read_only: opt.read_only,
             lossy: opt.lossy,
+            timeouts: ClientTimeouts::new(opt.client_timeouts.as_ref()),
+            region_set_size,
+            capture: opt.capture.as_ref().map(|c| CaptureConfig {
+                dir: c.dir.clone().into(),
+                redact: c.redact,
+            }),
+            audit: Arc::new(audit),
         });
 
         info!(log, "Crucible stats registered with UUID: {}", uuid);
-        let downstairs = Downstairs::new(
+        let mut downstairs = Downstairs::new(
             cfg.clone(),
             ds_target,
-            tls_context,
+            tls_context.clone(),
+            stats.clone(),
             log.new(o!("" => "downstairs")),
         );
+        if let Some(r) = &opt.replica {
+            info!(log, "Asynchronous replica at {}", r.target);
+            downstairs.set_replica(Replica::new(
+                r,
+                cfg.clone(),
+                tls_context.clone(),
+                log.new(o!("" => "replica")),
+            ));
+        }
         let flush_timeout_secs = opt.flush_timeout.unwrap_or(0.5);
         let (control_tx, control_rx) = tokio::sync::mpsc::channel(500);
 
@@ -410,6 +502,7 @@ impl Upstairs {
             need_flush: false,
             stats,
             counters,
+            tls_context,
             log,
             downstairs,
             control_rx,
@@ -438,7 +531,12 @@ impl Upstairs {
             key_pem: None,
             root_cert_pem: None,
             control: None,
+            control_token: None,
             read_only: false,
+            client_timeouts: None,
+            replica: None,
+            capture: None,
+            audit_log: None,
         };
 
         let log = crucible_common::build_logger();
@@ -628,7 +726,7 @@ impl Upstairs {
 
         // Send jobs downstairs as they become available.  This must be called
         // after `continue_live_repair`, which may enqueue jobs.
-        for i in ClientId::iter() {
+        for i in self.downstairs.clients.ids() {
             if self.downstairs.clients[i].should_do_more_work() {
                 let ddef = self.ddef.get_def().unwrap();
                 self.downstairs.io_send(i, &ddef);
@@ -637,14 +735,13 @@ impl Upstairs {
 
         // Handle any jobs that have become ready for acks
         if self.downstairs.has_ackable_jobs() {
-            self.downstairs
-                .ack_jobs(&mut self.guest.guest_work, &self.stats)
+            self.downstairs.ack_jobs(&mut self.guest.guest_work)
         }
 
         // Check for client-side deactivation
         if matches!(&self.state, UpstairsState::Deactivating(..)) {
             info!(self.log, "checking for deactivation");
-            for i in ClientId::iter() {
+            for i in self.downstairs.clients.ids() {
                 // Clients become Deactivated, then New (when the IO task
                 // completes and the client is restarted).  We don't try to
                 // deactivate them _again_ in such cases.
@@ -666,10 +763,8 @@ impl Upstairs {
                 .all(|c| c.ready_to_deactivate())
             {
                 info!(self.log, "All DS in the proper state! -> INIT");
-                let prev = std::mem::replace(
-                    &mut self.state,
-                    UpstairsState::Initializing,
-                );
+                let prev =
+                    self.set_state(UpstairsState::Initializing, "deactivated");
                 let UpstairsState::Deactivating(res) = prev else {
                     panic!("invalid upstairs state {prev:?}"); // checked above
                 };
@@ -680,6 +775,7 @@ impl Upstairs {
         // For now, check backpressure after every event.  We may want to make
         // this more nuanced in the future.
         self.set_backpressure();
+        self.set_can_persist();
     }
 
     /// Helper function to await all deferred block requests
@@ -722,7 +818,7 @@ impl Upstairs {
             return;
         }
 
-        for cid in ClientId::iter() {
+        for cid in self.downstairs.clients.ids() {
             if self.downstairs.clients[cid].state() == DsState::Offline {
                 self.downstairs.check_gone_too_long(cid, &self.state);
             }
@@ -782,7 +878,7 @@ impl Upstairs {
     }
 
     /// Handles a request from the (optional) control server
-    fn on_control_req(&self, c: ControlRequest) {
+    fn on_control_req(&mut self, c: ControlRequest) {
         match c {
             ControlRequest::UpstairsStats(tx) => {
                 let ds_state = self.downstairs.collect_stats(|c| c.state());
@@ -791,6 +887,8 @@ impl Upstairs {
                 let reconcile_done = self.downstairs.reconcile_repaired();
                 let reconcile_needed =
                     self.downstairs.reconcile_repair_needed();
+                let reconcile_aborted =
+                    self.downstairs.reconcile_repair_aborted();
                 let extents_repaired =
                     self.downstairs.collect_stats(|c| c.stats.extents_repaired);
                 let extents_confirmed = self
@@ -799,6 +897,7 @@ impl Upstairs {
                 let extent_limit = self
                     .downstairs
                     .collect_stats(|c| matches!(c.state(), DsState::LiveRepair))
+                    .into_iter()
                     .map(|b| {
                         if b {
                             self.downstairs
@@ -807,13 +906,41 @@ impl Upstairs {
                         } else {
                             None
                         }
-                    });
+                    })
+                    .collect();
                 let live_repair_completed = self
                     .downstairs
                     .collect_stats(|c| c.stats.live_repair_completed);
                 let live_repair_aborted = self
                     .downstairs
                     .collect_stats(|c| c.stats.live_repair_aborted);
+                let ds_round_trip_us = self.downstairs.collect_stats(|c| {
+                    c.round_trip_time().map(|d| d.as_micros() as u64)
+                });
+                let ds_timeout_secs =
+                    self.downstairs.collect_stats(|c| c.inactivity_secs());
+                let ds_capabilities =
+                    self.downstairs.collect_stats(|c| c.capabilities().names());
+                let ds_compression = self.downstairs.collect_stats(|c| {
+                    let (algorithm, counts) = c.compression();
+                    crate::control::CompressionStatus::new(algorithm, counts)
+                });
+                let ds_io_count =
+                    self.downstairs.collect_stats(|c| c.io_state_count);
+                let ds_connected =
+                    self.downstairs.collect_stats(|c| c.stats.connected);
+                let ds_replaced =
+                    self.downstairs.collect_stats(|c| c.stats.replaced);
+                let ds_delay_us = self
+                    .downstairs
+                    .collect_stats(|c| c.get_delay_us() as usize);
+                let ds_job_latency = self.downstairs.collect_stats(|c| {
+                    c.stats
+                        .job_latency
+                        .iter()
+                        .map(|(op, h)| (op.to_string(), h.clone()))
+                        .collect()
+                });
 
                 // Translate from rich UpstairsState to simplified UpState
                 // TODO: remove this distinction?
@@ -830,16 +957,36 @@ impl Upstairs {
 
                 let r = tx.send(crate::control::UpstairsStats {
                     state,
-                    ds_state: ds_state.to_vec(),
+                    ds_state,
                     up_jobs,
                     ds_jobs,
                     reconcile_done,
                     reconcile_needed,
-                    extents_repaired: extents_repaired.to_vec(),
-                    extents_confirmed: extents_confirmed.to_vec(),
-                    extent_limit: extent_limit.to_vec(),
-                    live_repair_completed: live_repair_completed.to_vec(),
-                    live_repair_aborted: live_repair_aborted.to_vec(),
+                    reconcile_aborted,
+                    extents_repaired,
+                    extents_confirmed,
+                    extent_limit,
+                    live_repair_completed,
+                    live_repair_aborted,
+                    client_timeouts: self.cfg.timeouts,
+                    ds_round_trip_us,
+                    ds_timeout_secs,
+                    ds_capabilities,
+                    ds_compression,
+                    ds_io_count,
+                    ds_connected,
+                    ds_replaced,
+                    ds_delay_us,
+                    ds_job_latency,
+                    io_latency: self.stats.latency_summaries(),
+                    next_job_id: self.downstairs.peek_next_id(),
+                    backpressure_us: self.guest.backpressure_us(),
+                    write_bytes_out: self.downstairs.write_bytes_outstanding(),
+                    replica: self.downstairs.replica_stats(),
+                    tls: self
+                        .tls_context
+                        .as_deref()
+                        .map(crate::control::TlsStatus::new),
                 });
                 if r.is_err() {
                     warn!(self.log, "control message reply failed");
@@ -852,9 +999,109 @@ impl Upstairs {
                     warn!(self.log, "control message reply failed");
                 }
             }
+            ControlRequest::DownstairsState(tx) => {
+                let r = tx.send(self.downstairs.collect_stats(|c| c.state()));
+                if r.is_err() {
+                    warn!(self.log, "control message reply failed");
+                }
+            }
+            ControlRequest::Op { op, done } => {
+                info!(self.log, "control request {op:?}");
+                let r = self.apply_control_op(op);
+                if done.send(r).is_err() {
+                    warn!(self.log, "control message reply failed");
+                }
+            }
         }
     }
 
+    /// Starts an operation requested through the control server
+    fn apply_control_op(
+        &mut self,
+        op: ControlOp,
+    ) -> Result<ControlOpStarted, CrucibleError> {
+        let mut out = ControlOpStarted {
+            before: self.downstairs.collect_stats(|c| c.state()),
+            replace: None,
+            wait: None,
+        };
+        match op {
+            ControlOp::FaultDownstairs {
+                client_id: i,
+                force,
+            } => {
+                if i as usize >= self.downstairs.clients.len() {
+                    return Err(CrucibleError::Unsupported(format!(
+                        "no downstairs {i}"
+                    )));
+                }
+                let client_id = ClientId::new(i);
+                let state = self.downstairs.clients[client_id].state();
+                if !matches!(self.state, UpstairsState::Active)
+                    || !matches!(
+                        state,
+                        DsState::Active
+                            | DsState::LiveRepair
+                            | DsState::LiveRepairReady
+                            | DsState::Offline
+                            | DsState::Replay
+                    )
+                {
+                    return Err(CrucibleError::Unsupported(format!(
+                        "cannot fault downstairs {i} in state {state}"
+                    )));
+                }
+                // Without a write quorum, the guest's writes would fail until
+                // this downstairs has been repaired.
+                let clients = &self.downstairs.clients;
+                let quorum = crate::write_quorum(clients.len());
+                let remaining = clients
+                    .ids()
+                    .filter(|&j| {
+                        j != client_id && clients[j].state() == DsState::Active
+                    })
+                    .count();
+                if remaining < quorum && !force {
+                    return Err(CrucibleError::Unsupported(format!(
+                        "faulting downstairs {i} would leave {remaining} \
+                        active, fewer than the {quorum} needed for writes; \
+                        use force to fault it anyway"
+                    )));
+                }
+                self.downstairs.skip_all_jobs(client_id);
+                self.downstairs.clients[client_id].fault(
+                    &self.state,
+                    crate::client::ClientStopReason::RequestedFault,
+                );
+            }
+            ControlOp::ReplaceDownstairs { id, old, new } => {
+                let r = self.downstairs.replace(id, old, new, &self.state)?;
+                out.replace = Some(r);
+            }
+            ControlOp::Flush => {
+                if !self.guest_io_ready() {
+                    return Err(CrucibleError::UpstairsInactive);
+                }
+                let (wait, res) = BlockOpWaiter::pair();
+                self.submit_flush(Some(res), None);
+                out.wait = Some(wait);
+            }
+            ControlOp::Deactivate => {
+                let (wait, res) = BlockOpWaiter::pair();
+                self.set_deactivate(res);
+                out.wait = Some(wait);
+            }
+            ControlOp::AbortLiveRepair => {
+                if !self.downstairs.abort_live_repair(&self.state) {
+                    return Err(CrucibleError::Unsupported(
+                        "no live-repair to abort".to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(out)
+    }
+
     /// Checks if a repair is possible. If so, checks if any Downstairs is in
     /// the [DsState::LiveRepairReady] state, indicating it needs to be
     /// repaired. If a Downstairs needs to be repaired, try to start repairing
@@ -863,7 +1110,9 @@ impl Upstairs {
     ///
     /// If this Upstairs is [UpstairsConfig::read_only], this function will move
     /// any Downstairs from [DsState::LiveRepairReady] back to [DsState::Active]
-    /// without actually performing any repair.
+    /// without actually performing any repair.  The same goes for a lone
+    /// Downstairs, which has no peer to repair from; in that case, the Guest
+    /// is told that the Downstairs needs to be rebuilt by its owner.
     pub(crate) fn on_repair_check(&mut self) {
         info!(self.log, "Checking if live repair is needed");
         if !matches!(self.state, UpstairsState::Active) {
@@ -884,6 +1133,27 @@ impl Upstairs {
             return;
         }
 
+        if self.cfg.region_set_size == 1 {
+            info!(self.log, "lone downstairs, no live repair possible");
+            // There's no other downstairs to repair from; the erasure-coded
+            // region set that owns this Upstairs rebuilds the fragment itself,
+            // once we tell it (before the downstairs goes active again) that
+            // the fragment missed writes.
+            if self
+                .downstairs
+                .clients
+                .iter()
+                .any(|c| c.state() == DsState::LiveRepairReady)
+            {
+                self.guest.set_needs_rebuild();
+            }
+            for c in self.downstairs.clients.iter_mut() {
+                c.skip_live_repair(&self.state);
+            }
+            self.repair_check_interval = None;
+            return;
+        }
+
         // Verify that all downstairs and the upstairs are in the proper state
         // before we begin a live repair.
         let repair_in_progress = self.downstairs.live_repair_in_progress();
@@ -1099,10 +1369,10 @@ impl Upstairs {
 
             #[cfg(test)]
             BlockOp::GetDownstairsState { done } => {
-                let mut out = crate::ClientData::new(DsState::New);
-                for i in ClientId::iter() {
-                    out[i] = self.downstairs.clients[i].state();
-                }
+                let out = crate::ClientData::from_fn(
+                    self.downstairs.clients.len(),
+                    |i| self.downstairs.clients[i].state(),
+                );
                 done.send_ok(out);
             }
 
@@ -1167,7 +1437,10 @@ impl Upstairs {
     fn set_active_request(&mut self, res: BlockRes) {
         match &self.state {
             UpstairsState::Initializing => {
-                self.state = UpstairsState::GoActive(res);
+                self.set_state(
+                    UpstairsState::GoActive(res),
+                    "activation_requested",
+                );
                 info!(self.log, "{} active request set", self.cfg.upstairs_id);
             }
             UpstairsState::GoActive(..) => {
@@ -1233,7 +1506,31 @@ impl Upstairs {
             // Upstairs::apply.
         }
 
-        self.state = UpstairsState::Deactivating(res);
+        self.set_state(
+            UpstairsState::Deactivating(res),
+            "deactivation_requested",
+        );
+    }
+
+    /// Sets `self.state`, recording the change in the audit log
+    ///
+    /// Returns the previous state.
+    fn set_state(
+        &mut self,
+        new_state: UpstairsState,
+        cause: &str,
+    ) -> UpstairsState {
+        if self.state.name() != new_state.name() {
+            self.cfg.audit(
+                None,
+                AuditEvent::UpstairsState {
+                    old: self.state.name().to_owned(),
+                    new: new_state.name().to_owned(),
+                    cause: cause.to_owned(),
+                },
+            );
+        }
+        std::mem::replace(&mut self.state, new_state)
     }
 
     pub(crate) fn submit_flush(
@@ -1743,6 +2040,7 @@ impl Upstairs {
             | Message::ExtentReopen { .. }
             | Message::ExtentVersionsPlease
             | Message::PromoteToActive { .. }
+            | Message::TraceContext { .. }
             | Message::Unknown(..) => {
                 panic!("invalid response {m:?}")
             }
@@ -1898,10 +2196,11 @@ impl Upstairs {
 
         // Swap out the state for UpstairsState::Active
         let UpstairsState::GoActive(res) =
-            std::mem::replace(&mut self.state, UpstairsState::Active)
+            self.set_state(UpstairsState::Active, "reconciliation_done")
         else {
             unreachable!(); // checked above
         };
+        self.set_can_persist();
         res.send_ok(());
         info!(
             self.log,
@@ -1960,8 +2259,18 @@ impl Upstairs {
             );
         };
 
+        self.cfg.audit(
+            Some(client_id),
+            AuditEvent::TakenOver {
+                new_upstairs_id,
+                new_session_id,
+                new_generation: new_gen,
+            },
+        );
+
         // Restart the state machine for this downstairs client
-        self.downstairs.clients[client_id].disable(&self.state);
+        self.downstairs.clients[client_id]
+            .disable(&self.state, "no_longer_active");
         self.set_inactive(CrucibleError::NoLongerActive);
     }
 
@@ -1977,7 +2286,8 @@ impl Upstairs {
         );
 
         // Restart the state machine for this downstairs client
-        self.downstairs.clients[client_id].disable(&self.state);
+        self.downstairs.clients[client_id]
+            .disable(&self.state, "uuid_mismatch");
         self.set_inactive(CrucibleError::UuidMismatch);
     }
 
@@ -2008,7 +2318,7 @@ impl Upstairs {
 
     fn set_inactive(&mut self, err: CrucibleError) {
         let prev =
-            std::mem::replace(&mut self.state, UpstairsState::Initializing);
+            self.set_state(UpstairsState::Initializing, &err.to_string());
         if let UpstairsState::GoActive(res) = prev {
             res.send_err(err);
         }
@@ -2059,6 +2369,22 @@ impl Upstairs {
         self.downstairs.set_client_backpressure();
     }
 
+    /// Tells the `Guest` whether writes that it acks will be persisted
+    ///
+    /// With fewer than a write quorum of active downstairs, writes are acked
+    /// and held for replay, so they may only exist in memory.
+    fn set_can_persist(&self) {
+        let clients = &self.downstairs.clients;
+        let active = clients
+            .iter()
+            .filter(|c| c.state() == DsState::Active)
+            .count();
+        self.guest.set_can_persist(
+            matches!(self.state, UpstairsState::Active)
+                && active >= crate::write_quorum(clients.len()),
+        );
+    }
+
     /// Returns the `RegionDefinition`
     ///
     /// # Panics
@@ -2094,7 +2420,7 @@ pub(crate) mod test {
         client::ClientStopReason,
         downstairs::test::set_all_active,
         test::{make_encrypted_upstairs, make_upstairs},
-        Block, BlockOp, BlockOpWaiter, DsState, JobId,
+        Block, BlockIO, BlockOp, BlockOpWaiter, DsState, JobId,
     };
     use bytes::BytesMut;
     use crucible_common::integrity_hash;
@@ -2165,6 +2491,31 @@ pub(crate) mod test {
         assert!(!matches!(&up.state, &UpstairsState::Active))
     }
 
+    #[test]
+    fn audit_records_ds_transitions() {
+        let mut up = create_test_upstairs();
+        let seq = up.cfg.audit.after(0).seq;
+
+        up.downstairs.clients[ClientId::new(1)]
+            .fault(&up.state, ClientStopReason::IOError);
+
+        let records = up.cfg.audit.after(seq).records;
+        assert_eq!(records.len(), 1);
+        let r = &records[0];
+        assert_eq!(r.client_id, Some(1));
+        assert_eq!(r.session.upstairs_id, up.cfg.upstairs_id);
+        assert_eq!(r.session.session_id, up.cfg.session_id);
+        let AuditEvent::DsState {
+            old, new, cause, ..
+        } = &r.event
+        else {
+            panic!("unexpected audit event {:?}", r.event);
+        };
+        assert_eq!(old, "active");
+        assert_eq!(new, "faulted");
+        assert_eq!(cause, "io_error");
+    }
+
     #[tokio::test]
     async fn deactivate_not_while_deactivating() {
         // Verify that we can't set deactivate on the upstairs when
@@ -3504,6 +3855,96 @@ pub(crate) mod test {
         assert!(up.downstairs.live_repair_in_progress());
     }
 
+    #[tokio::test]
+    async fn test_check_for_repair_lone_downstairs() {
+        let mut ddef = RegionDefinition::default();
+        ddef.set_block_size(512);
+        ddef.set_extent_size(Block::new_512(3));
+        ddef.set_extent_count(4);
+
+        let opts = CrucibleOpts {
+            target: vec!["127.0.0.1:1".parse().unwrap()],
+            ..Default::default()
+        };
+        let (guest, io) = crate::guest::Guest::new(None);
+        let mut up = Upstairs::new(&opts, 0, Some(ddef), io, None);
+        up.force_active().unwrap();
+        set_all_active(&mut up.downstairs);
+        assert!(!guest.take_needs_rebuild());
+
+        // With nothing to repair from, the downstairs goes straight back to
+        // active, but the guest hears that it missed writes.
+        up.ds_transition(ClientId::new(0), DsState::Faulted);
+        up.ds_transition(ClientId::new(0), DsState::LiveRepairReady);
+        up.on_repair_check();
+        assert!(up.repair_check_interval.is_none());
+        assert!(!up.downstairs.live_repair_in_progress());
+        assert_eq!(up.ds_state(ClientId::new(0)), DsState::Active);
+        assert!(guest.take_needs_rebuild());
+        assert!(!guest.take_needs_rebuild());
+    }
+
+    #[tokio::test]
+    async fn can_persist_needs_write_quorum() {
+        let mut ddef = RegionDefinition::default();
+        ddef.set_block_size(512);
+        ddef.set_extent_size(Block::new_512(3));
+        ddef.set_extent_count(4);
+
+        let opts = CrucibleOpts {
+            target: vec![
+                "127.0.0.1:1".parse().unwrap(),
+                "127.0.0.1:2".parse().unwrap(),
+                "127.0.0.1:3".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        let (guest, io) = crate::guest::Guest::new(None);
+        let mut up = Upstairs::new(&opts, 0, Some(ddef), io, None);
+        up.set_can_persist();
+        assert!(!guest.can_persist_writes());
+
+        up.force_active().unwrap();
+        set_all_active(&mut up.downstairs);
+        up.set_can_persist();
+        assert!(guest.can_persist_writes());
+
+        // Writes acked with one of three downstairs would only be in memory
+        for i in 0..2 {
+            up.ds_transition(ClientId::new(i), DsState::Faulted);
+        }
+        up.set_can_persist();
+        assert!(!guest.can_persist_writes());
+    }
+
+    #[tokio::test]
+    async fn fault_downstairs_keeps_write_quorum() {
+        let mut up = create_test_upstairs();
+
+        // Faulting one of three downstairs leaves enough for writes
+        up.apply_control_op(ControlOp::FaultDownstairs {
+            client_id: 0,
+            force: false,
+        })
+        .unwrap();
+        assert_eq!(up.ds_state(ClientId::new(0)), DsState::Faulted);
+
+        // Faulting a second would not, so it needs to be forced
+        let r = up.apply_control_op(ControlOp::FaultDownstairs {
+            client_id: 1,
+            force: false,
+        });
+        assert!(matches!(r, Err(CrucibleError::Unsupported(..))));
+        assert_eq!(up.ds_state(ClientId::new(1)), DsState::Active);
+
+        up.apply_control_op(ControlOp::FaultDownstairs {
+            client_id: 1,
+            force: true,
+        })
+        .unwrap();
+        assert_eq!(up.ds_state(ClientId::new(1)), DsState::Faulted);
+    }
+
     // Deactivate tests
     #[tokio::test]
     async fn deactivate_after_work_completed_write() {
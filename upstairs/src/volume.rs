@@ -247,6 +247,67 @@ impl Volume {
         self.add_subvolume(Arc::new(guest)).await
     }
 
+    /// Builds an erasure-coded region set, with one single-downstairs `Guest`
+    /// per fragment, and adds it as a subvolume
+    pub async fn add_subvolume_create_erasure(
+        &mut self,
+        opts: CrucibleOpts,
+        extent_info: RegionExtentInfo,
+        data_fragments: usize,
+        parity_fragments: usize,
+        gen: u64,
+        producer_registry: Option<ProducerRegistry>,
+    ) -> Result<(), CrucibleError> {
+        if opts.target.len() != data_fragments + parity_fragments {
+            crucible_bail!(
+                GenericError,
+                "erasure coding {data_fragments}+{parity_fragments} needs {} \
+                 targets, got {}",
+                data_fragments + parity_fragments,
+                opts.target.len()
+            );
+        }
+        let region_def = build_region_definition(&extent_info, &opts)?;
+
+        let mut fragments: Vec<Arc<dyn BlockIO + Send + Sync>> = vec![];
+        for (i, target) in opts.target.iter().enumerate() {
+            // The control server and replica belong to the region set as a
+            // whole, so fragments don't get their own.  Each fragment is its
+            // own upstairs though, so it needs its own id and audit log.
+            let fragment_opts = CrucibleOpts {
+                id: erasure::fragment_id(opts.id, i),
+                target: vec![*target],
+                control: None,
+                control_token: None,
+                replica: None,
+                capture: None,
+                audit_log: opts.audit_log.as_ref().map(|p| format!("{p}.{i}")),
+                ..opts.clone()
+            };
+            let log = self.log.new(o!("fragment" => i));
+            let (guest, io) = Guest::new(Some(log));
+            let _join_handle = up_main(
+                fragment_opts,
+                gen,
+                Some(region_def),
+                io,
+                producer_registry.clone(),
+            )?;
+            fragments.push(Arc::new(guest));
+        }
+
+        let block_io = ErasureBlockIO::new(
+            opts.id,
+            extent_info.block_size,
+            extent_info.blocks_per_extent * extent_info.extent_count as u64,
+            data_fragments,
+            parity_fragments,
+            fragments,
+            self.log.new(o!("erasure" => opts.id.to_string())),
+        )?;
+        self.add_subvolume(Arc::new(block_io)).await
+    }
+
     // Add a "parent" source for blocks.
     //
     // Imagine three sub volumes:
@@ -1097,6 +1158,14 @@ impl Volume {
                 opts,
                 gen,
             } => {
+                if opts.target.len() < MIN_REGION_SET_SIZE {
+                    crucible_bail!(
+                        GenericError,
+                        "region needs at least {MIN_REGION_SET_SIZE} \
+                         targets, got {}",
+                        opts.target.len()
+                    );
+                }
                 let mut vol = Volume::new(block_size, log.clone());
                 vol.add_subvolume_create_guest(
                     opts,
@@ -1112,6 +1181,32 @@ impl Volume {
                 Ok(vol)
             }
 
+            VolumeConstructionRequest::ErasureCoded {
+                block_size,
+                blocks_per_extent,
+                extent_count,
+                data_fragments,
+                parity_fragments,
+                opts,
+                gen,
+            } => {
+                let mut vol = Volume::new(block_size, log.clone());
+                vol.add_subvolume_create_erasure(
+                    opts,
+                    RegionExtentInfo {
+                        block_size,
+                        blocks_per_extent,
+                        extent_count,
+                    },
+                    data_fragments as usize,
+                    parity_fragments as usize,
+                    gen,
+                    producer_registry,
+                )
+                .await?;
+                Ok(vol)
+            }
+
             VolumeConstructionRequest::File {
                 id,
                 block_size,
@@ -1134,7 +1229,8 @@ impl Volume {
     //
     // The requirements to allow a new VCR are:
     // 1. Only VolumeConstructionRequests::Volume type is supported.
-    // 2. Sub volumes must all be VolumeConstructionRequest::Region
+    // 2. Sub volumes must all be VolumeConstructionRequest::Region (or
+    //    VolumeConstructionRequest::ErasureCoded with the same layout)
     // 3. Everything must be the same between the two Volumes, except:
     //    A. The new generation number must be greater than the old.
     //    B. The new Volume can have None for read only parent if the
@@ -1424,6 +1520,10 @@ impl Volume {
             (
                 VolumeConstructionRequest::Region { .. },
                 VolumeConstructionRequest::Region { .. },
+            )
+            | (
+                VolumeConstructionRequest::ErasureCoded { .. },
+                VolumeConstructionRequest::ErasureCoded { .. },
             ) => Ok(CompareResult::Region {
                 delta: Self::compare_vcr_region_for_replacement(
                     log, o_vol, n_vol, read_only,
@@ -1437,7 +1537,8 @@ impl Volume {
     }
 
     // Given two VCRs where we expect the only type to be a
-    // VolumeConstructionRequest::Region.  The VCR can be from a sub_volume
+    // VolumeConstructionRequest::Region (or two ErasureCoded region sets,
+    // which must have the same layout).  The VCR can be from a sub_volume
     // or a read_only_parent.  The caller is expected to know how to
     // handle the result depending on what it sends us.
     //
@@ -1463,13 +1564,15 @@ impl Volume {
         n_vol: &VolumeConstructionRequest,
         read_only: bool,
     ) -> Result<VCRDelta, CrucibleError> {
-        // Volumes to compare must all be VolumeConstructionRequest::Region
+        // Volumes to compare must all be VolumeConstructionRequest::Region or
+        // VolumeConstructionRequest::ErasureCoded
         let (
             o_sv_block_size,
             o_sv_blocks_per_extent,
             o_sv_extent_count,
             o_sv_opts,
             o_sv_gen,
+            o_sv_layout,
         ) = match *o_vol {
             VolumeConstructionRequest::Region {
                 block_size,
@@ -1477,7 +1580,24 @@ impl Volume {
                 extent_count,
                 ref opts,
                 gen,
-            } => (block_size, blocks_per_extent, extent_count, opts, gen),
+            } => (block_size, blocks_per_extent, extent_count, opts, gen, None),
+
+            VolumeConstructionRequest::ErasureCoded {
+                block_size,
+                blocks_per_extent,
+                extent_count,
+                data_fragments,
+                parity_fragments,
+                ref opts,
+                gen,
+            } => (
+                block_size,
+                blocks_per_extent,
+                extent_count,
+                opts,
+                gen,
+                Some((data_fragments, parity_fragments)),
+            ),
 
             _ => {
                 crucible_bail!(
@@ -1493,6 +1613,7 @@ impl Volume {
             n_sv_extent_count,
             n_sv_opts,
             n_sv_gen,
+            n_sv_layout,
         ) = match *n_vol {
             VolumeConstructionRequest::Region {
                 block_size,
@@ -1500,7 +1621,24 @@ impl Volume {
                 extent_count,
                 ref opts,
                 gen,
-            } => (block_size, blocks_per_extent, extent_count, opts, gen),
+            } => (block_size, blocks_per_extent, extent_count, opts, gen, None),
+
+            VolumeConstructionRequest::ErasureCoded {
+                block_size,
+                blocks_per_extent,
+                extent_count,
+                data_fragments,
+                parity_fragments,
+                ref opts,
+                gen,
+            } => (
+                block_size,
+                blocks_per_extent,
+                extent_count,
+                opts,
+                gen,
+                Some((data_fragments, parity_fragments)),
+            ),
 
             _ => {
                 crucible_bail!(
@@ -1534,6 +1672,15 @@ impl Volume {
             )
         }
 
+        if o_sv_layout != n_sv_layout {
+            crucible_bail!(
+                ReplaceRequestInvalid,
+                "sub_volume erasure coding mismatch {:?} vs. {:?}",
+                o_sv_layout,
+                n_sv_layout
+            )
+        }
+
         // The original generation number should always be lower than the new.
         // This is only valid for non read-only parent checks.
         if !read_only && o_sv_gen >= n_sv_gen {
@@ -3293,7 +3440,12 @@ mod test {
             key_pem: None,
             root_cert_pem: None,
             control: None,
+            control_token: None,
             read_only: false,
+            client_timeouts: None,
+            replica: None,
+            capture: None,
+            audit_log: None,
         }
     }
 
@@ -3367,6 +3519,72 @@ mod test {
         Ok(())
     }
 
+    fn erasure_vcr(
+        vol_id: Uuid,
+        opts: &CrucibleOpts,
+        parity_fragments: u8,
+        gen: u64,
+    ) -> VolumeConstructionRequest {
+        VolumeConstructionRequest::Volume {
+            id: vol_id,
+            block_size: 512,
+            sub_volumes: vec![VolumeConstructionRequest::ErasureCoded {
+                block_size: 512,
+                blocks_per_extent: 10,
+                extent_count: 9,
+                data_fragments: 2,
+                parity_fragments,
+                opts: opts.clone(),
+                gen,
+            }],
+            read_only_parent: None,
+        }
+    }
+
+    #[test]
+    fn volume_replace_erasure() {
+        // A fragment of an erasure-coded region set can be replaced just like
+        // a mirrored downstairs.
+        let vol_id = Uuid::new_v4();
+        let mut opts = generic_crucible_opts(vol_id);
+        let original = erasure_vcr(vol_id, &opts, 1, 2);
+
+        let original_target = opts.target[2];
+        let new_target: SocketAddr = "127.0.0.1:8888".parse().unwrap();
+        opts.target[2] = new_target;
+        let replacement = erasure_vcr(vol_id, &opts, 1, 3);
+
+        let ReplacementRequestCheck::Valid { old, new } =
+            Volume::compare_vcr_for_target_replacement(
+                original,
+                replacement,
+                &csl(),
+            )
+            .unwrap()
+        else {
+            panic!("wrong variant returned!");
+        };
+        assert_eq!(original_target, old);
+        assert_eq!(new_target, new);
+    }
+
+    #[test]
+    fn volume_replace_erasure_layout_mismatch() {
+        // The erasure coding of a region set can't be changed by a
+        // replacement.
+        let vol_id = Uuid::new_v4();
+        let opts = generic_crucible_opts(vol_id);
+        let original = erasure_vcr(vol_id, &opts, 1, 2);
+        let replacement = erasure_vcr(vol_id, &opts, 2, 3);
+
+        assert!(Volume::compare_vcr_for_target_replacement(
+            original,
+            replacement,
+            &csl(),
+        )
+        .is_err());
+    }
+
     #[test]
     fn volume_replace_rop() {
         // A replacement VCR is provided with one target being
@@ -5057,7 +5275,12 @@ mod test {
                     key_pem: None,
                     root_cert_pem: None,
                     control: None,
+                    control_token: None,
                     read_only: false,
+                    client_timeouts: None,
+                    replica: None,
+                    capture: None,
+                    audit_log: None,
                 },
                 gen: 1,
             }],
@@ -5087,7 +5310,12 @@ mod test {
                         key_pem: None,
                         root_cert_pem: None,
                         control: None,
+                        control_token: None,
                         read_only: false,
+                        client_timeouts: None,
+                        replica: None,
+                        capture: None,
+                        audit_log: None,
                     },
                     gen: 1,
                 },
@@ -5105,7 +5333,12 @@ mod test {
                         key_pem: None,
                         root_cert_pem: None,
                         control: None,
+                        control_token: None,
                         read_only: false,
+                        client_timeouts: None,
+                        replica: None,
+                        capture: None,
+                        audit_log: None,
                     },
                     gen: 1,
                 },
@@ -5135,7 +5368,12 @@ mod test {
                     key_pem: None,
                     root_cert_pem: None,
                     control: None,
+                    control_token: None,
                     read_only: false,
+                    client_timeouts: None,
+                    replica: None,
+                    capture: None,
+                    audit_log: None,
                 },
                 gen: 1,
             }],
@@ -5154,7 +5392,12 @@ mod test {
                         key_pem: None,
                         root_cert_pem: None,
                         control: None,
+                        control_token: None,
                         read_only: false,
+                        client_timeouts: None,
+                        replica: None,
+                        capture: None,
+                        audit_log: None,
                     },
                     gen: 1,
                 },
@@ -5183,7 +5426,12 @@ mod test {
                     key_pem: None,
                     root_cert_pem: None,
                     control: None,
+                    control_token: None,
                     read_only: false,
+                    client_timeouts: None,
+                    replica: None,
+                    capture: None,
+                    audit_log: None,
                 },
                 gen: 1,
             }],
@@ -5205,7 +5453,12 @@ mod test {
                             key_pem: None,
                             root_cert_pem: None,
                             control: None,
+                            control_token: None,
                             read_only: false,
+                            client_timeouts: None,
+                            replica: None,
+                            capture: None,
+                            audit_log: None,
                         },
                         gen: 1,
                     }],